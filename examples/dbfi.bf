@@ -0,0 +1,15 @@
+dbfi: a Brainfuck interpreter written in Brainfuck by Daniel B Cristofani
+from brainfuck dot org slash dbfi dot b and described in the paper
+"A very short self interpreter" by Oleg Mazonka and Daniel B Cristofani
+Licensed under the Creative Commons Attribution ShareAlike license
+
+It reads a program up to an exclamation mark and runs it with the rest of the
+input as its input
+The code below is unchanged from the original
+
+>>>+[[-]>>[-]++>+>+++++++[<++++>>++<-]++>>+>+>+++++[>++>++++++<<-]+>>>,<++[[>[
+->>]<[>>]<<-]<[<]<+>>[>]>[<+>-[[<+>-]>]<[[[-]<]++<-[<+++++++++>[<->-]>>]>>]]<<
+]<]<[[<]>[[>]>>[>>]+[<<]<[<]<+>>-]>[>]+[->>]<<<<[[<<]<[<]+<<[+>+<<-[>-->+<<-[>
++<[>>+<<-]]]>[<+>-]<]++>>-->[>]>>[>>]]<<[>>+<[[<]<]>[[<<]<[<]+[-<+>>-[<<+>++>-
+[<->[<<+>>-]]]<[>+<-]>]>[>]>]>[>>]>>]<<[>>+>>+>>]<<[->>>>>>>>]<<[>.>>>>>>>]<<[
+>->>>>>]<<[>,>>>]<<[>+>]<<[+<<]<]
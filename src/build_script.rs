@@ -0,0 +1,99 @@
+//! Compiling Brainfuck source into object files, e.g. from the build script of another crate
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tempfile::tempdir;
+
+use crate::compiler::compile_function;
+use crate::error::{Error, Result};
+use crate::options::CompileOptions;
+use crate::parser::try_parse;
+use crate::target_abi::{Assembler, LinkerInfo, ABI};
+
+/// Assembles the source with the assembler of the target into an object file in `dir`,
+/// returning its path
+pub fn assemble(asm: &str, link: &LinkerInfo, dir: &Path) -> Result<PathBuf> {
+    let file_asm = dir.join("input.asm");
+    let file_obj = dir.join("output.obj");
+
+    fs::write(file_asm.clone(), asm.as_bytes())?;
+
+    let mut assembler = match &link.assembler {
+        Assembler::Nasm => {
+            let mut nasm = Command::new("nasm");
+            nasm.arg("-f").arg(&link.object_format);
+            nasm
+        },
+        Assembler::Gnu(command) => Command::new(command),
+    };
+    let status = assembler.arg("-o").arg(file_obj.clone()).arg(file_asm).status()?;
+
+    if !status.success() {
+        return Err(Error::Nasm);
+    }
+    Ok(file_obj)
+}
+
+/// Name of the function compiled from the file, its name without the extension
+/// with the characters that can't be used in symbols replaced
+pub fn function_name(path: &Path) -> String {
+    // Other characters can't be used in symbols, which also can't start with a digit
+    let mut name: String = path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// Compiles the source file into an object file, to be called from `build.rs`.
+/// A relative `out_path` is placed in cargo's `OUT_DIR` when set,
+/// and cargo is told to rerun the build script when the source changes.
+/// The object defines a function `int name(void)` like `compile_function`, named by `function_name`
+/// of `out_path`, so that it doesn't clash with the `main` of the crate.
+/// It is also archived as `lib<name>.a` next to it, which cargo is told to link.
+/// Returns the path of the object file.
+pub fn compile_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    src_path: P, out_path: Q, abi: ABI, options: &CompileOptions,
+) -> Result<PathBuf> {
+    let src_path = src_path.as_ref();
+    println!("cargo:rerun-if-changed={}", src_path.display());
+
+    let out_path = match env::var_os("OUT_DIR") {
+        Some(out_dir) if out_path.as_ref().is_relative() => Path::new(&out_dir).join(out_path),
+        _ => out_path.as_ref().to_owned(),
+    };
+    let name = function_name(&out_path);
+
+    let source = fs::read_to_string(src_path)?;
+    let (asm, link) = compile_function(try_parse(&source)?, &name, abi, options)?;
+    let dir = tempdir()?;
+    let file_obj = assemble(&asm, &link, dir.path())?;
+    fs::copy(file_obj, &out_path)?;
+
+    let out_dir = out_path.parent().unwrap_or_else(|| Path::new("."));
+    let archive = out_dir.join(format!("lib{}.a", name));
+    // Members are added to an existing archive
+    if archive.exists() {
+        fs::remove_file(&archive)?;
+    }
+    let status = Command::new("ar")
+        .arg("rcs")
+        .arg(&archive)
+        .arg(&out_path)
+        .status()?;
+    if !status.success() {
+        return Err(Error::Archiver);
+    }
+    println!("cargo:rustc-link-search=native={}", out_dir.display());
+    println!("cargo:rustc-link-lib=static={}", name);
+    Ok(out_path)
+}
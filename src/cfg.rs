@@ -0,0 +1,284 @@
+//! Control-flow graph of the instructions, for passes that need to follow the jumps.
+//! Passes working on windows of the instructions keep running on the linear form.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::instruction::{source_names, Instruction};
+use crate::label::LabelId;
+
+/// Instructions entered only at the start and left only at the end
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// Starting with a label if the block is jumped to, and ending with a jump if it leaves with one
+    pub ops: Vec<Instruction>,
+    /// Indices of the blocks run next, either jumped to or fallen through to.
+    /// Jumps to labels outside the instructions, e.g. the underflow handler, have none.
+    pub successors: Vec<usize>,
+}
+
+/// Instructions split into basic blocks, in their original order with the first block as the entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+impl Cfg {
+    /// Splits the instructions into blocks before each label and after each jump
+    pub fn new(ops: Vec<Instruction>) -> Self {
+        use Instruction::*;
+        let mut blocks: Vec<Vec<Instruction>> = Vec::new();
+        let mut current = Vec::new();
+        for op in ops {
+            if matches!(op, Label(_)) && !current.is_empty() {
+                blocks.push(current);
+                current = Vec::new();
+            }
+            let ends_block = matches!(op, Jump(_) | JumpZero(_) | JumpNonZero(_));
+            current.push(op);
+            if ends_block {
+                blocks.push(current);
+                current = Vec::new();
+            }
+        }
+        if !current.is_empty() {
+            blocks.push(current);
+        }
+
+        let labels: BTreeMap<LabelId, usize> = blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, ops)| match ops.first() {
+                Some(Label(label)) => Some((*label, index)),
+                _ => None,
+            })
+            .collect();
+        let target = |label: &LabelId| labels.get(label).copied();
+        let count = blocks.len();
+        let blocks = blocks
+            .into_iter()
+            .enumerate()
+            .map(|(index, ops)| {
+                let next = Some(index + 1).filter(|next| *next < count);
+                let successors = match ops.last() {
+                    Some(Jump(label)) => target(label).into_iter().collect(),
+                    Some(JumpZero(label) | JumpNonZero(label)) => {
+                        target(label).into_iter().chain(next).collect()
+                    },
+                    _ => next.into_iter().collect(),
+                };
+                Block { ops, successors }
+            })
+            .collect();
+        Self { blocks }
+    }
+
+    /// Whether a path from the entry reaches each block
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            if index >= self.blocks.len() || reachable[index] {
+                continue;
+            }
+            reachable[index] = true;
+            pending.extend(&self.blocks[index].successors);
+        }
+        reachable
+    }
+
+    /// Whether each block may be entered from outside the instructions: the first one,
+    /// and those starting with a global label or one named by other instructions than jumps,
+    /// e.g. in the source of a black box or as the underflow handler of a pointer check
+    pub fn external_entries(&self) -> Vec<bool> {
+        use Instruction::*;
+        let names = source_names(self.blocks.iter().flat_map(|block| &block.ops).filter(|op| {
+            matches!(
+                op,
+                BlackBox(_, _) | NamedBlackBox(_, _, _) | CheckPointer(_, _) | MovImmVar(_, _)
+            )
+        }));
+        self.blocks
+            .iter()
+            .enumerate()
+            .map(|(index, block)| match block.ops.first() {
+                _ if index == 0 => true,
+                Some(Label(label)) => !label.is_local() || names.contains(&label.name()),
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// Facts holding at the start of each block, those holding at the end of all of its predecessors,
+    /// found by following the edges until they settle. Blocks entered from outside start with `entry`.
+    /// `transfer` gives the facts at the end of a block from those at its start,
+    /// and `meet` the facts holding on both of two paths. Blocks no path reaches have `None`.
+    pub fn forward<T, F, M>(&self, entry: &T, transfer: F, meet: M) -> Vec<Option<T>>
+    where
+        T: Clone + PartialEq,
+        F: Fn(&[Instruction], &T) -> T,
+        M: Fn(&T, &T) -> T,
+    {
+        let mut facts: Vec<Option<T>> = self
+            .external_entries()
+            .into_iter()
+            .map(|external| Some(entry.clone()).filter(|_| external))
+            .collect();
+        let mut pending: Vec<usize> = (0..facts.len()).filter(|index| facts[*index].is_some()).collect();
+        while let Some(index) = pending.pop() {
+            let exit = match &facts[index] {
+                Some(start) => transfer(&self.blocks[index].ops, start),
+                None => continue,
+            };
+            for &successor in &self.blocks[index].successors {
+                let merged = match &facts[successor] {
+                    Some(start) => meet(start, &exit),
+                    None => exit.clone(),
+                };
+                if facts[successor].as_ref() != Some(&merged) {
+                    facts[successor] = Some(merged);
+                    pending.push(successor);
+                }
+            }
+        }
+        facts
+    }
+
+    /// Instructions of the blocks in order
+    pub fn into_instructions(self) -> Vec<Instruction> {
+        self.blocks.into_iter().flat_map(|block| block.ops).collect()
+    }
+
+    /// Graph in Graphviz DOT format, with a node of the instructions of each block.
+    /// Edges of conditional jumps are labeled with the zero flag that takes them.
+    pub fn to_dot(&self) -> String {
+        use Instruction::*;
+        let mut dot = String::from("digraph cfg {\n    node [shape=box, fontname=monospace];\n");
+        for (index, block) in self.blocks.iter().enumerate() {
+            // Left-justified lines, with the characters special in quoted strings escaped
+            let mut lines = String::new();
+            for op in &block.ops {
+                for line in op.to_source().lines() {
+                    lines.push_str(&line.trim().replace('\\', "\\\\").replace('"', "\\\""));
+                    lines.push_str("\\l");
+                }
+            }
+            dot.push_str(&format!("    b{} [label=\"{}\"];\n", index, lines));
+            for (i, successor) in block.successors.iter().enumerate() {
+                // The jump target comes first, so a jump to the next block has two edges
+                let jumped = i == 0
+                    && match (block.ops.last(), self.blocks[*successor].ops.first()) {
+                        (Some(Jump(target) | JumpZero(target) | JumpNonZero(target)), Some(Label(label))) => {
+                            target == label
+                        },
+                        _ => false,
+                    };
+                let label = match (block.ops.last(), jumped) {
+                    (Some(JumpZero(_)), true) | (Some(JumpNonZero(_)), false) => " [label=\"zero\"]",
+                    (Some(JumpNonZero(_)), true) | (Some(JumpZero(_)), false) => " [label=\"nonzero\"]",
+                    _ => "",
+                };
+                dot.push_str(&format!("    b{} -> b{}{};\n", index, successor, label));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cfg;
+    use crate::instruction::Instruction::*;
+    use crate::instruction::{Effects, Instruction};
+    use crate::instruction::Register64::*;
+
+    #[test]
+    fn test_blocks() {
+        // `[+]` followed by a jump out of the instructions
+        let ops = vec![
+            IsZeroPtr8(rbx),
+            JumpZero(".end".into()),
+            Label(".start".into()),
+            AddPtr8Imm(rbx, 1),
+            JumpNonZero(".start".into()),
+            Label(".end".into()),
+            Label(".after".into()),
+            Jump(".underflow".into()),
+            AddImm(rbx, 1),
+        ];
+        let cfg = Cfg::new(ops.clone());
+        let blocks: Vec<_> = cfg
+            .blocks
+            .iter()
+            .map(|block| (block.ops.len(), block.successors.clone()))
+            .collect();
+        assert_eq!(blocks, [
+            (2, vec![2, 1]),
+            (3, vec![1, 2]),
+            (1, vec![3]),
+            (2, vec![]),
+            (1, vec![])
+        ]);
+        assert_eq!(cfg.reachable(), [true, true, true, true, false]);
+        assert_eq!(cfg.into_instructions(), ops);
+    }
+
+    #[test]
+    fn test_forward() {
+        // Whether the cell was changed, on every path with `&&` and on some path with `||`
+        let ops = vec![
+            IsZeroPtr8(rbx),
+            JumpZero(".end".into()),
+            Label(".start".into()),
+            AddPtr8Imm(rbx, 1),
+            JumpNonZero(".start".into()),
+            Label(".end".into()),
+            Jump(".end".into()),
+            IsZeroPtr8(rbx),
+        ];
+        let cfg = Cfg::new(ops);
+        let changed =
+            |ops: &[Instruction], start: &bool| *start || ops.iter().any(|op| matches!(op, AddPtr8Imm(_, _)));
+        let every = cfg.forward(&false, changed, |a, b| *a && *b);
+        assert_eq!(every, [Some(false), Some(false), Some(false), None]);
+        let some = cfg.forward(&false, changed, |a, b| *a || *b);
+        assert_eq!(some, [Some(false), Some(true), Some(true), None]);
+
+        // Global labels and those named in inline assembly may be jumped to from anywhere
+        let cfg = Cfg::new(vec![
+            BlackBox("jmp .inline".to_owned(), Effects::VOLATILE),
+            Label(".inline".into()),
+            Label(".local".into()),
+            Label("global".into()),
+        ]);
+        assert_eq!(cfg.external_entries(), [true, true, false, true]);
+    }
+
+    #[test]
+    fn test_dot() {
+        // `[+]`
+        let ops = vec![
+            IsZeroPtr8(rbx),
+            JumpZero(".end".into()),
+            Label(".start".into()),
+            AddPtr8Imm(rbx, 1),
+            JumpNonZero(".start".into()),
+            Label(".end".into()),
+        ];
+        let expected = r#"digraph cfg {
+    node [shape=box, fontname=monospace];
+    b0 [label="cmp byte [rbx], 0\ljz .end\l"];
+    b0 -> b2 [label="zero"];
+    b0 -> b1 [label="nonzero"];
+    b1 [label=".start:\linc byte [rbx]\ljnz .start\l"];
+    b1 -> b1 [label="nonzero"];
+    b1 -> b2 [label="zero"];
+    b2 [label=".end:\l"];
+}
+"#;
+        assert_eq!(Cfg::new(ops).to_dot(), expected);
+    }
+}
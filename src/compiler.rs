@@ -1,13 +1,26 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
-use std::ops::Index;
+use std::hash::Hasher;
+use std::io::{self, Write};
+use std::mem;
+use std::thread;
 
-use crate::instruction::{Effects, Instruction, Register64};
-use crate::optimizer;
-use crate::parser::Token;
-use crate::target_abi::{self, LinkerInfo, ABI};
+use crate::cfg::Cfg;
+use crate::error::{CompileError, CompileResult, Error, Result, Warning, Warnings};
+use crate::instruction::{Effects, Instruction, Register64, UNDERFLOW_LABEL};
+use crate::interpreter::{BufferIO, TapeSnapshot};
+use crate::llvm;
+use crate::machine::{Machine, Op};
+use crate::optimizer::{self, LoopNotes, LoopSources, PassStats};
+use crate::options::{CompileOptions, CpuFeature, OptGoal, TapeMode, WideOutput};
+use crate::parser::{Span, Token};
+use crate::report::Report;
+use crate::riscv;
+use crate::stats::{StepStats, Stats};
+use crate::target_abi::{self, LinkerInfo, Operations, ABI, EMBEDDED_INPUT_LABEL, EMBEDDED_POSITION_LABEL};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct Label(pub usize);
+pub(crate) struct Label(pub usize);
 impl fmt::Display for Label {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, ".label{}", self.0)
@@ -17,18 +30,70 @@ impl fmt::Display for Label {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct State {
     scope: Vec<(Label, Label)>,
+    /// Labels of each loop in source order, with nesting depth
+    loops: Vec<(Label, Label, usize)>,
     next_label: Label,
     steps: Vec<Step>,
+    /// Source commands of each step, kept along the steps only by appending and combining them
+    step_sources: Vec<String>,
+    /// Bytes of the embedded input consumed by compile-time evaluation
+    input_position: usize,
+    /// What the optimizations of the steps did to loops, at their source position
+    notes: LoopNotes,
+    /// Warnings about the steps, without the ones of the optimizer passes
+    warnings: Warnings,
+    /// Number of steps after each optimization of the steps
+    step_stats: StepStats,
 }
 impl State {
     pub fn new() -> Self {
         Self {
             scope: Vec::new(),
+            loops: Vec::new(),
             next_label: Label(0),
             steps: Vec::new(),
+            step_sources: Vec::new(),
+            input_position: 0,
+            notes: LoopNotes::new(),
+            warnings: Warnings::new(),
+            step_stats: StepStats::default(),
         }
     }
 
+    /// Lowers and optimizes a program
+    pub fn from_tokens(tokens: Vec<Token>, options: &CompileOptions) -> CompileResult<Self> {
+        options.validate()?;
+        if options.tape == TapeMode::Standard {
+            if let Some(index) = prefix_underflow(&tokens) {
+                return Err(CompileError::PointerUnderflow(index));
+            }
+        }
+        let mut state = Self::new();
+        state.step_stats.tokens = tokens.len();
+        for token in tokens {
+            state.append(token);
+        }
+        state.warn_empty_loops(options);
+        // Denied, a deep nesting stops compiling before the optimizer spends its time on it
+        if state.warn_deep_nesting(options) {
+            state.warnings.check(options.deny_warnings)?;
+        }
+        state.optimize(options);
+        log::debug!(
+            "{} tokens, {} steps after combining, {} after startup evaluation",
+            state.step_stats.tokens,
+            state.step_stats.combined,
+            state.step_stats.after_startup
+        );
+        if !options.wide_cells.is_empty() && pointer_cells(&state.steps).is_none() {
+            return Err(CompileError::InvalidOption(
+                "wide_cells",
+                "the pointer must be at the same cell whenever a loop starts or ends",
+            ));
+        }
+        Ok(state)
+    }
+
     fn get_label(&mut self) -> Label {
         let result = self.next_label;
         self.next_label = Label(self.next_label.0 + 1);
@@ -36,6 +101,11 @@ impl State {
     }
 
     pub fn append(&mut self, token: Token) {
+        // A loop is a check and a label, the source is given to the check
+        self.step_sources.push(match token {
+            Token::InlineAsm(_) => String::new(),
+            token => token.to_string(),
+        });
         match token {
             Token::Next => self.steps.push(Step::Next(1)),
             Token::Prev => self.steps.push(Step::Prev(1)),
@@ -43,9 +113,11 @@ impl State {
             Token::Decrement => self.steps.push(Step::Add(255)),
             Token::Output => self.steps.push(Step::Output),
             Token::Input => self.steps.push(Step::Input),
+            Token::InlineAsm(asm) => self.steps.push(Step::InlineAsm(asm)),
             Token::JumpForwards => {
                 let source_label = self.get_label();
                 let target_label = self.get_label();
+                self.loops.push((source_label, target_label, self.scope.len()));
                 self.scope.push((source_label, target_label));
                 self.steps.push(Step::JumpToIf(false, target_label));
                 self.steps.push(Step::Label(source_label));
@@ -56,12 +128,21 @@ impl State {
                 self.steps.push(Step::Label(target_label));
             },
         }
+        self.step_sources.resize(self.steps.len(), String::new());
     }
 
-    /// Simple peephole instruction combinator
-    fn combine(a: Step, b: Step) -> Vec<Step> {
+    /// Simple peephole instruction combinator.
+    /// Without `wrapping` adds are only combined while the sum fits in a signed byte,
+    /// as wider cells take them as signed deltas.
+    fn combine(a: Step, b: Step, wrapping: bool) -> Vec<Step> {
         if let Step::Add(v0) = a {
             if let Step::Add(v1) = b {
+                let signed = |v: u8| i16::from(v) - if v < 128 { 0 } else { 256 };
+                if !wrapping && !(-128..128).contains(&(signed(v0) + signed(v1))) {
+                    return vec![a, b];
+                }
+                // Cells are bytes, so wrapping is intended: 256 `+` add nothing.
+                // The `Add(0)` is kept, as the add it is lowered to still sets the zero flag.
                 vec![Step::Add(v0.wrapping_add(v1))]
             } else {
                 vec![a, b]
@@ -83,15 +164,8 @@ impl State {
         } else if let Step::Prev(v0) = a {
             if let Step::Prev(v1) = b {
                 vec![Step::Prev(v0.checked_add(v1).unwrap())]
-            } else if let Step::Next(v1) = b {
-                if v0 == v1 {
-                    vec![]
-                } else if v0 < v1 {
-                    vec![Step::Next(v0 - v1)]
-                } else {
-                    vec![Step::Prev(v1 - v0)]
-                }
             } else {
+                // Not combined with a following `Next`, as moving left of the tape must still fail
                 vec![a, b]
             }
         } else {
@@ -101,45 +175,119 @@ impl State {
 
     /// Simple peephole optimization pass
     fn optimize_peephole_combine(&mut self) {
-        let mut index: usize = 0;
-        while index + 1 < self.steps.len() {
-            let a = self.steps.remove(index);
-            let b = self.steps.remove(index);
-            let c = Self::combine(a, b);
-            for (i, v) in c.iter().copied().enumerate() {
-                self.steps.insert(index + i, v);
+        self.combine_steps(true);
+    }
+
+    fn combine_steps(&mut self, wrapping: bool) {
+        let sources = self.step_sources.len() == self.steps.len();
+        // Steps are combined at the front of the rest until they don't change, then moved to done
+        let mut done = Vec::with_capacity(self.steps.len());
+        let mut rest: VecDeque<Step> = mem::take(&mut self.steps).into();
+        let mut done_sources = Vec::new();
+        let mut rest_sources: VecDeque<String> = if sources {
+            mem::take(&mut self.step_sources).into()
+        } else {
+            VecDeque::new()
+        };
+        while rest.len() >= 2 {
+            let a = rest.pop_front().unwrap();
+            let b = rest.pop_front().unwrap();
+            let c = Self::combine(a, b, wrapping);
+            for v in c.iter().rev() {
+                rest.push_front(*v);
+            }
+            if sources && c.len() < 2 {
+                let a_source = rest_sources.pop_front().unwrap();
+                let b_source = rest_sources.pop_front().unwrap();
+                if c.len() == 1 {
+                    rest_sources.push_front(a_source + &b_source);
+                }
             }
             if vec![a, b] == c {
-                index += 1;
+                done.extend(rest.pop_front());
+                if sources {
+                    done_sources.extend(rest_sources.pop_front());
+                }
             }
         }
+        done.extend(rest);
+        self.steps = done;
+        if sources {
+            done_sources.extend(rest_sources);
+            self.step_sources = done_sources;
+        }
     }
 
-    /// Runs programs until some input is required.
-    /// This also fully reduces programs with no input.
-    fn optimize_startup(&mut self) {
+    /// Removes the bodies of loops that only add zero to the current cell, like `[+-]`.
+    /// Such a loop is skipped or never ends, which the remaining empty loop still does.
+    /// Loops that were empty before combining have been warned about already.
+    fn optimize_no_effect_loops(&mut self, warned: &HashSet<Label>, options: &CompileOptions) {
+        let sources = self.step_sources.len() == self.steps.len();
+        let mut emptied = HashSet::new();
+        let mut index: usize = 0;
+        while index < self.steps.len() {
+            if let Step::Label(start) = self.steps[index] {
+                if let Some(length) = no_effect_body(&self.steps[index + 1..], start) {
+                    self.steps.drain(index + 1..=index + length);
+                    if sources {
+                        self.step_sources.drain(index + 1..=index + length);
+                    }
+                    if !warned.contains(&start) {
+                        emptied.insert(start);
+                    }
+                }
+            }
+            index += 1;
+        }
+        self.warn_infinite_loops(&emptied, options);
+        self.note_loops(&emptied, "Removed loop body without effect", options);
+    }
+
+    /// Runs the program at compile time until some input is required,
+    /// or until the evaluation budget has been used.
+    /// Input embedded in the binary is read like the compiled program would.
+    /// Returns the end state, and whether the execution completed.
+    fn evaluate<'a>(&self, options: &'a CompileOptions) -> (StepInterpreterState<'a>, bool) {
+        let cells = match options.tape {
+            TapeMode::Standard => options.tape_size,
+            TapeMode::Bidirectional => options.tape_size - options.tape_size / 2,
+        };
         let mut intp = StepInterpreter {
             steps: &self.steps,
+            // Without input every `,` reads end of input, like at the end of embedded input
+            embedded_input: options.embedded_input.is_some() || options.assume_no_input,
+            max_cells: cells.min(MAX_EVAL_CELLS),
             state: StepInterpreterState {
                 index: 0,
-                tape: Tape::new(),
-                pointer: 0,
-                output: Vec::new(),
+                machine: Machine::new(options.eof),
+                io: BufferIO::new(options.embedded_input.as_deref().unwrap_or_default()),
+                iterations: HashMap::new(),
             },
         };
-        while !intp.done() {
-            if !intp.step() {
+        for _ in 0..options.eval_budget {
+            if intp.done() || !intp.step() {
                 break;
             }
         }
-        if intp.done() {
+        let done = intp.done();
+        (intp.state, done)
+    }
+
+    /// Runs programs until some input is required.
+    /// This also fully reduces programs with no input.
+    fn optimize_startup(&mut self, options: &CompileOptions) {
+        let (mut end_state, done) = self.evaluate(options);
+        self.input_position = options
+            .embedded_input
+            .as_ref()
+            .map_or(0, |input| input.len() - end_state.io.input.len());
+        if done {
             // Whole execution complete, the program takes no input,
             // so just print the correct output and exit
-            let end_state = intp.state;
             let mut new_steps = Vec::new();
 
             // Print initial output
-            for v in end_state.output {
+            for v in end_state.io.output {
                 // Output value
                 new_steps.push(Step::Add(v));
                 new_steps.push(Step::Output);
@@ -151,12 +299,11 @@ impl State {
             }
             self.steps = new_steps;
         } else {
-            intp.state.tape.trim();
-            let end_state = intp.state;
+            end_state.machine.tape.trim();
             let mut new_steps = Vec::new();
 
             // Print initial output
-            for v in end_state.output {
+            for v in end_state.io.output {
                 // Output value
                 new_steps.push(Step::Add(v));
                 new_steps.push(Step::Output);
@@ -167,19 +314,41 @@ impl State {
                 new_steps.push(Step::JumpToIf(true, label_zero));
             }
 
-            // Insert tape contents
-            let tape_len = end_state.tape.0.len();
-            for v in end_state.tape.0 {
+            // Insert tape contents.
+            // The output leaves the origin cell zero, so the whole tape is as at the first input.
+            let pointer = end_state.machine.pointer;
+            let tape_len = end_state.machine.tape.0.len();
+            for v in end_state.machine.tape.0 {
                 new_steps.push(Step::Add(v));
                 new_steps.push(Step::Next(1));
             }
 
             // Adjust tape pointer
-            if tape_len > end_state.pointer {
-                new_steps.push(Step::Prev((tape_len - end_state.pointer) as u64));
-            } else if tape_len < end_state.pointer {
-                new_steps.push(Step::Next((end_state.pointer - tape_len) as u64));
+            if tape_len > pointer {
+                new_steps.push(Step::Prev((tape_len - pointer) as u64));
+            } else if tape_len < pointer {
+                new_steps.push(Step::Next((pointer - tape_len) as u64));
+            }
+
+            // Loops before the position to continue from ran to completion, and are left unreachable.
+            // Loops inside a loop still running at that position may run again.
+            let mut evaluated = HashSet::new();
+            let mut ended = Vec::new();
+            let mut depth = 0;
+            for step in &self.steps[..end_state.index] {
+                match step {
+                    Step::JumpToIf(false, _) => depth += 1,
+                    Step::JumpToIf(true, label) => {
+                        depth -= 1;
+                        ended.push(*label);
+                        if depth == 0 {
+                            evaluated.extend(ended.drain(..));
+                        }
+                    },
+                    _ => {},
+                }
             }
+            self.note_loops(&evaluated, "Evaluated loop at compile time", options);
 
             // Jump to proper position in code to continue
             if end_state.index != 0 {
@@ -193,78 +362,884 @@ impl State {
         }
     }
 
-    /// Run optimizations
-    pub fn optimize(&mut self) {
+    /// Unrolls straight-line loops whose trip count is known at compile time,
+    /// e.g. from the cells stored by the startup evaluation.
+    /// Loops that would unroll into more than the unroll budget of steps are kept.
+    fn optimize_known_loops(&mut self, options: &CompileOptions) {
+        if options.unroll_budget() == 0 {
+            return;
+        }
+        let mut result = Vec::new();
+        // `None` where the code is unreachable
+        let mut known = Some(KnownCells::zeroed());
+        // State at each jump over unreachable code
+        let mut jumps: HashMap<Label, KnownCells> = HashMap::new();
+        let mut index = 0;
+        while index < self.steps.len() {
+            let step = self.steps[index];
+            // Only loops ending with the label their check skips to, so that another label there,
+            // such as the one resuming after the startup evaluation, isn't dropped with the loop
+            if let (Some(end), Some(state)) = (loop_end(&self.steps, index), &known) {
+                if let Some((unrolled, after)) =
+                    state.unroll(&self.steps[index + 2..end], options.unroll_budget())
+                {
+                    result.extend(unrolled);
+                    known = Some(after);
+                    // Skip the loop, including the label after it
+                    index = end + 2;
+                    continue;
+                }
+            }
+
+            known = match step {
+                Step::JumpTo(label) => {
+                    if let Some(state) = known {
+                        jumps.insert(label, state);
+                    }
+                    None
+                },
+                // Only the fall through of a loop reaches the label after it, with the cell zero
+                Step::Label(_) if index > 0 && matches!(self.steps[index - 1], Step::JumpToIf(true, _)) => {
+                    Some(KnownCells::after_loop())
+                },
+                // Jumped to from unreachable code, or from anywhere
+                Step::Label(label) => match (known, jumps.remove(&label)) {
+                    (None, Some(state)) => Some(state),
+                    _ => Some(KnownCells::unknown()),
+                },
+                Step::JumpToIf(..) => known,
+                _ => known.map(|mut state| {
+                    state.apply(step);
+                    state
+                }),
+            };
+            result.push(step);
+            index += 1;
+        }
+        self.steps = result;
+    }
+
+    /// Unrolls straight-line loops that the profile shows iterating many times per entry,
+    /// repeating the body with an exit check between the copies.
+    /// Loops with inline assembly are kept, as its labels can't be repeated.
+    fn optimize_hot_loops(&mut self, options: &CompileOptions) {
+        for (index, &(source, target, _)) in self.loops.iter().enumerate() {
+            let counts = match options.profile.get(index) {
+                Some(Some(counts)) if counts.entries > 0 => counts,
+                _ => continue,
+            };
+            if counts.iterations / counts.entries < options.hot_loop_iterations {
+                continue;
+            }
+
+            // The loop is gone if the startup evaluation removed it
+            let start = match self.steps.iter().position(|s| *s == Step::Label(source)) {
+                Some(position) => position + 1,
+                None => continue,
+            };
+            let end = start
+                + self.steps[start..]
+                    .iter()
+                    .position(|s| *s == Step::JumpToIf(true, source))
+                    .unwrap();
+            let body = self.steps[start..end].to_vec();
+            if body.is_empty()
+                || body.iter().any(|s| {
+                    matches!(
+                        s,
+                        Step::Label(_) | Step::JumpTo(_) | Step::JumpToIf(..) | Step::InlineAsm(_)
+                    )
+                })
+            {
+                continue;
+            }
+
+            let factor = (options.max_unroll / body.len()).min(options.hot_loop_copies());
+            let mut unrolled = Vec::new();
+            for _ in 1..factor {
+                unrolled.extend_from_slice(&body);
+                unrolled.push(Step::JumpToIf(false, target));
+            }
+            self.steps.splice(start..start, unrolled);
+        }
+    }
+
+    /// Replaces loops that run at most once, as their cell is zero or one on entry
+    /// and the body clears it, with a check skipping the body
+    fn optimize_conditional_loops(&mut self) {
+        self.steps = conditional_loops(&self.steps, PossibleCells::zeroed());
+    }
+
+    /// Start labels of the loops whose jump back is still in the program
+    fn loop_jumps(&self) -> HashSet<Label> {
+        self.steps
+            .iter()
+            .filter_map(|s| match s {
+                Step::JumpToIf(true, label) => Some(*label),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Source positions of the loops that had a jump back in `before`, but no longer do
+    fn removed_loops(&self, before: &HashSet<Label>, options: &CompileOptions) -> Vec<Span> {
+        let after = self.loop_jumps();
+        self.loops
+            .iter()
+            .zip(&options.loop_spans)
+            .filter(|((source, _, _), _)| before.contains(source) && !after.contains(source))
+            .map(|(_, span)| *span)
+            .collect()
+    }
+
+    /// Notes and logs a rewrite of the loops starting at the labels, at their source position
+    fn note_loops(&mut self, labels: &HashSet<Label>, rewrite: &str, options: &CompileOptions) {
+        let spans: Vec<Span> = self
+            .loops
+            .iter()
+            .zip(&options.loop_spans)
+            .filter(|((source, _, _), _)| labels.contains(source))
+            .map(|(_, span)| *span)
+            .collect();
+        for span in spans {
+            log::debug!("{} at line {}", rewrite, span);
+            self.notes.push((span, rewrite.to_owned()));
+        }
+    }
+
+    /// Notes and logs the loops an optimization removed since `before`, at their source position
+    fn note_removed_loops(&mut self, before: &HashSet<Label>, rewrite: &str, options: &CompileOptions) {
+        for span in self.removed_loops(before, options) {
+            log::debug!("{} at line {}", rewrite, span);
+            self.notes.push((span, rewrite.to_owned()));
+        }
+    }
+
+    /// Warns about the loops with an empty body, at their source position
+    fn warn_empty_loops(&mut self, options: &CompileOptions) {
+        let empty = self.empty_loops();
+        self.warn_infinite_loops(&empty, options);
+    }
+
+    /// Start labels of the loops without a body
+    fn empty_loops(&self) -> HashSet<Label> {
+        self.steps
+            .windows(2)
+            .filter_map(|pair| match pair {
+                [Step::Label(start), Step::JumpToIf(true, target)] if start == target => Some(*start),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Warns that the loops starting at the labels never end once entered
+    fn warn_infinite_loops(&mut self, empty: &HashSet<Label>, options: &CompileOptions) {
+        for (_, span) in self
+            .loops
+            .iter()
+            .zip(&options.loop_spans)
+            .filter(|((source, _, _), _)| empty.contains(source))
+        {
+            self.warnings.push(Warning::InfiniteLoop(*span));
+        }
+    }
+
+    /// Warns about the first of the most deeply nested loops if it is deeper than `max_loop_depth`.
+    /// Returns whether it warned.
+    fn warn_deep_nesting(&mut self, options: &CompileOptions) -> bool {
+        let deepest = self
+            .loops
+            .iter()
+            .enumerate()
+            .rev()
+            .max_by_key(|(_, &(_, _, depth))| depth);
+        match deepest {
+            Some((index, &(_, _, depth))) if depth >= options.max_loop_depth => {
+                let span = options.loop_spans.get(index).copied();
+                self.warnings.push(Warning::DeepNesting(depth + 1, span));
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Label starting each loop in the instructions, with the source position of the loop
+    fn loop_sources(&self, options: &CompileOptions) -> LoopSources {
+        self.loops
+            .iter()
+            .zip(&options.loop_spans)
+            .map(|(&(source, _, _), span)| (format!("{}", source).into(), *span))
+            .collect()
+    }
+
+    /// Labels starting and ending each loop in the instructions,
+    /// with the source positions of its `[` and `]` respectively
+    fn loop_lines(&self, options: &CompileOptions) -> LoopSources {
+        self.loops
+            .iter()
+            .zip(options.loop_spans.iter().zip(&options.loop_end_spans))
+            .flat_map(|(&(source, target, _), (start, end))| {
+                vec![
+                    (format!("{}", source).into(), *start),
+                    (format!("{}", target).into(), *end),
+                ]
+            })
+            .collect()
+    }
+
+    /// Run optimizations enabled by the optimization level
+    pub fn optimize(&mut self, options: &CompileOptions) {
+        if !options.wide_cells.is_empty() {
+            // The other optimizations evaluate the program with byte cells
+            if options.opt_level >= 1 {
+                self.combine_steps(false);
+            }
+            self.step_stats.combined = self.steps.len();
+            self.step_stats.after_startup = self.steps.len();
+            return;
+        }
+        if options.opt_level >= 1 {
+            let warned = self.empty_loops();
+            self.optimize_peephole_combine();
+            self.optimize_no_effect_loops(&warned, options);
+        }
+        self.step_stats.combined = self.steps.len();
+        self.step_stats.after_startup = self.steps.len();
+        if options.opt_level >= 2 {
+            let before = self.loop_jumps();
+            self.optimize_startup(options);
+            self.step_stats.after_startup = self.steps.len();
+            self.note_removed_loops(&before, "Evaluated loop at compile time", options);
+            let before = self.loop_jumps();
+            self.optimize_known_loops(options);
+            self.note_removed_loops(&before, "Unrolled loop with a known trip count", options);
+            self.optimize_peephole_combine();
+            if options.conditional_loops {
+                let before = self.loop_jumps();
+                self.optimize_conditional_loops();
+                self.note_removed_loops(
+                    &before,
+                    "Replaced loop running at most once with a check",
+                    options,
+                );
+            }
+            // Copies of the loop body only make the code faster
+            if options.hot_loop_copies() > 1 {
+                self.optimize_hot_loops(options);
+            }
+        }
+    }
+
+    /// Output of the program, if it takes no input and terminates within the evaluation budget
+    pub fn precompute_output(&mut self, options: &CompileOptions) -> Option<Vec<u8>> {
+        self.optimize_peephole_combine();
+        let (end_state, done) = self.evaluate(options);
+        if done {
+            Some(end_state.io.output)
+        } else {
+            None
+        }
+    }
+
+    /// Tape reached by compile-time evaluation, and whether the execution completed.
+    /// Otherwise evaluation stopped at the first input or when the budget was used.
+    pub fn evaluated_tape(&mut self, options: &CompileOptions) -> (TapeSnapshot, bool) {
         self.optimize_peephole_combine();
-        self.optimize_startup();
+        let (mut end_state, done) = self.evaluate(options);
+        end_state.machine.tape.trim();
+        let tape = TapeSnapshot {
+            cells: end_state.machine.tape.0,
+            pointer: end_state.machine.pointer,
+        };
+        (tape, done)
     }
 
-    pub fn to_assembly(&self, abi: ABI) -> String {
-        let mut abi_ops = abi.operations();
+    /// Iteration count of each loop in source order,
+    /// if compile-time evaluation determines it fully
+    pub fn loop_iterations(&mut self, options: &CompileOptions) -> Vec<Option<u64>> {
+        self.optimize_peephole_combine();
+        let (end_state, done) = self.evaluate(options);
+        let position = |label| self.steps.iter().position(|s| *s == Step::Label(label)).unwrap();
+
+        // Loops ending before the resume point run again only if an enclosing loop does
+        let mut outermost_end = 0;
+        self.loops
+            .iter()
+            .map(|&(source, target, depth)| {
+                if depth == 0 {
+                    outermost_end = position(target);
+                }
+                if done || outermost_end < end_state.index {
+                    let count = |label| end_state.iterations.get(&label).copied().unwrap_or(0);
+                    Some(count(source) + count(target))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
 
-        let ptr_reg = Register64::rbx;
-        let steps: Vec<Instruction> = self
+    /// Fails if a jump targets a label that none of the steps defines,
+    /// which the optimizer passes and the backends assume can't happen
+    fn check_jumps(&self) -> CompileResult<()> {
+        let labels: HashSet<Label> = self
             .steps
             .iter()
-            .flat_map(|x| x.to_assembly(ptr_reg, &mut *abi_ops))
+            .filter_map(|step| match step {
+                Step::Label(label) => Some(*label),
+                _ => None,
+            })
             .collect();
-        let startup: Vec<Instruction> = abi_ops.startup();
-        let exit: Vec<Instruction> = abi_ops.exit();
+        for step in &self.steps {
+            if let Step::JumpTo(label) | Step::JumpToIf(_, label) = step {
+                if !labels.contains(label) {
+                    return Err(CompileError::MissingLabel(label.to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let body = optimizer::optimize(
-            startup
+    /// Instructions of the program before the optimizer passes, ending with exit
+    fn to_instructions(
+        &self, abi_ops: &mut dyn target_abi::Operations, options: &CompileOptions,
+    ) -> Vec<Instruction> {
+        let mut steps: Vec<Vec<Instruction>> = if options.wide_cells.is_empty() {
+            self.steps
                 .iter()
-                .chain(steps.iter())
-                .chain(exit.iter())
-                .cloned()
-                .collect(),
-        );
-        let (body, data) = optimizer::separate_data(body);
-
-        let header = vec![
-            Instruction::BlackBox("sub rsp, $arraylen".to_owned(), Effects::VOLATILE),
-            Instruction::BlackBox("mov rcx, $arraylen".to_owned(), Effects::VOLATILE),
-            Instruction::BlackBox("mov rdi, rsp".to_owned(), Effects::VOLATILE),
-            Instruction::BlackBox("xor al, al".to_owned(), Effects::VOLATILE),
-            Instruction::BlackBox("rep stosb".to_owned(), Effects::VOLATILE),
-            Instruction::BlackBox("mov $pointer, rsp".to_owned(), Effects::VOLATILE),
-            Instruction::BlackBox("sub rsp, 8".to_owned(), Effects::VOLATILE),
-        ];
+                .map(|x| x.to_assembly(POINTER_REG, BASE_REG, abi_ops, options))
+                .collect()
+        } else {
+            let cells = pointer_cells(&self.steps).expect("Checked when the steps were optimized");
+            self.steps
+                .iter()
+                .zip(cells)
+                .map(|(x, cell)| x.to_wide_assembly(cell, POINTER_REG, abi_ops, options))
+                .collect()
+        };
+        if options.source_comments && self.step_sources.len() == self.steps.len() {
+            for (ops, source) in steps.iter_mut().zip(&self.step_sources) {
+                if !source.is_empty() {
+                    ops.insert(0, Instruction::BlackBox(format!("; {}", source), Effects::VOLATILE));
+                }
+            }
+        }
+        let steps: Vec<Instruction> = steps.into_iter().flatten().collect();
+        let startup: Vec<Instruction> = abi_ops.startup();
+        let exit: Vec<Instruction> = abi_ops.exit(0);
+
+        startup
+            .iter()
+            .chain(steps.iter())
+            .chain(exit.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Optimized instructions of the program, ending with exit, and its data.
+    /// Fails if there are warnings and they are denied.
+    fn to_body(
+        &self, abi_ops: &mut dyn target_abi::Operations, options: &CompileOptions,
+    ) -> CompileResult<(Vec<Instruction>, Vec<Instruction>)> {
+        let mut warnings = self.warnings.clone();
+        self.to_body_with_stats(abi_ops, options, &mut warnings, &mut PassStats::new())
+    }
+
+    /// Like `to_body`, but pushes the warnings of the optimizer to `warnings`, which are checked with
+    /// the ones already there, and adds what each pass did to `passes`
+    fn to_body_with_stats(
+        &self, abi_ops: &mut dyn target_abi::Operations, options: &CompileOptions, warnings: &mut Warnings,
+        passes: &mut PassStats,
+    ) -> CompileResult<(Vec<Instruction>, Vec<Instruction>)> {
+        self.check_jumps()?;
+        let body = self.to_instructions(abi_ops, options);
+        // The passes don't keep the source comments, so the instructions of each command are written as is
+        let body = if options.opt_level >= 1 && !options.source_comments {
+            // Loops evaluated at compile time are left unreachable, which is no mistake in the program
+            let sources = self
+                .loop_sources(options)
+                .into_iter()
+                .filter(|(_, span)| !self.notes.iter().any(|(noted, _)| noted == span))
+                .collect();
+            optimizer::optimize_with_stats(body, options, &sources, warnings, passes).0
+        } else {
+            body
+        };
+        warnings.check(options.deny_warnings)?;
+        let (body, mut data) = optimizer::separate_data(body);
+        data.extend(embedded_input_data(options, self.input_position));
+        data.extend(checksum_data(options));
+        Ok((body, data))
+    }
+
+    pub fn to_assembly(&self, abi: ABI, options: &CompileOptions) -> CompileResult<(String, Stats)> {
+        let mut asm = Vec::new();
+        let stats = match self.write_assembly(&mut asm, abi, options) {
+            Ok(stats) => stats,
+            Err(Error::Compile(error)) => return Err(error),
+            Err(error) => panic!("Writing to a Vec can't fail: {}", error),
+        };
+        Ok((String::from_utf8(asm).unwrap(), stats))
+    }
+
+    /// Like `to_assembly`, but writes the assembly a line at a time instead of building a string
+    pub fn write_assembly<W: Write>(&self, out: &mut W, abi: ABI, options: &CompileOptions) -> Result<Stats> {
+        if abi == ABI::RiscV64 {
+            self.check_jumps()?;
+            self.warnings.check(options.deny_warnings)?;
+            let (asm, stats) = riscv::program(&self.steps, options)?;
+            out.write_all(asm.as_bytes())?;
+            return Ok(Stats {
+                steps: self.step_stats,
+                ..stats
+            });
+        }
+        let mut abi_ops = abi.operations(options)?;
+        let (body, data) = self.to_body(&mut *abi_ops, options)?;
+        self.write_text(out, &mut *abi_ops, options, body, &data)
+    }
+
+    /// Writes the program of the optimized instructions and their data
+    fn write_text<W: Write>(
+        &self, out: &mut W, abi_ops: &mut dyn target_abi::Operations, options: &CompileOptions,
+        mut body: Vec<Instruction>, data: &[Instruction],
+    ) -> Result<Stats> {
+        let stats = Stats {
+            steps: self.step_stats,
+            ..Stats::from_instructions(&body)
+        };
+        let checks_pointer = checks_pointer(&body);
+        if checks_pointer {
+            body.push(Instruction::Label(UNDERFLOW_LABEL.into()));
+            body.extend(abi_ops.exit(1));
+        }
+        let mut text = tape_header(options, checks_pointer, abi_ops)?;
+        text.extend(body);
+        if let Some(file) = &options.line_directives {
+            text = with_line_directives(text, &self.loop_lines(options), file);
+        }
+        let text = optimizer::renumber_labels(text);
+        write_program(out, &*abi_ops, options, &text, data)?;
+        Ok(stats)
+    }
+
+    /// Brainfuck source equivalent to the steps.
+    /// Resuming in the middle of loops after the startup evaluation is written
+    /// as the rest of each loop body followed by the whole loop.
+    pub fn to_brainfuck(&self) -> CompileResult<String> {
+        // The labels jumped to are looked up in the steps
+        self.check_jumps()?;
+        let resume_jump = self.steps.iter().enumerate().find_map(|(i, s)| match s {
+            Step::JumpTo(label) => Some((i, *label)),
+            _ => None,
+        });
+        Ok(match resume_jump {
+            Some((jump, resume)) => self.resumed_to_brainfuck(jump, resume),
+            None => steps_to_brainfuck(&self.steps),
+        })
+    }
+
+    /// Brainfuck source of steps that jump from `jump` to the label `resume`,
+    /// which must be one of the steps like the starts of the loops
+    fn resumed_to_brainfuck(&self, jump: usize, resume: Label) -> String {
+        let target = self.steps.iter().position(|s| *s == Step::Label(resume)).unwrap();
+        let mut bf = steps_to_brainfuck(&self.steps[..jump]);
+
+        // Loops around the resume point are the ones starting before it
+        let enclosing = |step: &Step| match step {
+            Step::JumpToIf(true, start) => self.steps[jump..target].contains(&Step::Label(*start)),
+            _ => false,
+        };
+        let mut index = target + 1;
+        while let Some(offset) = self.steps[index..].iter().position(enclosing) {
+            let back = index + offset;
+            bf.push_str(&steps_to_brainfuck(&self.steps[index..back]));
+            if let Step::JumpToIf(true, start) = self.steps[back] {
+                let start = self.steps.iter().position(|s| *s == Step::Label(start)).unwrap();
+                let body: Vec<Step> = self.steps[start + 1..back]
+                    .iter()
+                    .copied()
+                    .filter(|s| *s != Step::Label(resume))
+                    .collect();
+                bf.push('[');
+                bf.push_str(&steps_to_brainfuck(&body));
+                bf.push(']');
+            }
+            index = back + 1;
+        }
+        bf.push_str(&steps_to_brainfuck(&self.steps[index..]));
+        bf
+    }
+}
+
+/// Brainfuck source of steps without unconditional jumps.
+/// A loop without the check before it runs its body once before the loop.
+fn steps_to_brainfuck(steps: &[Step]) -> String {
+    let mut bf = String::new();
+    let mut index = 0;
+    while index < steps.len() {
+        match steps[index] {
+            Step::Next(n) => bf.push_str(&">".repeat(n as usize)),
+            Step::Prev(n) => bf.push_str(&"<".repeat(n as usize)),
+            Step::Add(n) if n <= 128 => bf.push_str(&"+".repeat(n as usize)),
+            Step::Add(n) => bf.push_str(&"-".repeat(n.wrapping_neg() as usize)),
+            Step::Output => bf.push('.'),
+            Step::Input => bf.push(','),
+            Step::InlineAsm(asm) => bf.push_str(&format!("{}", Token::InlineAsm(asm))),
+            // Check skipping the body of a loop that runs at most once
+            Step::JumpToIf(false, end) if !matches!(steps.get(index + 1), Some(Step::Label(_))) => {
+                let close = index
+                    + steps[index..]
+                        .iter()
+                        .position(|s| *s == Step::Label(end))
+                        .unwrap();
+                bf.push('[');
+                bf.push_str(&steps_to_brainfuck(&steps[index + 1..close]));
+                bf.push(']');
+                index = close;
+            },
+            // Check before a loop, part of its `[`
+            Step::JumpToIf(false, _) => {},
+            // Labels that are not jumped back to end a loop
+            Step::Label(start) => {
+                if let Some(offset) = steps[index..]
+                    .iter()
+                    .position(|s| *s == Step::JumpToIf(true, start))
+                {
+                    let end = index + offset;
+                    let body = steps_to_brainfuck(&steps[index + 1..end]);
+                    if index == 0 || !matches!(steps[index - 1], Step::JumpToIf(false, _)) {
+                        bf.push_str(&body);
+                    }
+                    bf.push('[');
+                    bf.push_str(&body);
+                    bf.push(']');
+                    index = end;
+                }
+            },
+            Step::JumpToIf(true, _) | Step::JumpTo(_) => unreachable!("Jump outside a loop"),
+        }
+        index += 1;
+    }
+    bf
+}
+
+/// Most cells used by the compile-time evaluation, even if the tape of the program is larger
+const MAX_EVAL_CELLS: usize = 1 << 20;
+
+/// Register holding the tape pointer
+const POINTER_REG: Register64 = Register64::rbx;
+
+/// Register holding the address of the first cell, when moving left of it is checked
+const BASE_REG: Register64 = Register64::r12;
 
-        let s = format!(
-            "{}\nsection .text\n$entrypoint:\n{}\n{}\nsection .data\n{}\n",
-            abi.operations().linker_info().to_assembly(),
+/// Whether the instructions check for moving left of the first cell
+fn checks_pointer(body: &[Instruction]) -> bool {
+    body.iter()
+        .any(|op| matches!(op, Instruction::CheckPointer(_, _)))
+}
+
+/// Allocates and clears the tape, and points to its starting cell.
+/// The tape is on the stack unless the target places it at a fixed address, or `guard_pages` maps it.
+/// Either of the first two holds leftovers, of the process startup or of the previous user,
+/// so clearing it can't be skipped like it could for freshly mapped memory.
+fn tape_header(
+    options: &CompileOptions, checks_pointer: bool, abi_ops: &mut dyn target_abi::Operations,
+) -> CompileResult<Vec<Instruction>> {
+    if options.guard_pages {
+        return guarded_tape_header(options, checks_pointer, abi_ops);
+    }
+    let tape_address = abi_ops.tape_address();
+    let start = match tape_address {
+        None => "rsp".to_owned(),
+        Some(address) => format!("{:#x}", address),
+    };
+    let allocate = |size: String| match tape_address {
+        None => vec![Instruction::BlackBox(
+            format!("sub rsp, {}", size),
+            Effects::VOLATILE,
+        )],
+        Some(_) => Vec::new(),
+    };
+    let mut header = match tape_vectors(options) {
+        None => {
+            let mut header = allocate("$arraylen".to_owned());
+            header.extend(vec![
+                Instruction::BlackBox(format!("mov rcx, {}", options.tape_bytes()), Effects::VOLATILE),
+                Instruction::BlackBox(format!("mov rdi, {}", start), Effects::VOLATILE),
+                Instruction::BlackBox("xor al, al".to_owned(), Effects::VOLATILE),
+                Instruction::BlackBox("rep stosb".to_owned(), Effects::VOLATILE),
+            ]);
             header
-                .iter()
-                .map(Instruction::to_source)
-                .collect::<Vec<_>>()
-                .join("\n"),
-            body.iter()
-                .map(Instruction::to_source)
-                .collect::<Vec<_>>()
-                .join("\n"),
-            if data.is_empty() {
-                String::new()
-            } else {
-                data.iter()
-                    .map(Instruction::to_source)
-                    .collect::<Vec<_>>()
-                    .join("\n")
+        },
+        Some(cpu) => {
+            let bytes = cpu.vector_bytes();
+            // Rounded up to whole vectors, so that the last store stays in the frame
+            let size = options.tape_bytes().div_ceil(bytes) * bytes;
+            let (zero, store) = match cpu {
+                CpuFeature::Sse2 => ("pxor xmm0, xmm0", "movdqu [rdi], xmm0"),
+                CpuFeature::Avx2 => ("vpxor ymm0, ymm0, ymm0", "vmovdqu [rdi], ymm0"),
+            };
+            let mut header = allocate("$arraylen".to_owned());
+            header.extend(vec![
+                Instruction::BlackBox(format!("mov rcx, {}", size / bytes), Effects::VOLATILE),
+                Instruction::BlackBox(format!("mov rdi, {}", start), Effects::VOLATILE),
+                Instruction::BlackBox(
+                    format!(
+                        "{}\n.zero_tape:\n{}\nadd rdi, {}\ndec rcx\njnz .zero_tape",
+                        zero, store, bytes
+                    ),
+                    Effects::VOLATILE,
+                ),
+            ]);
+            if cpu == CpuFeature::Avx2 {
+                header.push(Instruction::BlackBox("vzeroupper".to_owned(), Effects::VOLATILE));
             }
-        );
-        s.replace("$entrypoint", &abi_ops.linker_info().entrypoint)
-            .replace("$pointer", &format!("{}", ptr_reg))
-            .replace("$arraylen", "30000")
+            header
+        },
+    };
+    header.push(Instruction::BlackBox(
+        format!("mov $pointer, {}", start),
+        Effects::VOLATILE,
+    ));
+    if tape_address.is_none() {
+        header.push(Instruction::BlackBox("sub rsp, 8".to_owned(), Effects::VOLATILE));
+    }
+    header.extend(tape_pointer_setup(options, checks_pointer));
+    Ok(header)
+}
+
+/// Start of the program mapping the tape between inaccessible pages instead of allocating it on the stack.
+/// The tape ends at the last guard page, as moving right of the end is never checked,
+/// unlike moving left of the start.
+fn guarded_tape_header(
+    options: &CompileOptions, checks_pointer: bool, abi_ops: &mut dyn target_abi::Operations,
+) -> CompileResult<Vec<Instruction>> {
+    let bytes = options.tape_bytes() as u64;
+    let pages = bytes.div_ceil(target_abi::PAGE_SIZE);
+    let exit = abi_ops.exit(1);
+    let mapped = abi_ops
+        .map_guarded(POINTER_REG, pages + 2, exit)
+        .ok_or(CompileError::InvalidOption("guard_pages", "the target can't map memory"))?;
+    let mut header = vec![Instruction::BlackBox("sub rsp, 8".to_owned(), Effects::VOLATILE)];
+    header.extend(mapped);
+    header.push(Instruction::AddImm(POINTER_REG, pages * target_abi::PAGE_SIZE - bytes));
+    header.extend(tape_pointer_setup(options, checks_pointer));
+    Ok(header)
+}
+
+/// Keeps the start of the tape starting at the pointer, and moves the pointer to its first cell
+fn tape_pointer_setup(options: &CompileOptions, checks_pointer: bool) -> Vec<Instruction> {
+    let mut setup = Vec::new();
+    // Moving left of the tape start exits with status 1
+    if checks_pointer {
+        setup.push(Instruction::Mov(BASE_REG, POINTER_REG));
+    }
+    if options.tape == TapeMode::Bidirectional {
+        setup.push(Instruction::AddImm(POINTER_REG, (options.tape_size / 2) as u64));
+    }
+    setup
+}
+
+/// Label of the checksum of the source embedded in the binary, see `source_checksum`
+pub const SOURCE_CHECKSUM_LABEL: &str = "source_checksum";
+
+/// FNV-1a hash of the source, which `CompileOptions::source_checksum` embeds in the binary
+/// so that it can be traced back to its source
+pub fn source_checksum(source: &[u8]) -> u64 {
+    let mut hasher = optimizer::Fnv::default();
+    hasher.write(source);
+    hasher.finish()
+}
+
+/// The checksum of the source as little-endian data, if it is embedded
+fn checksum_data(options: &CompileOptions) -> Option<Instruction> {
+    let checksum = options.source_checksum?;
+    Some(Instruction::Data(
+        SOURCE_CHECKSUM_LABEL.to_owned(),
+        checksum.to_le_bytes().to_vec(),
+    ))
+}
+
+/// Data of the input embedded in the binary, with the read position after compile-time evaluation
+fn embedded_input_data(options: &CompileOptions, position: usize) -> Vec<Instruction> {
+    match &options.embedded_input {
+        Some(input) => {
+            let mut bytes = input.clone();
+            bytes.push(options.eof.value().unwrap_or(0));
+            vec![
+                Instruction::Data(EMBEDDED_INPUT_LABEL.to_owned(), bytes),
+                Instruction::Data(
+                    EMBEDDED_POSITION_LABEL.to_owned(),
+                    (position as u64).to_le_bytes().to_vec(),
+                ),
+            ]
+        },
+        None => Vec::new(),
+    }
+}
+
+/// Vectors clearing the tape, as a vector loop is faster, but `rep stosb` is shorter
+fn tape_vectors(options: &CompileOptions) -> Option<CpuFeature> {
+    options.target_cpu.filter(|_| options.opt_for != OptGoal::Size)
+}
+
+/// Size of the tape allocated on the stack, which is freed before exiting.
+/// Rounded up to 16 bytes, so that the stack stays aligned for calls as the System V ABI requires,
+/// and to whole vectors when they clear the tape.
+fn stack_tape_bytes(options: &CompileOptions) -> usize {
+    if options.guard_pages {
+        return 0;
+    }
+    let align = tape_vectors(options).map_or(16, |cpu| cpu.vector_bytes().max(16));
+    options.tape_bytes().div_ceil(align) * align
+}
+
+/// Writes the assembly source of the instructions, separated by newlines
+fn write_source<W: Write>(out: &mut W, options: &CompileOptions, ops: &[Instruction]) -> io::Result<()> {
+    for (index, op) in ops.iter().enumerate() {
+        if index > 0 {
+            writeln!(out)?;
+        }
+        let source = op
+            .to_source()
+            .replace("$pointer", &format!("{}", POINTER_REG))
+            .replace("$arraylen", &format!("{}", stack_tape_bytes(options)));
+        out.write_all(source.as_bytes())?;
+        if let Instruction::Data(name, _) = op {
+            if options.data_length_symbols {
+                write!(out, "\n{}_len equ $ - {}", name, name)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Assembly source of the instructions
+fn to_source(options: &CompileOptions, ops: &[Instruction]) -> String {
+    let mut source = Vec::new();
+    write_source(&mut source, options, ops).expect("Writing to a Vec can't fail");
+    String::from_utf8(source).unwrap()
+}
+
+/// Precedes each loop label with a `%line` directive giving its source line in the file,
+/// so that NASM reports errors in the instructions until the next one at that line
+fn with_line_directives(text: Vec<Instruction>, lines: &LoopSources, file: &str) -> Vec<Instruction> {
+    let mut result = Vec::with_capacity(text.len());
+    for op in text {
+        if let Instruction::Label(label) = &op {
+            if let Some(span) = lines.get(label) {
+                result.push(Instruction::BlackBox(
+                    format!("%line {}+0 {}", span.line, file),
+                    Effects::VOLATILE,
+                ));
+            }
+        }
+        result.push(op);
+    }
+    result
+}
+
+/// Writes the data, keeping everything but the embedded input position in a read-only section
+fn write_data<W: Write>(
+    out: &mut W, abi_ops: &dyn target_abi::Operations, options: &CompileOptions, data: &[Instruction],
+) -> io::Result<()> {
+    let (writable, constant): (Vec<Instruction>, Vec<Instruction>) = data
+        .iter()
+        .cloned()
+        .partition(|op| matches!(op, Instruction::Data(label, _) if label == EMBEDDED_POSITION_LABEL));
+    for (section, ops) in &[
+        (abi_ops.rodata_section(), constant),
+        (abi_ops.data_section(), writable),
+    ] {
+        if !ops.is_empty() {
+            writeln!(out, "{}", section)?;
+            write_source(out, options, ops)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Loads the addresses of the data relative to `rip` if the target requires it.
+/// Other labels loaded with `MovImmVar`, such as the lengths of the data, are constants.
+fn address_data(
+    abi_ops: &dyn target_abi::Operations, text: Vec<Instruction>, data: &[Instruction],
+) -> Vec<Instruction> {
+    if !abi_ops.rip_relative_data() {
+        return text;
     }
+    let labels: HashSet<&str> = data
+        .iter()
+        .filter_map(|op| match op {
+            Instruction::Data(label, _) => Some(label.as_str()),
+            _ => None,
+        })
+        .collect();
+    text.into_iter()
+        .map(|op| match op {
+            Instruction::MovImmVar(r, label) if labels.contains(label.as_str()) => {
+                Instruction::BlackBox(format!("lea {}, [rel {}]", r, label), Effects::REG)
+            },
+            op => op,
+        })
+        .collect()
+}
+
+/// Writes the assembly source of a whole program, starting at the entry point
+fn write_program<W: Write>(
+    out: &mut W, abi_ops: &dyn target_abi::Operations, options: &CompileOptions, text: &[Instruction],
+    data: &[Instruction],
+) -> io::Result<()> {
+    let linker_info = abi_ops.linker_info();
+    write_build_comment(out, abi_ops, options)?;
+    write!(
+        out,
+        "{}\n{}\n{}:\n",
+        linker_info.to_assembly(),
+        abi_ops.text_section(),
+        linker_info.entrypoint
+    )?;
+    write_source(out, options, &address_data(abi_ops, text.to_vec(), data))?;
+    writeln!(out)?;
+    write_data(out, abi_ops, options, data)?;
+    if let Some(note) = abi_ops.stack_note() {
+        writeln!(out, "{}", note)?;
+    }
+    Ok(())
+}
+
+/// Comment with the commands that build the assembly file named by the options, if any
+fn write_build_comment<W: Write>(
+    out: &mut W, abi_ops: &dyn target_abi::Operations, options: &CompileOptions,
+) -> io::Result<()> {
+    if let Some(source) = &options.build_comment {
+        let link = options
+            .linker_driver
+            .and_then(|driver| abi_ops.driver_linker_info(driver))
+            .unwrap_or_else(|| abi_ops.linker_info());
+        writeln!(out, "{}", link.build_comment(source))?;
+    }
+    Ok(())
+}
+
+/// Assembly source of a whole program, starting at the entry point
+fn assemble(
+    abi_ops: &dyn target_abi::Operations, options: &CompileOptions, text: &[Instruction],
+    data: &[Instruction],
+) -> String {
+    let mut asm = Vec::new();
+    write_program(&mut asm, abi_ops, options, text, data).expect("Writing to a Vec can't fail");
+    String::from_utf8(asm).unwrap()
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-enum Step {
+pub(crate) enum Step {
     /// Move to right
     Next(u64),
     /// Move to left
     Prev(u64),
-    /// Add to current cell (or subtract by overflowing)
+    /// Add to current cell (or subtract by overflowing), a signed delta for wider cells
     Add(u8),
     /// Unconditional jump to label
     JumpTo(Label),
@@ -276,137 +1251,2609 @@ enum Step {
     Output,
     /// Call to read function
     Input,
+    /// Assembly from the source, which may change any cell but keeps the pointer
+    InlineAsm(&'static str),
 }
 impl Step {
-    fn to_assembly(self, pointer: Register64, abi_ops: &mut dyn target_abi::Operations) -> Vec<Instruction> {
+    fn to_assembly(
+        self, pointer: Register64, tape_base: Register64, abi_ops: &mut dyn target_abi::Operations,
+        options: &CompileOptions,
+    ) -> Vec<Instruction> {
         match self {
             Self::Next(count) => vec![Instruction::AddImm(pointer, count)],
-            Self::Prev(count) => vec![Instruction::SubImm(pointer, count)],
+            Self::Prev(count) => vec![
+                Instruction::SubImm(pointer, count),
+                Instruction::CheckPointer(pointer, tape_base),
+            ],
             Self::Add(n) => vec![Instruction::AddPtr8Imm(pointer, n)],
-            Self::JumpTo(label) => vec![Instruction::Jump(format!("{}", label))],
+            Self::JumpTo(label) => vec![Instruction::Jump(format!("{}", label).into())],
             Self::JumpToIf(condition, label) => vec![
                 Instruction::IsZeroPtr8(pointer),
                 if condition {
-                    Instruction::JumpNonZero(format!("{}", label))
+                    Instruction::JumpNonZero(format!("{}", label).into())
                 } else {
-                    Instruction::JumpZero(format!("{}", label))
+                    Instruction::JumpZero(format!("{}", label).into())
                 },
             ],
-            Self::Label(label) => vec![Instruction::Label(format!("{}", label))],
+            Self::Label(label) => vec![Instruction::Label(format!("{}", label).into())],
             Self::Output => abi_ops.write_bytes(pointer, 1),
-            Self::Input => abi_ops.read_byte(pointer),
+            Self::Input => match &options.embedded_input {
+                Some(input) => abi_ops.read_embedded_byte(pointer, options.eof, input.len()),
+                None => abi_ops.read_byte(pointer, options.eof),
+            },
+            Self::InlineAsm(asm) => vec![Instruction::BlackBox(asm.to_owned(), Effects::VOLATILE)],
         }
     }
-}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-struct StepInterpreterState {
-    /// Step index
-    index: usize,
-    /// Tape
-    tape: Tape,
-    /// Tape pointer (index)
-    pointer: usize,
-    /// Output buffer
-    output: Vec<u8>,
+    /// Like `to_assembly`, but with the pointer at `cell`, which is known for every step
+    /// when some cells are wider than a byte. Pointer moves stride over the wider cells,
+    /// and can't go left of the tape.
+    fn to_wide_assembly(
+        self, cell: usize, pointer: Register64, abi_ops: &mut dyn target_abi::Operations,
+        options: &CompileOptions,
+    ) -> Vec<Instruction> {
+        let bytes = options.cell_bytes(cell);
+        let offset = |cell: usize| options.cell_offset(cell) as u64;
+        match self {
+            Self::Next(count) => vec![Instruction::AddImm(
+                pointer,
+                offset(cell + count as usize) - offset(cell),
+            )],
+            Self::Prev(count) => vec![Instruction::SubImm(
+                pointer,
+                offset(cell) - offset(cell - count as usize),
+            )],
+            Self::Add(n) => {
+                // Sign-extended, as `-` is `Add(255)`
+                let delta = u32::from(n).wrapping_sub(if n < 128 { 0 } else { 256 });
+                vec![match bytes {
+                    2 => Instruction::AddPtr16Imm(pointer, delta as u16),
+                    4 => Instruction::AddPtr32Imm(pointer, delta),
+                    _ => Instruction::AddPtr8Imm(pointer, n),
+                }]
+            },
+            Self::JumpToIf(condition, label) => vec![
+                match bytes {
+                    2 => Instruction::IsZeroPtr16(pointer),
+                    4 => Instruction::IsZeroPtr32(pointer),
+                    _ => Instruction::IsZeroPtr8(pointer),
+                },
+                if condition {
+                    Instruction::JumpNonZero(format!("{}", label).into())
+                } else {
+                    Instruction::JumpZero(format!("{}", label).into())
+                },
+            ],
+            // The cell is little-endian, so writing its first byte writes the lowest one
+            Self::Output if options.wide_output == WideOutput::Full => {
+                abi_ops.write_bytes(pointer, u64::from(bytes))
+            },
+            // The byte read is zero-extended to the whole cell
+            Self::Input if bytes > 1 => {
+                let mut ops = self.to_assembly(pointer, BASE_REG, abi_ops, options);
+                let (width, register) = if bytes == 2 {
+                    ("word", "ax")
+                } else {
+                    ("dword", "eax")
+                };
+                ops.push(Instruction::BlackBox(
+                    format!(
+                        "movzx eax, byte [{}]\nmov {} [{}], {}",
+                        pointer, width, pointer, register
+                    ),
+                    Effects::REG,
+                ));
+                ops
+            },
+            _ => self.to_assembly(pointer, BASE_REG, abi_ops, options),
+        }
+    }
 }
 
+/// Cell values known at compile time at some point of the program
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct StepInterpreter<'a> {
-    /// Instructions
-    steps: &'a [Step],
-    /// Current state
-    state: StepInterpreterState,
+struct KnownCells {
+    /// Pointer, relative to an arbitrary origin and wrapping around
+    pointer: u64,
+    /// Cells that differ from the rest, `None` if the value is unknown
+    cells: HashMap<u64, Option<u8>>,
+    /// Whether the rest of the cells are zero, instead of unknown
+    zeroed: bool,
 }
-impl<'a> StepInterpreter<'a> {
-    #[must_use]
-    #[inline]
-    pub fn done(&self) -> bool {
-        self.state.index == self.steps.len()
+impl KnownCells {
+    /// Tape at the start of the program
+    fn zeroed() -> Self {
+        Self {
+            pointer: 0,
+            cells: HashMap::new(),
+            zeroed: true,
+        }
     }
 
-    pub fn jump_to(&mut self, label: Label) {
-        for (i, s) in self.steps.iter().enumerate() {
-            if s == &Step::Label(label) {
-                self.state.index = i;
+    fn unknown() -> Self {
+        Self {
+            zeroed: false,
+            ..Self::zeroed()
+        }
+    }
+
+    /// Only the current cell is known to be zero
+    fn after_loop() -> Self {
+        let mut state = Self::unknown();
+        state.cells.insert(0, Some(0));
+        state
+    }
+
+    fn current(&self) -> Option<u8> {
+        match self.cells.get(&self.pointer) {
+            Some(value) => *value,
+            None if self.zeroed => Some(0),
+            None => None,
+        }
+    }
+
+    /// Applies a step without control flow
+    fn apply(&mut self, step: Step) {
+        match step {
+            Step::Next(n) => self.pointer = self.pointer.wrapping_add(n),
+            Step::Prev(n) => self.pointer = self.pointer.wrapping_sub(n),
+            Step::Add(n) => {
+                let value = self.current().map(|v| v.wrapping_add(n));
+                self.cells.insert(self.pointer, value);
+            },
+            Step::Input => {
+                self.cells.insert(self.pointer, None);
+            },
+            Step::Output => {},
+            Step::InlineAsm(_) => {
+                *self = Self {
+                    pointer: self.pointer,
+                    ..Self::unknown()
+                }
+            },
+            Step::JumpTo(_) | Step::JumpToIf(_, _) | Step::Label(_) => unreachable!("Control flow step"),
+        }
+    }
+
+    /// Steps of a loop with the given body fully unrolled, and the state after it.
+    /// `None` if the body has control flow or inline assembly, the trip count is unknown,
+    /// or the unrolled loop would be longer than `max_steps`.
+    fn unroll(&self, body: &[Step], max_steps: usize) -> Option<(Vec<Step>, Self)> {
+        if body.is_empty()
+            || body.iter().any(|s| {
+                matches!(
+                    s,
+                    Step::Label(_) | Step::JumpTo(_) | Step::JumpToIf(..) | Step::InlineAsm(_)
+                )
+            })
+        {
+            return None;
+        }
+        let mut state = self.clone();
+        let mut unrolled = Vec::new();
+        while state.current()? != 0 {
+            if unrolled.len() + body.len() > max_steps {
+                return None;
+            }
+            unrolled.extend_from_slice(body);
+            for &step in body {
+                state.apply(step);
+            }
+        }
+        Some((unrolled, state))
+    }
+}
+
+/// Index of the jump back in a loop starting at `steps[index]`,
+/// if it starts with the check skipping it and ends with the label after it
+fn loop_end(steps: &[Step], index: usize) -> Option<usize> {
+    let (target, source) = match steps.get(index..index + 2)? {
+        [Step::JumpToIf(false, target), Step::Label(source)] => (*target, *source),
+        _ => return None,
+    };
+    let end = index
+        + 2
+        + steps[index + 2..]
+            .iter()
+            .position(|s| *s == Step::JumpToIf(true, source))?;
+    if steps.get(end + 1) == Some(&Step::Label(target)) {
+        Some(end)
+    } else {
+        None
+    }
+}
+
+/// Length of the body of the loop starting at the label before `steps`,
+/// if the body only adds zero to the current cell in total
+fn no_effect_body(steps: &[Step], start: Label) -> Option<usize> {
+    let mut sum: u8 = 0;
+    for (length, step) in steps.iter().enumerate() {
+        match step {
+            Step::Add(value) => sum = sum.wrapping_add(*value),
+            Step::JumpToIf(true, target) if *target == start && sum == 0 => return Some(length),
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Cell of the pointer before each step, if it is the same every time the step runs.
+/// Without it steps can't be lowered when some cells are wider than a byte.
+fn pointer_cells(steps: &[Step]) -> Option<Vec<usize>> {
+    let mut labels: HashMap<Label, usize> = HashMap::new();
+    // `None` after an unconditional jump, until the next label
+    let mut cell = Some(0);
+    let mut result = Vec::new();
+    for step in steps {
+        if let Step::Label(label) = step {
+            match (cell, labels.get(label)) {
+                (Some(current), Some(&known)) if current != known => return None,
+                (None, known) => cell = Some(*known?),
+                _ => {},
+            }
+        }
+        let current = cell?;
+        result.push(current);
+        match *step {
+            Step::Next(n) => cell = Some(current.checked_add(n as usize)?),
+            Step::Prev(n) => cell = Some(current.checked_sub(n as usize)?),
+            Step::JumpTo(label) | Step::JumpToIf(_, label) | Step::Label(label) => {
+                if *labels.entry(label).or_insert(current) != current {
+                    return None;
+                }
+                if let Step::JumpTo(_) = step {
+                    cell = None;
+                }
+            },
+            Step::Add(_) | Step::Output | Step::Input | Step::InlineAsm(_) => {},
+        }
+    }
+    Some(result)
+}
+
+/// Whether the steps only have loops found by `loop_end`, and return the pointer to where it started
+fn balanced(steps: &[Step]) -> bool {
+    let mut offset: u64 = 0;
+    let mut index = 0;
+    while index < steps.len() {
+        if let Some(end) = loop_end(steps, index) {
+            if !balanced(&steps[index + 2..end]) {
+                return false;
+            }
+            index = end + 2;
+            continue;
+        }
+        match steps[index] {
+            Step::Next(n) => offset = offset.wrapping_add(n),
+            Step::Prev(n) => offset = offset.wrapping_sub(n),
+            Step::Add(_) | Step::Input | Step::Output => {},
+            _ => return false,
+        }
+        index += 1;
+    }
+    offset == 0
+}
+
+/// Steps with loops that run at most once replaced by a check skipping the body,
+/// given the cells at the start of the steps
+fn conditional_loops(steps: &[Step], mut state: PossibleCells) -> Vec<Step> {
+    let mut result = Vec::new();
+    let mut index = 0;
+    while index < steps.len() {
+        if let Some(end) = loop_end(steps, index) {
+            let body = &steps[index + 2..end];
+            let (entered, exited) = state.run_loop(body, 0);
+            if state.runs_at_most_once(body) {
+                let mut once = state.clone();
+                once.set_current(Some((1, 1)));
+                result.push(steps[index]);
+                result.extend(conditional_loops(body, once));
+                result.push(steps[end + 1]);
+            } else {
+                result.extend_from_slice(&steps[index..index + 2]);
+                result.extend(conditional_loops(
+                    body,
+                    entered.unwrap_or_else(PossibleCells::unknown),
+                ));
+                result.extend_from_slice(&steps[end..end + 2]);
+            }
+            state = exited.unwrap_or_else(PossibleCells::unknown);
+            index = end + 2;
+            continue;
+        }
+
+        let step = steps[index];
+        state = match step {
+            // Code after a jump is reached through labels only
+            Step::JumpTo(_) | Step::Label(_) => None,
+            Step::JumpToIf(jump_nonzero, _) => state.assume(!jump_nonzero),
+            _ => {
+                state.apply(step);
+                Some(state)
+            },
+        }
+        .unwrap_or_else(PossibleCells::unknown);
+        result.push(step);
+        index += 1;
+    }
+    result
+}
+
+/// At most two values a cell may have, sorted, or `None` if it may have more
+type Values = Option<(u8, u8)>;
+
+/// Values of a cell that may have either of the given values
+fn join_values(a: Values, b: Values) -> Values {
+    let ((a0, a1), (b0, b1)) = (a?, b?);
+    let mut values = vec![a0, a1, b0, b1];
+    values.sort_unstable();
+    values.dedup();
+    match values[..] {
+        [v] => Some((v, v)),
+        [v0, v1] => Some((v0, v1)),
+        _ => None,
+    }
+}
+
+/// Values cells may have at some point of the program.
+/// Unlike `KnownCells`, follows the program through loops,
+/// which lets it find cells that are e.g. either zero or one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PossibleCells {
+    /// Pointer, relative to an arbitrary origin and wrapping around
+    pointer: u64,
+    /// Cells that differ from the rest
+    cells: HashMap<u64, Values>,
+    /// Whether the rest of the cells are zero, instead of unknown
+    zeroed: bool,
+}
+impl PossibleCells {
+    /// Nesting depth of loops whose effect is analyzed, deeper ones are assumed to change everything
+    const MAX_DEPTH: usize = 4;
+    /// Passes over a loop body before assuming that it changes everything
+    const MAX_PASSES: usize = 3;
+
+    /// Tape at the start of the program
+    fn zeroed() -> Self {
+        Self {
+            pointer: 0,
+            cells: HashMap::new(),
+            zeroed: true,
+        }
+    }
+
+    fn unknown() -> Self {
+        Self {
+            zeroed: false,
+            ..Self::zeroed()
+        }
+    }
+
+    /// Nothing is known, but the pointer keeps its origin
+    fn forget(&self) -> Self {
+        Self {
+            pointer: self.pointer,
+            ..Self::unknown()
+        }
+    }
+
+    fn get(&self, cell: u64) -> Values {
+        match self.cells.get(&cell) {
+            Some(values) => *values,
+            None if self.zeroed => Some((0, 0)),
+            None => None,
+        }
+    }
+
+    fn current(&self) -> Values {
+        self.get(self.pointer)
+    }
+
+    fn set_current(&mut self, values: Values) {
+        self.set(self.pointer, values);
+    }
+
+    /// Cells with the values of the rest are left out, so that the cells don't grow with every
+    /// cell the program visits, and equal states compare equal
+    fn set(&mut self, cell: u64, values: Values) {
+        if values == Some((0, 0)).filter(|_| self.zeroed) {
+            self.cells.remove(&cell);
+        } else {
+            self.cells.insert(cell, values);
+        }
+    }
+
+    /// State where the current cell is zero or nonzero, `None` if it can't be
+    fn assume(&self, nonzero: bool) -> Option<Self> {
+        let values = match (self.current(), nonzero) {
+            (Some((0, 0)), true) => return None,
+            (Some((0, v)), true) => Some((v, v)),
+            (Some(_), false) if !self.may_be(0) => return None,
+            (_, true) => self.current(),
+            (_, false) => Some((0, 0)),
+        };
+        let mut state = self.clone();
+        state.set_current(values);
+        Some(state)
+    }
+
+    fn may_be(&self, value: u8) -> bool {
+        match self.current() {
+            Some((a, b)) => a == value || b == value,
+            None => true,
+        }
+    }
+
+    /// Applies a step without control flow
+    fn apply(&mut self, step: Step) {
+        match step {
+            Step::Next(n) => self.pointer = self.pointer.wrapping_add(n),
+            Step::Prev(n) => self.pointer = self.pointer.wrapping_sub(n),
+            Step::Add(n) => {
+                let values = self.current().map(|(a, b)| {
+                    let (a, b) = (a.wrapping_add(n), b.wrapping_add(n));
+                    (a.min(b), a.max(b))
+                });
+                self.set_current(values);
+            },
+            Step::Input => self.set_current(None),
+            Step::Output => {},
+            Step::InlineAsm(_) => *self = self.forget(),
+            Step::JumpTo(_) | Step::JumpToIf(_, _) | Step::Label(_) => unreachable!("Control flow step"),
+        }
+    }
+
+    /// Cells that may have the values of either state, with the same pointer
+    fn join(&self, other: &Self) -> Self {
+        debug_assert_eq!(self.pointer, other.pointer);
+        let mut joined = Self {
+            zeroed: self.zeroed && other.zeroed,
+            ..self.forget()
+        };
+        for &cell in self.cells.keys().chain(other.cells.keys()) {
+            joined.set(cell, join_values(self.get(cell), other.get(cell)));
+        }
+        joined
+    }
+
+    /// State after steps accepted by `balanced`, `None` if they don't finish
+    fn run(&self, steps: &[Step], depth: usize) -> Option<Self> {
+        let mut state = self.clone();
+        let mut index = 0;
+        while index < steps.len() {
+            if let Some(end) = loop_end(steps, index) {
+                state = state.run_loop(&steps[index + 2..end], depth).1?;
+                index = end + 2;
+            } else {
+                state.apply(steps[index]);
+                index += 1;
+            }
+        }
+        Some(state)
+    }
+
+    /// States at the start of the body of a loop entered with this state, and after the loop.
+    /// `None` where the program never gets.
+    fn run_loop(&self, body: &[Step], depth: usize) -> (Option<Self>, Option<Self>) {
+        if !balanced(body) {
+            let mut exited = Self::unknown();
+            exited.set_current(Some((0, 0)));
+            return (Some(Self::unknown()), Some(exited));
+        }
+        if depth < Self::MAX_DEPTH {
+            let mut entered = self.assume(true);
+            for _ in 0..Self::MAX_PASSES {
+                let out = match &entered {
+                    Some(state) => state.run(body, depth + 1),
+                    None => return (None, self.assume(false)),
+                };
+                let again = join(self.assume(true), out.as_ref().and_then(|s| s.assume(true)));
+                if again == entered {
+                    return (
+                        entered,
+                        join(self.assume(false), out.and_then(|s| s.assume(false))),
+                    );
+                }
+                entered = again;
+            }
+        }
+        let forgotten = self.forget();
+        (forgotten.assume(true), forgotten.assume(false))
+    }
+
+    /// Whether a loop with the given body runs at most once,
+    /// as the current cell is zero or one and the body clears it
+    fn runs_at_most_once(&self, body: &[Step]) -> bool {
+        if !matches!(self.current(), Some((_, v)) if v <= 1) || !balanced(body) {
+            return false;
+        }
+        let mut once = self.clone();
+        once.set_current(Some((1, 1)));
+        match once.run(body, 0) {
+            Some(state) => state.current() == Some((0, 0)),
+            // The body never gets back to the check
+            None => true,
+        }
+    }
+}
+
+/// Cells that may be in either state, `None` for an unreachable state
+fn join(a: Option<PossibleCells>, b: Option<PossibleCells>) -> Option<PossibleCells> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.join(&b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StepInterpreterState<'a> {
+    /// Step index
+    index: usize,
+    /// Tape and pointer
+    machine: Machine,
+    /// Remaining embedded input, and the output written
+    io: BufferIO<'a>,
+    /// Number of loop iterations started by a jump to (or past) each label
+    iterations: HashMap<Label, u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StepInterpreter<'s, 'a> {
+    /// Instructions
+    steps: &'s [Step],
+    /// Whether input is embedded in the binary, instead of read from stdin at runtime
+    embedded_input: bool,
+    /// Cells right of the starting one that can be used, moving further stops the evaluation
+    max_cells: usize,
+    /// Current state
+    state: StepInterpreterState<'a>,
+}
+impl StepInterpreter<'_, '_> {
+    #[must_use]
+    #[inline]
+    pub fn done(&self) -> bool {
+        self.state.index == self.steps.len()
+    }
+
+    pub fn jump_to(&mut self, label: Label) {
+        for (i, s) in self.steps.iter().enumerate() {
+            if s == &Step::Label(label) {
+                self.state.index = i;
                 return;
             }
         }
         unreachable!("Missing label");
     }
 
-    /// Returns true if next step can be ran without input
+    /// Returns true if next step can be ran without input from stdin,
+    /// and without moving the pointer left of the tape start or past the last usable cell
     #[must_use]
     pub fn step(&mut self) -> bool {
         use Step::*;
         debug_assert!(!self.done());
         match self.steps[self.state.index] {
-            Next(n) => self.state.pointer = self.state.pointer.checked_add(n as usize).unwrap(),
-            Prev(n) => self.state.pointer = self.state.pointer.checked_sub(n as usize).unwrap(),
-            Add(n) => self.state.tape.add(self.state.pointer, n),
             JumpTo(label) => self.jump_to(label),
             JumpToIf(cond, label) => {
-                if cond == (self.state.tape[self.state.pointer] != 0) {
+                let nonzero = self.state.machine.current() != 0;
+                if cond == nonzero {
                     self.jump_to(label);
                 }
+                // Both a taken `]` and an untaken `[` start an iteration
+                if nonzero {
+                    *self.state.iterations.entry(label).or_insert(0) += 1;
+                }
             },
             Label(_) => {},
-            Output => self.state.output.push(self.state.tape[self.state.pointer]),
-            Input => return false,
+            Input if !self.embedded_input => return false,
+            // Could change any cell
+            InlineAsm(_) => return false,
+            // Left to the runtime, instead of growing the tape without bound
+            Next(n) if n >= self.max_cells.saturating_sub(self.state.machine.pointer) as u64 => return false,
+            step => {
+                let op = Op::from_step(step).unwrap();
+                // Moving left of the tape start is left for the runtime check to report
+                if self.state.machine.apply(op, &mut self.state.io).is_err() {
+                    return false;
+                }
+            },
         }
         self.state.index += 1;
         true
     }
 }
 
-#[derive(Debug, Clone)]
-struct Tape(Vec<u8>);
-impl Tape {
-    pub fn new() -> Self {
-        Self(Vec::new())
+/// Lowers tokens to steps, with runs of the same operation combined
+pub(crate) fn lower(tokens: &[Token]) -> Vec<Step> {
+    let mut state = State::new();
+    for token in tokens {
+        state.append(*token);
+    }
+    state.optimize_peephole_combine();
+    state.steps
+}
+
+/// Output of the program, if it can be fully evaluated at compile time.
+/// The evaluation has only cells of a byte, so programs with wide cells are not evaluated.
+pub fn precompute_output(tokens: Vec<Token>, options: &CompileOptions) -> Option<Vec<u8>> {
+    if !options.wide_cells.is_empty() {
+        return None;
+    }
+    let mut state = State::new();
+    for token in tokens {
+        state.append(token);
+    }
+    state.precompute_output(options)
+}
+
+/// Tape computed by the startup evaluation, and whether the program completed.
+/// Cells are counted from the starting cell.
+pub fn evaluated_tape(tokens: Vec<Token>, options: &CompileOptions) -> (TapeSnapshot, bool) {
+    let mut state = State::new();
+    for token in tokens {
+        state.append(token);
+    }
+    state.evaluated_tape(options)
+}
+
+/// Brainfuck source of the optimized program.
+/// Input is always read from stdin, so any embedded input or profile is not used.
+/// Brainfuck has no wide cells, so programs with them are rejected.
+pub fn to_brainfuck(tokens: Vec<Token>, options: &CompileOptions) -> CompileResult<String> {
+    if !options.wide_cells.is_empty() {
+        return Err(CompileError::InvalidOption("wide_cells", "the cells of Brainfuck are bytes"));
     }
+    let options = CompileOptions {
+        embedded_input: None,
+        profile: Vec::new(),
+        ..options.clone()
+    };
+    let state = State::from_tokens(tokens, &options)?;
+    state.to_brainfuck()
+}
+
+/// LLVM IR of the optimized program, lowered from the steps instead of the x86-64 instructions.
+/// Input is always read from stdin, so any embedded input or profile is not used.
+pub fn to_llvm_ir(tokens: Vec<Token>, options: &CompileOptions) -> CompileResult<String> {
+    let options = CompileOptions {
+        embedded_input: None,
+        profile: Vec::new(),
+        ..options.clone()
+    };
+    let state = State::from_tokens(tokens, &options)?;
+    state.check_jumps()?;
+    state.warnings.check(options.deny_warnings)?;
+    llvm::module(&state.steps, &options)
+}
 
-    pub fn add(&mut self, index: usize, add: u8) {
-        while self.0.len() <= index {
-            self.0.push(0);
+/// Loop in the source program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoopInfo {
+    /// Position of `[`
+    pub start: Span,
+    /// Position of `]`
+    pub end: Span,
+    /// Total number of iterations, if compile-time evaluation determined it
+    pub iterations: Option<u64>,
+    /// Loops directly inside this one
+    pub children: Vec<LoopInfo>,
+}
+impl LoopInfo {
+    fn write_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{}[{} .. {}]", "  ".repeat(depth), self.start, self.end)?;
+        if let Some(iterations) = self.iterations {
+            write!(f, " x{}", iterations)?;
         }
-        self.0[index] = self.0[index].wrapping_add(add);
+        writeln!(f)?;
+        for child in &self.children {
+            child.write_tree(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+impl fmt::Display for LoopInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_tree(f, 0)
+    }
+}
+
+/// Nesting structure of the loops in the program
+pub fn loop_report(tokens: &[(Token, Span)], options: &CompileOptions) -> Vec<LoopInfo> {
+    let mut state = State::new();
+    for (token, _) in tokens {
+        state.append(*token);
     }
+    let mut iterations = state.loop_iterations(options).into_iter();
 
-    pub fn trim(&mut self) {
-        let mut len = self.0.len();
-        while len > 0 && self.0[len - 1] == 0 {
-            self.0.pop();
-            len -= 1;
+    let mut result = Vec::new();
+    let mut open: Vec<LoopInfo> = Vec::new();
+    for (token, span) in tokens {
+        match token {
+            Token::JumpForwards => open.push(LoopInfo {
+                start: *span,
+                end: *span,
+                iterations: iterations.next().unwrap(),
+                children: Vec::new(),
+            }),
+            Token::JumpBackwards => {
+                let mut info = open.pop().unwrap();
+                info.end = *span;
+                open.last_mut()
+                    .map_or(&mut result, |p| &mut p.children)
+                    .push(info);
+            },
+            _ => {},
         }
     }
+    result
 }
-impl Index<usize> for Tape {
-    type Output = u8;
 
-    fn index(&self, i: usize) -> &Self::Output {
-        self.0.get(i).unwrap_or(&0)
+/// Source with a comment before each loop the optimizer changed, saying what it did.
+/// Characters of the comments that are commands are replaced with spaces.
+pub fn annotate(
+    source: &str, tokens: &[(Token, Span)], abi: ABI, options: &CompileOptions,
+) -> CompileResult<String> {
+    let options = CompileOptions {
+        loop_spans: tokens
+            .iter()
+            .filter(|(token, _)| *token == Token::JumpForwards)
+            .map(|(_, span)| *span)
+            .collect(),
+        ..options.clone()
+    };
+    let state = State::from_tokens(tokens.iter().map(|(token, _)| *token).collect(), &options)?;
+    let mut notes = state.notes.clone();
+    if options.opt_level >= 1 {
+        let body = state.to_instructions(&mut *abi.operations(&options)?, &options);
+        let (_, pass_notes) = optimizer::optimize_with_sources(
+            body,
+            &options,
+            &state.loop_sources(&options),
+            &mut Warnings::new(),
+        );
+        // Loops evaluated at compile time are left unreachable, so later rewrites of them never run
+        notes.extend(
+            pass_notes
+                .into_iter()
+                .filter(|(span, _)| !state.notes.iter().any(|(noted, _)| noted == span)),
+        );
+    }
+    // The sort is stable, so the notes of a loop stay in the order they were made
+    notes.sort_by_key(|(span, _)| span.offset);
+
+    let mut result = String::new();
+    let mut position = 0;
+    for (span, note) in notes {
+        result.push_str(&source[position..span.offset]);
+        position = span.offset;
+        let comment: String = note
+            .chars()
+            .map(|c| if "+-<>[].,".contains(c) { ' ' } else { c })
+            .collect();
+        result.push_str(&format!("({}) ", comment));
     }
+    result.push_str(&source[position..]);
+    Ok(result)
 }
 
-impl PartialEq for Tape {
-    fn eq(&self, other: &Self) -> bool {
-        for i in 0..(self.0.len().max(other.0.len())) {
-            if self.0.get(i).unwrap_or(&0) != other.0.get(i).unwrap_or(&0) {
-                return false;
-            }
+/// Index of the token moving the pointer left of the first cell,
+/// if that happens before the first loop
+fn prefix_underflow(tokens: &[Token]) -> Option<usize> {
+    let mut pointer: usize = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Next => pointer += 1,
+            Token::Prev if pointer == 0 => return Some(index),
+            Token::Prev => pointer -= 1,
+            Token::JumpForwards | Token::JumpBackwards => break,
+            _ => {},
         }
-        true
     }
+    None
+}
+
+/// Whether the program has no observable effect: it only moves the pointer and changes cells,
+/// with the pointer staying on the tape, so it doesn't output, wait for input or fail
+fn has_no_effect(tokens: &[Token], options: &CompileOptions) -> bool {
+    let mut pointer = match options.tape {
+        TapeMode::Standard => 0,
+        TapeMode::Bidirectional => options.tape_size / 2,
+    };
+    tokens.iter().all(|token| match token {
+        Token::Next => {
+            pointer += 1;
+            pointer < options.tape_size
+        },
+        Token::Prev if pointer == 0 => false,
+        Token::Prev => {
+            pointer -= 1;
+            true
+        },
+        Token::Increment | Token::Decrement => true,
+        _ => false,
+    })
+}
+
+pub fn compile_tokens(
+    tokens: Vec<Token>, abi: ABI, options: &CompileOptions,
+) -> CompileResult<(String, LinkerInfo)> {
+    let (asm, linker_info, _) = compile_tokens_with_stats(tokens, abi, options)?;
+    Ok((asm, linker_info))
+}
+
+/// Like `compile_tokens`, but also returns statistics of the optimized program
+pub fn compile_tokens_with_stats(
+    tokens: Vec<Token>, abi: ABI, options: &CompileOptions,
+) -> CompileResult<(String, LinkerInfo, Stats)> {
+    let token_count = tokens.len();
+    // Without running the program, or the optimizer, it compiles to setting up the tape and exiting
+    let tokens = if has_no_effect(&tokens, options) {
+        log::info!("The program has no observable effect, compiling an empty program");
+        Vec::new()
+    } else {
+        tokens
+    };
+    let state = State::from_tokens(tokens, options)?;
+    let (asm, mut asm_stats) = state.to_assembly(abi, options)?;
+    asm_stats.steps.tokens = token_count;
+    Ok((asm, abi.linker_info(options), asm_stats))
+}
+
+/// Like `compile_tokens_with_stats`, but returns a report of the compilation, without the output path.
+/// Only targets lowered to x86-64 instructions have one.
+pub fn compile_tokens_with_report(
+    tokens: Vec<Token>, abi: ABI, options: &CompileOptions,
+) -> CompileResult<(String, LinkerInfo, Report)> {
+    if abi == ABI::RiscV64 {
+        return Err(CompileError::InvalidOption("report", "the target isn't lowered to x86-64 instructions"));
+    }
+    let state = State::from_tokens(tokens, options)?;
+    let before = Stats {
+        steps: state.step_stats,
+        ..Stats::from_instructions(&state.to_instructions(&mut *abi.operations(options)?, options))
+    };
+    let mut abi_ops = abi.operations(options)?;
+    let mut warnings = state.warnings.clone();
+    let mut passes = PassStats::new();
+    let (body, data) = state.to_body_with_stats(&mut *abi_ops, options, &mut warnings, &mut passes)?;
+    let mut asm = Vec::new();
+    let after = match state.write_text(&mut asm, &mut *abi_ops, options, body, &data) {
+        Ok(stats) => stats,
+        Err(Error::Compile(error)) => return Err(error),
+        Err(error) => panic!("Writing to a Vec can't fail: {}", error),
+    };
+    let link = abi.linker_info(options);
+    let report = Report::new(abi, options, &link, before, after, &passes, &warnings, &data);
+    Ok((String::from_utf8(asm).unwrap(), link, report))
+}
+
+/// Like `compile_tokens_with_stats`, but writes the assembly to `out` instead of returning it
+pub fn write_assembly<W: Write>(
+    out: &mut W, tokens: Vec<Token>, abi: ABI, options: &CompileOptions,
+) -> Result<(LinkerInfo, Stats)> {
+    let stats = State::from_tokens(tokens, options)?.write_assembly(out, abi, options)?;
+    Ok((abi.linker_info(options), stats))
+}
+
+/// Compiles the program into a function `int name(void)` of the System V AMD64 calling convention,
+/// which runs the program on a tape of its own and returns the exit code instead of exiting.
+/// The function is the only global symbol, so the objects of several programs can be linked together.
+pub fn compile_function(
+    tokens: Vec<Token>, name: &str, abi: ABI, options: &CompileOptions,
+) -> CompileResult<(String, LinkerInfo)> {
+    let state = State::from_tokens(tokens, options)?;
+    let abi_ops = target_abi::function::Interface::new(abi.operations(options)?, name);
+    function_assembly(&state, abi_ops, options)
+}
+
+/// Like `compile_function`, but position-independent, to be linked into a shared library
+/// with the returned linker info. The data is addressed relative to `rip`,
+/// and on Linux the C library is called through the procedure linkage table.
+pub fn compile_shared_function(
+    tokens: Vec<Token>, name: &str, abi: ABI, options: &CompileOptions,
+) -> CompileResult<(String, LinkerInfo)> {
+    let state = State::from_tokens(tokens, options)?;
+    let abi_ops = target_abi::function::Interface::shared(abi.operations(options)?, name);
+    function_assembly(&state, abi_ops, options)
+}
+
+/// Assembly of the program as a function of the interface
+fn function_assembly(
+    state: &State, mut abi_ops: target_abi::function::Interface, options: &CompileOptions,
+) -> CompileResult<(String, LinkerInfo)> {
+    let (mut body, data) = state.to_body(&mut abi_ops, options)?;
+
+    let checks_pointer = checks_pointer(&body);
+    if checks_pointer {
+        body.push(Instruction::Label(UNDERFLOW_LABEL.into()));
+        body.extend(abi_ops.exit(1));
+    }
+    let mut text = vec![target_abi::function::Interface::prologue()];
+    text.extend(tape_header(options, checks_pointer, &mut abi_ops)?);
+    text.extend(body);
+    let text = optimizer::renumber_labels(text);
+    Ok((assemble(&abi_ops, options, &text, &data), abi_ops.linker_info()))
 }
-impl Eq for Tape {}
 
-pub fn compile_tokens(tokens: Vec<Token>, abi: ABI) -> (String, LinkerInfo) {
+/// Diff of the instructions changed by running only the named optimizer pass
+/// on the program as written, without any other optimizations.
+/// Empty if the pass changes nothing, and `None` if there is no such pass
+/// or the target doesn't lower the program to x86-64 instructions.
+pub fn explain_pass(tokens: Vec<Token>, pass: &str, abi: ABI, options: &CompileOptions) -> Option<String> {
     let mut state = State::new();
     for token in tokens {
         state.append(token);
     }
-    state.optimize();
-    let linker_info = abi.operations().linker_info();
-    (state.to_assembly(abi), linker_info)
+    let before = state.to_instructions(&mut *abi.operations(options).ok()?, options);
+    let after = optimizer::run_pass(pass, before.clone(), options)?;
+    if before == after {
+        return Some(String::new());
+    }
+    Some(optimizer::listing_diff(&before, &after))
+}
+
+/// Control-flow graph of the optimized program in Graphviz DOT format, without the data.
+/// Only targets lowered to x86-64 instructions have one.
+pub fn to_dot(tokens: Vec<Token>, abi: ABI, options: &CompileOptions) -> CompileResult<String> {
+    let state = State::from_tokens(tokens, options)?;
+    let (body, _) = state.to_body(&mut *abi.operations(options)?, options)?;
+    Ok(Cfg::new(body).to_dot())
+}
+
+/// Labels and contents of the data stored in the compiled program, in the order they are emitted.
+/// These include the constant strings written by the program, which are named `constant_output{n}`.
+pub fn data_blobs(
+    tokens: Vec<Token>, abi: ABI, options: &CompileOptions,
+) -> CompileResult<Vec<(String, Vec<u8>)>> {
+    let state = State::from_tokens(tokens, options)?;
+    let (_, data) = state.to_body(&mut *abi.operations(options)?, options)?;
+    Ok(data
+        .into_iter()
+        .filter_map(|op| match op {
+            Instruction::Data(label, bytes) => Some((label, bytes)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Lowered and optimized steps of each program, in order.
+/// The programs are split into `options.threads` runs of consecutive ones, each lowered on a thread.
+fn program_states(programs: &[(String, Vec<Token>)], options: &CompileOptions) -> Vec<CompileResult<State>> {
+    let lower = |programs: &[(String, Vec<Token>)]| -> Vec<CompileResult<State>> {
+        programs
+            .iter()
+            .map(|(_, tokens)| State::from_tokens(tokens.clone(), options))
+            .collect()
+    };
+    if options.threads <= 1 || programs.len() <= 1 {
+        return lower(programs);
+    }
+    let run = programs.len().div_ceil(options.threads);
+    thread::scope(|scope| {
+        let lower = &lower;
+        let handles: Vec<_> = programs
+            .chunks(run)
+            .map(|programs| scope.spawn(move || lower(programs)))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("Lowering a program panicked"))
+            .collect()
+    })
+}
+
+/// Compiles several named programs into one binary, which shares the tape between them
+/// and runs the program named by its first argument.
+/// Without a matching argument the names are written to stdout, and the exit status is 2.
+pub fn compile_programs(
+    programs: &[(String, Vec<Token>)], abi: ABI, options: &CompileOptions,
+) -> CompileResult<(String, LinkerInfo)> {
+    use Instruction::*;
+    const SEVERAL_PROGRAMS: &str = "can't be used when compiling several programs together";
+    if options.embedded_input.is_some() {
+        return Err(CompileError::InvalidOption("embedded_input", SEVERAL_PROGRAMS));
+    }
+    if !options.profile.is_empty() {
+        return Err(CompileError::InvalidOption("profile", SEVERAL_PROGRAMS));
+    }
+    // The program is selected by the command line arguments
+    if matches!(abi, ABI::BareMetal | ABI::Freestanding) {
+        return Err(CompileError::InvalidOption("target", SEVERAL_PROGRAMS));
+    }
+    options.validate()?;
+
+    let mut abi_ops = abi.operations(options)?;
+    let mut bodies = Vec::new();
+    let mut data = Vec::new();
+    let states = program_states(programs, options);
+    for (index, ((name, _), state)) in programs.iter().zip(states).enumerate() {
+        let in_program = |error| CompileError::InProgram(name.clone(), Box::new(error));
+        let (body, program_data) = state
+            .map_err(in_program)?
+            .to_body(&mut *abi_ops, options)
+            .map_err(in_program)?;
+        let symbol = format!("program{}", index);
+        // Data labels are global, so they are prefixed to keep them apart
+        let prefix = |op| match op {
+            MovImmVar(r, label) => MovImmVar(r, format!("{}_{}", symbol, label)),
+            MovPtr128(r, label) => MovPtr128(r, format!("{}_{}", symbol, label)),
+            MovPtr256(r, label) => MovPtr256(r, format!("{}_{}", symbol, label)),
+            Data(label, bytes) => Data(format!("{}_{}", symbol, label), bytes),
+            op => op,
+        };
+        bodies.push(Label(symbol.as_str().into()));
+        bodies.extend(body.into_iter().map(prefix));
+        data.extend(program_data.into_iter().map(prefix));
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+        data.push(Data(format!("{}_name", symbol), name));
+    }
+
+    let checks_pointer = checks_pointer(&bodies);
+    // Arguments are kept in registers that the programs don't use
+    let mut text = vec![
+        Mov(Register64::r13, Register64::rdi),
+        Mov(Register64::r14, Register64::rsi),
+    ];
+    text.extend(tape_header(options, checks_pointer, &mut *abi_ops)?);
+    text.push(BlackBox(
+        "cmp r13, 2\njb dispatch_unknown\nmov r14, [r14 + 8]".to_owned(),
+        Effects::VOLATILE,
+    ));
+    for index in 0..programs.len() {
+        // Compares the argument to the name byte by byte, including the terminating zero
+        text.push(BlackBox(
+            [
+                format!("lea rdi, [rel program{}_name]", index),
+                "mov rsi, r14".to_owned(),
+                format!(".dispatch{}:", index),
+                "mov al, [rdi]".to_owned(),
+                "cmp al, [rsi]".to_owned(),
+                format!("jne .dispatch{}_next", index),
+                "inc rdi".to_owned(),
+                "inc rsi".to_owned(),
+                "test al, al".to_owned(),
+                format!("jnz .dispatch{}", index),
+                format!("jmp program{}", index),
+                format!(".dispatch{}_next:", index),
+            ]
+            .join("\n"),
+            Effects::VOLATILE,
+        ));
+    }
+
+    let names: Vec<&str> = programs.iter().map(|(name, _)| name.as_str()).collect();
+    let usage = format!("{}\n", names.join(" ")).into_bytes();
+    text.push(Label("dispatch_unknown".into()));
+    text.push(MovImmVar(POINTER_REG, "dispatch_names".to_owned()));
+    text.extend(abi_ops.write_bytes(POINTER_REG, usage.len() as u64));
+    text.extend(abi_ops.exit(2));
+    data.push(Data("dispatch_names".to_owned(), usage));
+
+    text.extend(bodies);
+    if checks_pointer {
+        text.push(Label(UNDERFLOW_LABEL.into()));
+        text.extend(abi_ops.exit(1));
+    }
+    Ok((assemble(&*abi_ops, options, &text, &data), abi_ops.linker_info()))
+}
+
+/// Compiles a program given a part at a time, writing the assembly as it goes,
+/// for sources too large to hold all of their steps and instructions at once.
+///
+/// The steps are lowered and optimized in windows that end outside of loops,
+/// so memory use is bounded by the window size and the longest loop.
+/// Optimizations that need the whole program are skipped:
+/// compile-time evaluation and the unrolling of loops it finds trip counts for,
+/// and the compile-time check for moving left of the first cell, which is left to the runtime check.
+/// The optimizer passes still run within each window.
+pub struct StreamCompiler<W: Write> {
+    out: W,
+    options: CompileOptions,
+    abi_ops: Box<dyn target_abi::Operations>,
+    /// Steps not written yet, and the loops open at their end
+    state: State,
+    /// Number of steps after which a window is written, once no loop is open
+    window: usize,
+    /// Number of windows written
+    windows: usize,
+}
+impl<W: Write> StreamCompiler<W> {
+    /// Writes the start of the program.
+    /// Profiles can't be used, as loops are not known in advance.
+    pub fn new(mut out: W, abi: ABI, options: &CompileOptions, window: usize) -> Result<Self> {
+        if !options.profile.is_empty() {
+            return Err(
+                CompileError::InvalidOption("profile", "can't be used with streaming compilation").into(),
+            );
+        }
+        if !options.wide_cells.is_empty() {
+            return Err(CompileError::InvalidOption(
+                "wide_cells",
+                "can't be used with streaming compilation",
+            )
+            .into());
+        }
+        options.validate()?;
+        let mut abi_ops = abi.operations(options)?;
+        let linker_info = abi_ops.linker_info();
+        write_build_comment(&mut out, &*abi_ops, options)?;
+        let header = tape_header(options, true, &mut *abi_ops)?;
+        // Whether the pointer is checked is not known yet, so the tape start is always kept
+        writeln!(
+            out,
+            "{}\n{}\n{}:\n{}",
+            linker_info.to_assembly(),
+            abi_ops.text_section(),
+            linker_info.entrypoint,
+            to_source(options, &header)
+        )?;
+        Ok(Self {
+            out,
+            options: options.clone(),
+            abi_ops,
+            state: State::new(),
+            window: window.max(1),
+            windows: 0,
+        })
+    }
+
+    /// Adds tokens to the program, writing the finished windows
+    pub fn push(&mut self, tokens: &[Token]) -> Result<()> {
+        for &token in tokens {
+            self.state.append(token);
+            if self.state.scope.is_empty() && self.state.steps.len() >= self.window {
+                self.write_window(Vec::new())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the rest of the program, followed by exit
+    pub fn finish(mut self) -> Result<W> {
+        let exit = self.abi_ops.exit(0);
+        self.write_window(exit)?;
+
+        let mut handler = vec![Instruction::Label(UNDERFLOW_LABEL.into())];
+        handler.extend(self.abi_ops.exit(1));
+        let data = embedded_input_data(&self.options, 0);
+        writeln!(self.out, "{}", to_source(&self.options, &handler))?;
+        write_data(&mut self.out, &*self.abi_ops, &self.options, &data)?;
+        if let Some(note) = self.abi_ops.stack_note() {
+            writeln!(self.out, "{}", note)?;
+        }
+        Ok(self.out)
+    }
+
+    /// Optimizes and writes the pending steps, followed by the given instructions
+    fn write_window(&mut self, end: Vec<Instruction>) -> Result<()> {
+        use Instruction::*;
+        if self.options.opt_level >= 1 {
+            self.state.optimize_peephole_combine();
+        }
+        let mut ops: Vec<Instruction> = Vec::new();
+        if self.windows == 0 {
+            ops.extend(self.abi_ops.startup());
+        } else {
+            // Cells are only known to be zero at the start of the program,
+            // so the optimizer must not look past the start of a window
+            ops.push(BlackBox(format!("; window {}", self.windows), Effects::VOLATILE));
+        }
+        for step in self.state.steps.drain(..) {
+            ops.extend(step.to_assembly(POINTER_REG, BASE_REG, &mut *self.abi_ops, &self.options));
+        }
+        self.state.loops.clear();
+        ops.extend(end);
+
+        if self.options.opt_level >= 1 {
+            ops = optimizer::optimize(ops, &self.options);
+        }
+        let (text, data) = optimizer::separate_data(ops);
+        // Data labels are only unique within a window
+        let symbol = format!("window{}", self.windows);
+        let prefix = |op| match op {
+            MovImmVar(r, label) => MovImmVar(r, format!("{}_{}", symbol, label)),
+            MovPtr128(r, label) => MovPtr128(r, format!("{}_{}", symbol, label)),
+            MovPtr256(r, label) => MovPtr256(r, format!("{}_{}", symbol, label)),
+            Data(label, bytes) => Data(format!("{}_{}", symbol, label), bytes),
+            op => op,
+        };
+        let data: Vec<Instruction> = data.into_iter().map(prefix).collect();
+        let text = address_data(&*self.abi_ops, text.into_iter().map(prefix).collect(), &data);
+        writeln!(self.out, "{}", to_source(&self.options, &text))?;
+        if !data.is_empty() {
+            write_data(&mut self.out, &*self.abi_ops, &self.options, &data)?;
+            writeln!(self.out, "{}", self.abi_ops.text_section())?;
+        }
+        self.windows += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        annotate, compile_function, compile_programs, compile_shared_function, compile_tokens,
+        compile_tokens_with_report, compile_tokens_with_stats, data_blobs, evaluated_tape, explain_pass,
+        has_no_effect, loop_report, lower, precompute_output, source_checksum, steps_to_brainfuck,
+        to_brainfuck, to_llvm_ir, write_assembly, Label, State, Step, StreamCompiler,
+    };
+    use crate::error::{CompileError, Error, Warning};
+    use crate::instruction::UNDERFLOW_LABEL;
+    use crate::interpreter::{Interpreter, TapeSnapshot};
+    use crate::optimizer;
+    use crate::options::{
+        BareMetal, CompileOptions, CpuFeature, EofPolicy, LinkerDriver, LoopCounts, OptGoal, TapeMode,
+        WideCells, WideOutput, MAX_TAPE_SIZE,
+    };
+    use crate::parser::{parse, parse_spanned, try_parse_inline_asm, Token};
+    use crate::target_abi::{ABI, EMBEDDED_INPUT_LABEL};
+
+    #[test]
+    fn test_precompute_output() {
+        let options = CompileOptions::default();
+        assert_eq!(
+            precompute_output(parse("++++++++[>++++++++<-]>+."), &options),
+            Some(b"A".to_vec())
+        );
+        assert_eq!(precompute_output(parse("+.,."), &options), None);
+
+        let options = CompileOptions {
+            eval_budget: 1000,
+            ..CompileOptions::default()
+        };
+        assert_eq!(precompute_output(parse("+[]"), &options), None);
+    }
+
+    #[test]
+    fn test_evaluated_tape() {
+        let options = CompileOptions::default();
+        let tape = |cells: &[u8], pointer| TapeSnapshot {
+            cells: cells.to_vec(),
+            pointer,
+        };
+        assert_eq!(
+            evaluated_tape(parse("++[>+++<-]>>>+<"), &options),
+            (tape(&[0, 6, 0, 1], 2), true)
+        );
+        // Stops at the first input
+        assert_eq!(
+            evaluated_tape(parse(">++.<,>"), &options),
+            (tape(&[0, 2], 0), false)
+        );
+        // Trailing zero cells are not included
+        assert_eq!(evaluated_tape(parse("+>>+[-]"), &options), (tape(&[1], 2), true));
+    }
+
+    #[test]
+    fn test_startup_matches_interpreter() {
+        let programs = [
+            include_str!("../examples/helloworld.bf"),
+            include_str!("../examples/print_ab.bf"),
+            "-.+.",
+            "+[+.]",
+            ",.-,.+,+.",
+            ">++[<+++>-]<[>+>+<<-]>.>.",
+            "++[>+++[>++<-]<-]>>.",
+            "+.<",
+        ];
+        for &eof in &[EofPolicy::Zero, EofPolicy::Max, EofPolicy::Unchanged] {
+            // Reads see the end of an empty input, as they do in the interpreter
+            let options = CompileOptions {
+                embedded_input: Some(Vec::new()),
+                eof,
+                ..CompileOptions::default()
+            };
+            for program in &programs {
+                let tokens = parse(program);
+                let interpreted = Interpreter::with_eof(eof).run_collect(&tokens, &[]).ok();
+                assert_eq!(
+                    precompute_output(tokens, &options),
+                    interpreted,
+                    "{} with {:?}",
+                    program,
+                    eof
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_removed_loops() {
+        let spanned = parse_spanned(",>+++[>++<-]<[-]");
+        let options = CompileOptions {
+            loop_spans: vec![spanned[5].1, spanned[13].1],
+            ..CompileOptions::default()
+        };
+        let mut state = State::new();
+        for (token, _) in &spanned {
+            state.append(*token);
+        }
+        state.optimize_peephole_combine();
+        // The first loop runs three times, the second one depends on the input
+        let before = state.loop_jumps();
+        state.optimize_known_loops(&options);
+        assert_eq!(state.removed_loops(&before, &options), vec![spanned[5].1]);
+    }
+
+    #[test]
+    fn test_missing_label() {
+        let options = CompileOptions::default();
+        let mut state = State::from_tokens(parse(",[.,]"), &options).unwrap();
+        assert!(state.to_assembly(ABI::Linux, &options).is_ok());
+
+        state.steps.retain(|step| !matches!(step, Step::Label(_)));
+        for abi in [ABI::Linux, ABI::RiscV64].iter() {
+            assert!(matches!(
+                state.to_assembly(*abi, &options),
+                Err(CompileError::MissingLabel(_))
+            ));
+        }
+
+        state.steps = vec![Step::Add(1), Step::JumpToIf(true, Label(7))];
+        match state.to_assembly(ABI::Linux, &options) {
+            Err(CompileError::MissingLabel(label)) => assert_eq!(label, ".label7"),
+            other => panic!("Expected a missing label, got {:?}", other),
+        }
+
+        // Also when resuming after the startup evaluation
+        state.steps = vec![Step::JumpTo(Label(7)), Step::Add(1)];
+        assert!(matches!(state.to_brainfuck(), Err(CompileError::MissingLabel(_))));
+    }
+
+    #[test]
+    fn test_no_effect_loops() {
+        let spanned = parse_spanned(",[],[><],[+-],[<>]");
+        let tokens: Vec<Token> = spanned.iter().map(|(token, _)| *token).collect();
+        let spans = vec![spanned[1].1, spanned[4].1, spanned[9].1, spanned[14].1];
+        let options = CompileOptions {
+            loop_spans: spans.clone(),
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let state = State::from_tokens(tokens, &options).unwrap();
+        // Moving left first may leave the tape, so the last loop keeps its body
+        assert_eq!(steps_to_brainfuck(&state.steps), ",[],[],[],[<>]");
+        assert_eq!(state.warnings.as_slice(), [
+            Warning::InfiniteLoop(spans[0]),
+            Warning::InfiniteLoop(spans[1]),
+            Warning::InfiniteLoop(spans[2]),
+        ]);
+        assert_eq!(state.notes.len(), 2);
+    }
+
+    #[test]
+    fn test_warnings() {
+        let spanned = parse_spanned(",[][-][.-]");
+        let tokens: Vec<Token> = spanned.iter().map(|(token, _)| *token).collect();
+        let options = CompileOptions {
+            loop_spans: vec![spanned[1].1, spanned[3].1, spanned[6].1],
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let state = State::from_tokens(tokens.clone(), &options).unwrap();
+        assert_eq!(state.warnings.as_slice(), [Warning::InfiniteLoop(spanned[1].1)]);
+        assert!(compile_tokens(tokens.clone(), ABI::Linux, &options).is_ok());
+
+        // The cell is zero after `[-]`, so the last loop is never entered
+        let options = CompileOptions {
+            deny_warnings: true,
+            ..options
+        };
+        let mut warnings = state.warnings.clone();
+        let body = state.to_instructions(&mut *ABI::Linux.operations(&options).unwrap(), &options);
+        optimizer::optimize_with_sources(body, &options, &state.loop_sources(&options), &mut warnings);
+        assert_eq!(warnings.as_slice(), [
+            Warning::InfiniteLoop(spanned[1].1),
+            Warning::UnreachableLoop(spanned[6].1)
+        ]);
+        assert!(matches!(
+            compile_tokens(tokens, ABI::Linux, &options),
+            Err(CompileError::DeniedWarnings(2))
+        ));
+
+        // Loops evaluated at compile time are no mistake
+        let spanned = parse_spanned("++[>+<-]>.");
+        let options = CompileOptions {
+            loop_spans: vec![spanned[2].1],
+            deny_warnings: true,
+            ..CompileOptions::default()
+        };
+        let tokens = spanned.iter().map(|(token, _)| *token).collect();
+        assert!(compile_tokens(tokens, ABI::Linux, &options).is_ok());
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        // The first of the two loops nested two deep is reported
+        let spanned = parse_spanned(",[[-]]>,[[-]]");
+        let tokens: Vec<Token> = spanned.iter().map(|(token, _)| *token).collect();
+        let loop_spans = spanned
+            .iter()
+            .filter(|(token, _)| *token == Token::JumpForwards)
+            .map(|(_, span)| *span)
+            .collect();
+        let options = CompileOptions {
+            loop_spans,
+            max_loop_depth: 1,
+            ..CompileOptions::default()
+        };
+        let state = State::from_tokens(tokens.clone(), &options).unwrap();
+        assert_eq!(state.warnings.as_slice(), [Warning::DeepNesting(2, Some(spanned[2].1))]);
+
+        let options = CompileOptions {
+            deny_warnings: true,
+            ..options
+        };
+        assert!(matches!(
+            State::from_tokens(tokens.clone(), &options),
+            Err(CompileError::DeniedWarnings(1))
+        ));
+        let options = CompileOptions {
+            max_loop_depth: 2,
+            ..options
+        };
+        assert!(State::from_tokens(tokens, &options).is_ok());
+    }
+
+    #[test]
+    fn test_assume_no_input() {
+        assert_eq!(precompute_output(parse(",+."), &CompileOptions::default()), None);
+        for &(eof, output) in &[
+            (EofPolicy::Zero, 1),
+            (EofPolicy::Max, 0),
+            (EofPolicy::Unchanged, 3),
+        ] {
+            let options = CompileOptions {
+                assume_no_input: true,
+                eof,
+                ..CompileOptions::default()
+            };
+            assert_eq!(precompute_output(parse("++,+."), &options), Some(vec![output]));
+        }
+
+        // Input is still read from stdin after the evaluation budget is used
+        let options = CompileOptions {
+            assume_no_input: true,
+            eval_budget: 10,
+            ..CompileOptions::default()
+        };
+        let (asm, _) = compile_tokens(parse("++++++++[-],[+.]"), ABI::Linux, &options).unwrap();
+        assert!(!asm.contains(EMBEDDED_INPUT_LABEL));
+    }
+
+    #[test]
+    fn test_read_retry() {
+        let (asm, link) = compile_tokens(parse(",."), ABI::Linux, &CompileOptions::default()).unwrap();
+        assert!(link.externs.contains(&"__errno_location".to_owned()));
+        // An interrupted read sets up the arguments again
+        assert!(asm.contains(".@0:\nxor rdi, rdi\n"));
+        assert!(asm.contains("call read\ncmp rax, -1\njnz .@1\n"));
+        // Other errors are the end of the input
+        assert!(asm.contains("call __errno_location\ncmp dword [rax], 4\njz .@0\n"));
+        assert!(asm.contains("jz .@0\nxor rax, rax\n.@1:\n"));
+    }
+
+    #[test]
+    fn test_startup_restores_state() {
+        // Output is written from the origin cell, which the code after the first input reads
+        let options = CompileOptions::default();
+        for source in &[
+            "+++.>,<[>+<-]>.",
+            ".>,<+[>+<-]>.",
+            "+++.---.+++++>,<[>+<-]>.",
+            "+++++.>++.<,[>+<-]>.<.",
+            "++[>+++.<-]>>,<<+++.[>>+<<-]>>.",
+            ">+++.<,>[<+>-]<.",
+        ] {
+            let bf = to_brainfuck(parse(source), &options).unwrap();
+            for input in &[&b""[..], b"a", b"ab"] {
+                assert_eq!(
+                    Interpreter::new().run_collect(&parse(&bf), input),
+                    Interpreter::new().run_collect(&parse(source), input),
+                    "{} as {}",
+                    source,
+                    bf
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_eval_tape_limit() {
+        let evaluate = |program: &str, options: &CompileOptions| {
+            let mut state = State::new();
+            for token in parse(program) {
+                state.append(token);
+            }
+            state.optimize_peephole_combine();
+            let (end_state, done) = state.evaluate(options);
+            (end_state.machine, done)
+        };
+
+        // Writes every cell to the right until the end of the tape
+        let options = CompileOptions::default();
+        let (machine, done) = evaluate("+[>+]", &options);
+        assert!(!done);
+        assert_eq!(machine.tape.0.len(), options.tape_size);
+
+        // Moves far right before reading input, which is left to the runtime
+        let options = CompileOptions {
+            tape_size: 1000,
+            ..CompileOptions::default()
+        };
+        let program = format!("+{}+,.", ">".repeat(2000));
+        let (machine, done) = evaluate(&program, &options);
+        assert!(!done);
+        assert_eq!((machine.pointer, machine.tape.0.len()), (0, 1));
+    }
+
+    #[test]
+    fn test_pointer_underflow() {
+        let options = CompileOptions::default();
+        assert_eq!(precompute_output(parse("+.<."), &options), None);
+        assert_eq!(precompute_output(parse(">+<.<>"), &options), None);
+        assert_eq!(precompute_output(parse(">+<>."), &options), Some(vec![1]));
+
+        let (asm, _) = compile_tokens(parse("+.[<.]"), ABI::Linux, &options).unwrap();
+        assert!(asm.contains(&format!("jb {}", UNDERFLOW_LABEL)));
+        assert!(asm.contains(&format!("{}:", UNDERFLOW_LABEL)));
+    }
+
+    #[test]
+    fn test_no_effect() {
+        // Also without compile-time evaluation, which would run them to their end
+        let options = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let (empty, _) = compile_tokens(parse(""), ABI::Linux, &options).unwrap();
+        for source in &[">>><<", ">+>-<<", "+++"] {
+            let (asm, _) = compile_tokens(parse(source), ABI::Linux, &options).unwrap();
+            assert_eq!(asm, empty, "{}", source);
+        }
+        assert!(has_no_effect(&parse(">><"), &options));
+        assert!(!has_no_effect(&parse(">+."), &options));
+        assert!(!has_no_effect(&parse(">,"), &options));
+        assert!(!has_no_effect(&parse("+[]"), &options));
+        // Leaving the tape is an error, or exits with its status
+        assert!(!has_no_effect(&parse("><<"), &options));
+        let tiny = CompileOptions {
+            tape_size: 2,
+            ..options.clone()
+        };
+        assert!(!has_no_effect(&parse(">><<"), &tiny));
+        assert!(matches!(
+            compile_tokens(parse("><<"), ABI::Linux, &options),
+            Err(CompileError::PointerUnderflow(2))
+        ));
+        let bidirectional = CompileOptions {
+            tape: TapeMode::Bidirectional,
+            ..options
+        };
+        assert!(has_no_effect(&parse("<<+"), &bidirectional));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let compile = |options| compile_tokens(parse("+."), ABI::Linux, &options);
+        assert!(matches!(
+            compile(CompileOptions {
+                tape_size: 0,
+                ..CompileOptions::default()
+            }),
+            Err(CompileError::InvalidOption("tape_size", _))
+        ));
+        assert!(matches!(
+            compile(CompileOptions {
+                opt_level: 3,
+                ..CompileOptions::default()
+            }),
+            Err(CompileError::InvalidOption("opt_level", _))
+        ));
+        assert!(matches!(
+            compile(CompileOptions {
+                tape_size: MAX_TAPE_SIZE + 1,
+                ..CompileOptions::default()
+            }),
+            Err(CompileError::TapeTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_prefix_underflow() {
+        let options = CompileOptions::default();
+        let result = compile_tokens(parse("+>.<<[-]"), ABI::Linux, &options);
+        assert!(matches!(result, Err(CompileError::PointerUnderflow(4))));
+        assert!(compile_tokens(parse("+[<]<"), ABI::Linux, &options).is_ok());
+
+        let options = CompileOptions {
+            tape: TapeMode::Bidirectional,
+            ..CompileOptions::default()
+        };
+        let (asm, _) = compile_tokens(parse("<+."), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("add rbx, 15000"));
+    }
+
+    #[test]
+    fn test_explain_pass() {
+        let options = CompileOptions::default();
+        let diff = explain_pass(parse(",[-]."), "optimize_zero_loop", ABI::Linux, &options).unwrap();
+        assert!(diff.contains("\n-dec byte [rbx]\n"));
+        assert!(diff.contains("\n+mov byte [rbx], 0\n"));
+        assert_eq!(
+            explain_pass(parse(",."), "optimize_zero_loop", ABI::Linux, &options),
+            Some(String::new())
+        );
+        assert_eq!(
+            explain_pass(parse(",."), "optimize_nothing", ABI::Linux, &options),
+            None
+        );
+    }
+
+    #[test]
+    fn test_data_blobs() {
+        let options = CompileOptions::default();
+        let blobs = data_blobs(parse("++++++++[>++++++++<-]>+.+."), ABI::Linux, &options).unwrap();
+        assert_eq!(blobs, vec![("constant_output0".to_owned(), b"AB".to_vec())]);
+
+        let options = CompileOptions {
+            embedded_input: Some(b"x".to_vec()),
+            ..CompileOptions::default()
+        };
+        let blobs = data_blobs(parse(",[.,]"), ABI::Linux, &options).unwrap();
+        assert_eq!(blobs[0], ("constant_output0".to_owned(), b"x".to_vec()));
+        assert_eq!(blobs[1], (EMBEDDED_INPUT_LABEL.to_owned(), b"x\0".to_vec()));
+    }
+
+    #[test]
+    fn test_to_brainfuck() {
+        let options = CompileOptions::default();
+        let unoptimized = CompileOptions {
+            opt_level: 0,
+            ..CompileOptions::default()
+        };
+        assert_eq!(
+            to_brainfuck(parse("+++--[->+<]"), &unoptimized).unwrap(),
+            "+++--[->+<]"
+        );
+        assert_eq!(to_brainfuck(parse("+++-->>><<-"), &options).unwrap(), "");
+        assert_eq!(to_brainfuck(parse("+++-.>,"), &options).unwrap(), "++.+[+]++>,");
+
+        // Resuming inside loops after the startup evaluation
+        for source in &[
+            "++[>,.<-]",
+            "+++[>++[>,.<-]<-]>>.",
+            "++[>,[.-]<-]",
+            ">+++++[<++++>-]<[>,.<-]",
+        ] {
+            let bf = to_brainfuck(parse(source), &options).unwrap();
+            let input = b"abcdefghijklmnopqrstuvwxyz";
+            assert_eq!(
+                Interpreter::new().run_collect(&parse(&bf), input),
+                Interpreter::new().run_collect(&parse(source), input),
+                "{} as {}",
+                source,
+                bf
+            );
+        }
+    }
+
+    #[test]
+    fn test_long_runs() {
+        assert_eq!(lower(&parse(&"+".repeat(300))), vec![Step::Add(44)]);
+        assert_eq!(lower(&parse(&"-".repeat(256))), vec![Step::Add(0)]);
+
+        // The add of zero is removed by `optimize_remove_nops`
+        let options = CompileOptions {
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let source = format!(",{}.", "+".repeat(256));
+        let (asm, _) = compile_tokens(parse(&source), ABI::Linux, &options).unwrap();
+        assert!(!asm.contains("add byte") && !asm.contains("inc byte"));
+    }
+
+    #[test]
+    fn test_compile_programs() {
+        let options = CompileOptions::default();
+        let programs = vec![("a".to_owned(), parse("+.")), ("b".to_owned(), parse("++."))];
+        let (asm, _) = compile_programs(&programs, ABI::Linux, &options).unwrap();
+        assert!(asm.contains("jmp program1"));
+        assert!(asm.contains("program0_constant_output0: db 0x1"));
+        assert!(asm.contains("program1_constant_output0: db 0x2"));
+        assert!(asm.contains("dispatch_names: db \"a b\",0xa"));
+
+        let programs = vec![("a".to_owned(), parse("+.")), ("b".to_owned(), parse("<"))];
+        let result = compile_programs(&programs, ABI::Linux, &options);
+        match result {
+            Err(CompileError::InProgram(name, error)) => {
+                assert_eq!(name, "b");
+                assert!(matches!(*error, CompileError::PointerUnderflow(0)));
+            },
+            _ => panic!("Expected an error in program b"),
+        }
+
+        // Threads give the same assembly, and the error of the first failing program
+        let programs: Vec<(String, Vec<Token>)> = (0..5)
+            .map(|i| {
+                let source = if i < 3 { format!("{}.", "+".repeat(i + 1)) } else { "<".to_owned() };
+                (format!("p{}", i), parse(&source))
+            })
+            .collect();
+        let sequential = compile_programs(&programs[..3], ABI::Linux, &options).unwrap();
+        for threads in 2..=6 {
+            let options = CompileOptions {
+                threads,
+                ..CompileOptions::default()
+            };
+            assert_eq!(
+                compile_programs(&programs[..3], ABI::Linux, &options).unwrap().0,
+                sequential.0
+            );
+            match compile_programs(&programs, ABI::Linux, &options) {
+                Err(CompileError::InProgram(name, _)) => assert_eq!(name, "p3"),
+                _ => panic!("Expected an error in program p3"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_compile_function() {
+        let options = CompileOptions::default();
+        let (asm, link) = compile_function(parse(",.[<]"), "program", ABI::Linux, &options).unwrap();
+        assert_eq!(link.entrypoint, "program");
+        assert!(asm.contains("global program\n"));
+        assert!(asm.contains("push rbx\npush r12"));
+        assert!(asm.contains("mov eax, 1\nret"));
+        assert!(!asm.contains("call exit"));
+
+        let (asm, _) = compile_function(parse(",."), "program", ABI::MacOS, &options).unwrap();
+        assert!(asm.contains("global _program\n"));
+        assert!(compile_function(parse("."), "program", ABI::RiscV64, &options).is_err());
+    }
+
+    #[test]
+    fn test_compile_shared_function() {
+        // The constant output is data, loaded relative to rip like on macOS
+        let source = parse("++++++++[>++++++++<-]>+.,.");
+        let options = CompileOptions::default();
+        let (asm, link) = compile_shared_function(source.clone(), "program", ABI::Linux, &options).unwrap();
+        assert_eq!(link.entrypoint, "program");
+        assert_eq!(link.linker_args, ["-shared"]);
+        assert!(asm.contains("lea rsi, [rel constant_output0]"));
+        assert!(asm.contains("call read wrt ..plt"));
+        assert!(asm.contains("call write wrt ..plt"));
+
+        let (asm, link) = compile_shared_function(source, "program", ABI::MacOS, &options).unwrap();
+        assert_eq!(link.linker_args[0], "-dylib");
+        assert!(asm.contains("call _read\n"));
+    }
+
+    #[test]
+    fn test_stats() {
+        let source = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let stats = |opt_level| {
+            let options = CompileOptions {
+                opt_level,
+                ..CompileOptions::default()
+            };
+            compile_tokens_with_stats(parse(source), ABI::Linux, &options)
+                .unwrap()
+                .2
+        };
+        let baseline = stats(0);
+        let optimized = stats(2);
+        assert!(optimized.instructions > 0);
+        assert!(optimized.instructions < baseline.instructions);
+        assert!(optimized.cost < baseline.cost);
+
+        // Repeated commands are combined from -O1, and the program without input is evaluated at -O2
+        let combined = stats(1);
+        assert_eq!(baseline.steps.tokens, parse(source).len());
+        assert_eq!(combined.steps.tokens, baseline.steps.tokens);
+        assert!(combined.steps.combined < baseline.steps.combined);
+        assert_eq!(combined.steps.after_startup, combined.steps.combined);
+        assert_eq!(optimized.steps.combined, combined.steps.combined);
+        // The program is replaced by 5 steps per byte of output, setting, writing and clearing the cell,
+        // which happens to be as many steps as before the evaluation
+        assert_eq!(optimized.steps.after_startup, 5 * b"Hello World!\n".len());
+    }
+
+    #[test]
+    fn test_report() {
+        let spanned = parse_spanned(",[][-][.-]");
+        let tokens: Vec<Token> = spanned.iter().map(|(token, _)| *token).collect();
+        let options = CompileOptions {
+            loop_spans: vec![spanned[1].1, spanned[3].1, spanned[6].1],
+            opt_level: 1,
+            ..CompileOptions::default()
+        };
+        let (asm, link, report) = compile_tokens_with_report(tokens.clone(), ABI::Linux, &options).unwrap();
+        let (expected_asm, expected_link, stats) =
+            compile_tokens_with_stats(tokens.clone(), ABI::Linux, &options).unwrap();
+        assert_eq!(asm, expected_asm);
+        assert_eq!(link, expected_link);
+        assert_eq!(report.after, stats);
+        assert!(report.before.instructions > report.after.instructions);
+        assert_eq!(report.target, ABI::Linux.triple());
+        assert_eq!(report.linker, link.linker_cmd);
+        assert_eq!(report.opt_for, "balanced");
+        assert!(report.passes.iter().any(|pass| pass.changes > 0));
+        assert!(report.passes.iter().all(|pass| pass.changes <= pass.runs));
+        assert_eq!(report.warnings, [
+            Warning::InfiniteLoop(spanned[1].1).to_string(),
+            Warning::UnreachableLoop(spanned[6].1).to_string()
+        ]);
+        assert_eq!(report.output, None);
+
+        assert!(compile_tokens_with_report(tokens, ABI::RiscV64, &options).is_err());
+    }
+
+    #[test]
+    fn test_loop_report() {
+        let options = CompileOptions::default();
+        let report = loop_report(&parse_spanned("++[>+++[-]<-]\n,[.,]"), &options);
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].iterations, Some(2));
+        assert_eq!(report[0].children[0].iterations, Some(6));
+        assert_eq!(report[1].iterations, None);
+        assert_eq!(format!("{}", report[0]), "[1:3 .. 1:13] x2\n  [1:8 .. 1:10] x6\n");
+    }
+
+    #[test]
+    fn test_hot_loops() {
+        let exit_checks = |iterations, opt_for| {
+            let options = CompileOptions {
+                profile: vec![Some(LoopCounts {
+                    entries: 1,
+                    iterations,
+                })],
+                opt_for,
+                ..CompileOptions::default()
+            };
+            let mut state = State::new();
+            for token in parse(",[->+<]>.") {
+                state.append(token);
+            }
+            state.optimize(&options);
+            state
+                .steps
+                .iter()
+                .filter(|s| matches!(s, Step::JumpToIf(false, _)))
+                .count()
+        };
+        assert_eq!(exit_checks(10, OptGoal::Speed), 1);
+        assert_eq!(exit_checks(5000, OptGoal::Speed), 4);
+        assert_eq!(exit_checks(5000, OptGoal::Balanced), 2);
+        assert_eq!(exit_checks(5000, OptGoal::Size), 1);
+    }
+
+    #[test]
+    fn test_known_loops() {
+        let steps_with = |program, options: &CompileOptions| {
+            let mut state = State::new();
+            for token in parse(program) {
+                state.append(token);
+            }
+            state.optimize_known_loops(options);
+            state.steps
+        };
+        let steps = |program, max_unroll| {
+            let options = CompileOptions {
+                max_unroll,
+                ..CompileOptions::default()
+            };
+            steps_with(program, &options)
+        };
+        let jumps = |steps: &[Step]| steps.iter().filter(|s| matches!(s, Step::JumpToIf(..))).count();
+
+        let unrolled = steps("+++[>+<-]>.", 64);
+        assert_eq!(jumps(&unrolled), 0);
+        assert_eq!(unrolled.len(), 3 + 3 * 4 + 2);
+
+        // Too many steps, or unknown counter
+        assert_eq!(jumps(&steps("+++[>+<-]>.", 11)), 2);
+        assert_eq!(jumps(&steps(",[>+<-]>.", 64)), 2);
+        // Only short loops are unrolled when optimizing for size
+        let size = CompileOptions {
+            opt_for: OptGoal::Size,
+            ..CompileOptions::default()
+        };
+        assert_eq!(jumps(&steps_with("+++[>+<-]>.", &size)), 2);
+        assert_eq!(jumps(&steps_with("++[-]>.", &size)), 0);
+        // The inner loop runs to zero, so the second one is skipped
+        assert_eq!(jumps(&steps(",[-]++[>+<-][-]", 64)), 2);
+
+        // The startup evaluation stored the counter after input
+        let mut state = State::new();
+        for token in parse(",>++++[<+>-]<.") {
+            state.append(token);
+        }
+        state.optimize(&CompileOptions::default());
+        assert_eq!(jumps(&state.steps), 0);
+
+        // The startup evaluation stopped inside the loop, which must not be unrolled
+        let mut state = State::new();
+        for token in parse("+++[>,<-]") {
+            state.append(token);
+        }
+        state.optimize(&CompileOptions::default());
+        assert_eq!(jumps(&state.steps), 2);
+
+        // The startup evaluation stopped by its budget next to the loops, keeping the label to resume at
+        for &(program, eval_budget) in &[("+[->+<]+[->+<]>.", 11), ("[.-]>[-]---.[+]", 13)] {
+            let options = CompileOptions {
+                eval_budget,
+                ..CompileOptions::default()
+            };
+            assert!(compile_tokens(parse(program), ABI::Linux, &options).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_conditional_loops() {
+        let steps = |program| {
+            let mut state = State::new();
+            for token in parse(program) {
+                state.append(token);
+            }
+            state.optimize_peephole_combine();
+            state.optimize_conditional_loops();
+            state
+        };
+        let back_jumps = |state: &State| {
+            state
+                .steps
+                .iter()
+                .filter(|s| matches!(s, Step::JumpToIf(true, _)))
+                .count()
+        };
+
+        // The flag in the first cell is cleared if the input is nonzero, so it is zero or one
+        let program = "+>,[<->[-]]<[>+++<-]>.";
+        let state = steps(program);
+        assert_eq!(back_jumps(&state), 2);
+        assert_eq!(state.to_brainfuck().unwrap(), program);
+        for input in &[b"\0", b"a"] {
+            let options = CompileOptions {
+                embedded_input: Some(input.to_vec()),
+                ..CompileOptions::default()
+            };
+            let (end_state, done) = state.evaluate(&options);
+            assert!(done);
+            let expected = Interpreter::new().run_collect(&parse(program), *input).unwrap();
+            assert_eq!(end_state.io.output, expected);
+        }
+
+        // Zero or one trips, but the body doesn't clear the cell
+        assert_eq!(back_jumps(&steps("+>,[<->[-]]<[>+<]")), 3);
+        // The cell may be two
+        assert_eq!(back_jumps(&steps("++>,[<->[-]]<[>+<-]")), 3);
+        // The cell is read in the body
+        assert_eq!(back_jumps(&steps("+>,[<->[-]]<[,-]")), 3);
+        // Inside a loop, the flag is set on each iteration and moved back to the first cell
+        assert_eq!(back_jumps(&steps(",[>+<[>-<[-]]>[<+>-]<,]")), 3);
+    }
+
+    #[test]
+    fn test_embedded_input() {
+        let options = CompileOptions {
+            embedded_input: Some(b"a\"b".to_vec()),
+            ..CompileOptions::default()
+        };
+        assert_eq!(
+            precompute_output(parse(",[.,]"), &options),
+            Some(b"a\"b".to_vec())
+        );
+
+        let options = CompileOptions {
+            eval_budget: 2,
+            ..options
+        };
+        let (asm, _) = compile_tokens(parse(",.,[.,]"), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("embedded_input: db \"a\",0x22,\"b\",0x0\n"));
+        assert!(asm.contains("embedded_input_position: db 0x1,"));
+        assert!(!asm.contains("call read"));
+    }
+
+    #[test]
+    fn test_stream_compiler() {
+        // The loop is kept, so that windows can end after it
+        let options = CompileOptions {
+            disabled_passes: vec!["optimize_multiply_loop".to_owned()],
+            ..CompileOptions::default()
+        };
+        let tokens = parse("+++.+.[->+<]>.");
+        let stream = |window| {
+            let mut compiler = StreamCompiler::new(Vec::new(), ABI::Linux, &options, window).unwrap();
+            for chunk in tokens.chunks(5) {
+                compiler.push(chunk).unwrap();
+            }
+            String::from_utf8(compiler.finish().unwrap()).unwrap()
+        };
+
+        // The whole program fits in one window
+        let asm = stream(100);
+        assert!(asm.contains("window0_constant_output0: db 0x3\n"));
+        assert!(!asm.contains("; window 1"));
+        assert!(asm.contains(&format!("{}:", UNDERFLOW_LABEL)));
+
+        // Windows end outside of loops, and the first one ends with a constant output
+        let asm = stream(4);
+        assert!(asm.contains("; window 1"));
+        assert!(asm.contains("window0_constant_output0: db 0x3\n"));
+        assert!(asm.contains("jnz .label0"));
+
+        let options = CompileOptions {
+            profile: vec![None],
+            ..CompileOptions::default()
+        };
+        assert!(matches!(
+            StreamCompiler::new(Vec::new(), ABI::Linux, &options, 8),
+            Err(Error::Compile(CompileError::InvalidOption("profile", _)))
+        ));
+    }
+
+    #[test]
+    fn test_write_assembly() {
+        let options = CompileOptions::default();
+        let tokens = parse("++++++++[>++++++++<-]>+.+.,[.,]");
+        let (asm, link, stats) = compile_tokens_with_stats(tokens.clone(), ABI::Linux, &options).unwrap();
+        let mut written = Vec::new();
+        let (written_link, written_stats) =
+            write_assembly(&mut written, tokens, ABI::Linux, &options).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), asm);
+        assert_eq!(written_link, link);
+        assert_eq!(written_stats, stats);
+    }
+
+    #[test]
+    fn test_data_length_symbols() {
+        let options = CompileOptions {
+            data_length_symbols: true,
+            ..CompileOptions::default()
+        };
+        let (asm, _) = compile_tokens(parse("++++++++[>++++++++<-]>+.+."), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("mov rdx, constant_output0_len\n"));
+        assert!(asm.contains("constant_output0: db \"AB\"\nconstant_output0_len equ $ - constant_output0\n"));
+
+        let (asm, _) = compile_tokens(
+            parse("++++++++[>++++++++<-]>+.+."),
+            ABI::Linux,
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        assert!(!asm.contains("_len"));
+    }
+
+    #[test]
+    fn test_target_cpu() {
+        // Sixteen cells are initialized before the input, and printed after it
+        let mut source = String::from(">");
+        for value in 0..16 {
+            source.push_str(&"+".repeat(65 + value));
+            source.push('>');
+        }
+        source.push_str(",[<]>[.>]");
+
+        let (asm, _) = compile_tokens(parse(&source), ABI::Linux, &CompileOptions::default()).unwrap();
+        assert!(asm.contains("rep stosb"));
+        assert!(!asm.contains("xmm0"));
+
+        let options = CompileOptions {
+            target_cpu: Some(CpuFeature::Sse2),
+            tape_size: 100,
+            ..CompileOptions::default()
+        };
+        let (asm, _) = compile_tokens(parse(&source), ABI::Linux, &options).unwrap();
+        assert!(!asm.contains("rep stosb"));
+        assert!(asm.contains("sub rsp, 112\n"));
+        assert!(asm.contains("movdqu [rdi], xmm0"));
+        assert!(asm.contains("vector_4142434445464748494a4b4c4d4e4f50:"));
+
+        let options = CompileOptions {
+            target_cpu: Some(CpuFeature::Avx2),
+            ..options
+        };
+        let (asm, _) = compile_tokens(parse(&source), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("sub rsp, 128\n"));
+        assert!(asm.contains("vmovdqu [rdi], ymm0"));
+        assert!(asm.contains("vzeroupper"));
+
+        // The runs are still stored with vectors, but the tape is cleared with the shorter loop
+        let options = CompileOptions {
+            opt_for: OptGoal::Size,
+            ..options
+        };
+        let (asm, _) = compile_tokens(parse(&source), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("rep stosb"));
+        assert!(!asm.contains(".zero_tape:"));
+        assert!(asm.contains("vector_4142434445464748494a4b4c4d4e4f50:"));
+    }
+
+    #[test]
+    fn test_inline_asm() {
+        let tokens = |source| {
+            try_parse_inline_asm(source)
+                .unwrap()
+                .into_iter()
+                .map(|(token, _)| token)
+                .collect::<Vec<_>>()
+        };
+        let options = CompileOptions::default();
+        // Evaluation stops before the assembly, as it may change the cell
+        let source = "++++++++[>++++++++<-]>{! inc byte [$pointer] !}.";
+        assert_eq!(precompute_output(tokens(source), &options), None);
+        let (asm, _) = compile_tokens(tokens(source), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("\ninc byte [rbx]\n"));
+        assert_eq!(
+            to_brainfuck(tokens(source), &options).unwrap(),
+            format!(">{}{{!inc byte [$pointer]!}}.", "+".repeat(64))
+        );
+
+        // Known values are forgotten, so the loop is not unrolled
+        let (asm, _) = compile_tokens(tokens(",>+++{! nop !}[-<+>]<."), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("\nnop\n"));
+        assert!(asm.contains("jnz"));
+    }
+
+    #[test]
+    fn test_section_attributes() {
+        let options = CompileOptions {
+            embedded_input: Some(b"ab".to_vec()),
+            ..CompileOptions::default()
+        };
+        let (asm, _) = compile_tokens(parse("+++++[>+++++++++++++<-]>.,[.,]"), ABI::Linux, &options).unwrap();
+        let text = asm.find("section .text progbits alloc exec nowrite").unwrap();
+        let rodata = asm.find("section .rodata progbits alloc noexec nowrite").unwrap();
+        let data = asm.find("section .data progbits alloc noexec write").unwrap();
+        assert!(text < rodata && rodata < data);
+        // The constant output and the input are never written to, unlike the input position
+        assert!(asm[rodata..data].contains("embedded_input:"));
+        assert!(asm[data..].contains("embedded_input_position:"));
+        assert!(asm.ends_with("section .note.GNU-stack noalloc noexec nowrite progbits\n"));
+
+        let (asm, _) = compile_tokens(parse("+."), ABI::MacOS, &CompileOptions::default()).unwrap();
+        assert!(asm.contains("section .text\n"));
+        assert!(!asm.contains("GNU-stack"));
+    }
+
+    #[test]
+    fn test_macos_relative_data() {
+        let options = CompileOptions {
+            data_length_symbols: true,
+            ..CompileOptions::default()
+        };
+        let source = "++++++++[>++++++++<-]>+.+.";
+        let (asm, _) = compile_tokens(parse(source), ABI::MacOS, &options).unwrap();
+        assert!(asm.contains("lea rsi, [rel constant_output0]\nmov rdx, constant_output0_len\n"));
+        assert!(!asm.contains("mov rsi, constant_output0"));
+
+        // The data labels of each streamed window are prefixed
+        let mut compiler = StreamCompiler::new(Vec::new(), ABI::MacOS, &options, 100).unwrap();
+        compiler.push(&parse("+++.+.")).unwrap();
+        let asm = String::from_utf8(compiler.finish().unwrap()).unwrap();
+        assert!(asm.contains("lea rsi, [rel window0_constant_output0]\n"));
+
+        // Linux links without position-independent code
+        let (asm, _) = compile_tokens(parse(source), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("mov rsi, constant_output0\n"));
+    }
+
+    #[test]
+    fn test_guard_pages() {
+        let options = CompileOptions {
+            guard_pages: true,
+            ..CompileOptions::default()
+        };
+        let (asm, link) = compile_tokens(parse(",[>.,]"), ABI::Linux, &options).unwrap();
+        assert!(link.externs.contains(&"mmap".to_owned()));
+        // Eight pages of tape between the guards, ending at the second guard
+        assert!(asm.contains("mov rsi, 40960\n"));
+        assert!(asm.contains("call mmap\n"));
+        assert!(asm.contains("mov rsi, 32768\nmov rdx, 3\ncall mprotect\n"));
+        assert!(asm.contains("add rbx, 2768\n"));
+        assert!(!asm.contains("rep stosb"));
+        assert!(!asm.contains("sub rsp, 30000"));
+        assert!(!asm.contains("add rsp"));
+
+        let (asm, _) = compile_tokens(parse(",[>.,]"), ABI::MacOS, &options).unwrap();
+        assert!(asm.contains("mov rcx, 4098\n"));
+        assert!(asm.contains("call _mprotect\n"));
+
+        assert!(matches!(
+            compile_tokens(parse(",[>.,]"), ABI::BareMetal, &options),
+            Err(CompileError::InvalidOption("guard_pages", _))
+        ));
+    }
+
+    #[test]
+    fn test_bare_metal() {
+        // Input is only known at run time, and the output is a byte at a time
+        let source = parse(",[.,]");
+        let options = CompileOptions {
+            bare_metal: BareMetal {
+                tape_address: 0x8000,
+                output_address: 0x3f8,
+                input_address: Some(0x3fc),
+                exit_symbol: None,
+                ..BareMetal::default()
+            },
+            ..CompileOptions::default()
+        };
+        let (asm, link) = compile_tokens(source.clone(), ABI::BareMetal, &options).unwrap();
+        assert_eq!(link.entrypoint, "_start");
+        assert!(link.externs.is_empty());
+        assert!(asm.contains("mov rdi, 0x8000\nxor al, al\nrep stosb\nmov rbx, 0x8000\n"));
+        assert!(asm.contains("mov rdx, 0x3fc\nmov al, [rdx]\nmov [rbx], al\n"));
+        assert!(asm.contains("mov al, [rbx]\nmov rdx, 0x3f8\nmov [rdx], al\n"));
+        assert!(asm.contains("cli\nhlt\n"));
+        assert!(!asm.contains("rsp"));
+        assert!(!asm.contains("call"));
+
+        // Without an input register `,` reads end of input, and the exit function is called
+        let options = CompileOptions {
+            bare_metal: BareMetal {
+                exit_symbol: Some("kernel_exit".to_owned()),
+                ..BareMetal::default()
+            },
+            eof: EofPolicy::Max,
+            ..CompileOptions::default()
+        };
+        let (asm, link) = compile_tokens(source, ABI::BareMetal, &options).unwrap();
+        assert_eq!(link.externs, vec!["kernel_exit".to_owned()]);
+        assert!(asm.starts_with("extern kernel_exit\n"));
+        assert!(asm.contains("call kernel_exit"));
+        assert!(!asm.contains("[rdx]\nmov [rbx]"));
+
+        let programs = [("a".to_owned(), parse("."))];
+        assert!(matches!(
+            compile_programs(&programs, ABI::BareMetal, &options),
+            Err(CompileError::InvalidOption("target", _))
+        ));
+    }
+
+    #[test]
+    fn test_freestanding() {
+        let options = CompileOptions {
+            bare_metal: BareMetal {
+                serial_port: 0x2f8,
+                ..BareMetal::default()
+            },
+            ..CompileOptions::default()
+        };
+        let (asm, link) = compile_tokens(parse(",[.,]"), ABI::Freestanding, &options).unwrap();
+        assert_eq!(link.entrypoint, "_start");
+        assert_eq!(link.linker_args, vec![
+            "--oformat".to_owned(),
+            "binary".to_owned()
+        ]);
+        assert!(asm.contains("mov rdi, 0x200000\nxor al, al\nrep stosb\nmov rbx, 0x200000\n"));
+        // Each byte waits for the line status register, polling it until it's set
+        assert!(asm.contains(".@0:\nmov dx, 0x2fd\nin al, dx\ntest al, 0x1\njz .@0\n"));
+        assert!(asm.contains("mov dx, 0x2f8\nin al, dx\nmov [rbx], al\n"));
+        assert!(asm.contains("test al, 0x20\n"));
+        assert!(asm.contains("mov dx, 0x2f8\nmov al, [rsi]\nout dx, al\n"));
+        assert!(asm.contains("cli\nhlt\n"));
+        assert!(!asm.contains("call"));
+        assert!(!asm.contains("rsp"));
+
+        let programs = [("a".to_owned(), parse("."))];
+        assert!(matches!(
+            compile_programs(&programs, ABI::Freestanding, &options),
+            Err(CompileError::InvalidOption("target", _))
+        ));
+    }
+
+    #[test]
+    fn test_wide_cells() {
+        // Cells 1 and 2 have four bytes, so cell 3 starts 9 bytes after cell 0
+        let wide = WideCells {
+            first: 1,
+            last: 2,
+            bytes: 4,
+        };
+        let options = CompileOptions {
+            wide_cells: vec![wide],
+            ..CompileOptions::default()
+        };
+        let source = format!(",[>-<-]>{}[>>.<<-]", "+".repeat(200));
+        let (asm, _) = compile_tokens(parse(&source), ABI::Linux, &options).unwrap();
+        // The stack is allocated in multiples of 16 bytes, keeping it aligned for calls
+        assert!(asm.contains("sub rsp, 30016\nmov rcx, 30006\n"));
+        assert!(asm.contains("add rsp, 30016\n"));
+        assert!(asm.contains("inc rbx\nadd dword [rbx], 4294967295\ndec rbx\n"));
+        // Adds to a wide cell are not wrapped to a byte
+        assert!(asm.contains("add dword [rbx], 127\nadd dword [rbx], 73\ncmp dword [rbx], 0\n"));
+        assert!(asm.contains("add rbx, 8\n"));
+
+        // The pointer must be at a known cell
+        assert!(matches!(
+            compile_tokens(parse("+[>]"), ABI::Linux, &options),
+            Err(CompileError::InvalidOption("wide_cells", _))
+        ));
+        let options = CompileOptions {
+            wide_cells: vec![wide, WideCells { first: 2, ..wide }],
+            ..CompileOptions::default()
+        };
+        assert!(matches!(
+            compile_tokens(parse("+"), ABI::Linux, &options),
+            Err(CompileError::InvalidOption("wide_cells", "the ranges overlap"))
+        ));
+
+        // `.` writes the low byte of a 16-bit cell, or both of its bytes
+        let options = CompileOptions {
+            wide_cells: vec![WideCells {
+                first: 0,
+                last: 0,
+                bytes: 2,
+            }],
+            ..CompileOptions::default()
+        };
+        let (asm, _) = compile_tokens(parse(",.>."), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("mov rsi, rbx
+mov rdx, 1
+call write
+add rbx, 2
+"));
+        let options = CompileOptions {
+            wide_output: WideOutput::Full,
+            ..options
+        };
+        let (asm, _) = compile_tokens(parse(",.>."), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("mov rsi, rbx
+mov rdx, 2
+call write
+add rbx, 2
+"));
+        // Byte cells are written as before
+        assert!(asm.contains("add rbx, 2
+mov rdi, 1
+mov rsi, rbx
+mov rdx, 1
+call write
+"));
+
+        // The compile-time evaluation and Brainfuck only have byte cells
+        let program = parse(&format!("{}.", "+".repeat(300)));
+        assert_eq!(precompute_output(program.clone(), &options), None);
+        assert!(matches!(
+            to_brainfuck(program, &options),
+            Err(CompileError::InvalidOption("wide_cells", _))
+        ));
+    }
+
+    #[test]
+    fn test_llvm_ir() {
+        let options = CompileOptions {
+            opt_level: 1,
+            eof: EofPolicy::Max,
+            ..CompileOptions::default()
+        };
+        let ir = to_llvm_ir(parse(",[<.,]"), &options).unwrap();
+        assert!(ir.contains("@tape = internal global [30000 x i8] zeroinitializer\n"));
+        assert!(ir.contains("= call i32 @getchar()\n"));
+        assert!(ir.contains(", i8 255, i8 %t"));
+        assert!(ir.contains(" = call i32 @putchar(i32 %t"));
+        assert!(ir.contains("= icmp ult ptr %t9, @tape\n  br i1 %t10, label %underflow, label %block1\n"));
+        assert!(ir.ends_with("  ret i32 0\nunderflow:\n  ret i32 1\n}\n"));
+
+        // The tape starts in the middle
+        let options = CompileOptions {
+            tape: TapeMode::Bidirectional,
+            tape_size: 100,
+            ..options
+        };
+        let ir = to_llvm_ir(parse(",<."), &options).unwrap();
+        assert!(ir.contains("getelementptr inbounds ([100 x i8], ptr @tape, i64 0, i64 50)"));
+
+        let tokens: Vec<_> = try_parse_inline_asm("{! nop !}")
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect();
+        assert!(matches!(
+            to_llvm_ir(tokens, &options),
+            Err(CompileError::InvalidOption("inline_asm", _))
+        ));
+
+        // The loop never ends
+        let spanned = parse_spanned(",[]");
+        let options = CompileOptions {
+            loop_spans: vec![spanned[1].1],
+            deny_warnings: true,
+            ..options
+        };
+        assert!(matches!(
+            to_llvm_ir(parse(",[]"), &options),
+            Err(CompileError::DeniedWarnings(1))
+        ));
+    }
+
+    #[test]
+    fn test_annotate() {
+        let source = "++++++++[>++++++++<-]>+.\n,[>[-]<[->+>+<<]>.<,]\n";
+        let tokens = parse_spanned(source);
+        let options = CompileOptions {
+            opt_level: 2,
+            ..CompileOptions::default()
+        };
+        assert_eq!(
+            annotate(source, &tokens, ABI::Linux, &options).unwrap(),
+            "++++++++(Evaluated loop at compile time) [>++++++++<-]>+.\n,[>(Replaced zeroing loop with a \
+             store) [-]<(Replaced multiply loop with multiply adds) [->+>+<<]>.<,]\n"
+        );
+
+        // Loops inside a loop still running when evaluation stops are not evaluated
+        let source = "+[[-]>,<]";
+        let annotated = annotate(source, &parse_spanned(source), ABI::Linux, &options).unwrap();
+        assert_eq!(annotated, "+[(Replaced zeroing loop with a store) [-]>,<]");
+
+        let options = CompileOptions {
+            opt_level: 0,
+            ..options
+        };
+        assert_eq!(
+            annotate(source, &parse_spanned(source), ABI::Linux, &options).unwrap(),
+            source
+        );
+    }
+
+    #[test]
+    fn test_source_checksum() {
+        // FNV-1a of the empty input is its offset basis
+        assert_eq!(source_checksum(b""), 0xcbf2_9ce4_8422_2325);
+        assert_ne!(source_checksum(b"+."), source_checksum(b"-."));
+
+        let checksum = source_checksum(b"+.");
+        let options = CompileOptions {
+            source_checksum: Some(checksum),
+            ..CompileOptions::default()
+        };
+        let blobs = data_blobs(parse("+."), ABI::Linux, &options).unwrap();
+        assert!(blobs.contains(&("source_checksum".to_owned(), checksum.to_le_bytes().to_vec())));
+        let (asm, _) = compile_tokens(parse("+."), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("source_checksum: "));
+    }
+
+    #[test]
+    fn test_source_comments() {
+        let options = CompileOptions {
+            opt_level: 1,
+            source_comments: true,
+            ..CompileOptions::default()
+        };
+        let (asm, _) = compile_tokens(parse("+++++[->>+<<]><>."), ABI::Linux, &options).unwrap();
+        assert!(asm.contains("; +++++\nadd byte [rbx], 5\n; [\ncmp byte [rbx], 0\n"));
+        assert!(asm.contains("; -\ndec byte [rbx]\n; >>\nadd rbx, 2\n; +\ninc byte [rbx]\n; <<\n"));
+        assert!(asm.contains("; ]\ncmp byte [rbx], 0\n"));
+        // `><` cancels out, leaving the last `>`
+        assert!(asm.contains("; >\ninc rbx\n; .\n"));
+        assert!(!asm.contains("; ><"));
+
+        let without = CompileOptions {
+            source_comments: false,
+            ..options.clone()
+        };
+        let (asm, _) = compile_tokens(parse("+++++"), ABI::Linux, &without).unwrap();
+        assert!(!asm.contains("; +"));
+
+        let optimized = CompileOptions {
+            opt_level: 2,
+            ..options
+        };
+        let result = compile_tokens(parse("+"), ABI::Linux, &optimized);
+        assert!(matches!(result, Err(CompileError::InvalidOption("source_comments", _))));
+    }
+
+    #[test]
+    fn test_line_directives() {
+        let spanned = parse_spanned(",[\n.,\n]");
+        let tokens: Vec<Token> = spanned.iter().map(|(token, _)| *token).collect();
+        let options = CompileOptions {
+            loop_spans: vec![spanned[1].1],
+            loop_end_spans: vec![spanned[4].1],
+            line_directives: Some("program.bf".to_owned()),
+            ..CompileOptions::default()
+        };
+        let (asm, _) = compile_tokens(tokens.clone(), ABI::Linux, &options).unwrap();
+        // Each directive precedes the label of the loop it is on, renumbered after the directives are placed
+        let label_after = |directive: &str| {
+            let start = asm.find(directive).unwrap() + directive.len();
+            asm[start..].lines().next().unwrap().to_owned()
+        };
+        let start = label_after("%line 1+0 program.bf\n");
+        let end = label_after("%line 3+0 program.bf\n");
+        assert!(start.starts_with(".@") && start.ends_with(':'));
+        assert!(end.starts_with(".@") && end.ends_with(':'));
+        assert!(asm.contains(&format!("jnz {}\n", start.trim_end_matches(':'))));
+
+        let options = CompileOptions {
+            line_directives: None,
+            ..options
+        };
+        let (asm, _) = compile_tokens(tokens, ABI::Linux, &options).unwrap();
+        assert!(!asm.contains("%line"));
+    }
+
+    #[test]
+    fn test_build_comment() {
+        let options = CompileOptions {
+            build_comment: Some("out/hello.asm".to_owned()),
+            ..CompileOptions::default()
+        };
+        let (asm, _) = compile_tokens(parse("."), ABI::Linux, &options).unwrap();
+        assert!(asm.starts_with(
+            "; Build with:\n\
+             ;   nasm -f elf64 -o out/hello.o out/hello.asm\n\
+             ;   clang -no-pie -o out/hello out/hello.o\n"
+        ));
+
+        let (asm, _) = compile_tokens(parse("."), ABI::Linux, &CompileOptions::default()).unwrap();
+        assert!(!asm.contains("Build with"));
+    }
+
+    #[test]
+    fn test_linker_driver() {
+        let tokens = parse("+.");
+        let with_driver = |driver| CompileOptions {
+            linker_driver: Some(driver),
+            ..CompileOptions::default()
+        };
+        let (_, link) = compile_tokens(tokens.clone(), ABI::Linux, &with_driver(LinkerDriver::Gcc)).unwrap();
+        assert_eq!(link.linker_cmd, "gcc");
+        assert_eq!(link.linker_args, ["-no-pie"]);
+        let (_, link) = compile_tokens(tokens.clone(), ABI::Linux, &with_driver(LinkerDriver::Ld)).unwrap();
+        assert_eq!(link.linker_cmd, "ld");
+        assert!(link.linker_args.iter().any(|arg| arg == "-dynamic-linker"));
+        assert!(link.linker_args.iter().any(|arg| arg.ends_with("/crt1.o")));
+        assert_eq!(link.libraries, ["c", ":crtn.o"]);
+        let (_, link) =
+            compile_tokens(tokens.clone(), ABI::MacOS, &with_driver(LinkerDriver::Clang)).unwrap();
+        assert_eq!(link.linker_cmd, "clang");
+
+        // Bare metal code is only linked with `ld`, and riscv64 with the cross linker
+        let (_, link) =
+            compile_tokens(tokens.clone(), ABI::BareMetal, &with_driver(LinkerDriver::Ld)).unwrap();
+        assert_eq!(link, ABI::BareMetal.linker_info(&CompileOptions::default()));
+        for &abi in &[ABI::BareMetal, ABI::RiscV64] {
+            assert!(matches!(
+                compile_tokens(tokens.clone(), abi, &with_driver(LinkerDriver::Gcc)),
+                Err(CompileError::InvalidOption("linker_driver", _))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_riscv64() {
+        let options = CompileOptions {
+            opt_level: 1,
+            eof: EofPolicy::Max,
+            ..CompileOptions::default()
+        };
+        let (asm, link) = compile_tokens(parse(",[<.,]"), ABI::RiscV64, &options).unwrap();
+        assert_eq!(link.entrypoint, "_start");
+        assert!(asm.contains("    .globl _start\n    .text\n_start:\n    la s2, tape\n    mv s1, s2\n"));
+        assert!(asm.contains("    li a7, 63\n    ecall\n    bgtz a0, .Lskip0\n    li t0, 255\n"));
+        assert!(asm.contains("    addi s1, s1, -1\n    bltu s1, s2, .Lunderflow\n"));
+        assert!(asm.contains("    li a7, 64\n    ecall\n"));
+        assert!(asm.contains(".Lunderflow:\n    li a0, 1\n    li a7, 93\n    ecall\n"));
+        assert!(asm.ends_with("tape:\n    .zero 30000\n"));
+
+        // The tape starts in the middle
+        let options = CompileOptions {
+            tape: TapeMode::Bidirectional,
+            tape_size: 10000,
+            ..options
+        };
+        let (asm, _) = compile_tokens(parse(",<."), ABI::RiscV64, &options).unwrap();
+        assert!(asm.contains("    mv s1, s2\n    li t0, 5000\n    add s1, s1, t0\n"));
+
+        let programs = [("a".to_owned(), parse("."))];
+        assert!(matches!(
+            compile_programs(&programs, ABI::RiscV64, &options),
+            Err(CompileError::InvalidOption("target", _))
+        ));
+    }
 }
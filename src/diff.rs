@@ -0,0 +1,152 @@
+//! Line based unified diffs, used for tracing optimizer passes
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::iter;
+
+/// Largest LCS table computed, larger changes are shown as a full replacement
+const MAX_TABLE: usize = 1 << 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Edit {
+    Keep,
+    Remove,
+    Add,
+}
+
+/// Edits transforming `old` into `new`
+fn edit_script(old: &[String], new: &[String]) -> Vec<Edit> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let a = &old[prefix..old.len() - suffix];
+    let b = &new[prefix..new.len() - suffix];
+
+    let mut script = vec![Edit::Keep; prefix];
+    if (a.len() + 1).saturating_mul(b.len() + 1) <= MAX_TABLE {
+        // lcs[i * w + j] is the length of the longest common subsequence of a[i..] and b[j..]
+        let w = b.len() + 1;
+        let mut lcs = vec![0_u32; (a.len() + 1) * w];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * w + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * w + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * w + j].max(lcs[i * w + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                script.push(Edit::Keep);
+                i += 1;
+                j += 1;
+            } else if j == b.len() || (i < a.len() && lcs[(i + 1) * w + j] >= lcs[i * w + j + 1]) {
+                script.push(Edit::Remove);
+                i += 1;
+            } else {
+                script.push(Edit::Add);
+                j += 1;
+            }
+        }
+    } else {
+        script.extend(iter::repeat(Edit::Remove).take(a.len()));
+        script.extend(iter::repeat(Edit::Add).take(b.len()));
+    }
+    script.extend(iter::repeat(Edit::Keep).take(suffix));
+    script
+}
+
+/// Unified diff hunks between two listings, with `context` unchanged lines around changes
+pub fn unified_diff(old: &[String], new: &[String], context: usize) -> String {
+    let script = edit_script(old, new);
+
+    // Line indices in `old` and `new` before each edit
+    let mut positions = Vec::with_capacity(script.len() + 1);
+    let (mut o, mut n) = (0, 0);
+    for edit in &script {
+        positions.push((o, n));
+        match edit {
+            Edit::Keep => {
+                o += 1;
+                n += 1;
+            },
+            Edit::Remove => o += 1,
+            Edit::Add => n += 1,
+        }
+    }
+    positions.push((o, n));
+
+    let mut result = String::new();
+    let mut k = 0;
+    while k < script.len() {
+        if script[k] == Edit::Keep {
+            k += 1;
+            continue;
+        }
+
+        // Extend the hunk until the changes are separated by enough unchanged lines
+        let start = k.saturating_sub(context);
+        let mut end = k;
+        let mut keeps = 0;
+        for (e, edit) in script.iter().enumerate().skip(k) {
+            if *edit == Edit::Keep {
+                keeps += 1;
+                if keeps > 2 * context {
+                    break;
+                }
+            } else {
+                keeps = 0;
+                end = e + 1;
+            }
+        }
+        let stop = (end + context).min(script.len());
+
+        let (os, ns) = positions[start];
+        let (oe, ne) = positions[stop];
+        result.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            os + 1,
+            oe - os,
+            ns + 1,
+            ne - ns
+        ));
+        for (edit, &(o, n)) in script[start..stop].iter().zip(&positions[start..stop]) {
+            match edit {
+                Edit::Keep => result.push_str(&format!(" {}\n", old[o])),
+                Edit::Remove => result.push_str(&format!("-{}\n", old[o])),
+                Edit::Add => result.push_str(&format!("+{}\n", new[n])),
+            }
+        }
+        k = stop;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unified_diff;
+
+    fn lines(s: &str) -> Vec<String> {
+        s.split_whitespace().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let old = lines("a b c d e f g h i j");
+        let new = lines("a b X d e f g h j k");
+        assert_eq!(
+            unified_diff(&old, &new, 1),
+            "@@ -2,3 +2,3 @@\n b\n-c\n+X\n d\n@@ -8,3 +8,3 @@\n h\n-i\n j\n+k\n"
+        );
+        assert_eq!(unified_diff(&old, &old, 3), "");
+        assert_eq!(unified_diff(&[], &lines("a"), 3), "@@ -1,0 +1,1 @@\n+a\n");
+    }
+}
@@ -1,30 +1,263 @@
-use std::io;
-use std::path::PathBuf;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::{error, io, path::PathBuf};
 
+use crate::parser::{Span, Token, COMPILER_LABEL_PREFIX};
+
+#[cfg(feature = "std")]
 #[must_use]
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = core::result::Result<T, Error>;
+
+pub type CompileResult<T> = core::result::Result<T, CompileError>;
 
+#[cfg(feature = "std")]
+/// Errors of the command line tool, which also runs the assembler and linker
 #[derive(Debug)]
 pub enum Error {
     /// Generic IO error
     Io(io::Error),
     /// Invalid argument
     Argument(Argument),
+    /// The program can't be compiled
+    Compile(CompileError),
     /// Unknown target ABI
     UnknownTarget,
-    /// Nasm failed to execute
+    /// The assembler, e.g. nasm, failed to execute
     Nasm,
     /// Linker failed to execute
     Linker,
+    /// `ar` failed to create the archive
+    Archiver,
+    /// The disassembler, e.g. objdump, failed to execute
+    Disassembler,
+    /// Invalid profile file, at the given line number
+    InvalidProfile(usize),
+    /// Targets, by triple, that failed or whose program gave the wrong output with `--target all`
+    FailedTargets(Vec<&'static str>),
+    /// Invalid config file
+    #[cfg(feature = "config")]
+    Config(toml::de::Error),
 }
+#[cfg(feature = "std")]
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
         Error::Io(error)
     }
 }
+#[cfg(feature = "std")]
+impl From<CompileError> for Error {
+    fn from(error: CompileError) -> Self {
+        Error::Compile(error)
+    }
+}
+#[cfg(feature = "config")]
+impl From<toml::de::Error> for Error {
+    fn from(error: toml::de::Error) -> Self {
+        Error::Config(error)
+    }
+}
+#[cfg(feature = "std")]
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{}", error),
+            Self::Argument(argument) => write!(f, "{}", argument),
+            Self::Compile(error) => write!(f, "{}", error),
+            Self::UnknownTarget => write!(
+                f,
+                "Unknown target ABI, select one with --target, or run the program with --interpret"
+            ),
+            Self::Nasm => write!(f, "Assembling failed"),
+            Self::Linker => write!(f, "Linking failed"),
+            Self::Archiver => write!(f, "Archiving failed"),
+            Self::Disassembler => write!(f, "Disassembling failed"),
+            Self::InvalidProfile(line) => write!(f, "Invalid profile file at line {}", line),
+            Self::FailedTargets(triples) => write!(f, "Failed for targets {}", triples.join(", ")),
+            #[cfg(feature = "config")]
+            Self::Config(error) => write!(f, "Invalid config file: {}", error),
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Compile(error) => Some(error),
+            #[cfg(feature = "config")]
+            Self::Config(error) => Some(error),
+            _ => None,
+        }
+    }
+}
 
+#[cfg(feature = "std")]
 #[derive(Debug)]
 pub enum Argument {
     /// Path: Required file, got directory
     FileRequired(PathBuf),
+    /// Option requires a cargo feature that was not enabled
+    FeatureDisabled(&'static str),
+    /// Option can't be used when compiling several programs together
+    SingleSourceRequired(&'static str),
+    /// No optimizer pass has the given name
+    UnknownPass(String),
+    /// Option runs the compiled program, so the target must be the host platform
+    HostTargetRequired(&'static str),
+    /// Several sources give a function of the same name
+    DuplicateName(String),
+    /// Pass order runs the second pass before the first, which must run before it
+    PassOrder(&'static str, &'static str),
+    /// Cleanup passes run each other in a cycle, from a pass back to itself
+    CleanupCycle(Vec<String>),
+    /// Command to run is in none of the directories of `PATH`
+    CommandNotFound(&'static str),
+    /// Option evaluates or writes the program with cells of a byte, so the source can't declare wider cells
+    ByteCellsRequired(&'static str),
+    /// Option only applies to cells wider than a byte
+    WideCellsRequired(&'static str),
+    /// Option runs the program in the interpreter, whose tape starts at the first cell and grows as needed
+    StandardTapeRequired(&'static str),
+}
+#[cfg(feature = "std")]
+impl fmt::Display for Argument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileRequired(path) => write!(f, "{} is a directory, expected a file", path.display()),
+            Self::FeatureDisabled(feature) => write!(f, "Requires the {} feature", feature),
+            Self::SingleSourceRequired(flag) => write!(f, "--{} takes a single source", flag),
+            Self::UnknownPass(pass) => write!(f, "No optimizer pass named {}", pass),
+            Self::HostTargetRequired(flag) => write!(f, "--{} requires the host target", flag),
+            Self::DuplicateName(name) => write!(f, "Several sources are named {}", name),
+            Self::PassOrder(first, second) => write!(f, "Pass {} must run before {}", first, second),
+            Self::CleanupCycle(passes) => write!(f, "Cleanup passes run in a cycle: {}", passes.join(" -> ")),
+            Self::CommandNotFound(command) => write!(f, "{} was not found in PATH", command),
+            Self::ByteCellsRequired(flag) => write!(f, "--{} can't be used with wide cells", flag),
+            Self::WideCellsRequired(flag) => write!(f, "--{} requires --wide-cells", flag),
+            Self::StandardTapeRequired(flag) => write!(
+                f,
+                "--{} can't be used with --tape bidirectional or --tape-size",
+                flag
+            ),
+        }
+    }
+}
+
+/// Errors of the compiler library, about the program or the options it is compiled with
+#[derive(Debug)]
+pub enum CompileError {
+    /// Bracket without a matching one, `[` or `]`
+    UnbalancedBracket { position: Span, kind: Token },
+    /// Inline assembly without the closing `!}`, starting at the given position
+    UnterminatedInlineAsm(Span),
+    /// Inline assembly containing the prefix of the labels named by the compiler, at the given position
+    CompilerLabel(Span),
+    /// Character reserved by the parse options, at the given position
+    ReservedCharacter(char, Span),
+    /// Program moves the pointer left of the first cell, at the given token index
+    PointerUnderflow(usize),
+    /// Option can't be used, with its name and the reason
+    InvalidOption(&'static str, &'static str),
+    /// Tape has more cells than fit in the stack frame
+    TapeTooLarge(usize),
+    /// Pragma comment that can't be parsed, at the given position
+    InvalidPragma(Span),
+    /// Error in one of several programs compiled together, with its name
+    InProgram(String, Box<CompileError>),
+    /// Compiling gave the given number of warnings, and they are denied
+    DeniedWarnings(usize),
+    /// Jump to a label that doesn't exist in the program, with the label
+    MissingLabel(String),
+}
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnbalancedBracket { position, kind } => write!(f, "Unbalanced '{}' at {}", kind, position),
+            Self::UnterminatedInlineAsm(position) => {
+                write!(f, "Unterminated inline assembly at {}", position)
+            },
+            Self::CompilerLabel(position) => write!(
+                f,
+                "Inline assembly at {} contains {}, which starts the labels of the compiler",
+                position, COMPILER_LABEL_PREFIX
+            ),
+            Self::ReservedCharacter(c, position) => write!(f, "Reserved character '{}' at {}", c, position),
+            Self::PointerUnderflow(index) => {
+                write!(f, "Pointer moves left of the first cell at token {}", index)
+            },
+            Self::InvalidOption(name, reason) => write!(f, "Invalid option {}: {}", name, reason),
+            Self::TapeTooLarge(cells) => write!(f, "Tape of {} cells doesn't fit in the stack frame", cells),
+            Self::InvalidPragma(position) => write!(f, "Invalid pragma at {}", position),
+            Self::InProgram(name, error) => write!(f, "{}: {}", name, error),
+            Self::DeniedWarnings(count) => write!(f, "Denied {} warnings", count),
+            Self::MissingLabel(label) => write!(f, "Jump to missing label {}", label),
+        }
+    }
+}
+#[cfg(feature = "std")]
+impl error::Error for CompileError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::InProgram(_, error) => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Something in the program that is likely a mistake, though it can be compiled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    /// Loop that no path from the start reaches, at its source position
+    UnreachableLoop(Span),
+    /// Loop with an empty body, which never exits once entered, at its source position
+    InfiniteLoop(Span),
+    /// Loop nested more deeply than `max_loop_depth`, by its depth and its source position if known
+    DeepNesting(usize, Option<Span>),
+}
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnreachableLoop(position) => write!(f, "Loop at {} is never reached", position),
+            Self::InfiniteLoop(position) => {
+                write!(
+                    f,
+                    "Loop at {} has an empty body, so it never exits once entered",
+                    position
+                )
+            },
+            Self::DeepNesting(depth, Some(position)) => {
+                write!(f, "Loop at {} is nested {} loops deep", position, depth)
+            },
+            Self::DeepNesting(depth, None) => write!(f, "Loops are nested {} deep", depth),
+        }
+    }
+}
+
+/// Warnings given while compiling a program, each logged when it is pushed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Warnings(Vec<Warning>);
+impl Warnings {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn push(&mut self, warning: Warning) {
+        log::warn!("{}", warning);
+        self.0.push(warning);
+    }
+
+    pub fn as_slice(&self) -> &[Warning] {
+        &self.0
+    }
+
+    /// Fails if there are any warnings and they are denied
+    pub fn check(&self, deny: bool) -> CompileResult<()> {
+        if deny && !self.0.is_empty() {
+            return Err(CompileError::DeniedWarnings(self.0.len()));
+        }
+        Ok(())
+    }
 }
@@ -1,13 +1,90 @@
-use std::fmt;
+use alloc::borrow::ToOwned;
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+
+use crate::label::LabelId;
 
 type AssemblyString = String;
 
+/// Label of the handler that aborts the program when the pointer moves left of the tape
+pub const UNDERFLOW_LABEL: &str = "pointer_underflow";
+
+/// Bytes of the `db` operands written by `format_bytes`
+fn parse_data(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"')?;
+            data.extend(quoted[..end].bytes());
+            rest = &quoted[end + 1..];
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            data.push(parse_number(rest[..end].trim())?);
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+        if let Some(next) = rest.strip_prefix(',') {
+            rest = next.trim_start();
+        } else if !rest.is_empty() {
+            return None;
+        }
+    }
+    Some(data)
+}
+
+/// Decimal or `0x` prefixed hexadecimal number
+fn parse_number<T: core::str::FromStr + TryFrom<u64>>(text: &str) -> Option<T> {
+    match text.strip_prefix("0x") {
+        Some(hex) => T::try_from(u64::from_str_radix(hex, 16).ok()?).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Register in a memory operand, e.g. `[rbx]`
+fn parse_pointer(text: &str) -> Option<Register64> {
+    Register64::from_name(text.strip_prefix('[')?.strip_suffix(']')?)
+}
+
+/// Runs of at least this many identical bytes are written with `times` instead of listing them
+const DATA_RUN: usize = 16;
+
+/// NASM directives of the data, a line each: `times` for long runs of one byte,
+/// and `db` of strings and numbers for the bytes between them
 fn format_data(data: &[u8]) -> String {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    let mut index = 0;
+    while index < data.len() {
+        let run = data[index..].iter().take_while(|byte| **byte == data[index]).count();
+        if run >= DATA_RUN {
+            if start < index {
+                lines.push(format!("db {}", format_bytes(&data[start..index])));
+            }
+            lines.push(format!("times {} db {:#02x}", run, data[index]));
+            start = index + run;
+        }
+        index += run;
+    }
+    if start < data.len() || lines.is_empty() {
+        lines.push(format!("db {}", format_bytes(&data[start..])));
+    }
+    lines.join("\n")
+}
+
+/// Operands of `db`, printable characters in strings and the other bytes as numbers
+fn format_bytes(data: &[u8]) -> String {
     let mut result = String::new();
     let mut in_string = false;
     for byte in data {
         let c = *byte as char;
-        if c.is_ascii_graphic() || c == ' ' {
+        // Quotes end the string, so they are written as numbers
+        if (c.is_ascii_graphic() && c != '"') || c == ' ' {
             if !in_string {
                 result.push('"');
                 in_string = true;
@@ -40,9 +117,77 @@ pub enum Register64 {
     rsi,
     rdi,
     rsp,
+    rbp,
+    r8,
+    r9,
     r10,
     r11,
     r12,
+    r13,
+    r14,
+    r15,
+}
+impl Register64 {
+    /// Whether a call to a C library function preserves the register,
+    /// as it is callee-saved in the System V AMD64 calling convention of both targets
+    pub fn preserved_by_calls(self) -> bool {
+        matches!(
+            self,
+            Self::rbx | Self::rsp | Self::rbp | Self::r12 | Self::r13 | Self::r14 | Self::r15
+        )
+    }
+
+    /// Name of the lowest byte of the register, e.g. `al` for `rax`
+    pub fn low_byte(self) -> &'static str {
+        match self {
+            Self::rax => "al",
+            Self::rbx => "bl",
+            Self::rcx => "cl",
+            Self::rdx => "dl",
+            Self::rsi => "sil",
+            Self::rdi => "dil",
+            Self::rsp => "spl",
+            Self::rbp => "bpl",
+            Self::r8 => "r8b",
+            Self::r9 => "r9b",
+            Self::r10 => "r10b",
+            Self::r11 => "r11b",
+            Self::r12 => "r12b",
+            Self::r13 => "r13b",
+            Self::r14 => "r14b",
+            Self::r15 => "r15b",
+        }
+    }
+
+    /// Register of the name of its lowest byte, e.g. `rax` for `al`
+    fn from_low_byte(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|r| r.low_byte() == name)
+    }
+}
+impl Register64 {
+    const ALL: [Self; 16] = [
+        Self::rax,
+        Self::rbx,
+        Self::rcx,
+        Self::rdx,
+        Self::rsi,
+        Self::rdi,
+        Self::rsp,
+        Self::rbp,
+        Self::r8,
+        Self::r9,
+        Self::r10,
+        Self::r11,
+        Self::r12,
+        Self::r13,
+        Self::r14,
+        Self::r15,
+    ];
+
+    /// Register of the name written in the assembly, e.g. `rbx`
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|r| format!("{}", r) == name)
+    }
 }
 impl fmt::Display for Register64 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -51,7 +196,7 @@ impl fmt::Display for Register64 {
 }
 
 /// What effects does instruction cause
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Effects {
     /// Affects flags (Zero flag considered here)
     pub flags: bool,
@@ -110,7 +255,8 @@ impl Effects {
         io: false,
     };
 
-    /// Label, considering origin
+    /// Label, considering origin: any jump may end here.
+    /// Passes following the jumps with the CFG know what holds on each of them instead.
     pub const LABEL: Self = Self {
         flags: true,
         registers: true,
@@ -129,11 +275,12 @@ impl Effects {
     };
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Instruction {
     /// Black box, i.e. raw assembly that optimizer should pass through
     BlackBox(AssemblyString, Effects),
-    /// Named black box, i.e. black box containing identifier for optimizer
+    /// Named black box, i.e. black box containing identifier for optimizer.
+    /// These call the named C library function.
     NamedBlackBox(String, AssemblyString, Effects),
     /// `mov rax, 2`
     MovImm(Register64, u64),
@@ -149,10 +296,21 @@ pub enum Instruction {
     MovPtr32Imm(Register64, u32),
     /// `mov quad [rax], 2`
     MovPtr64Imm(Register64, u64),
+    /// `movzx rax, byte [rbx]`, loading the cell zero-extended into the first register
+    MovRegPtr8(Register64, Register64),
+    /// `mov byte [rbx], al`, storing the lowest byte of the second register to the cell
+    MovPtr8Reg(Register64, Register64),
+    /// `movdqu xmm0, [rel label]` + `movdqu [rax], xmm0`, storing 16 bytes of data.
+    /// Unaligned, as cells can be anywhere.
+    MovPtr128(Register64, String),
+    /// `vmovdqu ymm0, [rel label]` + `vmovdqu [rax], ymm0`, storing 32 bytes of data
+    MovPtr256(Register64, String),
     /// `add rax, 2`
     AddImm(Register64, u64),
     /// `sub rax, 2`
     SubImm(Register64, u64),
+    /// `add al, 2`, adding to the lowest byte of the register, which sets the flags like adding to a cell
+    AddReg8Imm(Register64, u8),
     /// `add byte [rax], 2`
     AddPtr8Imm(Register64, u8),
     /// `add word [rax], 2`
@@ -161,19 +319,28 @@ pub enum Instruction {
     AddPtr32Imm(Register64, u32),
     /// `add quad [rax], 2`
     AddPtr64Imm(Register64, u64),
+    /// `movzx eax, byte [rbx]` + `imul eax, eax, 3` + `add byte [rbx+2], al`,
+    /// adding a multiple of the cell to the one at the offset. Overwrites `rax`.
+    AddPtr8Mul(Register64, i64, u8),
     /// `test eax, eax` (always followed by conditional jump)
     IsZero(Register64),
     /// `cmp byte [eax], 0` (always followed by conditional jump)
     IsZeroPtr8(Register64),
+    /// `cmp word [eax], 0` (always followed by conditional jump)
+    IsZeroPtr16(Register64),
+    /// `cmp dword [eax], 0` (always followed by conditional jump)
+    IsZeroPtr32(Register64),
+    /// `cmp rbx, r12` + `jb pointer_underflow`, aborts if the pointer is below the tape start
+    CheckPointer(Register64, Register64),
     /// `jz .label2`
-    JumpZero(String),
+    JumpZero(LabelId),
     /// `jnz .label2`
-    JumpNonZero(String),
+    JumpNonZero(LabelId),
     /// `jmp .label2`
-    Jump(String),
+    Jump(LabelId),
     /// `.label2:` (Label(".label2"))
-    Label(String),
-    /// `name: db "abc", 10, 13` (in section .data)
+    Label(LabelId),
+    /// `name: db "abc", 10, 13` (in section .data), with long runs of a byte as `times 32 db 0`
     Data(String, Vec<u8>),
 }
 impl Instruction {
@@ -191,6 +358,13 @@ impl Instruction {
             Self::MovPtr16Imm(r, imm) => format!("mov word [{}], {}", r, imm),
             Self::MovPtr32Imm(r, imm) => format!("mov dword [{}], {}", r, imm),
             Self::MovPtr64Imm(r, imm) => format!("mov quad [{}], {}", r, imm),
+            Self::MovRegPtr8(r, ptr) => format!("movzx {}, byte [{}]", r, ptr),
+            Self::MovPtr8Reg(ptr, r) => format!("mov byte [{}], {}", ptr, r.low_byte()),
+            Self::MovPtr128(r, label) => format!("movdqu xmm0, [rel {}]\nmovdqu [{}], xmm0", label, r),
+            // Clearing the upper halves avoids the penalty of mixing with SSE code, e.g. in libc
+            Self::MovPtr256(r, label) => {
+                format!("vmovdqu ymm0, [rel {}]\nvmovdqu [{}], ymm0\nvzeroupper", label, r)
+            },
             Self::AddImm(r, imm) => match imm {
                 1 => format!("inc {}", r),
                 i => format!("add {}, {}", r, i),
@@ -199,6 +373,11 @@ impl Instruction {
                 1 => format!("dec {}", r),
                 i => format!("sub {}, {}", r, i),
             },
+            Self::AddReg8Imm(r, imm) => match imm {
+                255 => format!("dec {}", r.low_byte()),
+                1 => format!("inc {}", r.low_byte()),
+                i => format!("add {}, {}", r.low_byte(), i),
+            },
             Self::AddPtr8Imm(r, imm) => match imm {
                 255 => format!("dec byte [{}]", r),
                 1 => format!("inc byte [{}]", r),
@@ -207,13 +386,148 @@ impl Instruction {
             Self::AddPtr16Imm(r, imm) => format!("add word [{}], {}", r, imm),
             Self::AddPtr32Imm(r, imm) => format!("add dword [{}], {}", r, imm),
             Self::AddPtr64Imm(r, imm) => format!("add quad [{}], {}", r, imm),
+            Self::AddPtr8Mul(r, offset, factor) => {
+                let load = format!("movzx eax, byte [{}]", r);
+                let target = if *offset < 0 {
+                    format!("[{}-{}]", r, offset.unsigned_abs())
+                } else {
+                    format!("[{}+{}]", r, offset)
+                };
+                match factor {
+                    1 => format!("{}\nadd byte {}, al", load, target),
+                    255 => format!("{}\nsub byte {}, al", load, target),
+                    f => format!("{}\nimul eax, eax, {}\nadd byte {}, al", load, f, target),
+                }
+            },
             Self::IsZero(r) => format!("test {}, {}", r, r),
             Self::IsZeroPtr8(r) => format!("cmp byte [{}], 0", r),
+            Self::IsZeroPtr16(r) => format!("cmp word [{}], 0", r),
+            Self::IsZeroPtr32(r) => format!("cmp dword [{}], 0", r),
+            Self::CheckPointer(r, base) => format!("cmp {}, {}\njb {}", r, base, UNDERFLOW_LABEL),
             Self::JumpZero(n) => format!("jz {}", n),
             Self::JumpNonZero(n) => format!("jnz {}", n),
             Self::Jump(n) => format!("jmp {}", n),
             Self::Label(n) => format!("{}:", n),
-            Self::Data(n, v) => format!("{}: db {}", n, format_data(v)),
+            Self::Data(n, v) => format!("{}: {}", n, format_data(v)),
+        }
+    }
+
+    /// Parses one line written by `to_source`, so that tests can give instructions as text.
+    /// Black boxes and the instructions written on several lines are not supported,
+    /// as the text doesn't tell them apart from other assembly.
+    pub fn from_ir_line(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if let Some((name, data)) = line.split_once(": db ") {
+            return Some(Self::Data(name.to_owned(), parse_data(data)?));
+        }
+        if let Some(label) = line.strip_suffix(':') {
+            return Some(Self::Label(label.into()));
+        }
+        let (mnemonic, operands) = line.split_once(' ').unwrap_or((line, ""));
+        let operands: Vec<&str> = operands.split(',').map(str::trim).collect();
+        Some(match (mnemonic, operands.as_slice()) {
+            ("jz", [target]) => Self::JumpZero((*target).into()),
+            ("jnz", [target]) => Self::JumpNonZero((*target).into()),
+            ("jmp", [target]) => Self::Jump((*target).into()),
+            ("xor", [a, b]) if a == b => Self::MovImm(Register64::from_name(a)?, 0),
+            ("test", [a, b]) if a == b => Self::IsZero(Register64::from_name(a)?),
+            ("cmp", [pointer, "0"]) => Self::IsZeroPtr8(parse_pointer(pointer.strip_prefix("byte ")?)?),
+            ("movzx", [r, pointer]) => Self::MovRegPtr8(
+                Register64::from_name(r)?,
+                parse_pointer(pointer.strip_prefix("byte ")?)?,
+            ),
+            ("inc", [operand]) | ("dec", [operand]) => {
+                let delta = if mnemonic == "inc" { 1 } else { 255 };
+                if let Some(pointer) = operand.strip_prefix("byte ") {
+                    Self::AddPtr8Imm(parse_pointer(pointer)?, delta)
+                } else if let Some(r) = Register64::from_low_byte(operand) {
+                    Self::AddReg8Imm(r, delta)
+                } else if mnemonic == "inc" {
+                    Self::AddImm(Register64::from_name(operand)?, 1)
+                } else {
+                    Self::SubImm(Register64::from_name(operand)?, 1)
+                }
+            },
+            ("mov", [target, source]) | ("add", [target, source]) | ("sub", [target, source]) => {
+                Self::from_ir_operands(mnemonic, target, source)?
+            },
+            _ => return None,
+        })
+    }
+
+    fn from_ir_operands(mnemonic: &str, target: &str, source: &str) -> Option<Self> {
+        if let (Some(r), "add") = (Register64::from_low_byte(target), mnemonic) {
+            return Some(Self::AddReg8Imm(r, parse_number(source)?));
+        }
+        if let Some(r) = Register64::from_name(target) {
+            return Some(match (mnemonic, Register64::from_name(source)) {
+                ("mov", Some(source)) => Self::Mov(r, source),
+                ("mov", None) => match parse_number(source) {
+                    Some(imm) => Self::MovImm(r, imm),
+                    None => Self::MovImmVar(r, source.to_owned()),
+                },
+                ("add", None) => Self::AddImm(r, parse_number(source)?),
+                ("sub", None) => Self::SubImm(r, parse_number(source)?),
+                _ => return None,
+            });
+        }
+        let (size, pointer) = target.split_once(' ')?;
+        let r = parse_pointer(pointer)?;
+        Some(match (mnemonic, size) {
+            ("mov", "byte") => match Register64::from_low_byte(source) {
+                Some(source_r) => Self::MovPtr8Reg(r, source_r),
+                None => Self::MovPtr8Imm(r, parse_number(source)?),
+            },
+            ("mov", "word") => Self::MovPtr16Imm(r, parse_number(source)?),
+            ("mov", "dword") => Self::MovPtr32Imm(r, parse_number(source)?),
+            ("mov", "quad") => Self::MovPtr64Imm(r, parse_number(source)?),
+            ("add", "byte") => Self::AddPtr8Imm(r, parse_number(source)?),
+            ("add", "word") => Self::AddPtr16Imm(r, parse_number(source)?),
+            ("add", "dword") => Self::AddPtr32Imm(r, parse_number(source)?),
+            ("add", "quad") => Self::AddPtr64Imm(r, parse_number(source)?),
+            _ => return None,
+        })
+    }
+
+    /// Rough estimate of the runtime cost of the instruction
+    pub fn cost(&self) -> u32 {
+        match self {
+            Self::BlackBox(_, e) | Self::NamedBlackBox(_, _, e) => {
+                if e.io {
+                    100
+                } else {
+                    1
+                }
+            },
+            Self::MovImm(_, _) => 1,
+            Self::MovImmVar(_, _) => 1,
+            Self::Mov(_, _) => 1,
+            Self::MovPtr8Imm(_, _) => 1,
+            Self::MovPtr16Imm(_, _) => 1,
+            Self::MovPtr32Imm(_, _) => 1,
+            Self::MovPtr64Imm(_, _) => 1,
+            Self::MovRegPtr8(_, _) => 1,
+            Self::MovPtr8Reg(_, _) => 1,
+            Self::MovPtr128(_, _) => 2,
+            Self::MovPtr256(_, _) => 3,
+            Self::AddImm(_, _) => 1,
+            Self::SubImm(_, _) => 1,
+            Self::AddReg8Imm(_, _) => 1,
+            Self::AddPtr8Imm(_, _) => 2,
+            Self::AddPtr16Imm(_, _) => 2,
+            Self::AddPtr32Imm(_, _) => 2,
+            Self::AddPtr64Imm(_, _) => 2,
+            Self::AddPtr8Mul(_, _, _) => 4,
+            Self::IsZero(_) => 1,
+            Self::IsZeroPtr8(_) => 2,
+            Self::IsZeroPtr16(_) => 2,
+            Self::IsZeroPtr32(_) => 2,
+            Self::CheckPointer(_, _) => 4,
+            Self::JumpZero(_) => 3,
+            Self::JumpNonZero(_) => 3,
+            Self::Jump(_) => 3,
+            Self::Label(_) => 0,
+            Self::Data(_, _) => 0,
         }
     }
 
@@ -226,7 +540,8 @@ impl Instruction {
     pub fn reads_zf(&self) -> bool {
         match self {
             Self::BlackBox(_, _) => true,
-            Self::NamedBlackBox(_, _, _) => true,
+            // Calls don't take arguments in the flags
+            Self::NamedBlackBox(_, _, _) => false,
             Self::MovImm(_, _) => false,
             Self::MovImmVar(_, _) => false,
             Self::Mov(_, _) => false,
@@ -234,10 +549,16 @@ impl Instruction {
             Self::MovPtr16Imm(_, _) => false,
             Self::MovPtr32Imm(_, _) => false,
             Self::MovPtr64Imm(_, _) => false,
+            // Neither reads nor sets the flags, unlike the `cmp` testing the cell
+            Self::MovRegPtr8(_, _) => false,
+            Self::MovPtr8Reg(_, _) => false,
+            Self::MovPtr128(_, _) => false,
+            Self::MovPtr256(_, _) => false,
             Self::AddImm(_, 0) => false,
             Self::SubImm(_, 0) => false,
             Self::AddImm(_, _) => false,
             Self::SubImm(_, _) => false,
+            Self::AddReg8Imm(_, _) => false,
             Self::AddPtr8Imm(_, 0) => false,
             Self::AddPtr16Imm(_, 0) => false,
             Self::AddPtr32Imm(_, 0) => false,
@@ -246,8 +567,12 @@ impl Instruction {
             Self::AddPtr16Imm(_, _) => false,
             Self::AddPtr32Imm(_, _) => false,
             Self::AddPtr64Imm(_, _) => false,
+            Self::AddPtr8Mul(_, _, _) => false,
             Self::IsZero(_) => false,
             Self::IsZeroPtr8(_) => false,
+            Self::IsZeroPtr16(_) => false,
+            Self::IsZeroPtr32(_) => false,
+            Self::CheckPointer(_, _) => false,
             Self::JumpZero(_) => true,
             Self::JumpNonZero(_) => true,
             Self::Jump(_) => false,
@@ -268,10 +593,16 @@ impl Instruction {
             Self::MovPtr16Imm(_, _) => Effects::REG,
             Self::MovPtr32Imm(_, _) => Effects::REG,
             Self::MovPtr64Imm(_, _) => Effects::REG,
+            Self::MovRegPtr8(_, _) => Effects::REG,
+            Self::MovPtr8Reg(_, _) => Effects::REG,
+            Self::MovPtr128(_, _) => Effects::REG,
+            Self::MovPtr256(_, _) => Effects::REG,
             Self::AddImm(_, 0) => Effects::FLAG,
             Self::SubImm(_, 0) => Effects::FLAG,
             Self::AddImm(_, _) => Effects::ARITHMETIC,
             Self::SubImm(_, _) => Effects::ARITHMETIC,
+            Self::AddReg8Imm(_, 0) => Effects::FLAG,
+            Self::AddReg8Imm(_, _) => Effects::ARITHMETIC,
             Self::AddPtr8Imm(_, 0) => Effects::FLAG,
             Self::AddPtr16Imm(_, 0) => Effects::FLAG,
             Self::AddPtr32Imm(_, 0) => Effects::FLAG,
@@ -280,8 +611,18 @@ impl Instruction {
             Self::AddPtr16Imm(_, _) => Effects::ARITHMETIC,
             Self::AddPtr32Imm(_, _) => Effects::ARITHMETIC,
             Self::AddPtr64Imm(_, _) => Effects::ARITHMETIC,
+            Self::AddPtr8Mul(_, _, _) => Effects::ARITHMETIC,
             Self::IsZero(_) => Effects::FLAG,
             Self::IsZeroPtr8(_) => Effects::FLAG,
+            Self::IsZeroPtr16(_) => Effects::FLAG,
+            Self::IsZeroPtr32(_) => Effects::FLAG,
+            Self::CheckPointer(_, _) => Effects {
+                flags: true,
+                registers: false,
+                control_flow: true,
+                stack: false,
+                io: false,
+            },
             Self::JumpZero(_) => Effects::JUMP,
             Self::JumpNonZero(_) => Effects::JUMP,
             Self::Jump(_) => Effects::JUMP,
@@ -310,6 +651,16 @@ impl Instruction {
                 if r0 == r1 {
                     return vec![MovPtr8Imm(r0, v0.wrapping_add(v1))];
                 }
+            } else if let MovPtr8Imm(r1, v1) = other.clone() {
+                if r0 == r1 {
+                    return vec![MovPtr8Imm(r0, v1)];
+                }
+            }
+        } else if let AddReg8Imm(r0, v0) = self.clone() {
+            if let AddReg8Imm(r1, v1) = other.clone() {
+                if r0 == r1 {
+                    return vec![AddReg8Imm(r0, v0.wrapping_add(v1))];
+                }
             }
         } else if let AddImm(r0, v0) = self.clone() {
             if let AddImm(r1, v1) = other.clone() {
@@ -319,7 +670,8 @@ impl Instruction {
             } else if let SubImm(r1, v1) = other.clone() {
                 if r0 == r1 {
                     if v0 == v1 {
-                        return Vec::new();
+                        // Kept for the zero flag the sub sets, removed as a nop if no jump reads it
+                        return vec![AddImm(r0, 0)];
                     } else if v0 < v1 {
                         return vec![SubImm(r0, v1 - v0)];
                     } else {
@@ -354,3 +706,155 @@ impl fmt::Display for Instruction {
         write!(f, "{}", self.to_source())
     }
 }
+
+/// Names in the assembly source of the instructions, e.g. `.loop` of `jmp .loop`,
+/// to look up the labels it refers to once instead of searching the source for each label
+pub fn source_names<'a>(ops: impl IntoIterator<Item = &'a Instruction>) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+    for op in ops {
+        let source = op.to_source();
+        let is_name = |c: char| c.is_ascii_alphanumeric() || "_$#@~.?".contains(c);
+        names.extend(
+            source
+                .split(|c| !is_name(c))
+                .filter(|name| !name.is_empty())
+                .map(ToOwned::to_owned),
+        );
+    }
+    names
+}
+
+/// Parses instructions written one per line by `to_source`, skipping empty lines.
+/// Returns the first line that is not an instruction `Instruction::from_ir_line` parses as the error.
+pub fn parse_ir(text: &str) -> Result<Vec<Instruction>, String> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Instruction::from_ir_line(line).ok_or_else(|| line.to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction::{self, *};
+    use super::Register64::{r11, r9, rax, rbx, rsi};
+    use super::{parse_ir, source_names, Effects, DATA_RUN};
+
+    #[test]
+    fn test_ir_round_trip() {
+        let ops = vec![
+            MovImm(rax, 0),
+            MovImm(rsi, 12),
+            MovImmVar(rsi, "data0".to_owned()),
+            Mov(rbx, rax),
+            MovPtr8Imm(rbx, 3),
+            MovPtr16Imm(rbx, 300),
+            MovPtr32Imm(rbx, 70000),
+            MovPtr64Imm(rbx, 1 << 40),
+            AddImm(rbx, 1),
+            AddImm(rbx, 4),
+            SubImm(rbx, 1),
+            SubImm(rbx, 4),
+            AddPtr8Imm(rbx, 1),
+            AddPtr8Imm(rbx, 255),
+            AddPtr8Imm(rbx, 7),
+            AddPtr16Imm(rbx, 7),
+            AddPtr32Imm(rbx, 7),
+            AddPtr64Imm(rbx, 7),
+            IsZero(rax),
+            IsZeroPtr8(rbx),
+            Label(".label0".into()),
+            JumpZero(".label0".into()),
+            JumpNonZero(".label0".into()),
+            Jump(".label0".into()),
+            Data("data0".to_owned(), b"Hi, \"you\"\n".to_vec()),
+            MovRegPtr8(rax, rbx),
+            MovPtr8Reg(rbx, r9),
+            AddReg8Imm(r11, 1),
+            AddReg8Imm(r11, 255),
+            AddReg8Imm(rax, 3),
+        ];
+        let text: Vec<String> = ops.iter().map(Instruction::to_source).collect();
+        assert_eq!(parse_ir(&text.join("\n")), Ok(ops));
+    }
+
+    #[test]
+    fn test_ir_unsupported() {
+        assert_eq!(
+            parse_ir("inc byte [rbx]\n\n  jz .label1\n"),
+            Ok(vec![AddPtr8Imm(rbx, 1), JumpZero(".label1".into())])
+        );
+        // Black boxes are written as their assembly, which doesn't say what the effects are
+        let black_box = BlackBox("syscall".to_owned(), Effects::VOLATILE).to_source();
+        assert_eq!(parse_ir(&black_box), Err("syscall".to_owned()));
+        assert_eq!(Instruction::from_ir_line("add byte [rbx], 256"), None);
+        assert_eq!(Instruction::from_ir_line("mov byte [rbx], rax"), None);
+    }
+
+    #[test]
+    fn test_cell_register_movs() {
+        assert_eq!(MovRegPtr8(rax, rbx).to_source(), "movzx rax, byte [rbx]");
+        assert_eq!(MovPtr8Reg(rbx, rax).to_source(), "mov byte [rbx], al");
+        assert_eq!(MovPtr8Reg(rbx, rsi).to_source(), "mov byte [rbx], sil");
+        assert_eq!(MovPtr8Reg(rbx, r9).to_source(), "mov byte [rbx], r9b");
+
+        // `movzx` and `mov` leave the flags of an earlier test of the cell for the jump after them
+        for op in &[MovRegPtr8(rax, rbx), MovPtr8Reg(rbx, rax)] {
+            assert_eq!(op.effects(), Some(Effects::REG));
+            assert!(!op.affects_zero_flag());
+            assert!(!op.reads_zf());
+        }
+    }
+
+    #[test]
+    fn test_add_reg8() {
+        assert_eq!(AddReg8Imm(r11, 1).to_source(), "inc r11b");
+        assert_eq!(AddReg8Imm(r11, 255).to_source(), "dec r11b");
+        assert_eq!(AddReg8Imm(rax, 3).to_source(), "add al, 3");
+        assert_eq!(AddReg8Imm(rax, 3).combine(AddReg8Imm(rax, 254)), [AddReg8Imm(rax, 1)]);
+        assert!(AddReg8Imm(rax, 3).affects_zero_flag());
+    }
+
+    #[test]
+    fn test_combine_add_sub() {
+        assert_eq!(AddImm(rbx, 3).combine(SubImm(rbx, 1)), [AddImm(rbx, 2)]);
+        assert_eq!(SubImm(rbx, 3).combine(AddImm(rbx, 1)), [SubImm(rbx, 2)]);
+        // The net zero still sets the zero flag
+        assert_eq!(AddImm(rbx, 2).combine(SubImm(rbx, 2)), [AddImm(rbx, 0)]);
+        assert_eq!(SubImm(rbx, 2).combine(AddImm(rbx, 2)), [AddImm(rbx, 0)]);
+        assert!(AddImm(rbx, 0).affects_zero_flag());
+    }
+
+    #[test]
+    fn test_data() {
+        let data = |bytes: Vec<u8>| Data("d".to_owned(), bytes).to_source();
+        assert_eq!(data(b"ab\"c\n".to_vec()), "d: db \"ab\",0x22,\"c\",0xa");
+
+        // Runs of the threshold are written with `times`, shorter ones are listed
+        let mut bytes = b"a".to_vec();
+        bytes.extend(vec![0; DATA_RUN - 1]);
+        bytes.push(b'b');
+        let listed = vec!["0x0"; DATA_RUN - 1].join(",");
+        assert_eq!(data(bytes.clone()), format!("d: db \"a\",{},\"b\"", listed));
+        bytes.insert(1, 0);
+        assert_eq!(
+            data(bytes),
+            format!("d: db \"a\"\ntimes {} db 0x0\ndb \"b\"", DATA_RUN)
+        );
+        assert_eq!(data(vec![b' '; 20]), "d: times 20 db 0x20");
+        assert_eq!(data(vec![1, 2, 2]), "d: db 0x1,0x2,0x2");
+    }
+
+    #[test]
+    fn test_source_names() {
+        let ops = vec![
+            BlackBox("jmp .label10\n.interface_linux0:".to_owned(), Effects::VOLATILE),
+            CheckPointer(rbx, r11),
+        ];
+        let names = source_names(&ops);
+        assert!(names.contains(".label10"));
+        assert!(names.contains(".interface_linux0"));
+        assert!(names.contains("pointer_underflow"));
+        // Only whole names, unlike searching the source for the label
+        assert!(!names.contains(".label1"));
+    }
+}
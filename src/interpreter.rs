@@ -1,161 +1,708 @@
+//! Reference interpreters, running programs with the semantics of compiled binaries:
+//! cells wrap around, `,` at the end of input follows the EOF policy, storing zero by default,
+//! and moving left of the first cell stops the program with status 1.
+//! The tape grows to the right as needed, where compiled programs have `tape_size` cells.
 #![allow(clippy::new_without_default)]
 
-use crate::parser::Token;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+
+use crate::compiler::{self, Step};
+use crate::machine::{Machine, Op, Tape};
+use crate::options::{CompileOptions, EofPolicy, LoopCounts, WideCells, WideOutput};
+use crate::parser::{Span, Token};
+
+/// Cells on each side of the current one in the trace of the interpreter
+const TRACE_RADIUS: usize = 8;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Interpreter {
-    cells: Vec<u8>,
-    pointer: usize,
+    machine: Machine,
 }
 impl Interpreter {
+    /// Interpreter storing zero on end of input, like compiled programs by default
     pub fn new() -> Self {
+        Self::with_eof(EofPolicy::Zero)
+    }
+
+    pub fn with_eof(eof: EofPolicy) -> Self {
         Self {
-            cells: vec![0],
-            pointer: 0,
+            machine: Machine::new(eof),
         }
     }
 
-    #[must_use]
-    fn step(&mut self, token: Token, io: &mut dyn IO) -> Mode {
-        println!(
-            "s: {:?} | {:?}",
-            self.cells
-                .iter()
-                .enumerate()
-                .map(|(i, v)| format!("{}{}", if i == self.pointer { "*" } else { "" }, v))
-                .collect::<Vec<_>>()
-                .join(", "),
-            token
+    /// Cells written so far, the ones after them are zero
+    pub fn cells(&self) -> &[u8] {
+        &self.machine.tape.0
+    }
+
+    /// Index of the current cell
+    pub fn pointer(&self) -> usize {
+        self.machine.pointer
+    }
+
+    /// Copy of the cells and the pointer
+    pub fn snapshot(&self) -> TapeSnapshot {
+        TapeSnapshot {
+            cells: self.cells().to_vec(),
+            pointer: self.pointer(),
+        }
+    }
+
+    /// Continues from the cells and the pointer of a snapshot, keeping the EOF policy
+    pub fn restore(&mut self, snapshot: TapeSnapshot) {
+        self.machine.tape = Tape(snapshot.cells);
+        self.machine.pointer = snapshot.pointer;
+    }
+
+    fn step(&mut self, token: Token, io: &mut dyn IO) -> Result<Mode, PointerUnderflow> {
+        log::trace!(
+            "{:?}\n{}",
+            token,
+            self.snapshot().render(TRACE_RADIUS, CellFormat::Decimal)
         );
 
         match token {
-            Token::Next => {
-                self.pointer += 1;
-                if self.pointer == self.cells.len() {
-                    self.cells.push(0);
-                }
-            },
-            Token::Prev => {
-                assert!(self.pointer != 0);
-                self.pointer -= 1
-            },
-            Token::Increment | Token::Decrement => {
-                self.cells[self.pointer] = if token == Token::Increment {
-                    self.cells[self.pointer].wrapping_add(1)
-                } else {
-                    self.cells[self.pointer].wrapping_sub(1)
-                };
-            },
-            Token::Output => io.write(self.cells[self.pointer]),
-            Token::Input => self.cells[self.pointer] = io.read(),
             Token::JumpForwards => {
-                if self.cells[self.pointer] == 0 {
-                    return Mode::ScrollForwards;
+                if self.machine.current() == 0 {
+                    return Ok(Mode::ScrollForwards);
                 }
             },
             Token::JumpBackwards => {
-                if self.cells[self.pointer] != 0 {
-                    return Mode::ScrollBackwards;
+                if self.machine.current() != 0 {
+                    return Ok(Mode::ScrollBackwards);
                 }
             },
+            // Assembly only runs in compiled programs
+            Token::InlineAsm(_) => {},
+            _ => self.machine.apply(Op::from_token(token).unwrap(), io)?,
         }
-        Mode::Normal
+        Ok(Mode::Normal)
     }
 
-    /// Requires that tokens contains balanced brackets
-    pub fn run(&mut self, tokens: &[Token], io: &mut dyn IO) {
-        // let mut executor = Executor {
-        //     interpreter: self.clone(),
-        //     index: 0,
-        //     tokens: &tokens,
-        // };
-
-        // while !executor.done() {
-        //     executor.step(io);
-        // }
-
-        let mut index: usize = 0;
-        while index < tokens.len() {
-            println!(
+    /// Requires that tokens contains balanced brackets.
+    /// Stops with an error if the pointer is moved left of the first cell.
+    pub fn run(&mut self, tokens: &[Token], io: &mut dyn IO) -> Result<(), PointerUnderflow> {
+        let mut executor = Executor::new(mem::replace(self, Self::new()), tokens);
+        let mut result = Ok(());
+        while !executor.is_done() {
+            log::trace!(
                 "t: {}",
                 tokens.iter().map(|t| format!("{}", t)).collect::<String>()
             );
 
-            println!("   {}^", " ".repeat(index));
+            log::trace!("   {}^", " ".repeat(executor.position()));
+
+            if let Err(error) = executor.step(io) {
+                result = Err(error);
+                break;
+            }
+        }
+        *self = executor.into_interpreter();
+        result
+    }
+
+    /// Runs the program with the given input and returns its output.
+    /// Reads after the input is exhausted follow the EOF policy.
+    pub fn run_collect(&mut self, tokens: &[Token], input: &[u8]) -> Result<Vec<u8>, PointerUnderflow> {
+        let mut io = BufferIO::new(input);
+        self.run(tokens, &mut io)?;
+        Ok(io.output)
+    }
+
+    /// Runs the program with the given input like a compiled binary, returning what it writes
+    /// and its exit status. Moving left of the first cell exits with `PointerUnderflow::EXIT_STATUS`,
+    /// keeping the output written before it.
+    pub fn run_status(&mut self, tokens: &[Token], input: &[u8]) -> (Vec<u8>, i32) {
+        let mut io = BufferIO::new(input);
+        let status = match self.run(tokens, &mut io) {
+            Ok(()) => 0,
+            Err(PointerUnderflow) => PointerUnderflow::EXIT_STATUS,
+        };
+        (io.output, status)
+    }
+}
+
+/// Faster interpreter running the compiler's `Step` IR,
+/// so that runs of `+` or `>` execute as single operations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FastInterpreter {
+    machine: Machine,
+}
+impl FastInterpreter {
+    /// Interpreter storing zero on end of input, like compiled programs by default
+    pub fn new() -> Self {
+        Self::with_eof(EofPolicy::Zero)
+    }
+
+    pub fn with_eof(eof: EofPolicy) -> Self {
+        Self {
+            machine: Machine::new(eof),
+        }
+    }
+
+    /// Requires that tokens contains balanced brackets.
+    /// Stops with an error if the pointer is moved left of the first cell.
+    pub fn run(&mut self, tokens: &[Token], io: &mut dyn IO) -> Result<(), PointerUnderflow> {
+        self.execute(tokens, io, None)
+    }
+
+    /// Like `run`, but also counts how often each loop runs, in the source order of `[`.
+    /// The counts are kept if execution stops with an error.
+    pub fn run_profiled(
+        &mut self, tokens: &[Token], io: &mut dyn IO, counts: &mut Vec<LoopCounts>,
+    ) -> Result<(), PointerUnderflow> {
+        self.execute(tokens, io, Some(counts))
+    }
+
+    fn execute(
+        &mut self, tokens: &[Token], io: &mut dyn IO, mut counts: Option<&mut Vec<LoopCounts>>,
+    ) -> Result<(), PointerUnderflow> {
+        let steps = compiler::lower(tokens);
+
+        // Resolve jump targets once, instead of searching for labels on each jump
+        let labels: HashMap<_, _> = steps
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| {
+                if let Step::Label(l) = s {
+                    Some((*l, i))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let targets: Vec<usize> = steps
+            .iter()
+            .map(|s| match s {
+                Step::JumpTo(l) | Step::JumpToIf(_, l) => labels[l],
+                _ => 0,
+            })
+            .collect();
+
+        // Loop number of each conditional jump, `[` is followed by the label `]` jumps to
+        let mut loop_labels = HashMap::new();
+        for (i, s) in steps.iter().enumerate() {
+            if let (Step::JumpToIf(false, end), Some(Step::Label(start))) = (s, steps.get(i + 1)) {
+                loop_labels.insert(*end, loop_labels.len() / 2);
+                loop_labels.insert(*start, loop_labels.len() / 2);
+            }
+        }
+        let loop_of: Vec<usize> = steps
+            .iter()
+            .map(|s| match s {
+                Step::JumpToIf(_, l) => loop_labels[l],
+                _ => 0,
+            })
+            .collect();
+        if let Some(counts) = &mut counts {
+            counts.clear();
+            counts.resize(loop_labels.len() / 2, LoopCounts::default());
+        }
 
-            let mode = self.step(tokens[index], io);
-            if mode == Mode::Normal {
+        let ops: Vec<Option<Op>> = steps.iter().map(|s| Op::from_step(*s)).collect();
+
+        let mut index: usize = 0;
+        while index < steps.len() {
+            if let Some(op) = ops[index] {
+                self.machine.apply(op, io)?;
                 index += 1;
                 continue;
             }
-
-            if mode == Mode::ScrollForwards {
-                let mut level = 1;
-                while level > 0 {
-                    index += 1;
-                    if tokens[index] == Token::JumpForwards {
-                        level += 1;
-                    } else if tokens[index] == Token::JumpBackwards {
-                        level -= 1;
+            match steps[index] {
+                Step::JumpTo(_) => index = targets[index],
+                Step::JumpToIf(cond, _) => {
+                    let nonzero = self.machine.current() != 0;
+                    if let Some(counts) = &mut counts {
+                        let c = &mut counts[loop_of[index]];
+                        if !cond {
+                            c.entries += 1;
+                        }
+                        // Both a taken `]` and an untaken `[` start an iteration
+                        if nonzero {
+                            c.iterations += 1;
+                        }
                     }
-                }
-            } else {
-                let mut level = 1;
-                while level > 0 {
-                    index -= 1;
-                    if tokens[index] == Token::JumpBackwards {
-                        level += 1;
-                    } else if tokens[index] == Token::JumpForwards {
-                        level -= 1;
+                    if cond == nonzero {
+                        index = targets[index];
                     }
+                },
+                _ => {},
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Runs the program with the given input and returns its output.
+    /// Reads after the input is exhausted follow the EOF policy.
+    pub fn run_collect(&mut self, tokens: &[Token], input: &[u8]) -> Result<Vec<u8>, PointerUnderflow> {
+        let mut io = BufferIO::new(input);
+        self.run(tokens, &mut io)?;
+        Ok(io.output)
+    }
+}
+
+/// Interpreter for programs declaring cells wider than a byte, see `CompileOptions::wide_cells`.
+/// Each cell wraps around at its own width, `.` writes the bytes given by the output mode,
+/// and `,` stores the byte read zero-extended to the whole cell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WideInterpreter {
+    cells: Vec<u32>,
+    pointer: usize,
+    eof: EofPolicy,
+    wide_cells: Vec<WideCells>,
+    output: WideOutput,
+}
+impl WideInterpreter {
+    /// Interpreter with the cell widths, output mode and EOF policy of the options
+    pub fn new(options: &CompileOptions) -> Self {
+        Self {
+            cells: Vec::new(),
+            pointer: 0,
+            eof: options.eof,
+            wide_cells: options.wide_cells.clone(),
+            output: options.wide_output,
+        }
+    }
+
+    /// Cells written so far, the ones after them are zero
+    pub fn cells(&self) -> &[u32] {
+        &self.cells
+    }
+
+    /// Index of the current cell
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    fn bytes(&self, cell: usize) -> u8 {
+        self.wide_cells
+            .iter()
+            .find(|wide| wide.first <= cell && cell <= wide.last)
+            .map_or(1, |wide| wide.bytes)
+    }
+
+    fn current(&self) -> u32 {
+        self.cells.get(self.pointer).copied().unwrap_or(0)
+    }
+
+    fn set(&mut self, value: u32) {
+        let mask = u32::MAX >> (32 - 8 * u32::from(self.bytes(self.pointer)));
+        if self.pointer >= self.cells.len() {
+            self.cells.resize(self.pointer + 1, 0);
+        }
+        self.cells[self.pointer] = value & mask;
+    }
+
+    /// Requires that tokens contains balanced brackets.
+    /// Stops with an error if the pointer is moved left of the first cell.
+    pub fn run(&mut self, tokens: &[Token], io: &mut dyn IO) -> Result<(), PointerUnderflow> {
+        let mut targets = vec![0; tokens.len()];
+        let mut open = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            match token {
+                Token::JumpForwards => open.push(i),
+                Token::JumpBackwards => {
+                    let start = open.pop().expect("Unbalanced brackets");
+                    targets[start] = i;
+                    targets[i] = start;
+                },
+                _ => {},
+            }
+        }
+
+        let mut index = 0;
+        while index < tokens.len() {
+            match tokens[index] {
+                Token::Next => self.pointer += 1,
+                Token::Prev => self.pointer = self.pointer.checked_sub(1).ok_or(PointerUnderflow)?,
+                Token::Increment => self.set(self.current().wrapping_add(1)),
+                Token::Decrement => self.set(self.current().wrapping_sub(1)),
+                Token::Output => {
+                    let count = match self.output {
+                        WideOutput::Low => 1,
+                        WideOutput::Full => self.bytes(self.pointer),
+                    };
+                    for byte in &self.current().to_le_bytes()[..usize::from(count)] {
+                        io.write(*byte);
+                    }
+                },
+                // Compiled programs zero-extend the lowest byte after reading, also when it's unchanged
+                Token::Input => {
+                    let value = io.read().or_else(|| self.eof.value()).unwrap_or(self.current() as u8);
+                    self.set(u32::from(value));
+                },
+                Token::JumpForwards if self.current() == 0 => index = targets[index],
+                Token::JumpBackwards if self.current() != 0 => index = targets[index],
+                // Assembly only runs in compiled programs
+                _ => {},
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+
+    /// Runs the program with the given input like a compiled binary, returning what it writes
+    /// and its exit status, see `Interpreter::run_status`
+    pub fn run_status(&mut self, tokens: &[Token], input: &[u8]) -> (Vec<u8>, i32) {
+        let mut io = BufferIO::new(input);
+        let status = match self.run(tokens, &mut io) {
+            Ok(()) => 0,
+            Err(PointerUnderflow) => PointerUnderflow::EXIT_STATUS,
+        };
+        (io.output, status)
+    }
+}
+
+/// State before a step, restored when stepping back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Undo {
+    index: usize,
+    pointer: usize,
+    /// Value of the current cell
+    cell: u8,
+    /// Whether the step consumed a byte of input
+    read: bool,
+    /// Whether the step wrote a byte
+    wrote: bool,
+}
+
+/// Passes IO through, noting what a step did with it
+struct Recorder<'b> {
+    io: &'b mut dyn IO,
+    read: bool,
+    wrote: bool,
+}
+impl IO for Recorder<'_> {
+    fn read(&mut self) -> Option<u8> {
+        let value = self.io.read();
+        self.read = value.is_some();
+        value
+    }
+    fn write(&mut self, value: u8) {
+        self.wrote = true;
+        self.io.write(value);
+    }
+    fn unread(&mut self) {
+        self.io.unread();
+    }
+    fn unwrite(&mut self) {
+        self.io.unwrite();
+    }
+}
+
+/// Runs a program one token at a time, e.g. for a debugger
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Executor<'a> {
+    interpreter: Interpreter,
+    /// Index of the next token
+    index: usize,
+    tokens: &'a [Token],
+    /// Index of the matching bracket for each bracket token
+    matching: Vec<usize>,
+    /// Undo log of the latest steps, oldest first
+    history: VecDeque<Undo>,
+    /// Maximum number of steps kept in the history, `0` disables it
+    history_limit: usize,
+    /// Source offset of each token, the token index if no spans are given
+    offsets: Vec<usize>,
+    /// Source offsets of the tokens to stop before
+    breakpoints: HashSet<usize>,
+    /// Cells to stop at when their value changes
+    watchpoints: HashSet<usize>,
+    /// Index of the token the last run stopped before at a breakpoint
+    stopped_at: Option<usize>,
+}
+impl<'a> Executor<'a> {
+    /// Requires that tokens contains balanced brackets
+    pub fn new(interpreter: Interpreter, tokens: &'a [Token]) -> Self {
+        let mut matching = vec![0; tokens.len()];
+        let mut open = Vec::new();
+        for (index, token) in tokens.iter().enumerate() {
+            match token {
+                Token::JumpForwards => open.push(index),
+                Token::JumpBackwards => {
+                    let start = open.pop().expect("Unbalanced brackets");
+                    matching[start] = index;
+                    matching[index] = start;
+                },
+                _ => {},
+            }
+        }
+        assert!(open.is_empty(), "Unbalanced brackets");
+        Self {
+            interpreter,
+            index: 0,
+            tokens,
+            matching,
+            history: VecDeque::new(),
+            history_limit: 0,
+            offsets: (0..tokens.len()).collect(),
+            breakpoints: HashSet::new(),
+            watchpoints: HashSet::new(),
+            stopped_at: None,
+        }
+    }
+
+    /// Executor that can step back over the last `limit` steps
+    pub fn with_history(interpreter: Interpreter, tokens: &'a [Token], limit: usize) -> Self {
+        Self {
+            history_limit: limit,
+            ..Self::new(interpreter, tokens)
+        }
+    }
+
+    /// Uses the source positions of the tokens, as given by `parse_spanned`, for breakpoints
+    pub fn set_spans(&mut self, spans: &[Span]) {
+        assert_eq!(spans.len(), self.tokens.len(), "A span is needed for each token");
+        self.offsets = spans.iter().map(|span| span.offset).collect();
+    }
+
+    /// Stops `run_until_stop` before the token at the source offset
+    pub fn add_breakpoint(&mut self, offset: usize) {
+        self.breakpoints.insert(offset);
+    }
+
+    /// Stops `run_until_stop` after a step that changes the value of the cell
+    pub fn add_watchpoint(&mut self, cell: usize) {
+        self.watchpoints.insert(cell);
+    }
+
+    /// Steps until the program is done, a breakpoint is reached or a watched cell changes.
+    /// Running again from the breakpoint stopped at continues past it.
+    pub fn run_until_stop(&mut self, io: &mut dyn IO) -> Result<Stop, PointerUnderflow> {
+        let mut resumed = self.stopped_at.take() == Some(self.index);
+        loop {
+            if self.is_done() {
+                return Ok(Stop::Done);
+            }
+            let offset = self.offsets[self.index];
+            if !resumed && self.breakpoints.contains(&offset) {
+                self.stopped_at = Some(self.index);
+                return Ok(Stop::Breakpoint(offset));
+            }
+            resumed = false;
+
+            // Only the current cell can change in a step
+            let cell = self.interpreter.pointer();
+            let old = self.interpreter.machine.current();
+            self.step(io)?;
+            if self.watchpoints.contains(&cell) {
+                let new = self.interpreter.cells().get(cell).copied().unwrap_or(0);
+                if new != old {
+                    return Ok(Stop::Watchpoint { cell, old, new });
                 }
             }
         }
     }
+
+    /// Whether the whole program has been executed
+    pub fn is_done(&self) -> bool {
+        debug_assert!(self.index <= self.tokens.len());
+        self.index == self.tokens.len()
+    }
+
+    /// Index of the token executed next
+    pub fn position(&self) -> usize {
+        self.index
+    }
+
+    /// Cells and pointer of the program
+    pub fn interpreter(&self) -> &Interpreter {
+        &self.interpreter
+    }
+
+    /// Interpreter with the cells and pointer of the program, to continue running it on its own
+    pub fn into_interpreter(self) -> Interpreter {
+        self.interpreter
+    }
+
+    /// Copy of the tape and the position, to continue from later with `restore`
+    pub fn snapshot(&self) -> ExecutorSnapshot {
+        ExecutorSnapshot {
+            tape: self.interpreter.snapshot(),
+            index: self.index,
+        }
+    }
+
+    /// Continues from a snapshot of an executor of the same tokens.
+    /// The history is cleared, as it can't step back past the snapshot.
+    pub fn restore(&mut self, snapshot: ExecutorSnapshot) {
+        assert!(snapshot.index <= self.tokens.len(), "Snapshot of other tokens");
+        self.interpreter.restore(snapshot.tape);
+        self.index = snapshot.index;
+        self.history.clear();
+    }
+
+    /// Executes the next token, and returns the index of the one after it.
+    /// A bracket that jumps continues after the matching bracket,
+    /// as the check there would pass the same way.
+    pub fn step(&mut self, io: &mut dyn IO) -> Result<usize, PointerUnderflow> {
+        assert!(!self.is_done(), "Program already done");
+        if self.history_limit == 0 {
+            self.execute(io)?;
+            return Ok(self.index);
+        }
+
+        let mut undo = Undo {
+            index: self.index,
+            pointer: self.interpreter.pointer(),
+            cell: self.interpreter.machine.current(),
+            read: false,
+            wrote: false,
+        };
+        let mut recorder = Recorder {
+            io,
+            read: false,
+            wrote: false,
+        };
+        self.execute(&mut recorder)?;
+        undo.read = recorder.read;
+        undo.wrote = recorder.wrote;
+        if self.history.len() == self.history_limit {
+            self.history.pop_front();
+        }
+        self.history.push_back(undo);
+        Ok(self.index)
+    }
+
+    fn execute(&mut self, io: &mut dyn IO) -> Result<(), PointerUnderflow> {
+        match self.interpreter.step(self.tokens[self.index], io)? {
+            Mode::Normal => self.index += 1,
+            Mode::ScrollForwards | Mode::ScrollBackwards => self.index = self.matching[self.index] + 1,
+        }
+        Ok(())
+    }
+
+    /// Undoes the latest step in the history, also returning its input and removing its output.
+    /// Returns `false` if the history is empty.
+    pub fn step_back(&mut self, io: &mut dyn IO) -> bool {
+        if let Some(undo) = self.history.pop_back() {
+            self.index = undo.index;
+            let machine = &mut self.interpreter.machine;
+            machine.pointer = undo.pointer;
+            // Cells are only written if changed, so that the tape doesn't grow
+            if machine.current() != undo.cell {
+                machine.tape.set(undo.pointer, undo.cell);
+            }
+            if undo.read {
+                io.unread();
+            }
+            if undo.wrote {
+                io.unwrite();
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Why `Executor::run_until_stop` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The whole program has been executed
+    Done,
+    /// Before the token at the source offset
+    Breakpoint(usize),
+    /// After a step that changed a watched cell
+    Watchpoint { cell: usize, old: u8, new: u8 },
+}
+
+/// Tape of an interpreter at some point, e.g. after a run
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TapeSnapshot {
+    /// Cells written so far, the ones after them are zero
+    pub cells: Vec<u8>,
+    /// Index of the current cell
+    pub pointer: usize,
+}
+impl fmt::Display for TapeSnapshot {
+    /// Cells up to the last written one or the current one, which is marked with `*`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for i in 0..self.cells.len().max(self.pointer + 1) {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            if i == self.pointer {
+                write!(f, "*")?;
+            }
+            write!(f, "{}", self.cells.get(i).unwrap_or(&0))?;
+        }
+        Ok(())
+    }
 }
 
-// pub struct Executor<'a> {
-//     interpreter: Interpreter,
-//     index: usize,
-//     tokens: &'a [Token],
-// }
-// impl<'a> Executor<'a> {
-//     #[must_use]
-//     #[inline]
-//     pub fn done(&self) -> bool {
-//         debug_assert!(self.index <= self.tokens.len());
-//         self.index == self.tokens.len()
-//     }
-
-//     pub fn step(&mut self, io: &mut dyn IO) {
-//         assert!(!self.done());
-
-//         let mode = self.interpreter.step(self.tokens[self.index], io);
-//         if mode == Mode::Normal {
-//             self.index += 1;
-//         } else if mode == Mode::ScrollForwards {
-//             let mut level = 1;
-//             while level > 0 {
-//                 self.index += 1;
-//                 if self.tokens[self.index] == Token::JumpForwards {
-//                     level += 1;
-//                 } else if self.tokens[self.index] == Token::JumpBackwards {
-//                     level -= 1;
-//                 }
-//             }
-//         } else {
-//             let mut level = 1;
-//             while level > 0 {
-//                 self.index -= 1;
-//                 if self.tokens[self.index] == Token::JumpBackwards {
-//                     level += 1;
-//                 } else if self.tokens[self.index] == Token::JumpForwards {
-//                     level -= 1;
-//                 }
-//             }
-//         }
-//     }
-// }
+impl TapeSnapshot {
+    /// The cells within `radius` of the current one in a row under their indices,
+    /// with the current one marked by `^` below it, e.g. for a debugger.
+    /// All cells are as wide as the widest value or index.
+    pub fn render(&self, radius: usize, format: CellFormat) -> String {
+        let start = self.pointer.saturating_sub(radius);
+        let end = self.pointer + radius;
+        let values: Vec<String> = (start..=end)
+            .map(|i| format.show(self.cells.get(i).copied().unwrap_or(0)))
+            .collect();
+        let width = values
+            .iter()
+            .map(String::len)
+            .chain(Some(end.to_string().len()))
+            .max()
+            .unwrap();
+        let row = |items: &[String]| -> String {
+            items
+                .iter()
+                .map(|item| format!("{:>width$}", item, width = width))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let indices: Vec<String> = (start..=end).map(|i| i.to_string()).collect();
+        let marker = " ".repeat((self.pointer - start) * (width + 1)) + &"^".repeat(width);
+        format!("{}\n{}\n{}", row(&indices), row(&values), marker)
+    }
+}
+
+/// How `TapeSnapshot::render` shows the value of a cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellFormat {
+    Decimal,
+    /// Two hexadecimal digits
+    Hex,
+    /// Printable ASCII characters as themselves, others as `.`
+    Char,
+}
+impl CellFormat {
+    fn show(self, value: u8) -> String {
+        match self {
+            Self::Decimal => value.to_string(),
+            Self::Hex => format!("{:02x}", value),
+            Self::Char if value.is_ascii_graphic() || value == b' ' => char::from(value).to_string(),
+            Self::Char => ".".to_owned(),
+        }
+    }
+}
+
+/// Tape and position of an executor at some point, e.g. to persist a long run and resume it later
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExecutorSnapshot {
+    pub tape: TapeSnapshot,
+    /// Index of the token executed next
+    pub index: usize,
+}
+
+/// The pointer was moved left of the first cell.
+/// Compiled programs exit with status 1 in this case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PointerUnderflow;
+impl PointerUnderflow {
+    /// Exit status of compiled programs moving left of the first cell
+    pub const EXIT_STATUS: i32 = 1;
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
@@ -165,62 +712,506 @@ pub enum Mode {
 }
 
 pub trait IO {
-    fn read(&mut self) -> u8;
+    /// Next input byte, or `None` on end of input
+    fn read(&mut self) -> Option<u8>;
     fn write(&mut self, value: u8);
+    /// Returns the last byte read to the input, when stepping back.
+    /// By default the byte stays read.
+    fn unread(&mut self) {}
+    /// Removes the last byte written, when stepping back.
+    /// By default the byte stays written.
+    fn unwrite(&mut self) {}
 }
 
-/// All reads return zeros, writes stored
+/// Reads from a fixed input, writes stored
 #[derive(Debug, Clone, PartialEq, Eq)]
-struct ZeroIO {
+pub struct BufferIO<'a> {
+    /// Input not read yet
+    pub input: &'a [u8],
     pub output: Vec<u8>,
+    /// The whole input, for returning read bytes
+    all_input: &'a [u8],
 }
-impl ZeroIO {
-    pub fn new() -> Self {
-        Self { output: Vec::new() }
+impl<'a> BufferIO<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            output: Vec::new(),
+            all_input: input,
+        }
     }
 }
-impl IO for ZeroIO {
-    fn read(&mut self) -> u8 {
-        0
+impl IO for BufferIO<'_> {
+    fn read(&mut self) -> Option<u8> {
+        let (first, rest) = self.input.split_first()?;
+        self.input = rest;
+        Some(*first)
     }
     fn write(&mut self, value: u8) {
         self.output.push(value);
     }
+    fn unread(&mut self) {
+        let position = self.all_input.len() - self.input.len();
+        self.input = &self.all_input[position - 1..];
+    }
+    fn unwrite(&mut self) {
+        self.output.pop();
+    }
+}
+
+/// Reads stdin only when the program reads a byte and writes through to stdout,
+/// flushing before each read so that a prompt is shown before waiting for the answer
+#[derive(Debug)]
+pub struct StdIO {
+    stdin: io::Stdin,
+    stdout: io::Stdout,
+    /// First error writing to stdout, later bytes are dropped
+    error: Option<io::Error>,
+}
+impl StdIO {
+    pub fn new() -> Self {
+        Self {
+            stdin: io::stdin(),
+            stdout: io::stdout(),
+            error: None,
+        }
+    }
+
+    /// Flushes stdout, returning the first error writing to it
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.stdout.flush(),
+        }
+    }
+}
+impl IO for StdIO {
+    /// Errors reading stdin end the input
+    fn read(&mut self) -> Option<u8> {
+        if self.error.is_none() {
+            if let Err(error) = self.stdout.flush() {
+                self.error = Some(error);
+            }
+        }
+        let mut byte = [0];
+        loop {
+            match self.stdin.read(&mut byte) {
+                Ok(0) => return None,
+                Ok(_) => return Some(byte[0]),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => return None,
+            }
+        }
+    }
+    fn write(&mut self, value: u8) {
+        if self.error.is_none() {
+            if let Err(error) = self.stdout.write_all(&[value]) {
+                self.error = Some(error);
+            }
+        }
+    }
+}
+
+/// Passes IO through to another, keeping a copy of the bytes written, e.g. for a log.
+/// Stepping back removes the bytes from the copy like from the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TeeIO<I> {
+    io: I,
+    trace: Vec<u8>,
+}
+impl<I: IO> TeeIO<I> {
+    pub fn new(io: I) -> Self {
+        Self {
+            io,
+            trace: Vec::new(),
+        }
+    }
+
+    /// Bytes written so far
+    pub fn trace(&self) -> &[u8] {
+        &self.trace
+    }
+
+    /// The IO written through
+    pub fn inner(&self) -> &I {
+        &self.io
+    }
+
+    pub fn into_inner(self) -> I {
+        self.io
+    }
+}
+impl<I: IO> IO for TeeIO<I> {
+    fn read(&mut self) -> Option<u8> {
+        self.io.read()
+    }
+    fn write(&mut self, value: u8) {
+        self.trace.push(value);
+        self.io.write(value);
+    }
+    fn unread(&mut self) {
+        self.io.unread();
+    }
+    fn unwrite(&mut self) {
+        self.trace.pop();
+        self.io.unwrite();
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Interpreter, ZeroIO};
-    use crate::parser::parse;
+    use super::{
+        BufferIO, CellFormat, Executor, FastInterpreter, Interpreter, PointerUnderflow, Stop, TapeSnapshot,
+        TeeIO, WideInterpreter, IO,
+    };
+    use crate::options::{CompileOptions, WideOutput};
+    use crate::parser::{parse, parse_spanned, parse_wide_cells};
 
     #[test]
     fn test_simple() {
-        let mut io = ZeroIO::new();
-        Interpreter::new().run(&parse("+."), &mut io);
-        assert_eq!(io.output, vec![1]);
+        assert_eq!(Interpreter::new().run_collect(&parse("+."), b""), Ok(vec![1]));
     }
 
     #[test]
     fn test_add() {
-        let mut io = ZeroIO::new();
-
-        Interpreter::new().run(&parse("++ > +++ < [->+<] > ."), &mut io);
-        assert_eq!(io.output, vec![5]);
+        assert_eq!(
+            Interpreter::new().run_collect(&parse("++ > +++ < [->+<] > ."), b""),
+            Ok(vec![5])
+        );
     }
 
     #[test]
     fn test_hello_world() {
-        let mut io = ZeroIO::new();
-
-        Interpreter::new().run(
+        let output = Interpreter::new().run_collect(
             &parse(
                 r"++++++++[>++++[>++>+++>+++
                 >+<<<<-]>+>+>->>+[<]<-]>>.>-
                 --.+++++++..+++.>>.<-.<.+++.
                 ------.--------.>>+.>++.",
             ),
-            &mut io,
+            b"",
+        );
+        assert_eq!(output.unwrap(), b"Hello World!\n");
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut intp = Interpreter::new();
+        intp.run_collect(&parse("+>+++>>"), b"").unwrap();
+        assert_eq!(intp.cells(), &[1, 3]);
+        assert_eq!(intp.pointer(), 3);
+        let snapshot = intp.snapshot();
+        assert_eq!(snapshot, TapeSnapshot {
+            cells: vec![1, 3],
+            pointer: 3,
+        });
+        assert_eq!(snapshot.to_string(), "1, 3, 0, *0");
+    }
+
+    #[test]
+    fn test_render() {
+        let snapshot = TapeSnapshot {
+            cells: vec![72, 101, 0, 10],
+            pointer: 1,
+        };
+        assert_eq!(snapshot.render(1, CellFormat::Decimal), "  0   1   2\n 72 101   0\n    ^^^");
+        assert_eq!(snapshot.render(1, CellFormat::Hex), " 0  1  2\n48 65 00\n   ^^");
+        assert_eq!(snapshot.render(2, CellFormat::Char), "0 1 2 3\nH e . .\n  ^");
+
+        // Only a window around the pointer of a large tape
+        let snapshot = TapeSnapshot {
+            cells: vec![0; 30000],
+            pointer: 15000,
+        };
+        assert_eq!(
+            snapshot.render(1, CellFormat::Decimal),
+            "14999 15000 15001\n    0     0     0\n      ^^^^^"
+        );
+    }
+
+    #[test]
+    fn test_restore() {
+        let tokens = parse(",[->+>+<<]>[-<+>]<.>>.");
+        let mut io = BufferIO::new(b"\x05");
+        let mut executor = Executor::new(Interpreter::new(), &tokens);
+        for _ in 0..20 {
+            executor.step(&mut io).unwrap();
+        }
+        let snapshot = executor.snapshot();
+        assert_eq!(snapshot.index, executor.position());
+
+        // A new executor continues from the snapshot like the original one
+        let mut resumed = Executor::new(Interpreter::new(), &tokens);
+        resumed.restore(snapshot.clone());
+        assert_eq!(resumed.snapshot(), snapshot);
+        let mut resumed_io = BufferIO::new(b"");
+        assert_eq!(executor.run_until_stop(&mut io), Ok(Stop::Done));
+        assert_eq!(resumed.run_until_stop(&mut resumed_io), Ok(Stop::Done));
+        assert_eq!(resumed.snapshot(), executor.snapshot());
+        assert_eq!(resumed_io.output, io.output);
+        assert_eq!(io.output, [5, 5]);
+
+        let mut intp = Interpreter::new();
+        intp.restore(snapshot.tape.clone());
+        assert_eq!(intp.snapshot(), snapshot.tape);
+    }
+
+    #[test]
+    fn test_tee_io() {
+        let tokens = parse(",[.,]+.");
+        let mut io = TeeIO::new(BufferIO::new(b"ab"));
+        Interpreter::new().run(&tokens, &mut io).unwrap();
+        assert_eq!(io.trace(), b"ab\x01");
+        assert_eq!(io.inner().output, b"ab\x01");
+
+        // Stepping back takes the byte out of both
+        let mut io = TeeIO::new(BufferIO::new(b"a"));
+        let mut executor = Executor::with_history(Interpreter::new(), &tokens, 100);
+        executor.run_until_stop(&mut io).unwrap();
+        assert!(executor.step_back(&mut io));
+        assert!(executor.step_back(&mut io));
+        assert_eq!(io.trace(), b"a");
+        assert_eq!(io.into_inner().output, b"a");
+    }
+
+    #[test]
+    fn test_executor() {
+        let tokens = parse("+[-[+]]>[.]+.");
+        let mut io = BufferIO::new(b"");
+        let mut executor = Executor::new(Interpreter::new(), &tokens);
+        let mut positions = Vec::new();
+        while !executor.is_done() {
+            positions.push(executor.step(&mut io).unwrap());
+        }
+        // Jumps continue after the matching bracket
+        assert_eq!(positions, vec![1, 2, 3, 6, 7, 8, 11, 12, 13]);
+        assert_eq!(executor.interpreter().pointer(), 1);
+        assert_eq!(io.output, vec![1]);
+
+        let tokens = parse("<");
+        let mut executor = Executor::new(Interpreter::new(), &tokens);
+        assert_eq!(executor.step(&mut io), Err(PointerUnderflow));
+        assert_eq!(executor.position(), 0);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let tokens = parse(",>+[-<.>]");
+        let mut io = BufferIO::new(b"ab");
+        let mut executor = Executor::with_history(Interpreter::new(), &tokens, 100);
+        while !executor.is_done() {
+            executor.step(&mut io).unwrap();
+        }
+        assert_eq!(io.output, b"a");
+        assert_eq!(io.input, b"b");
+
+        // Back to just before the output
+        for _ in 0..3 {
+            assert!(executor.step_back(&mut io));
+        }
+        assert_eq!(executor.position(), 6);
+        assert_eq!(executor.interpreter().snapshot().to_string(), "*97, 0");
+        assert!(io.output.is_empty());
+
+        // Back to the start, returning the input
+        while executor.step_back(&mut io) {}
+        assert_eq!(executor.position(), 0);
+        assert_eq!(executor.interpreter().snapshot().to_string(), "*0, 0");
+        assert_eq!(io.input, b"ab");
+
+        // Only the latest steps are kept
+        let mut executor = Executor::with_history(Interpreter::new(), &tokens, 2);
+        for _ in 0..4 {
+            executor.step(&mut io).unwrap();
+        }
+        assert!(executor.step_back(&mut io));
+        assert!(executor.step_back(&mut io));
+        assert!(!executor.step_back(&mut io));
+        assert_eq!(executor.position(), 2);
+
+        // Without undoing IO, only the tape and the position are stepped back
+        struct Written(usize);
+        impl IO for Written {
+            fn read(&mut self) -> Option<u8> {
+                Some(1)
+            }
+            fn write(&mut self, _value: u8) {
+                self.0 += 1;
+            }
+        }
+        let mut io = Written(0);
+        let tokens = parse(",.");
+        let mut executor = Executor::with_history(Interpreter::new(), &tokens, 2);
+        executor.step(&mut io).unwrap();
+        executor.step(&mut io).unwrap();
+        while executor.step_back(&mut io) {}
+        assert_eq!(executor.interpreter().snapshot().to_string(), "*0");
+        assert_eq!(io.0, 1);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let spanned = parse_spanned("++ [->+<]\n> .");
+        let (tokens, spans): (Vec<_>, Vec<_>) = spanned.into_iter().unzip();
+        let mut io = BufferIO::new(b"");
+        let mut executor = Executor::new(Interpreter::new(), &tokens);
+        executor.set_spans(&spans);
+        // The `-`, and the `.` on the second line
+        executor.add_breakpoint(4);
+        executor.add_breakpoint(12);
+        executor.add_watchpoint(1);
+
+        assert_eq!(executor.run_until_stop(&mut io), Ok(Stop::Breakpoint(4)));
+        assert_eq!(executor.position(), 3);
+        assert_eq!(
+            executor.run_until_stop(&mut io),
+            Ok(Stop::Watchpoint {
+                cell: 1,
+                old: 0,
+                new: 1
+            })
+        );
+        assert_eq!(executor.run_until_stop(&mut io), Ok(Stop::Breakpoint(4)));
+        assert_eq!(
+            executor.run_until_stop(&mut io),
+            Ok(Stop::Watchpoint {
+                cell: 1,
+                old: 1,
+                new: 2
+            })
+        );
+        assert_eq!(executor.run_until_stop(&mut io), Ok(Stop::Breakpoint(12)));
+        assert!(io.output.is_empty());
+        assert_eq!(executor.run_until_stop(&mut io), Ok(Stop::Done));
+        assert_eq!(io.output, vec![2]);
+
+        // A breakpoint at the first token stops before anything runs
+        let mut executor = Executor::new(Interpreter::new(), &tokens);
+        executor.set_spans(&spans);
+        executor.add_breakpoint(0);
+        assert_eq!(executor.run_until_stop(&mut io), Ok(Stop::Breakpoint(0)));
+        assert_eq!(executor.position(), 0);
+        assert_eq!(executor.run_until_stop(&mut io), Ok(Stop::Done));
+    }
+
+    #[test]
+    fn test_input() {
+        let output = Interpreter::new().run_collect(&parse(",[.,]"), b"abc");
+        assert_eq!(output.unwrap(), b"abc");
+        let output = Interpreter::new().run_collect(&parse(",+.,+."), b"a");
+        assert_eq!(output.unwrap(), b"b\x01");
+    }
+
+    #[test]
+    fn test_pointer_underflow() {
+        assert_eq!(
+            Interpreter::new().run_collect(&parse("+.<."), b""),
+            Err(PointerUnderflow)
+        );
+        assert_eq!(
+            Interpreter::new().run_collect(&parse("><<>"), b""),
+            Err(PointerUnderflow)
+        );
+        assert_eq!(Interpreter::new().run_collect(&parse(">+<>."), b""), Ok(vec![1]));
+
+        // Like a compiled binary, the output before moving left of the first cell is written
+        assert_eq!(Interpreter::new().run_status(&parse("+.<."), b""), (vec![1], 1));
+        assert_eq!(Interpreter::new().run_status(&parse(">+<>."), b""), (vec![1], 0));
+    }
+
+    #[test]
+    fn test_fast_interpreter() {
+        let programs: &[(&str, &[u8])] = &[
+            ("++ > +++ < [->+<] > .", b""),
+            (",[.,]", b"abc"),
+            (",+.,+.", b"a"),
+            ("+.<.", b""),
+            (">+<>.", b""),
+            (
+                "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.",
+                b"",
+            ),
+        ];
+        for (source, input) in programs {
+            let tokens = parse(source);
+            assert_eq!(
+                FastInterpreter::new().run_collect(&tokens, input),
+                Interpreter::new().run_collect(&tokens, input)
+            );
+        }
+    }
+
+    /// The Step interpreter against the token one on programs spending their time in nested loops,
+    /// like `mandelbrot.bf`. Timed, so ignored by default and best run with `--release`.
+    #[test]
+    #[ignore]
+    fn test_fast_interpreter_time() {
+        use std::time::{Duration, Instant};
+
+        let counter = "+".repeat(32);
+        let nested = format!(
+            "{}[>{}[>{}[>{}[>+>++>+++<<<-]<-]<-]<-]>>>>.>.>.",
+            counter, counter, counter, counter
+        );
+        let dbfi = include_str!("../examples/dbfi.bf");
+        let quicksort = format!("{}!987654321", include_str!("../examples/quicksort_bytes.bf"));
+        let programs: &[(&str, &str, &[u8])] = &[
+            ("nested loops", &nested, b""),
+            ("dbfi running quicksort", dbfi, quicksort.as_bytes()),
+        ];
+        // Shortest of a few runs, as a single one may be slowed down by the other tests
+        let time = |run: &dyn Fn() -> Vec<u8>| -> (Duration, Vec<u8>) {
+            let mut output = Vec::new();
+            let shortest = (0..3)
+                .map(|_| {
+                    let start = Instant::now();
+                    output = run();
+                    start.elapsed()
+                })
+                .min()
+                .unwrap();
+            (shortest, output)
+        };
+        for (name, source, input) in programs {
+            let tokens = parse(source);
+            let (token, expected) = time(&|| Interpreter::new().run_collect(&tokens, input).unwrap());
+            let (step, output) = time(&|| FastInterpreter::new().run_collect(&tokens, input).unwrap());
+            println!("{}: {:?} by tokens, {:?} by steps", name, token, step);
+            assert_eq!(output, expected);
+            assert!(
+                step < token,
+                "{} took {:?} by steps and {:?} by tokens",
+                name,
+                step,
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn test_wide_interpreter() {
+        // 300 in a 16-bit cell, then 255 in a byte cell wrapping to 0 after one more
+        let source = format!("@cells 0 0 u16\n{}. >-. +. <[-]-.", "+".repeat(300));
+        let tokens = parse(&source);
+        let mut options = CompileOptions {
+            wide_cells: parse_wide_cells(&source).unwrap(),
+            ..CompileOptions::default()
+        };
+
+        let mut interpreter = WideInterpreter::new(&options);
+        assert_eq!(interpreter.run_status(&tokens, b""), (vec![44, 255, 0, 255], 0));
+        assert_eq!(interpreter.cells(), &[0xffff, 0]);
+
+        options.wide_output = WideOutput::Full;
+        assert_eq!(
+            WideInterpreter::new(&options).run_status(&tokens, b""),
+            (vec![44, 1, 255, 0, 255, 255], 0)
+        );
+
+        // The byte read is zero-extended, and moving left of the first cell still stops the program
+        assert_eq!(
+            WideInterpreter::new(&options).run_status(&parse("-,.<"), b"a"),
+            (vec![b'a', 0], 1)
         );
-        assert_eq!(io.output, b"Hello World!\n");
     }
 }
@@ -0,0 +1,115 @@
+//! Labels of the instructions, interned so that comparing and looking them up compares integers.
+//! The names are kept in a table shared by all programs, and only looked up to write the assembly.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::convert::TryFrom;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Label of a jump target, e.g. `.label2` or `pointer_underflow`, as the index of its name in the table.
+/// Labels of the same name have the same id, so two labels are equal exactly when their names are.
+/// They are ordered by when their name was first seen, not by the name.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LabelId(u32);
+impl LabelId {
+    /// Id of the label with the name, given to the name the first time it is seen
+    pub fn new(name: &str) -> Self {
+        NAMES.with(|table| {
+            if let Some(&id) = table.ids.get(name) {
+                return Self(id);
+            }
+            let id = u32::try_from(table.names.len()).expect("Too many labels");
+            table.names.push(name.into());
+            table.ids.insert(name.into(), id);
+            Self(id)
+        })
+    }
+
+    /// Name of the label, as written in the assembly
+    pub fn name(self) -> String {
+        NAMES.with(|table| table.names[self.0 as usize].clone())
+    }
+
+    /// Whether the name starts with `.`, which NASM makes local to the last label without it
+    pub fn is_local(self) -> bool {
+        NAMES.with(|table| table.names[self.0 as usize].starts_with('.'))
+    }
+}
+impl From<&str> for LabelId {
+    fn from(name: &str) -> Self {
+        Self::new(name)
+    }
+}
+impl From<String> for LabelId {
+    fn from(name: String) -> Self {
+        Self::new(&name)
+    }
+}
+impl fmt::Display for LabelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+impl fmt::Debug for LabelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.name())
+    }
+}
+
+/// Names of the labels by id, and ids by name
+struct Table {
+    names: Vec<String>,
+    ids: BTreeMap<String, u32>,
+}
+
+/// The table behind a spin lock, as the optimizer builds without `std` and its `Mutex`.
+/// It's only held to look up or add a single name.
+struct Names {
+    locked: AtomicBool,
+    table: UnsafeCell<Table>,
+}
+// The lock gives a single thread at a time access to the table
+unsafe impl Sync for Names {}
+impl Names {
+    fn with<T>(&self, f: impl FnOnce(&mut Table) -> T) -> T {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        // SAFETY: Holding the lock, no other reference to the table exists until it's released
+        let result = f(unsafe { &mut *self.table.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+static NAMES: Names = Names {
+    locked: AtomicBool::new(false),
+    table: UnsafeCell::new(Table {
+        names: Vec::new(),
+        ids: BTreeMap::new(),
+    }),
+};
+
+#[cfg(test)]
+mod tests {
+    use super::LabelId;
+    use alloc::format;
+
+    #[test]
+    fn test_interning() {
+        let label = LabelId::new(".label0");
+        assert_eq!(label, LabelId::from(".label0"));
+        assert_ne!(label, LabelId::from(".label1"));
+        assert_eq!(label.name(), ".label0");
+        assert_eq!(format!("jmp {}", label), "jmp .label0");
+        assert!(label.is_local());
+        assert!(!LabelId::from("pointer_underflow").is_local());
+    }
+}
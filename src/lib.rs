@@ -1,3 +1,5 @@
+// Without the `std` feature only the parser, the instructions and the optimizer are built
+#![cfg_attr(not(feature = "std"), no_std)]
 #![allow(dead_code)]
 #![forbid(mutable_borrow_reservation_conflict)]
 #![forbid(bare_trait_objects)]
@@ -5,14 +7,59 @@
 #![allow(clippy::match_same_arms)]
 #![allow(clippy::cast_possible_truncation)]
 
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod build_script;
+mod cfg;
+#[cfg(feature = "std")]
 mod compiler;
+mod diff;
 pub mod error;
 mod instruction;
+#[cfg(feature = "std")]
+pub mod interpreter;
+mod label;
+#[cfg(feature = "std")]
+mod llvm;
+#[cfg(feature = "std")]
+mod machine;
+#[cfg(feature = "std")]
+pub mod minimize;
 mod optimizer;
+pub mod options;
 mod parser;
+#[cfg(feature = "std")]
+pub mod profile;
+#[cfg(feature = "std")]
+pub mod report;
+#[cfg(feature = "std")]
+mod riscv;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
 pub mod target_abi;
 
+#[cfg(feature = "std")]
 pub use target_abi::ABI;
 
-pub use compiler::compile_tokens;
-pub use parser::parse;
+#[cfg(feature = "std")]
+pub use compiler::{
+    annotate, compile_function, compile_programs, compile_shared_function, compile_tokens,
+    compile_tokens_with_report, compile_tokens_with_stats, data_blobs, evaluated_tape, explain_pass,
+    loop_report, precompute_output, source_checksum, to_brainfuck, to_dot, to_llvm_ir, write_assembly,
+    LoopInfo, StreamCompiler, SOURCE_CHECKSUM_LABEL,
+};
+pub use optimizer::{check_pass_cleanup, check_pass_order, describe_passes, neighbour_pass_orders, pass_order};
+pub use options::{
+    BareMetal, CompileOptions, CpuFeature, EofPolicy, LinkerDriver, LoopCounts, OptGoal, TapeMode, WideCells,
+    WideOutput,
+};
+pub use parser::{
+    check_balance, parse, parse_spanned, parse_wide_cells, try_parse, try_parse_inline_asm, try_parse_spanned,
+    try_parse_with, ParseOptions, Span, Token,
+};
+#[cfg(feature = "std")]
+pub use profile::Profile;
+#[cfg(feature = "std")]
+pub use stats::{StepStats, Stats};
@@ -0,0 +1,195 @@
+//! LLVM IR backend, lowering the steps directly instead of the x86-64 instructions,
+//! so that `opt` and `llc` can optimize the program further and compile it for any target
+
+use crate::compiler::{Label, Step};
+use crate::error::{CompileError, CompileResult};
+use crate::options::{CompileOptions, TapeMode};
+
+/// Block that exits with status 1 when the pointer moves left of the tape start
+const UNDERFLOW_BLOCK: &str = "underflow";
+
+/// LLVM IR function body, with every block ending in a terminator
+struct Function {
+    ir: String,
+    next_value: usize,
+    next_block: usize,
+}
+impl Function {
+    fn value(&mut self) -> String {
+        let result = format!("%t{}", self.next_value);
+        self.next_value += 1;
+        result
+    }
+
+    fn line(&mut self, line: &str) {
+        self.ir.push_str("  ");
+        self.ir.push_str(line);
+        self.ir.push('\n');
+    }
+
+    /// Ends the current block with the terminator, and starts the block `name`
+    fn terminate(&mut self, terminator: &str, name: &str) {
+        self.line(terminator);
+        self.ir.push_str(&format!("{}:\n", name));
+    }
+
+    /// Name for a block continuing after a conditional branch
+    fn next_block(&mut self) -> String {
+        let result = format!("block{}", self.next_block);
+        self.next_block += 1;
+        result
+    }
+
+    /// Pointer to the current cell
+    fn pointer(&mut self) -> String {
+        let pointer = self.value();
+        self.line(&format!("{} = load ptr, ptr %pointer", pointer));
+        pointer
+    }
+
+    /// Pointer to the current cell, and its value
+    fn load_cell(&mut self) -> (String, String) {
+        let pointer = self.pointer();
+        let value = self.value();
+        self.line(&format!("{} = load i8, ptr {}", value, pointer));
+        (pointer, value)
+    }
+
+    /// Moves the pointer by `offset` cells, which may be negative
+    fn move_pointer(&mut self, offset: &str) -> String {
+        let pointer = self.pointer();
+        let moved = self.value();
+        self.line(&format!(
+            "{} = getelementptr i8, ptr {}, i64 {}",
+            moved, pointer, offset
+        ));
+        self.line(&format!("store ptr {}, ptr %pointer", moved));
+        moved
+    }
+
+    fn step(&mut self, step: Step, options: &CompileOptions) -> CompileResult<()> {
+        match step {
+            Step::Next(n) => {
+                self.move_pointer(&n.to_string());
+            },
+            Step::Prev(n) => {
+                let moved = self.move_pointer(&format!("-{}", n));
+                let below = self.value();
+                self.line(&format!("{} = icmp ult ptr {}, @tape", below, moved));
+                let block = self.next_block();
+                self.terminate(
+                    &format!("br i1 {}, label %{}, label %{}", below, UNDERFLOW_BLOCK, block),
+                    &block,
+                );
+            },
+            Step::Add(n) => {
+                let (pointer, value) = self.load_cell();
+                let sum = self.value();
+                self.line(&format!("{} = add i8 {}, {}", sum, value, n));
+                self.line(&format!("store i8 {}, ptr {}", sum, pointer));
+            },
+            Step::Output => {
+                let (_, value) = self.load_cell();
+                let extended = self.value();
+                self.line(&format!("{} = zext i8 {} to i32", extended, value));
+                let result = self.value();
+                self.line(&format!("{} = call i32 @putchar(i32 {})", result, extended));
+            },
+            Step::Input => {
+                let pointer = self.pointer();
+                let read = self.value();
+                self.line(&format!("{} = call i32 @getchar()", read));
+                let eof = self.value();
+                self.line(&format!("{} = icmp eq i32 {}, -1", eof, read));
+                let byte = self.value();
+                self.line(&format!("{} = trunc i32 {} to i8", byte, read));
+                let fallback = match options.eof.value() {
+                    Some(value) => value.to_string(),
+                    None => {
+                        let old = self.value();
+                        self.line(&format!("{} = load i8, ptr {}", old, pointer));
+                        old
+                    },
+                };
+                let stored = self.value();
+                self.line(&format!(
+                    "{} = select i1 {}, i8 {}, i8 {}",
+                    stored, eof, fallback, byte
+                ));
+                self.line(&format!("store i8 {}, ptr {}", stored, pointer));
+            },
+            Step::JumpToIf(condition, label) => {
+                let (_, value) = self.load_cell();
+                let nonzero = self.value();
+                self.line(&format!("{} = icmp ne i8 {}, 0", nonzero, value));
+                let block = self.next_block();
+                let (if_nonzero, if_zero) = if condition {
+                    (block_name(label), block.clone())
+                } else {
+                    (block.clone(), block_name(label))
+                };
+                self.terminate(
+                    &format!("br i1 {}, label %{}, label %{}", nonzero, if_nonzero, if_zero),
+                    &block,
+                );
+            },
+            // Code after the jump is only reached through a label, so the block is left unreachable
+            Step::JumpTo(label) => {
+                let block = self.next_block();
+                self.terminate(&format!("br label %{}", block_name(label)), &block);
+            },
+            Step::Label(label) => {
+                let name = block_name(label);
+                self.terminate(&format!("br label %{}", name), &name);
+            },
+            Step::InlineAsm(_) => {
+                return Err(CompileError::InvalidOption(
+                    "inline_asm",
+                    "the assembly is x86-64, so it can't be used in LLVM IR",
+                ));
+            },
+        }
+        Ok(())
+    }
+}
+
+fn block_name(label: Label) -> String {
+    format!("label{}", label.0)
+}
+
+/// LLVM IR module of the steps, with `main` running the program using `getchar` and `putchar`
+pub(crate) fn module(steps: &[Step], options: &CompileOptions) -> CompileResult<String> {
+    if !options.wide_cells.is_empty() {
+        return Err(CompileError::InvalidOption(
+            "wide_cells",
+            "only the assembly backend supports them",
+        ));
+    }
+    let start = match options.tape {
+        TapeMode::Standard => 0,
+        TapeMode::Bidirectional => options.tape_size / 2,
+    };
+    let mut function = Function {
+        ir: String::new(),
+        next_value: 0,
+        next_block: 0,
+    };
+    for step in steps {
+        function.step(*step, options)?;
+    }
+
+    let tape = format!("[{} x i8]", options.tape_size);
+    let mut ir = String::new();
+    ir.push_str("; Generated by brain_opt, compile with e.g. `clang program.ll`\n");
+    ir.push_str(&format!("@tape = internal global {} zeroinitializer\n\n", tape));
+    ir.push_str("declare i32 @getchar()\ndeclare i32 @putchar(i32)\n\n");
+    ir.push_str("define i32 @main() {\nentry:\n");
+    ir.push_str("  %pointer = alloca ptr\n");
+    ir.push_str(&format!(
+        "  store ptr getelementptr inbounds ({}, ptr @tape, i64 0, i64 {}), ptr %pointer\n",
+        tape, start
+    ));
+    ir.push_str(&function.ir);
+    ir.push_str(&format!("  ret i32 0\n{}:\n  ret i32 1\n}}\n", UNDERFLOW_BLOCK));
+    Ok(ir)
+}
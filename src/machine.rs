@@ -0,0 +1,156 @@
+//! Execution core shared by the interpreters and compile-time evaluation,
+//! so that all of them agree on the semantics of each operation
+
+use std::ops::Index;
+
+use crate::compiler::Step;
+use crate::interpreter::{PointerUnderflow, IO};
+use crate::options::EofPolicy;
+use crate::parser::Token;
+
+/// Operation on the tape, both `Token`s and `Step`s lower to these
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Op {
+    /// Move the pointer right
+    Right(u64),
+    /// Move the pointer left, failing if it would move left of the first cell
+    Left(u64),
+    /// Add to the current cell, wrapping around
+    Add(u8),
+    /// Write the current cell
+    Output,
+    /// Read into the current cell, applying the EOF policy on end of input
+    Input,
+}
+impl Op {
+    /// Tape operation of a token, `None` for brackets and inline assembly
+    #[inline]
+    pub fn from_token(token: Token) -> Option<Self> {
+        Some(match token {
+            Token::Next => Self::Right(1),
+            Token::Prev => Self::Left(1),
+            Token::Increment => Self::Add(1),
+            Token::Decrement => Self::Add(255),
+            Token::Output => Self::Output,
+            Token::Input => Self::Input,
+            Token::JumpForwards | Token::JumpBackwards | Token::InlineAsm(_) => return None,
+        })
+    }
+
+    /// Tape operation of a step, `None` for jumps, labels and inline assembly
+    #[inline]
+    pub fn from_step(step: Step) -> Option<Self> {
+        Some(match step {
+            Step::Next(n) => Self::Right(n),
+            Step::Prev(n) => Self::Left(n),
+            Step::Add(n) => Self::Add(n),
+            Step::Output => Self::Output,
+            Step::Input => Self::Input,
+            Step::JumpTo(_) | Step::JumpToIf(_, _) | Step::Label(_) | Step::InlineAsm(_) => return None,
+        })
+    }
+}
+
+/// Tape and pointer, executing operations on them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Machine {
+    pub tape: Tape,
+    pub pointer: usize,
+    /// Value stored by `Input` on end of input
+    pub eof: EofPolicy,
+}
+impl Machine {
+    pub fn new(eof: EofPolicy) -> Self {
+        Self {
+            tape: Tape::new(),
+            pointer: 0,
+            eof,
+        }
+    }
+
+    /// Value of the cell under the pointer
+    #[inline]
+    pub fn current(&self) -> u8 {
+        self.tape[self.pointer]
+    }
+
+    /// Executes an operation. On error nothing is changed.
+    // Always inlined, as the interpreter loops spend most of their time here
+    #[allow(clippy::inline_always)]
+    #[inline(always)]
+    pub fn apply(&mut self, op: Op, io: &mut dyn IO) -> Result<(), PointerUnderflow> {
+        match op {
+            Op::Right(n) => self.pointer = self.pointer.checked_add(n as usize).unwrap(),
+            Op::Left(n) => self.pointer = self.pointer.checked_sub(n as usize).ok_or(PointerUnderflow)?,
+            Op::Add(n) => self.tape.add(self.pointer, n),
+            Op::Output => io.write(self.current()),
+            Op::Input => {
+                if let Some(value) = io.read().or_else(|| self.eof.value()) {
+                    self.tape.set(self.pointer, value);
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Cells of the tape, growing to the right when written
+#[derive(Debug, Clone)]
+pub(crate) struct Tape(pub Vec<u8>);
+impl Tape {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    #[inline]
+    fn cell(&mut self, index: usize) -> &mut u8 {
+        if index >= self.0.len() {
+            self.grow(index);
+        }
+        &mut self.0[index]
+    }
+
+    #[cold]
+    fn grow(&mut self, index: usize) {
+        self.0.resize(index + 1, 0);
+    }
+
+    #[inline]
+    pub fn add(&mut self, index: usize, add: u8) {
+        let cell = self.cell(index);
+        *cell = cell.wrapping_add(add);
+    }
+
+    #[inline]
+    pub fn set(&mut self, index: usize, value: u8) {
+        *self.cell(index) = value;
+    }
+
+    pub fn trim(&mut self) {
+        let mut len = self.0.len();
+        while len > 0 && self.0[len - 1] == 0 {
+            self.0.pop();
+            len -= 1;
+        }
+    }
+}
+impl Index<usize> for Tape {
+    type Output = u8;
+
+    #[inline]
+    fn index(&self, i: usize) -> &Self::Output {
+        self.0.get(i).unwrap_or(&0)
+    }
+}
+
+impl PartialEq for Tape {
+    fn eq(&self, other: &Self) -> bool {
+        for i in 0..(self.0.len().max(other.0.len())) {
+            if self.0.get(i).unwrap_or(&0) != other.0.get(i).unwrap_or(&0) {
+                return false;
+            }
+        }
+        true
+    }
+}
+impl Eq for Tape {}
@@ -1,6 +1,11 @@
+use std::env;
 use std::fs;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{self, Command, Stdio};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use env_logger::Env;
 use log::*;
@@ -8,15 +13,32 @@ use structopt::StructOpt;
 
 use tempfile::tempdir;
 
-use brain_opt::error::{Error, Result};
-use brain_opt::ABI;
-use brain_opt::{compile_tokens, parse};
+use brain_opt::build_script::{assemble, function_name};
+use brain_opt::error::{Argument, CompileError, Error, Result};
+use brain_opt::interpreter::{Interpreter, PointerUnderflow, StdIO, WideInterpreter};
+use brain_opt::minimize::minimize;
+use brain_opt::report::{Report, ReportFormat, Timings};
+use brain_opt::target_abi::{Assembler, LinkerInfo};
+use brain_opt::{
+    annotate, compile_function, compile_programs, compile_shared_function, compile_tokens,
+    compile_tokens_with_report, compile_tokens_with_stats, describe_passes, evaluated_tape,
+};
+use brain_opt::{
+    check_pass_cleanup, check_pass_order, explain_pass, loop_report, neighbour_pass_orders, parse_wide_cells,
+    pass_order, precompute_output, source_checksum, to_brainfuck, to_dot, to_llvm_ir, try_parse_with,
+};
+use brain_opt::{
+    CompileOptions, CpuFeature, EofPolicy, LinkerDriver, OptGoal, ParseOptions, Profile, Span, Stats,
+    TapeMode, Token, WideOutput, ABI,
+};
 
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 struct Args {
-    #[structopt(parse(from_os_str))]
-    source: PathBuf,
+    /// Source files, several are compiled into one binary
+    /// that runs the one named by its first argument, e.g. `hello` for `hello.bf`
+    #[structopt(parse(from_os_str), raw(required_unless = r#""explain""#))]
+    sources: Vec<PathBuf>,
 
     #[structopt(short, long, parse(from_os_str))]
     output: Option<PathBuf>,
@@ -29,9 +51,251 @@ struct Args {
     #[structopt(short, long)]
     skip_compilation: bool,
 
+    /// Print the disassembly of the assembled object file, to check how the instructions were encoded
+    #[structopt(long, raw(conflicts_with_all = r#"&["skip_compilation", "archive", "shared"]"#))]
+    disasm: bool,
+
+    /// Evaluate programs that take no input at compile time,
+    /// and write their output instead of an executable
+    #[structopt(long)]
+    precompute_output: bool,
+
+    /// Write the optimized program as Brainfuck source instead of an executable
+    #[structopt(long)]
+    emit_bf: bool,
+
+    /// Write the optimized program as LLVM IR instead of an executable, e.g. for `opt` and `llc`
+    #[structopt(long)]
+    emit_llvm: bool,
+
+    /// Save the control-flow graph of the optimized program in Graphviz DOT format,
+    /// give `-` to print it to stdout instead of an executable
+    #[structopt(long, parse(from_os_str))]
+    emit_dot: Option<PathBuf>,
+
+    /// Write the source with a comment before each loop the optimizer changed, saying what it did
+    #[structopt(long)]
+    annotate: bool,
+
+    /// Compile each source into a function `int name(void)` named by its file name without extension,
+    /// and bundle the objects into an archive with `ar`
+    #[structopt(long)]
+    archive: bool,
+
+    /// Compile each source into a function `int name(void)` like --archive,
+    /// and link the objects into a shared library exporting the functions
+    #[structopt(long, conflicts_with = "archive")]
+    shared: bool,
+
+    /// Print token and step counts, instruction count and estimated cost of the optimized program to stderr
+    #[structopt(long)]
+    stats: bool,
+
+    /// Print a report of the compilation to stdout: target, toolchain, instruction counts,
+    /// what each optimizer pass did, warnings, data sizes and the output path
+    #[structopt(long, raw(possible_values = "&ReportFormat::variants()"))]
+    report: Option<ReportFormat>,
+
+    /// Print the report of --report json with the source path and the time each phase took,
+    /// logging only errors like --quiet so that tools can read stdout and stderr
+    #[structopt(long, conflicts_with = "report")]
+    json: bool,
+
+    /// Print the loop nesting structure with source positions and known iteration counts
+    #[structopt(long)]
+    loops: bool,
+
+    /// Print the tape computed by compile-time evaluation to stderr
+    #[structopt(long)]
+    analyze_tape: bool,
+
+    /// Store the contents of a file in the binary, and read `,` from it instead of stdin
+    #[structopt(long, parse(from_os_str))]
+    embed_input: Option<PathBuf>,
+
+    /// Store a checksum of the source in the binary as the data `source_checksum`, and print it
+    #[structopt(long)]
+    embed_checksum: bool,
+
+    /// Run the program in the interpreter with stdin as input,
+    /// and write loop counts to the given file instead of compiling
+    #[structopt(long, parse(from_os_str))]
+    profile: Option<PathBuf>,
+
+    /// Run the program with stdin as input, and write the smallest part of it
+    /// whose compiled output differs from the interpreted one instead of compiling
+    #[structopt(long)]
+    minimize: bool,
+
+    /// Run the program with stdin as input, and search for the order of the optimizer passes giving
+    /// the fewest instructions without changing the output. Writes it as a `pass-order` config line.
+    #[structopt(long)]
+    tune_passes: bool,
+
+    /// Run the program in the interpreter with stdin as input,
+    /// and print the final tape to stderr instead of compiling
+    #[structopt(long)]
+    dump_tape: bool,
+
+    /// Run the program in the interpreter with stdin as input instead of compiling,
+    /// exiting with the status of a compiled binary. Needs no target, so it works on any host.
+    #[structopt(long)]
+    interpret: bool,
+
+    /// Use loop counts written by `--profile` to guide optimization
+    #[structopt(long, parse(from_os_str))]
+    pgo: Option<PathBuf>,
+
     /// Specify target ABI to use. Defaults to current OS ABI.
-    #[structopt(short, long, raw(possible_values = "&ABI::variants()"))]
-    target: Option<ABI>,
+    /// `all` compiles for every target and assembles each, running the host ones with stdin as input
+    #[structopt(short, long)]
+    target: Option<Target>,
+
+    /// Read options from a TOML file, flags given on the command line take precedence
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Optimization level, 0-2
+    #[structopt(short = "O", long)]
+    opt_level: Option<u8>,
+
+    /// Number of cells on the tape
+    #[structopt(long)]
+    tape_size: Option<usize>,
+
+    /// Where the pointer starts: `bidirectional` starts at the middle of the tape
+    #[structopt(long, raw(possible_values = "&TapeMode::variants()"))]
+    tape: Option<TapeMode>,
+
+    /// Value stored by `,` on end of input
+    #[structopt(long, raw(possible_values = "&EofPolicy::variants()"))]
+    eof: Option<EofPolicy>,
+
+    /// Vector instructions used to zero the tape and to store long runs of cells
+    #[structopt(long, raw(possible_values = "&CpuFeature::variants()"))]
+    target_cpu: Option<CpuFeature>,
+
+    /// Whether optimizations favor code size or speed, or balance the two, e.g. when unrolling loops
+    #[structopt(long, raw(possible_values = "&OptGoal::variants()"))]
+    opt_for: Option<OptGoal>,
+
+    /// Maximum number of instructions a loop can be unrolled into, 0 disables unrolling
+    #[structopt(long)]
+    max_unroll: Option<usize>,
+
+    /// Keep loops that run at most once as loops, instead of skipping the body with a single check
+    #[structopt(long)]
+    no_conditional_loops: bool,
+
+    /// Maximum number of steps evaluated at compile time
+    #[structopt(long)]
+    eval_budget: Option<usize>,
+
+    /// Warn about loops nested deeper than this, or fail with --deny-warnings
+    #[structopt(long)]
+    max_loop_depth: Option<usize>,
+
+    /// Number of sources compiled at the same time when given several, each on a thread
+    #[structopt(long)]
+    threads: Option<usize>,
+
+    /// Evaluate `,` at compile time as end of input, for programs that are run without input
+    #[structopt(long)]
+    assume_no_input: bool,
+
+    /// Address of the tape with `--target baremetal`, e.g. `0x200000`
+    #[structopt(long, parse(try_from_str = "parse_address"))]
+    tape_address: Option<u64>,
+
+    /// Memory-mapped register written by `.` with `--target baremetal`
+    #[structopt(long, parse(try_from_str = "parse_address"))]
+    output_address: Option<u64>,
+
+    /// Memory-mapped register read by `,` with `--target baremetal`, otherwise input is always empty
+    #[structopt(long, parse(try_from_str = "parse_address"))]
+    input_address: Option<u64>,
+
+    /// Function called with the exit code with `--target baremetal`, otherwise the CPU is halted
+    #[structopt(long)]
+    exit_symbol: Option<String>,
+
+    /// IO port of the serial port used by `--target freestanding`, e.g. `0x3f8`
+    #[structopt(long, parse(try_from_str = "parse_port"))]
+    serial_port: Option<u16>,
+
+    /// Extra argument passed to the linker, can be repeated
+    #[structopt(long = "link-arg", number_of_values = 1)]
+    link_args: Vec<String>,
+
+    /// Additional library to link against, as accepted by `-l`, e.g. `:libc.so.6`
+    #[structopt(long = "lib", number_of_values = 1)]
+    libs: Vec<String>,
+
+    /// Link with `clang`, `gcc` or `ld` itself, given the arguments each needs for the target
+    #[structopt(long, raw(possible_values = "&LinkerDriver::variants()"))]
+    linker_driver: Option<LinkerDriver>,
+
+    /// Define a `<name>_len` symbol after each data blob, and write constant strings using it
+    #[structopt(long)]
+    data_length_symbols: bool,
+
+    /// Splice the assembly in `{! ... !}` regions of the source into the compiled program.
+    /// It must keep the pointer register, `$pointer`, and is not checked in any way.
+    #[structopt(long)]
+    allow_inline_asm: bool,
+
+    /// Characters that are an error in the source instead of a comment, e.g. `--reserved '#!'`
+    #[structopt(long, default_value = "")]
+    reserved: String,
+
+    /// Give the cells in `@cells FIRST LAST u16` or `u32` comment lines of the source a wider type.
+    /// The pointer must then be at the same cell whenever a loop starts or ends.
+    #[structopt(long)]
+    wide_cells: bool,
+
+    /// Bytes of a wide cell written by `.`: `low` writes the lowest byte, `full` all of them
+    #[structopt(long, raw(possible_values = "&WideOutput::variants()"))]
+    wide_output: Option<WideOutput>,
+
+    /// Log a diff of the instructions changed by each optimizer pass
+    #[structopt(long)]
+    trace_passes: bool,
+
+    /// Write NASM `%line` directives before each loop in the assembly,
+    /// so that errors of the assembler are reported at the line of the source
+    #[structopt(long)]
+    line_directives: bool,
+
+    /// Write a comment with the source commands before their instructions in the assembly,
+    /// e.g. `; +++++` before `add byte [rbx], 5`. Requires `-O1` or `-O0`, and skips the optimizer passes.
+    #[structopt(long)]
+    source_comments: bool,
+
+    /// Map the tape between inaccessible pages, so that moving right of the last cell crashes
+    /// with a segmentation fault instead of overwriting the stack. A debugging aid for Linux and macOS.
+    #[structopt(long)]
+    guard_pages: bool,
+
+    /// Start the assembly with a comment giving the commands that assemble and link it,
+    /// so that the file can be built without this tool
+    #[structopt(long)]
+    build_comment: bool,
+
+    /// Fail if compiling gives any warnings, e.g. about loops that are never reached or never exit
+    #[structopt(long)]
+    deny_warnings: bool,
+
+    /// Skip the named optimizer pass, can be repeated
+    #[structopt(long = "disable-pass", number_of_values = 1)]
+    disabled_passes: Vec<String>,
+
+    /// Comma-separated names of optimizer passes to run first, in this order
+    #[structopt(long, raw(use_delimiter = "true"))]
+    pass_order: Vec<String>,
+
+    /// Describe the named optimizer pass, and show how it alone changes the given sources
+    #[structopt(long)]
+    explain: Option<String>,
 
     /// Verbose mode (-v, -vv, -vvv)
     #[structopt(short, long, group = "verbosity", parse(from_occurrences))]
@@ -44,28 +308,1050 @@ struct Args {
 impl Args {
     pub fn verbosity_name(&self) -> &'static str {
         match self.verbose {
-            0 if self.quiet => "error",
+            0 if self.quiet || self.json => "error",
             0 => "warn",
             1 => "info",
             2 => "debug",
             _ => "trace",
         }
     }
+
+    /// Format of the report to print, if any
+    pub fn report_format(&self) -> Option<ReportFormat> {
+        self.report.or_else(|| Some(ReportFormat::Json).filter(|_| self.json))
+    }
+
+    /// Overrides options with the ones given on the command line
+    pub fn apply_to(&self, mut options: CompileOptions) -> CompileOptions {
+        if let Some(opt_level) = self.opt_level {
+            options.opt_level = opt_level;
+        }
+        if let Some(tape_size) = self.tape_size {
+            options.tape_size = tape_size;
+        }
+        if let Some(tape) = self.tape {
+            options.tape = tape;
+        }
+        if let Some(eof) = self.eof {
+            options.eof = eof;
+        }
+        if let Some(cpu) = self.target_cpu {
+            options.target_cpu = Some(cpu);
+        }
+        if let Some(opt_for) = self.opt_for {
+            options.opt_for = opt_for;
+        }
+        if let Some(wide_output) = self.wide_output {
+            options.wide_output = wide_output;
+        }
+        if let Some(linker_driver) = self.linker_driver {
+            options.linker_driver = Some(linker_driver);
+        }
+        if let Some(max_unroll) = self.max_unroll {
+            options.max_unroll = max_unroll;
+        }
+        if let Some(eval_budget) = self.eval_budget {
+            options.eval_budget = eval_budget;
+        }
+        if let Some(max_loop_depth) = self.max_loop_depth {
+            options.max_loop_depth = max_loop_depth;
+        }
+        if let Some(threads) = self.threads {
+            options.threads = threads;
+        }
+        if self.assume_no_input {
+            options.assume_no_input = true;
+        }
+        if let Some(address) = self.tape_address {
+            options.bare_metal.tape_address = address;
+        }
+        if let Some(address) = self.output_address {
+            options.bare_metal.output_address = address;
+        }
+        if let Some(address) = self.input_address {
+            options.bare_metal.input_address = Some(address);
+        }
+        if let Some(symbol) = &self.exit_symbol {
+            options.bare_metal.exit_symbol = Some(symbol.clone());
+        }
+        if let Some(port) = self.serial_port {
+            options.bare_metal.serial_port = port;
+        }
+        if self.no_conditional_loops {
+            options.conditional_loops = false;
+        }
+        if self.data_length_symbols {
+            options.data_length_symbols = true;
+        }
+        if self.trace_passes {
+            options.trace_passes = true;
+        }
+        if self.source_comments {
+            options.source_comments = true;
+        }
+        if self.guard_pages {
+            options.guard_pages = true;
+        }
+        if self.deny_warnings {
+            options.deny_warnings = true;
+        }
+        if self.build_comment {
+            options.build_comment = Some(match &self.assembly {
+                Some(path) if path != Path::new("-") => path.display().to_string(),
+                _ => "program.asm".to_owned(),
+            });
+        }
+        options
+            .disabled_passes
+            .extend(self.disabled_passes.iter().cloned());
+        if !self.pass_order.is_empty() {
+            options.pass_order = self.pass_order.clone();
+        }
+        options
+    }
+}
+
+/// Address given in decimal, or in hexadecimal with a `0x` prefix
+fn parse_address(s: &str) -> std::result::Result<u64, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Port given in decimal, or in hexadecimal with a `0x` prefix
+fn parse_port(s: &str) -> std::result::Result<u16, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Target given with `--target`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    One(ABI),
+    All,
+}
+impl Target {
+    fn abi(self) -> Option<ABI> {
+        match self {
+            Self::One(abi) => Some(abi),
+            Self::All => None,
+        }
+    }
+}
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(Self::All);
+        }
+        s.parse()
+            .map(Self::One)
+            .map_err(|_| format!("expected all or one of {}", ABI::variants().join(", ")))
+    }
+}
+
+/// Contents of the `--config` file
+#[derive(Debug, Default)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(default, rename_all = "kebab-case")
+)]
+struct Config {
+    target: Option<ABI>,
+    /// Same as `--wide-cells`, the cell types are still declared by the source
+    wide_cells: bool,
+    #[cfg_attr(feature = "config", serde(flatten))]
+    options: CompileOptions,
+}
+impl Config {
+    #[cfg(feature = "config")]
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    #[cfg(not(feature = "config"))]
+    pub fn load(_path: &Path) -> Result<Self> {
+        use brain_opt::error::Argument;
+        Err(Error::Argument(Argument::FeatureDisabled("config")))
+    }
 }
 
 fn main() -> Result<()> {
-    let args = Args::from_args();
-    env_logger::from_env(Env::default().default_filter_or(args.verbosity_name())).init();
+    let mut args = Args::from_args();
+    let mut filter = args.verbosity_name().to_owned();
+    if args.trace_passes && args.verbose == 0 {
+        filter.push_str(",brain_opt::optimizer=info");
+    }
+    env_logger::from_env(Env::default().default_filter_or(filter)).init();
+
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    args.wide_cells |= config.wide_cells;
+    if args.wide_output.is_some() && !args.wide_cells {
+        return Err(Error::Argument(Argument::WideCellsRequired("wide-output")));
+    }
+    let mut options = args.apply_to(config.options);
+
+    let all_targets = args.target == Some(Target::All);
+    if all_targets && (args.archive || args.shared || args.sources.len() > 1) {
+        return Err(Error::Argument(Argument::SingleSourceRequired("target all")));
+    }
+    if args.embed_checksum && (args.archive || args.shared || args.sources.len() > 1) {
+        return Err(Error::Argument(Argument::SingleSourceRequired("embed-checksum")));
+    }
+    // The interpreter runs on any host, so these need no target
+    if args.interpret || args.profile.is_some() || args.dump_tape {
+        return interpret(&args, &options);
+    }
 
     let target_abi = args
         .target
+        .and_then(Target::abi)
+        .or(config.target)
         .or_else(ABI::pick_default)
         .ok_or(Error::UnknownTarget)?;
-    info!("Selected target ABI: {:?}", target_abi);
+    info!(
+        "Selected target ABI: {:?} ({}), {}",
+        target_abi,
+        target_abi.triple(),
+        target_abi.description()
+    );
+
+    let cleanup = options
+        .pass_cleanup
+        .iter()
+        .flat_map(|(pass, cleanup)| Some(pass).into_iter().chain(cleanup));
+    for pass in options.disabled_passes.iter().chain(&options.pass_order).chain(cleanup) {
+        check_pass_name(pass)?;
+    }
+    if let Err((first, second)) = check_pass_order(&options.pass_order) {
+        return Err(Error::Argument(Argument::PassOrder(first, second)));
+    }
+    if let Err(cycle) = check_pass_cleanup(&options.pass_cleanup) {
+        return Err(Error::Argument(Argument::CleanupCycle(cycle)));
+    }
+    if args.disasm && !in_path(disassembler(target_abi).0) {
+        return Err(Error::Argument(Argument::CommandNotFound(disassembler(target_abi).0)));
+    }
+    // Only the host target is linked
+    if let Some(driver) = options.linker_driver {
+        if target_abi.is_host() && !args.skip_compilation && !in_path(driver.command()) {
+            return Err(Error::Argument(Argument::CommandNotFound(driver.command())));
+        }
+    }
 
-    let source = fs::read(args.source)?;
-    let tokens = parse(&String::from_utf8_lossy(&source));
-    let (asm, link) = compile_tokens(tokens, target_abi);
+    if let Some(pass) = &args.explain {
+        return explain(&args, pass, target_abi, &options);
+    }
+
+    if args.archive {
+        return archive(&args, target_abi, &options);
+    }
+    if args.shared {
+        return shared_library(args, target_abi, &options);
+    }
+
+    if args.sources.len() > 1 {
+        let (asm, link) = compile_sources(&args, target_abi, &options)?;
+        return build(args, target_abi, asm, link, &mut Timings::default());
+    }
+
+    let source = fs::read(&args.sources[0])?;
+    let start = Instant::now();
+    let spanned = parse_source(&args, &source)?;
+    let parse_time = start.elapsed();
+    let tokens: Vec<_> = spanned.iter().map(|(token, _)| *token).collect();
+
+    if args.minimize {
+        return minimize_miscompilation(&args, target_abi, &options, &tokens);
+    }
+
+    if args.tune_passes {
+        return tune_passes(&args, target_abi, &options, &tokens);
+    }
+
+    if let Some(path) = &args.embed_input {
+        options.embedded_input = Some(fs::read(path)?);
+    }
+    if args.embed_checksum {
+        let checksum = source_checksum(&source);
+        eprintln!("Source checksum: {:016x}", checksum);
+        options.source_checksum = Some(checksum);
+    }
+
+    if args.wide_cells {
+        options.wide_cells = parse_wide_cells(&String::from_utf8_lossy(&source))?;
+    }
+    // These evaluate or write the program with every cell a byte
+    let byte_cell_flags = [
+        (args.precompute_output, "precompute-output"),
+        (args.analyze_tape, "analyze-tape"),
+        (args.emit_bf, "emit-bf"),
+        (args.loops, "loops"),
+    ];
+    if !options.wide_cells.is_empty() {
+        if let Some(&(_, flag)) = byte_cell_flags.iter().find(|(given, _)| *given) {
+            return Err(Error::Argument(Argument::ByteCellsRequired(flag)));
+        }
+    }
+
+    if let Some(path) = &args.pgo {
+        let profile = Profile::parse(&fs::read_to_string(path)?).map_err(Error::InvalidProfile)?;
+        options.profile = profile.loop_counts(&spanned);
+    }
+
+    // Changes the optimizer makes to loops are logged at their source position
+    options.loop_spans = spanned
+        .iter()
+        .filter(|(token, _)| *token == Token::JumpForwards)
+        .map(|(_, span)| *span)
+        .collect();
+    if args.line_directives {
+        options.loop_end_spans = loop_end_spans(&spanned);
+        options.line_directives = Some(args.sources[0].display().to_string());
+    }
+
+    if all_targets {
+        return check_targets(&args, &options, &tokens);
+    }
+
+    if args.loops {
+        for info in loop_report(&spanned, &options) {
+            print!("{}", info);
+        }
+    }
+
+    if args.analyze_tape {
+        let (tape, done) = evaluated_tape(tokens.clone(), &options);
+        if done {
+            eprintln!("Final tape, pointer at cell {}:", tape.pointer);
+        } else {
+            eprintln!("Tape where evaluation stopped, pointer at cell {}:", tape.pointer);
+        }
+        for (i, v) in tape.cells.iter().enumerate().filter(|(_, v)| **v != 0) {
+            eprintln!("cell {}: {}", i, v);
+        }
+    }
+
+    if args.precompute_output {
+        if let Some(output) = precompute_output(tokens.clone(), &options) {
+            match args.output {
+                Some(path) => fs::write(path, output)?,
+                None => io::stdout().write_all(&output)?,
+            }
+            return Ok(());
+        }
+        warn!(
+            "Program requires input or does not terminate within the evaluation budget, compiling normally"
+        );
+    }
+
+    let underflow = |index: usize| {
+        error!(
+            "Pointer moves left of the first cell at {}, use `--tape bidirectional` to allow this",
+            spanned[index].1
+        );
+        Error::Compile(CompileError::PointerUnderflow(index))
+    };
+
+    if args.emit_bf {
+        let bf = match to_brainfuck(tokens, &options) {
+            Err(CompileError::PointerUnderflow(index)) => return Err(underflow(index)),
+            result => result?,
+        };
+        match args.output {
+            Some(path) => fs::write(path, bf + "\n")?,
+            None => println!("{}", bf),
+        }
+        return Ok(());
+    }
+
+    if args.annotate {
+        let annotated = match annotate(&String::from_utf8_lossy(&source), &spanned, target_abi, &options) {
+            Err(CompileError::PointerUnderflow(index)) => return Err(underflow(index)),
+            result => result?,
+        };
+        match args.output {
+            Some(path) => fs::write(path, annotated)?,
+            None => print!("{}", annotated),
+        }
+        return Ok(());
+    }
+
+    if args.emit_llvm {
+        let ir = match to_llvm_ir(tokens, &options) {
+            Err(CompileError::PointerUnderflow(index)) => return Err(underflow(index)),
+            result => result?,
+        };
+        match args.output {
+            Some(path) => fs::write(path, ir)?,
+            None => print!("{}", ir),
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = &args.emit_dot {
+        let dot = match to_dot(tokens.clone(), target_abi, &options) {
+            Err(CompileError::PointerUnderflow(index)) => return Err(underflow(index)),
+            result => result?,
+        };
+        if path == Path::new("-") {
+            print!("{}", dot);
+            return Ok(());
+        } else {
+            fs::write(path, dot)?;
+        }
+    }
+
+    if args.report_format().is_some() && !cfg!(feature = "report") {
+        return Err(Error::Argument(Argument::FeatureDisabled("report")));
+    }
+    let start = Instant::now();
+    let compiled = if args.report_format().is_some() {
+        compile_tokens_with_report(tokens, target_abi, &options)
+            .map(|(asm, link, report)| (asm, link, report.after, Some(report)))
+    } else {
+        compile_tokens_with_stats(tokens, target_abi, &options)
+            .map(|(asm, link, stats)| (asm, link, stats, None))
+    };
+    let (asm, link, stats, report) = match compiled {
+        Err(CompileError::PointerUnderflow(index)) => return Err(underflow(index)),
+        result => result?,
+    };
+    let mut timings = Timings {
+        parse_us: micros(parse_time),
+        optimize_us: micros(start.elapsed()),
+        ..Timings::default()
+    };
+
+    if args.stats {
+        eprintln!("{}", stats);
+    }
+
+    let report = report.map(|mut report| {
+        report.source = Some(args.sources[0].display().to_string());
+        report.linker_args.extend(args.link_args.iter().cloned());
+        report.libraries.extend(args.libs.iter().cloned());
+        report.output = (args.output.as_ref())
+            .filter(|_| !args.skip_compilation)
+            .map(|path| path.display().to_string());
+        report
+    });
+    build(args, target_abi, asm, link, &mut timings)?;
+    if let Some(report) = report {
+        print_report(&Report { timings, ..report });
+    }
+    Ok(())
+}
+
+/// Duration in whole microseconds
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+#[cfg(feature = "report")]
+fn print_report(report: &Report) {
+    println!("{}", report.to_json());
+}
+
+#[cfg(not(feature = "report"))]
+fn print_report(_report: &Report) {}
+
+/// Tokens of a source file, with inline assembly if allowed
+fn parse_source(args: &Args, source: &[u8]) -> Result<Vec<(Token, Span)>> {
+    let source = String::from_utf8_lossy(source);
+    let options = ParseOptions {
+        inline_asm: args.allow_inline_asm,
+        reserved: args.reserved.chars().collect(),
+    };
+    Ok(try_parse_with(&source, &options)?)
+}
+
+/// Source positions of the `]` closing each loop, in the source order of `[`
+fn loop_end_spans(spanned: &[(Token, Span)]) -> Vec<Span> {
+    let mut ends: Vec<Option<Span>> = Vec::new();
+    let mut open = Vec::new();
+    for (token, span) in spanned {
+        match token {
+            Token::JumpForwards => {
+                open.push(ends.len());
+                ends.push(None);
+            },
+            // Brackets are balanced after parsing
+            Token::JumpBackwards => ends[open.pop().unwrap()] = Some(*span),
+            _ => {},
+        }
+    }
+    ends.into_iter().flatten().collect()
+}
+
+/// Fails if no optimizer pass has the given name, listing the passes
+fn check_pass_name(pass: &str) -> Result<()> {
+    let names: Vec<&str> = describe_passes().into_iter().map(|(name, _, _)| name).collect();
+    if names.contains(&pass) {
+        return Ok(());
+    }
+    error!(
+        "No optimizer pass named {}, the passes are: {}",
+        pass,
+        names.join(", ")
+    );
+    Err(Error::Argument(Argument::UnknownPass(pass.to_owned())))
+}
+
+/// Prints what the pass does, and the changes it makes to each source when run alone
+fn explain(args: &Args, pass: &str, target_abi: ABI, options: &CompileOptions) -> Result<()> {
+    check_pass_name(pass)?;
+    let (name, description, cleanup) = describe_passes()
+        .into_iter()
+        .find(|(name, _, _)| *name == pass)
+        .unwrap();
+    println!("{}: {}", name, description);
+    if !cleanup.is_empty() {
+        println!("Followed by: {}", cleanup.join(", "));
+    }
+
+    for path in &args.sources {
+        let source = fs::read(path)?;
+        let tokens = parse_source(args, &source)?;
+        let diff = explain_pass(
+            tokens.into_iter().map(|(token, _)| token).collect(),
+            pass,
+            target_abi,
+            options,
+        );
+        println!("\n{}:", path.display());
+        match diff {
+            Some(diff) if !diff.is_empty() => println!("{}", diff),
+            _ => println!("No changes"),
+        }
+    }
+    Ok(())
+}
+
+/// Runs the single source in the interpreter with stdin as input, for `--interpret`, `--profile`
+/// or `--dump-tape`
+fn interpret(args: &Args, options: &CompileOptions) -> Result<()> {
+    let flag = if args.profile.is_some() {
+        "profile"
+    } else if args.dump_tape {
+        "dump-tape"
+    } else {
+        "interpret"
+    };
+    if args.sources.len() > 1 {
+        return Err(Error::Argument(Argument::SingleSourceRequired(flag)));
+    }
+    let defaults = CompileOptions::default();
+    if options.tape != defaults.tape || options.tape_size != defaults.tape_size {
+        return Err(Error::Argument(Argument::StandardTapeRequired(flag)));
+    }
+    let source = fs::read(&args.sources[0])?;
+    let spanned = parse_source(args, &source)?;
+    let tokens: Vec<_> = spanned.iter().map(|(token, _)| *token).collect();
+    if args.wide_cells {
+        let mut options = options.clone();
+        options.wide_cells = parse_wide_cells(&String::from_utf8_lossy(&source))?;
+        if !options.wide_cells.is_empty() {
+            // The profile and the tape dump show cells of a byte
+            if args.profile.is_some() {
+                return Err(Error::Argument(Argument::ByteCellsRequired("profile")));
+            }
+            if args.dump_tape {
+                return Err(Error::Argument(Argument::ByteCellsRequired("dump-tape")));
+            }
+            let mut io = StdIO::new();
+            let result = WideInterpreter::new(&options).run(&tokens, &mut io);
+            return exit_like_binary(result, io);
+        }
+    }
+
+    if let Some(path) = &args.profile {
+        let mut io = StdIO::new();
+        let (profile, result) = Profile::record(&spanned, &mut io);
+        io.finish()?;
+        if result.is_err() {
+            warn!("Pointer moved left of the first cell, the profile only covers the run until then");
+        }
+        fs::write(path, profile.to_string())?;
+        return Ok(());
+    }
+
+    if args.dump_tape {
+        let mut io = StdIO::new();
+        let mut interpreter = Interpreter::with_eof(options.eof);
+        let result = interpreter.run(&tokens, &mut io);
+        io.finish()?;
+        if result.is_err() {
+            warn!("Pointer moved left of the first cell, the tape is shown as it was then");
+        }
+        eprintln!("{}", interpreter.snapshot());
+        return Ok(());
+    }
+
+    let mut io = StdIO::new();
+    let result = Interpreter::with_eof(options.eof).run(&tokens, &mut io);
+    exit_like_binary(result, io)
+}
+
+/// Flushes the output of an interpreted run, exiting with the status of a compiled binary
+fn exit_like_binary(result: std::result::Result<(), PointerUnderflow>, io: StdIO) -> Result<()> {
+    io.finish()?;
+    if result.is_err() {
+        process::exit(PointerUnderflow::EXIT_STATUS);
+    }
+    Ok(())
+}
+
+/// Compiles several sources into one binary, each named by its file name without extension
+fn compile_sources(args: &Args, target_abi: ABI, options: &CompileOptions) -> Result<(String, LinkerInfo)> {
+    let single_source_flags = [
+        (args.precompute_output, "precompute-output"),
+        (args.emit_bf, "emit-bf"),
+        (args.emit_llvm, "emit-llvm"),
+        (args.emit_dot.is_some(), "emit-dot"),
+        (args.annotate, "annotate"),
+        (args.stats, "stats"),
+        (args.report.is_some(), "report"),
+        (args.json, "json"),
+        (args.loops, "loops"),
+        (args.analyze_tape, "analyze-tape"),
+        (args.embed_input.is_some(), "embed-input"),
+        (args.pgo.is_some(), "pgo"),
+        (args.minimize, "minimize"),
+        (args.tune_passes, "tune-passes"),
+        (args.wide_cells, "wide-cells"),
+        (args.line_directives, "line-directives"),
+    ];
+    for &(given, flag) in &single_source_flags {
+        if given {
+            return Err(Error::Argument(Argument::SingleSourceRequired(flag)));
+        }
+    }
+
+    let mut spanned: Vec<(String, Vec<(Token, Span)>)> = Vec::new();
+    for path in &args.sources {
+        let name = path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        if spanned.iter().any(|(n, _)| *n == name) {
+            warn!(
+                "Program name {} is used by several sources, only the first one can be run",
+                name
+            );
+        }
+        let source = fs::read(path)?;
+        spanned.push((name, parse_source(args, &source)?));
+    }
+    let programs: Vec<_> = spanned
+        .iter()
+        .map(|(name, tokens)| (name.clone(), tokens.iter().map(|(token, _)| *token).collect()))
+        .collect();
+
+    let result = compile_programs(&programs, target_abi, options);
+    if let Err(CompileError::InProgram(name, error)) = &result {
+        if let CompileError::PointerUnderflow(index) = **error {
+            let (_, tokens) = spanned.iter().find(|(n, _)| n == name).unwrap();
+            error!(
+                "Pointer moves left of the first cell in {} at {}, use `--tape bidirectional` to allow this",
+                name, tokens[index].1
+            );
+        }
+    }
+    Ok(result?)
+}
+
+/// Compiles each source into a function in an object of its own, and bundles them into an archive
+fn archive(args: &Args, target_abi: ABI, options: &CompileOptions) -> Result<()> {
+    let dir = tempdir()?;
+    let objects: Vec<PathBuf> = function_objects(args, target_abi, options, dir.path(), false)?
+        .into_iter()
+        .map(|(object, _)| object)
+        .collect();
+
+    let output_path = args.output.clone().unwrap_or_else(|| {
+        warn!("No output file specified, discarding archive");
+        dir.path().join("output.a")
+    });
+    // Members are added to an existing archive
+    if output_path.exists() {
+        fs::remove_file(&output_path)?;
+    }
+    let status = Command::new("ar")
+        .arg("rcs")
+        .arg(&output_path)
+        .args(&objects)
+        .status()
+        .expect("failed to execute ar");
+    if !status.success() {
+        return Err(Error::Archiver);
+    }
+    Ok(())
+}
+
+/// Compiles each source into a position-independent function in an object of its own,
+/// and links them into a shared library
+fn shared_library(args: Args, target_abi: ABI, options: &CompileOptions) -> Result<()> {
+    let dir = tempdir()?;
+    let compiled = function_objects(&args, target_abi, options, dir.path(), true)?;
+    let objects: Vec<&Path> = compiled.iter().map(|(object, _)| object.as_path()).collect();
+    // The sources are required, and all have the linker info of the target
+    let mut link = compiled[0].1.clone();
+    link.linker_args.extend(args.link_args);
+    link.libraries.extend(args.libs);
+    let output_path = args.output.unwrap_or_else(|| {
+        warn!("No output file specified, discarding shared library");
+        dir.path().join("output.so")
+    });
+    link_objects(link, &objects, &output_path)
+}
+
+/// Compiles each source into a function named by its file name, in an object of its own in `dir`,
+/// position-independent if `shared`. Returns the objects with the linker info of each.
+/// The sources are compiled and assembled on `options.threads` threads.
+fn function_objects(
+    args: &Args, target_abi: ABI, options: &CompileOptions, dir: &Path, shared: bool,
+) -> Result<Vec<(PathBuf, LinkerInfo)>> {
+    let mut units = Vec::new();
+    for path in &args.sources {
+        let name = function_name(path);
+        let object_dir = dir.join(&name);
+        if object_dir.exists() {
+            return Err(Error::Argument(Argument::DuplicateName(name)));
+        }
+        fs::create_dir(&object_dir)?;
+        units.push((path, name, object_dir));
+    }
+
+    let compile = if shared { compile_shared_function } else { compile_function };
+    let build_object =
+        |(path, name, object_dir): &(&PathBuf, String, PathBuf)| -> Result<(PathBuf, LinkerInfo)> {
+            let source = fs::read(path)?;
+            let tokens = parse_source(args, &source)?
+                .into_iter()
+                .map(|(token, _)| token)
+                .collect();
+            let (asm, link) = compile(tokens, name, target_abi, options)?;
+            let object = object_dir.join(format!("{}.o", name));
+            fs::rename(assemble(&asm, &link, object_dir)?, &object)?;
+            Ok((object, link))
+        };
+    let mut objects = Vec::new();
+    for ((path, _, _), object) in units.iter().zip(in_parallel(&units, options.threads, build_object)) {
+        match object {
+            Ok(object) => objects.push(object),
+            Err(error) => {
+                error!("Compiling {} failed", path.display());
+                return Err(error);
+            },
+        }
+    }
+    Ok(objects)
+}
+
+/// Results of `f` for each item, in order. The items are split into `threads` runs
+/// of consecutive ones, each handled on a thread.
+fn in_parallel<T: Sync, R: Send>(items: &[T], threads: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    if threads <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let run = items.len().div_ceil(threads);
+    thread::scope(|scope| {
+        let f = &f;
+        let handles: Vec<_> = items
+            .chunks(run)
+            .map(|items| scope.spawn(move || items.iter().map(f).collect::<Vec<R>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("Compiling a source panicked"))
+            .collect()
+    })
+}
+
+/// How long a compiled program may run when minimizing, before it is considered stuck
+const MINIMIZE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Writes the smallest program found whose compiled output for the input given in stdin
+/// differs from the output of the compile-time evaluation, which shares the interpreter's semantics.
+/// Reduced programs that don't finish within the evaluation budget, or whose compiled program doesn't
+/// finish in time, are not considered.
+fn minimize_miscompilation(
+    args: &Args, target_abi: ABI, options: &CompileOptions, tokens: &[Token],
+) -> Result<()> {
+    if !target_abi.is_host() {
+        return Err(Error::Argument(Argument::HostTargetRequired("minimize")));
+    }
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
+    let reference_options = CompileOptions {
+        embedded_input: Some(input.clone()),
+        ..options.clone()
+    };
+
+    let dir = tempdir()?;
+    let mut checks = 0;
+    let mut reproduces = |tokens: &[Token]| {
+        checks += 1;
+        let expected = match precompute_output(tokens.to_vec(), &reference_options) {
+            Some(output) => output,
+            None => return false,
+        };
+        let compiled = compile_tokens(tokens.to_vec(), target_abi, options)
+            .map_err(Error::from)
+            .and_then(|(asm, link)| build_and_run(args, &asm, link, dir.path(), &input));
+        match compiled {
+            Ok(output) => output != Some(expected),
+            Err(_) => false,
+        }
+    };
+
+    if !reproduces(tokens) {
+        warn!("The compiled program gives the expected output for this input, nothing to minimize");
+        return Ok(());
+    }
+    let minimal = minimize(tokens, &mut reproduces);
+    info!(
+        "Minimized {} tokens to {} with {} compilations",
+        tokens.len(),
+        minimal.len(),
+        checks
+    );
+
+    let source: String = minimal.iter().map(ToString::to_string).collect();
+    match &args.output {
+        Some(path) => fs::write(path, source + "\n")?,
+        None => println!("{}", source),
+    }
+    Ok(())
+}
+
+/// Order of the optimizer passes found to give the fewest instructions, then the lowest cost,
+/// while the compiled program gives the same output for the input in stdin as the compile-time evaluation.
+/// Moves one pass at a time as long as that improves the program, so the order is only a local optimum.
+fn tune_passes(args: &Args, target_abi: ABI, options: &CompileOptions, tokens: &[Token]) -> Result<()> {
+    if !target_abi.is_host() {
+        return Err(Error::Argument(Argument::HostTargetRequired("tune-passes")));
+    }
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
+    let reference_options = CompileOptions {
+        embedded_input: Some(input.clone()),
+        ..options.clone()
+    };
+    let expected = match precompute_output(tokens.to_vec(), &reference_options) {
+        Some(output) => output,
+        None => {
+            warn!("The program does not finish within the evaluation budget, nothing to compare with");
+            return Ok(());
+        },
+    };
+
+    let dir = tempdir()?;
+    let size = |stats: Stats| (stats.instructions, stats.cost);
+    let mut best_order = pass_order(options);
+    let (_, _, stats) = compile_tokens_with_stats(tokens.to_vec(), target_abi, options)?;
+    let mut best = size(stats);
+    let mut checks = 0;
+    loop {
+        let mut improved = false;
+        for order in neighbour_pass_orders(&best_order) {
+            let candidate_options = CompileOptions {
+                pass_order: order.clone(),
+                ..options.clone()
+            };
+            let (asm, link, stats) =
+                compile_tokens_with_stats(tokens.to_vec(), target_abi, &candidate_options)?;
+            if size(stats) >= best {
+                continue;
+            }
+            // Only orders that shrink the program are run, as building and running it is the slow part
+            checks += 1;
+            let output = match build_and_run(args, &asm, link, dir.path(), &input) {
+                Err(Error::Io(error)) if error.kind() == io::ErrorKind::TimedOut => None,
+                result => result?,
+            };
+            if output != Some(expected.clone()) {
+                warn!(
+                    "Pass order {} changes the output of the program, which is a miscompilation",
+                    order.join(",")
+                );
+                continue;
+            }
+            info!(
+                "Pass order {} gives {} instructions with cost {}",
+                order.join(","),
+                stats.instructions,
+                stats.cost
+            );
+            best_order = order;
+            best = size(stats);
+            improved = true;
+            break;
+        }
+        if !improved {
+            break;
+        }
+    }
+    info!("Ran {} compiled programs to find the order", checks);
+
+    let names: Vec<String> = best_order.iter().map(|name| format!("{:?}", name)).collect();
+    let line = format!("pass-order = [{}]\n", names.join(", "));
+    match &args.output {
+        Some(path) => fs::write(path, line)?,
+        None => print!("{}", line),
+    }
+    Ok(())
+}
+
+/// Compiles the program for every target and assembles it, unless compilation is skipped,
+/// then runs the ones for the host platform with the input in stdin,
+/// comparing their output with the compile-time evaluation.
+/// Targets whose assembler isn't installed are only compiled.
+fn check_targets(args: &Args, options: &CompileOptions, tokens: &[Token]) -> Result<()> {
+    let runs = !args.skip_compilation && ABI::all().iter().any(|abi| abi.is_host());
+    let mut input = Vec::new();
+    let mut expected = None;
+    if runs {
+        io::stdin().read_to_end(&mut input)?;
+        let reference_options = CompileOptions {
+            embedded_input: Some(input.clone()),
+            ..options.clone()
+        };
+        expected = precompute_output(tokens.to_vec(), &reference_options);
+        if expected.is_none() {
+            warn!("The program does not finish within the evaluation budget, its output isn't compared");
+        }
+    }
+
+    let dir = tempdir()?;
+    let mut failed = Vec::new();
+    for &abi in ABI::all() {
+        // Named by the variant, as bare metal and freestanding code share a triple
+        let target_dir = dir.path().join(format!("{:?}", abi));
+        fs::create_dir(&target_dir)?;
+        match check_target(args, abi, options, tokens, &target_dir, &input, expected.as_deref()) {
+            Ok(true) => info!("Target {} passed", abi.triple()),
+            Ok(false) => {
+                error!(
+                    "The program compiled for {} gives different output than the compile-time evaluation",
+                    abi.triple()
+                );
+                failed.push(abi.triple());
+            },
+            Err(error) => {
+                error!("Target {}: {}", abi.triple(), error);
+                failed.push(abi.triple());
+            },
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::FailedTargets(failed))
+    }
+}
+
+/// Compiles and assembles the program for the target in the directory,
+/// and if the expected output is given and the target is the host platform, links and runs it.
+/// Returns whether the output was the expected one, or wasn't checked.
+fn check_target(
+    args: &Args, abi: ABI, options: &CompileOptions, tokens: &[Token], dir: &Path, input: &[u8],
+    expected: Option<&[u8]>,
+) -> Result<bool> {
+    let (asm, link) = compile_tokens(tokens.to_vec(), abi, options)?;
+    if args.skip_compilation {
+        return Ok(true);
+    }
+    let assembler = match &link.assembler {
+        Assembler::Nasm => "nasm",
+        Assembler::Gnu(command) => command.as_str(),
+    };
+    if !in_path(assembler) {
+        warn!("{} was not found in PATH, not assembling for {}", assembler, abi.triple());
+        return Ok(true);
+    }
+    match expected {
+        Some(expected) if abi.is_host() => {
+            Ok(build_and_run(args, &asm, link, dir, input)?.as_deref() == Some(expected))
+        },
+        _ => {
+            assemble(&asm, &link, dir)?;
+            Ok(true)
+        },
+    }
+}
+
+/// Assembles and links the program in the directory, then runs it with the input like `run_with_timeout`
+fn build_and_run(
+    args: &Args, asm: &str, mut link: LinkerInfo, dir: &Path, input: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    link.linker_args.extend(args.link_args.iter().cloned());
+    link.libraries.extend(args.libs.iter().cloned());
+    let executable = dir.join("program");
+    let file_obj = assemble(asm, &link, dir)?;
+    link_objects(link, &[&file_obj], &executable)?;
+    run_with_timeout(&executable, input)
+}
+
+/// Runs the executable with the input, returning its output, or `None` if it fails.
+/// Fails with `TimedOut` if it does not finish in time.
+fn run_with_timeout(executable: &Path, input: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut child = Command::new(executable)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // Both pipes are served on their own threads, so that a full pipe doesn't block waiting for the exit
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    // The program may exit before reading all of it
+    thread::spawn(move || stdin.write_all(&input));
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() > MINIMIZE_TIMEOUT {
+            child.kill()?;
+            child.wait()?;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "the program did not finish in time").into());
+        }
+        thread::sleep(Duration::from_millis(1));
+    };
+    let output = reader
+        .join()
+        .expect("reading the output of the program panicked")?;
+    Ok(if status.success() { Some(output) } else { None })
+}
+
+/// Whether the command is a file in one of the directories of `PATH`
+fn in_path(command: &str) -> bool {
+    env::var_os("PATH").map_or(false, |paths| {
+        env::split_paths(&paths).any(|dir| dir.join(command).is_file())
+    })
+}
+
+/// Writes the assembly if requested, then assembles and links it, recording the time both took
+fn build(
+    args: Args, target_abi: ABI, asm: String, mut link: LinkerInfo, timings: &mut Timings,
+) -> Result<()> {
+    link.linker_args.extend(args.link_args);
+    link.libraries.extend(args.libs);
 
     if let Some(out_asm) = args.assembly {
         if out_asm == Path::new("-") {
@@ -79,40 +1365,78 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let dir = tempdir()?;
-    let file_asm = dir.path().join("input.asm");
-    let file_obj = dir.path().join("output.obj");
-
-    fs::write(file_asm.clone(), asm.as_bytes())?;
+    // The host linker can't link binaries for other platforms
+    let cross = !target_abi.is_host();
+    if cross {
+        warn!(
+            "Target {} is not the host platform, writing the {} object file without linking it",
+            target_abi.triple(),
+            link.object_format
+        );
+    }
 
-    let status = Command::new("nasm")
-        .arg("-f")
-        .arg(link.object_format)
-        .arg("-o")
-        .arg(file_obj.clone())
-        .arg(file_asm)
-        .status()
-        .expect("failed to execute nasm");
+    let dir = tempdir()?;
+    let start = Instant::now();
+    let file_obj = assemble(&asm, &link, dir.path())?;
+    timings.assemble_us = micros(start.elapsed());
+    if args.disasm {
+        disassemble(target_abi, &file_obj)?;
+    }
 
-    if !status.success() {
-        return Err(Error::Nasm);
+    if cross {
+        match args.output {
+            Some(path) => {
+                fs::copy(file_obj, path)?;
+            },
+            None => warn!("No output file specified, discarding object file"),
+        }
+        return Ok(());
     }
 
     let output_path = args.output.unwrap_or_else(|| {
         warn!("No output file specified, discarding executable");
         dir.path().join("output")
     });
+    let start = Instant::now();
+    link_objects(link, &[&file_obj], &output_path)?;
+    timings.link_us = micros(start.elapsed());
+    Ok(())
+}
+
+/// Disassembler of the object files of the target, with its arguments
+fn disassembler(target_abi: ABI) -> (&'static str, &'static [&'static str]) {
+    match target_abi {
+        ABI::MacOS => ("otool", &["-tV"][..]),
+        ABI::RiscV64 => ("objdump", &["-d"][..]),
+        _ => ("objdump", &["-d", "-M", "intel"][..]),
+    }
+}
+
+/// Prints the disassembly of the object file to stdout
+fn disassemble(target_abi: ABI, object: &Path) -> Result<()> {
+    let (command, disassembler_args) = disassembler(target_abi);
+    let status = Command::new(command)
+        .args(disassembler_args)
+        .arg(object)
+        .status()
+        .expect("failed to execute the disassembler");
+    if !status.success() {
+        return Err(Error::Disassembler);
+    }
+    Ok(())
+}
 
+/// Links the object files into an executable, or a shared library with the linker info of one
+fn link_objects(link: LinkerInfo, objects: &[&Path], output_path: &Path) -> Result<()> {
     let mut linker = Command::new(link.linker_cmd);
     for arg in link.linker_args {
         linker.arg(arg);
     }
-    linker
-        .arg("-o")
-        .arg(output_path)
-        .arg(file_obj)
-        .status()
-        .expect("failed to execute linker");
+    linker.arg("-o").arg(output_path).args(objects);
+    for library in link.libraries {
+        linker.arg(format!("-l{}", library));
+    }
+    let status = linker.status().expect("failed to execute linker");
 
     if !status.success() {
         return Err(Error::Linker);
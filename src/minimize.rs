@@ -0,0 +1,122 @@
+//! Delta debugging of programs, finding a small part of a program that still shows some behavior,
+//! e.g. a miscompilation
+
+use crate::parser::Token;
+
+/// Whether the brackets in the tokens are balanced
+fn balanced(tokens: &[Token]) -> bool {
+    let mut depth: usize = 0;
+    for token in tokens {
+        match token {
+            Token::JumpForwards => depth += 1,
+            Token::JumpBackwards if depth == 0 => return false,
+            Token::JumpBackwards => depth -= 1,
+            _ => {},
+        }
+    }
+    depth == 0
+}
+
+/// Index of the `]` closing the loop started at `start`
+fn loop_end(tokens: &[Token], start: usize) -> usize {
+    let mut depth: usize = 0;
+    for (index, token) in tokens.iter().enumerate().skip(start) {
+        match token {
+            Token::JumpForwards => depth += 1,
+            Token::JumpBackwards if depth == 1 => return index,
+            Token::JumpBackwards => depth -= 1,
+            _ => {},
+        }
+    }
+    unreachable!("Unbalanced brackets")
+}
+
+/// Smallest program found by removing parts of the given one while `reproduces` holds for it.
+/// Removes whole loops, then ranges of tokens halving in length down to single tokens,
+/// then the brackets of single loops, until none of these can be removed.
+/// The given program should reproduce the behavior.
+pub fn minimize<F: FnMut(&[Token]) -> bool>(tokens: &[Token], mut reproduces: F) -> Vec<Token> {
+    let mut current = tokens.to_vec();
+    let mut try_remove = |current: &mut Vec<Token>, positions: &[usize]| {
+        let candidate: Vec<Token> = current
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !positions.contains(index))
+            .map(|(_, token)| *token)
+            .collect();
+        if reproduces(&candidate) {
+            *current = candidate;
+            true
+        } else {
+            false
+        }
+    };
+
+    loop {
+        let length = current.len();
+
+        let mut start = 0;
+        while start < current.len() {
+            if current[start] == Token::JumpForwards {
+                let positions: Vec<usize> = (start..=loop_end(&current, start)).collect();
+                if try_remove(&mut current, &positions) {
+                    continue;
+                }
+            }
+            start += 1;
+        }
+
+        let mut size = current.len() / 2;
+        while size > 0 {
+            let mut start = 0;
+            while start < current.len() {
+                let end = (start + size).min(current.len());
+                if balanced(&current[start..end]) {
+                    let positions: Vec<usize> = (start..end).collect();
+                    if try_remove(&mut current, &positions) {
+                        continue;
+                    }
+                }
+                start += size;
+            }
+            size /= 2;
+        }
+
+        let mut start = 0;
+        while start < current.len() {
+            if current[start] == Token::JumpForwards {
+                let end = loop_end(&current, start);
+                if try_remove(&mut current, &[start, end]) {
+                    continue;
+                }
+            }
+            start += 1;
+        }
+
+        if current.len() == length {
+            return current;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::minimize;
+    use crate::compiler::precompute_output;
+    use crate::options::CompileOptions;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_minimize() {
+        let options = CompileOptions {
+            eval_budget: 10_000,
+            ..CompileOptions::default()
+        };
+        // First output is 2, with loops that never terminate when reduced
+        let tokens = parse("+>+<[-]++[>+<-]>[<+>-]<.>>+[-].");
+        let minimal = minimize(&tokens, |tokens| {
+            precompute_output(tokens.to_vec(), &options).and_then(|output| output.first().copied()) == Some(2)
+        });
+        assert_eq!(minimal, parse("++."));
+    }
+}
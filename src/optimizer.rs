@@ -1,85 +1,174 @@
 #![allow(clippy::needless_pass_by_value)]
 
-use std::collections::{HashMap, HashSet};
+use alloc::borrow::ToOwned;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
 
-use super::instruction::{Effects, Instruction, Register64};
+use super::cfg::Cfg;
+use super::diff;
+use super::error::{Warning, Warnings};
+use super::instruction::{source_names, Effects, Instruction, Register64};
+use super::label::LabelId;
+use super::options::{CompileOptions, CpuFeature, OptGoal};
+use super::parser::{Span, COMPILER_LABEL_PREFIX};
 
-/// Removes redundant movs
+/// Removes movs of values the register already holds, either a known immediate
+/// or the value of the register it was copied from, e.g. the pointer in `rsi`.
+/// Calls keep the values of the registers they preserve.
+/// The pointer copied to `rsi` for each IO call is loaded again after every call,
+/// as `rsi` is an argument register the call may overwrite, so it can't be loaded once for a block.
+/// After a label the values are those known on every jump to it and on the fall through to it.
 pub fn optimize_redundant_movs(ops: Vec<Instruction>) -> Vec<Instruction> {
     use Instruction::*;
-    let mut last_known: HashMap<Register64, u64> = HashMap::new();
-    let mut result = Vec::new();
-    for op in ops {
-        let mut include_this = true; // Will Set to false to remove item
-        if let MovImm(r, imm) = op {
-            if last_known.get(&r) == Some(&imm) {
-                include_this = false;
+    let cfg = Cfg::new(ops);
+    let entries = cfg.forward(
+        &KnownRegisters::default(),
+        |ops, start| {
+            let mut known = start.clone();
+            for op in ops {
+                known.update(op);
             }
-        } else if let Mov(r1, r2) = op {
-            if let Some(v) = last_known.get(&r1) {
-                if Some(v) == last_known.get(&r2) {
-                    include_this = false;
-                }
+            known
+        },
+        KnownRegisters::meet,
+    );
+    let mut result = Vec::new();
+    for (block, entry) in cfg.blocks.into_iter().zip(entries) {
+        let mut known = entry.unwrap_or_default();
+        for op in block.ops {
+            let redundant = match op {
+                MovImm(r, imm) => known.values.get(&r) == Some(&imm),
+                Mov(r1, r2) => {
+                    let same_value = known
+                        .values
+                        .get(&r1)
+                        .map_or(false, |v| known.values.get(&r2) == Some(v));
+                    same_value
+                        || r1 == r2
+                        || known.copies.get(&r1) == Some(&r2)
+                        || known.copies.get(&r2) == Some(&r1)
+                },
+                _ => false,
+            };
+            known.update(&op);
+            if !redundant {
+                result.push(op);
             }
         }
-        if include_this {
-            result.push(op.clone());
-        }
+    }
+    result
+}
 
-        // Update last_kwown table
-        match op {
-            BlackBox(_, _) | NamedBlackBox(_, _, _) => {
-                last_known.clear();
+/// Values known to be in the registers at one point of the instructions
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct KnownRegisters {
+    values: BTreeMap<Register64, u64>,
+    /// Registers holding the same value as the register they were copied from
+    copies: BTreeMap<Register64, Register64>,
+}
+impl KnownRegisters {
+    /// Values known after the instruction
+    fn update(&mut self, op: &Instruction) {
+        use Instruction::*;
+        match *op {
+            BlackBox(_, _) => {
+                self.values.clear();
+                self.copies.clear();
+            },
+            NamedBlackBox(_, _, _) => {
+                self.values.retain(|r, _| r.preserved_by_calls());
+                self.copies
+                    .retain(|r, source| r.preserved_by_calls() && source.preserved_by_calls());
             },
             Mov(r, r2) => {
-                if let Some(v) = last_known.clone().get(&r2) {
-                    last_known.insert(r, *v);
+                if let Some(v) = self.values.get(&r2).copied() {
+                    self.values.insert(r, v);
                 } else {
-                    last_known.remove(&r);
+                    self.values.remove(&r);
+                }
+                // A mov between registers already holding the same value changes neither
+                if r != r2 && self.copies.get(&r) != Some(&r2) && self.copies.get(&r2) != Some(&r) {
+                    forget_copies(&mut self.copies, r);
+                    self.copies.insert(r, r2);
                 }
             },
             MovImm(r, imm) => {
-                last_known.insert(r, imm);
+                self.values.insert(r, imm);
+                forget_copies(&mut self.copies, r);
             },
-            AddImm(r, _) | SubImm(r, _) => {
-                // before jump target labels.
-
-                last_known.remove(&r);
+            MovImmVar(r, _) | MovRegPtr8(r, _) | AddReg8Imm(r, _) | AddImm(r, _) | SubImm(r, _) => {
+                self.values.remove(&r);
+                forget_copies(&mut self.copies, r);
             },
-            Label(_) => {
-                last_known.clear();
+            AddPtr8Mul(_, _, _) => {
+                self.values.remove(&Register64::rax);
+                forget_copies(&mut self.copies, Register64::rax);
             },
             _ => {},
         }
     }
-    result
+
+    /// Values known on both of two paths
+    fn meet(&self, other: &Self) -> Self {
+        Self {
+            values: self
+                .values
+                .iter()
+                .filter(|(r, v)| other.values.get(r) == Some(v))
+                .map(|(r, v)| (*r, *v))
+                .collect(),
+            copies: self
+                .copies
+                .iter()
+                .filter(|(r, source)| other.copies.get(r) == Some(source))
+                .map(|(r, source)| (*r, *source))
+                .collect(),
+        }
+    }
+}
+
+/// Forgets the copies that writing to the register `r` makes stale, its own and those copied from it
+fn forget_copies(copies: &mut BTreeMap<Register64, Register64>, r: Register64) {
+    copies.remove(&r);
+    copies.retain(|_, source| *source != r);
 }
 
 /// Combines adjancent instructions
 pub fn optimize_adjacent(ops: Vec<Instruction>) -> Vec<Instruction> {
     ops.into_iter()
-        .fold(Vec::new(), |a: Vec<Instruction>, b: Instruction| {
-            let mut result = a.clone();
+        .fold(Vec::new(), |mut result: Vec<Instruction>, b: Instruction| {
             if let Some(last) = result.pop() {
                 result.extend(last.combine(b));
-                result
             } else {
-                vec![b]
+                result.push(b);
             }
+            result
         })
 }
 
-/// Combines adjancent immediate memory moves
-pub fn optimize_adjancent_mem_movs(ops: Vec<Instruction>) -> Vec<Instruction> {
+/// Combines immediate memory moves to consecutive cells,
+/// i.e. `MovPtr8Imm(r, imm)` each followed by `AddImm(r, 1)`.
+/// With `target_cpu` set, long runs are stored from data with vector moves.
+pub fn optimize_adjancent_mem_movs(ops: Vec<Instruction>, options: &CompileOptions) -> Vec<Instruction> {
     use Instruction::*;
+    // There is no 64-bit immediate store, so without vectors at most four cells are combined
+    let max_cells = options.target_cpu.map_or(4, CpuFeature::vector_bytes);
     let mut result = Vec::new();
+    let mut vectors = Vec::new();
     let mut index: usize = 0;
     while index < ops.len() {
         if let MovPtr8Imm(r0, imm) = ops[index] {
             let mut imms = vec![imm];
-            while index + imms.len() < ops.len() {
-                if let MovPtr8Imm(r1, imm) = ops[index + imms.len()] {
-                    if r0 != r1 {
+            while imms.len() < max_cells && index + 2 * imms.len() + 1 < ops.len() {
+                if ops[index + 2 * imms.len() - 1] != AddImm(r0, 1) {
+                    break;
+                }
+                if let MovPtr8Imm(r1, imm) = ops[index + 2 * imms.len()] {
+                    if r0 != r1 || ops[index + 2 * imms.len() + 1] != AddImm(r0, 1) {
                         break;
                     }
                     imms.push(imm);
@@ -88,8 +177,30 @@ pub fn optimize_adjancent_mem_movs(ops: Vec<Instruction>) -> Vec<Instruction> {
                 }
             }
 
+            if imms.len() >= 16 {
+                let bytes = if imms.len() >= 32 { 32 } else { 16 };
+                imms.truncate(bytes);
+                // Named by the contents, so that repeated runs share the data
+                let mut label = "vector_".to_owned();
+                for imm in &imms {
+                    label.push_str(&format!("{:02x}", imm));
+                }
+                result.push(if bytes == 32 {
+                    MovPtr256(r0, label.clone())
+                } else {
+                    MovPtr128(r0, label.clone())
+                });
+                result.push(AddImm(r0, bytes as u64));
+                let data = Data(label, imms);
+                if !vectors.contains(&data) {
+                    vectors.push(data);
+                }
+                index += 2 * bytes;
+                continue;
+            }
+
+            imms.truncate(4);
             if imms.len() > 1 {
-                imms.truncate(8);
                 while !imms.len().is_power_of_two() {
                     imms.pop();
                 }
@@ -102,11 +213,10 @@ pub fn optimize_adjancent_mem_movs(ops: Vec<Instruction>) -> Vec<Instruction> {
                 result.push(match bytes {
                     2 => MovPtr16Imm(r0, orred as u16),
                     4 => MovPtr32Imm(r0, orred as u32),
-                    8 => MovPtr64Imm(r0, orred),
                     _ => unreachable!(),
                 });
                 result.push(AddImm(r0, bytes as u64));
-                index += bytes;
+                index += 2 * bytes;
                 continue;
             }
         }
@@ -114,45 +224,124 @@ pub fn optimize_adjancent_mem_movs(ops: Vec<Instruction>) -> Vec<Instruction> {
         result.push(ops[index].clone());
         index += 1;
     }
+    result.extend(vectors);
     result
 }
 
-/// If code begins with setting the first cell to value, use mov instead of add
-pub fn optimize_start_cells(mut ops: Vec<Instruction>) -> Vec<Instruction> {
+/// The tape starts zeroed, so adds to cells at the start of the code store the value instead.
+/// The start ends at the first instruction that is not an add to a cell or a move right,
+/// so each cell is reached once. Adds of zero are dropped, unless their zero flag is read.
+pub fn optimize_start_cells(ops: Vec<Instruction>) -> Vec<Instruction> {
     use Instruction::*;
+    let mut result = Vec::with_capacity(ops.len());
     let mut index = 0;
+    while let Some(op) = ops.get(index) {
+        match *op {
+            AddPtr8Imm(_, 0) if !flags_read_after(&ops, index) => {},
+            // The cell was already stored, left for `optimize_adjacent` to combine
+            AddPtr8Imm(_, _) if matches!(result.last(), Some(MovPtr8Imm(_, _))) => break,
+            AddPtr8Imm(r, imm) if imm != 0 => result.push(MovPtr8Imm(r, imm)),
+            AddImm(_, _) => result.push(op.clone()),
+            _ => break,
+        }
+        index += 1;
+    }
+    result.extend_from_slice(&ops[index..]);
+    result
+}
+
+/// Register and length of a zeroing loop body starting at `ops[index]`:
+/// a label, `AddPtr8Imm(r, ±1)`, an optional `IsZeroPtr8(r)` and a jump back to the label
+fn zero_loop_at(ops: &[Instruction], index: usize) -> Option<(Register64, usize)> {
+    use Instruction::*;
+    let (start, r, rest) = match ops.get(index..)? {
+        [Label(start), AddPtr8Imm(r, 1), rest @ ..] | [Label(start), AddPtr8Imm(r, 255), rest @ ..] => {
+            (start, *r, rest)
+        },
+        _ => return None,
+    };
+    match rest {
+        [JumpNonZero(label), ..] if label == start => Some((r, 3)),
+        [IsZeroPtr8(r1), JumpNonZero(label), ..] if *r1 == r && label == start => Some((r, 4)),
+        _ => None,
+    }
+}
+
+/// Zeroing loop: `[+]` or `[-]`.
+/// The check skipping the loop is removed too, as it would only skip storing a zero.
+pub fn optimize_zero_loop(ops: Vec<Instruction>) -> Vec<Instruction> {
+    use Instruction::*;
+    let mut result = Vec::new();
+    let mut index: usize = 0;
     while index < ops.len() {
-        if let AddPtr8Imm(r0, imm) = ops[index].clone() {
-            if imm == 0 {
-                ops.remove(0);
-                continue;
-            } else {
-                ops[index] = MovPtr8Imm(r0, imm);
+        if let JumpZero(end) = &ops[index] {
+            if let Some((r, len)) = zero_loop_at(&ops, index + 1) {
+                if ops.get(index + 1 + len) == Some(&Label(*end)) {
+                    if result.last() == Some(&IsZeroPtr8(r)) {
+                        result.pop();
+                    }
+                    result.push(MovPtr8Imm(r, 0));
+                    index += 1 + len;
+                    continue;
+                }
             }
-        } else if let AddImm(_, _) = ops[index] {
-        } else {
-            break;
+        }
+        if let Some((r, len)) = zero_loop_at(&ops, index) {
+            result.push(MovPtr8Imm(r, 0));
+            index += len;
+            continue;
         }
 
+        result.push(ops[index].clone());
         index += 1;
     }
-    ops
+    result
 }
 
-/// Zeroing loop: `[+]` or `[-]`
-pub fn optimize_zero_loop(ops: Vec<Instruction>) -> Vec<Instruction> {
+/// Scan loop moving the pointer by a constant stride in either direction until a zero cell,
+/// e.g. `[>]` or `[<<<]`. Returns the pointer register, the move and the number of instructions.
+fn scan_loop_at(ops: &[Instruction], index: usize) -> Option<(Register64, Instruction, usize)> {
+    use Instruction::*;
+    let (start, step, r, rest) = match ops.get(index..)? {
+        [Label(start), step, rest @ ..] => match step {
+            AddImm(r, _) | SubImm(r, _) => (start, step, *r, rest),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let rest = match rest {
+        [CheckPointer(r1, _), rest @ ..] if *r1 == r => rest,
+        _ => rest,
+    };
+    match rest {
+        [IsZeroPtr8(r1), JumpNonZero(label), ..] if *r1 == r && label == start => {
+            Some((r, step.clone(), ops.len() - index - rest.len() + 2))
+        },
+        _ => None,
+    }
+}
+
+/// Scan loops, e.g. `[>]` or `[<<<]`: the check skipping the loop is replaced
+/// with moving the pointer back by the stride, so that the first iteration arrives at the starting cell.
+/// The loop still ends with the zero flag set by the check of the cell.
+pub fn optimize_scan_loop(ops: Vec<Instruction>) -> Vec<Instruction> {
     use Instruction::*;
     let mut result = Vec::new();
     let mut index: usize = 0;
     while index < ops.len() {
-        if index + 2 < ops.len() {
-            if let JumpNonZero(label) = ops[index + 2].clone() {
-                if let AddPtr8Imm(r, 1) | AddPtr8Imm(r, 255) = ops[index + 1] {
-                    if ops[index] == Label(label) {
-                        result.push(MovPtr8Imm(r, 0));
-                        index += 3;
-                        continue;
+        if let JumpZero(end) = &ops[index] {
+            if let Some((r, step, len)) = scan_loop_at(&ops, index + 1) {
+                if ops.get(index + 1 + len) == Some(&Label(*end)) {
+                    if result.last() == Some(&IsZeroPtr8(r)) {
+                        result.pop();
                     }
+                    result.push(match step {
+                        AddImm(r, n) => SubImm(r, n),
+                        SubImm(r, n) => AddImm(r, n),
+                        _ => unreachable!(),
+                    });
+                    index += 1;
+                    continue;
                 }
             }
         }
@@ -164,23 +353,36 @@ pub fn optimize_zero_loop(ops: Vec<Instruction>) -> Vec<Instruction> {
 }
 
 /// Constant output cycle used by the startup optimizer etc
-pub fn optimize_constant_output(ops: Vec<Instruction>) -> Vec<Instruction> {
+pub fn optimize_constant_output(ops: Vec<Instruction>, options: &CompileOptions) -> Vec<Instruction> {
     use Instruction::*;
 
-    let mut name_label: usize = 0;
-    macro_rules! get_label {
-        () => {{
-            let label = format!("constant_output{}", name_label);
-            name_label += 1;
-            label
-        }};
-    }
-
     let mut result = Vec::new();
     let mut index: usize = 0;
     let mut current_bytes = Vec::new();
     let mut const_strings = Vec::new();
     let mut write_fn: Option<Instruction> = None;
+    let mut last_store: Option<Instruction> = None;
+    macro_rules! flush_bytes {
+        () => {
+            if !current_bytes.is_empty() {
+                let name = format!("constant_output{}", const_strings.len());
+
+                // The cell still holds the last written value
+                result.extend(last_store.take());
+                result.push(MovImm(Register64::rdi, 1));
+                result.push(MovImmVar(Register64::rsi, name.clone()));
+                if options.data_length_symbols {
+                    result.push(MovImmVar(Register64::rdx, format!("{}_len", name)));
+                } else {
+                    result.push(MovImm(Register64::rdx, current_bytes.len() as u64));
+                }
+                result.push(write_fn.clone().unwrap());
+
+                const_strings.push(Data(name, current_bytes.clone()));
+                current_bytes.clear();
+            }
+        };
+    }
     while index < ops.len() {
         if index + 4 < ops.len() {
             if let MovPtr8Imm(r0, imm) = ops[index] {
@@ -188,15 +390,15 @@ pub fn optimize_constant_output(ops: Vec<Instruction>) -> Vec<Instruction> {
                     && Mov(Register64::rsi, r0) == ops[index + 2]
                     && MovImm(Register64::rdx, 1) == ops[index + 3]
                 {
-                    if let NamedBlackBox(name, f, eff) = ops[index + 4].clone() {
+                    if let NamedBlackBox(name, _, _) = &ops[index + 4] {
                         if name == "write" {
-                            let bb = BlackBox(f, eff);
-                            if let Some(wf) = write_fn.clone() {
-                                debug_assert_eq!(wf, bb);
-                            } else {
-                                write_fn = Some(bb);
+                            // Each form of write, e.g. `call write` or `call _write`, gets its own run
+                            if write_fn.as_ref() != Some(&ops[index + 4]) {
+                                flush_bytes!();
+                                write_fn = Some(ops[index + 4].clone());
                             }
                             current_bytes.push(imm);
+                            last_store = Some(ops[index].clone());
                             index += 5;
                             continue;
                         }
@@ -205,63 +407,113 @@ pub fn optimize_constant_output(ops: Vec<Instruction>) -> Vec<Instruction> {
             }
         }
 
-        if !current_bytes.is_empty() {
-            let name = get_label!();
-
-            result.push(MovImm(Register64::rdi, 1));
-            result.push(MovImmVar(Register64::rsi, name.clone()));
-            result.push(MovImm(Register64::rdx, current_bytes.len() as u64));
-            result.push(write_fn.clone().unwrap());
-
-            const_strings.push(Data(name, current_bytes.clone()));
-            current_bytes.clear();
-        }
-
+        flush_bytes!();
         result.push(ops[index].clone());
         index += 1;
     }
+    // The writes may also end the instructions
+    flush_bytes!();
     result.extend(const_strings);
     result
 }
 
-/// Removes redundant cmp instructions where zero flag can was set by the previous instruction
+/// The nearest instruction before `ops[index]` that sets the flags,
+/// if neither the register `r` nor memory is modified after it
+fn zero_flag_source(ops: &[Instruction], index: usize, r: Register64) -> Option<&Instruction> {
+    use Instruction::*;
+    for op in ops[..index].iter().rev() {
+        if op.affects_zero_flag() {
+            return Some(op);
+        }
+        match *op {
+            MovImm(r1, _) | MovImmVar(r1, _) | Mov(r1, _) | MovRegPtr8(r1, _) if r1 == r => return None,
+            MovPtr8Imm(_, _) | MovPtr16Imm(_, _) | MovPtr32Imm(_, _) | MovPtr64Imm(_, _) => return None,
+            MovPtr8Reg(_, _) => return None,
+            MovPtr128(_, _) | MovPtr256(_, _) => return None,
+            BlackBox(_, _) | NamedBlackBox(_, _, _) => return None,
+            // Only reachable through a label, if at all
+            Jump(_) => return None,
+            _ => {},
+        }
+    }
+    None
+}
+
+/// Removes redundant cmp instructions where zero flag was already set for the same cell,
+/// either by adding to it or by an identical cmp, and likewise tests of the cached cell.
 pub fn optimize_zero_flags(ops: Vec<Instruction>) -> Vec<Instruction> {
+    zero_flags(ops, false)
+}
+
+/// Like `optimize_zero_flags`, but after a label the flag is also known if it was set
+/// for the same cell on every way to the label. The passes take labels to clobber the flags,
+/// and may remove what sets them before a jump, so this only runs once the passes are done.
+pub fn optimize_zero_flags_across_labels(ops: Vec<Instruction>) -> Vec<Instruction> {
+    zero_flags(ops, true)
+}
+
+fn zero_flags(ops: Vec<Instruction>, across_labels: bool) -> Vec<Instruction> {
     use Instruction::*;
+    let cfg = Cfg::new(ops);
+    let entries = if across_labels {
+        cfg.forward(
+            &None,
+            |ops, start| ops.iter().fold(*start, |flag, op| ZeroFlag::after(op, flag)),
+            |a, b| a.filter(|a| Some(*a) == *b),
+        )
+    } else {
+        vec![None; cfg.blocks.len()]
+    };
     let mut result = Vec::new();
-    let mut index: usize = 0;
-    while index < ops.len() {
-        if let IsZeroPtr8(r0) = ops[index] {
-            let mut i: usize = 1;
-            while i < index {
-                if ops[index - i].affects_zero_flag() {
-                    break;
-                }
-                i += 1;
-            }
-            if i >= 1 {
-                if IsZeroPtr8(r0) == ops[index - i] {
-                    index += 1;
-                    continue;
-                } else if let AddPtr8Imm(r1, _) = ops[index - i] {
-                    if r0 == r1 {
-                        index += 1;
-                        continue;
-                    } else if let NamedBlackBox(name, _, _) = &ops[index - i] {
-                        if name == "read" {
-                            index += 1;
-                            continue;
-                        }
-                    }
-                }
+    for (block, entry) in cfg.blocks.into_iter().zip(entries) {
+        let mut flag = entry.flatten();
+        for op in block.ops {
+            let redundant = match op {
+                IsZeroPtr8(r) => flag == Some(ZeroFlag::Cell(r)),
+                IsZero(r) => flag == Some(ZeroFlag::Register(r)),
+                _ => false,
+            };
+            flag = ZeroFlag::after(&op, flag);
+            if !redundant {
+                result.push(op);
             }
         }
-
-        result.push(ops[index].clone());
-        index += 1;
     }
     result
 }
 
+/// What the zero flag is known to tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ZeroFlag {
+    /// Whether the cell the register points to is zero
+    Cell(Register64),
+    /// Whether the low byte of the register is zero, e.g. of the cached cell
+    Register(Register64),
+}
+impl ZeroFlag {
+    /// What the flag tells after the instruction, given what it told before it.
+    /// Writing to the register or to memory makes it stale, as do black boxes.
+    fn after(op: &Instruction, flag: Option<Self>) -> Option<Self> {
+        use Instruction::*;
+        match *op {
+            AddPtr8Imm(r, _) | IsZeroPtr8(r) => Some(Self::Cell(r)),
+            AddReg8Imm(r, _) | IsZero(r) => Some(Self::Register(r)),
+            // Labels don't change the flags, what the jumps to them set is followed with the CFG
+            Label(_) => flag,
+            _ if op.affects_zero_flag() => None,
+            MovImm(r1, _) | MovImmVar(r1, _) | Mov(r1, _) | MovRegPtr8(r1, _) => {
+                flag.filter(|flag| match *flag {
+                    Self::Cell(r) | Self::Register(r) => r != r1,
+                })
+            },
+            MovPtr8Imm(_, _) | MovPtr16Imm(_, _) | MovPtr32Imm(_, _) | MovPtr64Imm(_, _) => None,
+            MovPtr8Reg(_, _) | MovPtr128(_, _) | MovPtr256(_, _) => None,
+            BlackBox(_, _) | NamedBlackBox(_, _, _) => None,
+            _ => flag,
+        }
+    }
+}
+
 /// Removes redundant instructions just before exit is called
 pub fn optimize_exit(mut ops: Vec<Instruction>) -> Vec<Instruction> {
     use Instruction::*;
@@ -339,14 +591,32 @@ pub fn optimize_remove_dead_code(ops: Vec<Instruction>) -> Vec<Instruction> {
     result
 }
 
+/// Removes instructions that no path from the first instruction reaches,
+/// e.g. after a loop that never exits. Conditional jumps may go either way,
+/// and only unconditional jumps don't fall through. Data is not code, so it is kept.
+pub fn optimize_remove_unreachable(ops: Vec<Instruction>) -> Vec<Instruction> {
+    let cfg = Cfg::new(ops);
+    let reachable = cfg.reachable();
+    cfg.blocks
+        .into_iter()
+        .zip(reachable)
+        .flat_map(|(block, reachable)| {
+            block
+                .ops
+                .into_iter()
+                .filter(move |op| reachable || matches!(op, Instruction::Data(_, _)))
+        })
+        .collect()
+}
+
 /// Removes unused labels
 pub fn optimize_remove_unused_labels(ops: Vec<Instruction>) -> Vec<Instruction> {
     use Instruction::*;
 
-    let mut used_labels = HashSet::new();
+    let mut used_labels = BTreeSet::new();
     for op in &ops {
         if let Jump(l) | JumpZero(l) | JumpNonZero(l) = op {
-            used_labels.insert(l.clone());
+            used_labels.insert(*l);
         }
     }
 
@@ -366,6 +636,24 @@ pub fn optimize_remove_unused_labels(ops: Vec<Instruction>) -> Vec<Instruction>
     result
 }
 
+/// Whether the flags set by `ops[index]` are read before the next instruction overwrites them.
+/// Note that the compiler currently makes almost no assumptions about events
+/// before jump target labels.
+fn flags_read_after(ops: &[Instruction], index: usize) -> bool {
+    for op in &ops[index + 1..] {
+        // The code at the target of a jump may read them, e.g. `][` jumping past the second loop
+        if op.reads_zf() || matches!(op, Instruction::Jump(_)) {
+            return true;
+        } else if let Some(e) = op.effects() {
+            if e.flags {
+                // Next effect shadows flag changes
+                return false;
+            }
+        }
+    }
+    false
+}
+
 /// Removes instructions that cause no effects
 pub fn optimize_remove_nops(mut ops: Vec<Instruction>) -> Vec<Instruction> {
     let mut index: usize = 0;
@@ -375,23 +663,7 @@ pub fn optimize_remove_nops(mut ops: Vec<Instruction>) -> Vec<Instruction> {
             if efs == Effects::NOP {
                 required = false;
             } else if efs.flags && !(efs.registers || efs.control_flow) {
-                // Test if the flags are overwritten before next instruction that uses them.
-                // Note that the compiler currently makes almost no assumptions about events
-                // before jump target labels.
-                required = false; // Switch default for the loop
-                let mut i: usize = 1;
-                while index + i < ops.len() {
-                    if ops[index + i].reads_zf() {
-                        required = true;
-                        break;
-                    } else if let Some(e) = ops[index + i].effects() {
-                        if e.flags {
-                            // Next effect shadows flag changes
-                            break;
-                        }
-                    }
-                    i += 1;
-                }
+                required = flags_read_after(&ops, index);
             }
             if !required {
                 ops.remove(index);
@@ -438,184 +710,2442 @@ pub fn optimize_dead_jumps(mut ops: Vec<Instruction>) -> Vec<Instruction> {
     ops
 }
 
-pub fn label_index(ops: &[Instruction], label: &str) -> usize {
-    let t = Instruction::Label(label.to_owned());
-    for (i, op) in ops.iter().cloned().enumerate() {
-        if op == t {
-            return i;
+/// Index of each label in the instructions
+fn label_indices(ops: &[Instruction]) -> BTreeMap<LabelId, usize> {
+    ops.iter()
+        .enumerate()
+        .filter_map(|(i, op)| match op {
+            Instruction::Label(label) => Some((*label, i)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Number of jumps to each label in the instructions
+fn jump_counts(ops: &[Instruction]) -> BTreeMap<LabelId, usize> {
+    let mut counts = BTreeMap::new();
+    for op in ops {
+        if let Instruction::Jump(label) | Instruction::JumpZero(label) | Instruction::JumpNonZero(label) = op
+        {
+            *counts.entry(*label).or_insert(0) += 1;
         }
     }
-    unreachable!("Label doesn't exist");
+    counts
 }
 
-/// Jumps directly over check if negation of condition is check after jump
-pub fn optimize_jump_skip_recheck(mut ops: Vec<Instruction>) -> Vec<Instruction> {
+/// Jumps directly over check if negation of condition is check after jump.
+/// The targets are found first and the labels inserted after, so each lookup is in a fixed index.
+pub fn optimize_jump_skip_recheck(ops: Vec<Instruction>) -> Vec<Instruction> {
     use Instruction::*;
 
-    let mut next_label: usize = 0;
-    macro_rules! get_label {
-        () => {{
-            let label = format!(".jump_skip_recheck{}", next_label);
-            next_label += 1;
-            label
-        }};
+    let labels = label_indices(&ops);
+    // New target of each jump taken past the check, and the labels inserted before each index
+    let mut retargeted: BTreeMap<usize, LabelId> = BTreeMap::new();
+    let mut inserted: BTreeMap<usize, Vec<LabelId>> = BTreeMap::new();
+    for index in 1..ops.len() {
+        let r = match ops[index - 1] {
+            IsZeroPtr8(r) => r,
+            _ => continue,
+        };
+        let label = match &ops[index] {
+            JumpZero(label) | JumpNonZero(label) => label,
+            _ => continue,
+        };
+        let li = labels[label];
+        let opposite = matches!(
+            (&ops[index], ops.get(li + 2)),
+            (JumpZero(_), Some(JumpNonZero(_))) | (JumpNonZero(_), Some(JumpZero(_)))
+        );
+        if opposite && IsZeroPtr8(r) == ops[li + 1] {
+            let new_label = LabelId::from(format!(".jump_skip_recheck{}", retargeted.len()));
+            retargeted.insert(index, new_label);
+            inserted.entry(li + 3).or_default().push(new_label);
+        }
     }
 
-    let mut index: usize = 1;
-    while index < ops.len() {
-        if let IsZeroPtr8(r) = ops[index - 1].clone() {
-            if let JumpZero(label) = ops[index].clone() {
-                let li = label_index(&ops, &label);
-                if li + 2 < ops.len() && IsZeroPtr8(r) == ops[li + 1] {
-                    if let JumpNonZero(_) = ops[li + 2].clone() {
-                        let new_label = get_label!();
-                        ops[index] = JumpZero(new_label.clone());
-                        ops.insert(li + 3, Label(new_label));
-                        if li < index {
-                            debug_assert!(li + 3 < index);
-                            index += 1;
-                        }
-                    }
-                }
-            } else if let JumpNonZero(label) = ops[index].clone() {
-                let li = label_index(&ops, &label);
-                if li + 2 < ops.len() && IsZeroPtr8(r) == ops[li + 1] {
-                    if let JumpZero(_) = ops[li + 2].clone() {
-                        let new_label = get_label!();
-                        ops[index] = JumpNonZero(new_label.clone());
-                        ops.insert(li + 3, Label(new_label));
-                        if li < index {
-                            debug_assert!(li + 3 < index);
-                            index += 1;
-                        }
-                    }
-                }
-            }
+    let mut result = Vec::with_capacity(ops.len() + retargeted.len());
+    let len = ops.len();
+    for (index, op) in ops.into_iter().enumerate() {
+        // Later labels go first, as if each was inserted before the ones already there
+        if let Some(new_labels) = inserted.remove(&index) {
+            result.extend(new_labels.into_iter().rev().map(Label));
         }
-        index += 1;
+        result.push(match (op, retargeted.remove(&index)) {
+            (JumpZero(_), Some(label)) => JumpZero(label),
+            (JumpNonZero(_), Some(label)) => JumpNonZero(label),
+            (op, _) => op,
+        });
     }
-    ops
+    if let Some(new_labels) = inserted.remove(&len) {
+        result.extend(new_labels.into_iter().rev().map(Label));
+    }
+    result
 }
 
-/// Separates instructions and data
-pub fn separate_data(mut ops: Vec<Instruction>) -> (Vec<Instruction>, Vec<Instruction>) {
+/// Jumps past a loop starting where another loop on the same cell ends, `][`,
+/// as leaving the first loop leaves the cell zero. A loop after a store of zero,
+/// e.g. from `[-]`, is always skipped.
+pub fn optimize_loop_after_loop(mut ops: Vec<Instruction>) -> Vec<Instruction> {
     use Instruction::*;
-    let mut data: Vec<Instruction> = Vec::new();
-    let mut index: usize = 0;
+    let mut index: usize = 1;
     while index < ops.len() {
-        if let Data(_, _) = ops[index] {
-            data.push(ops.remove(index));
+        let (r, target) = match (&ops[index - 1], &ops[index]) {
+            (IsZeroPtr8(r), JumpZero(target)) => (*r, *target),
+            _ => {
+                index += 1;
+                continue;
+            },
+        };
+        // The compare stays, as the code at the target may read the zero flag it sets
+        if known_cell_value(&ops, index - 1, r) == Some(0) {
+            ops[index] = Jump(target);
+            index += 1;
             continue;
         }
+        // Falling through the jump back of the first loop, the zero flag is set by its cell
+        let labels = ops[..index - 1]
+            .iter()
+            .rev()
+            .take_while(|op| matches!(op, Label(_)))
+            .count();
+        let exit = index - 1 - labels;
+        if exit > 0 && matches!(ops[exit - 1], JumpNonZero(_)) {
+            match zero_flag_source(&ops, exit - 1, r) {
+                Some(AddPtr8Imm(r1, _)) | Some(IsZeroPtr8(r1)) if *r1 == r => {
+                    ops.insert(exit, Jump(target));
+                    index += 1;
+                },
+                _ => {},
+            }
+        }
         index += 1;
     }
-    data.sort();
-    (ops, data)
-}
-
-/// Moves data instructions to the end of data buffer
-pub fn move_data_to_end(ops: Vec<Instruction>) -> Vec<Instruction> {
-    let (mut ops, data) = separate_data(ops);
-    ops.extend(data.into_iter());
     ops
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Pass {
-    /// Name of the pass
-    name: String,
-    /// Actual function
-    function: fn(Vec<Instruction>) -> Vec<Instruction>,
-    /// List of passes to be executed immediately after this
-    cleanup: Vec<PassId>,
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub struct PassId(usize);
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Optimizer {
-    /// Passes
-    passes: Vec<Pass>,
-}
-impl Optimizer {
-    pub fn new() -> Self {
-        Self { passes: Vec::new() }
+/// Value of the cell under pointer `r` just before `ops[index]`,
+/// if it can be determined from the straight-line code preceding it.
+/// The code may move the pointer, add to and store to other cells, and write output,
+/// so the counter of `+++++>+.<[>+<-]` is known to be 5 at the loop.
+fn known_cell_value(ops: &[Instruction], index: usize, r: Register64) -> Option<u8> {
+    use Instruction::*;
+    // Offset of the queried cell, relative to the pointer at the scan position
+    let mut offset: u64 = 0;
+    let mut added: u8 = 0;
+    // Byte of the queried cell in a store of several cells at the pointer, if the store covers it
+    let byte = |bytes: &[u8], offset: u64| bytes.get(offset as usize).copied();
+    for op in ops[..index].iter().rev() {
+        match *op {
+            AddImm(r1, n) if r1 == r => offset = offset.wrapping_add(n),
+            SubImm(r1, n) if r1 == r => offset = offset.wrapping_sub(n),
+            AddPtr8Imm(r1, v) if r1 == r => {
+                if offset == 0 {
+                    added = added.wrapping_add(v);
+                }
+            },
+            MovPtr8Imm(r1, v) if r1 == r => {
+                if offset == 0 {
+                    return Some(v.wrapping_add(added));
+                }
+            },
+            MovPtr16Imm(r1, v) if r1 == r => {
+                if let Some(v) = byte(&v.to_le_bytes(), offset) {
+                    return Some(v.wrapping_add(added));
+                }
+            },
+            MovPtr32Imm(r1, v) if r1 == r => {
+                if let Some(v) = byte(&v.to_le_bytes(), offset) {
+                    return Some(v.wrapping_add(added));
+                }
+            },
+            MovPtr64Imm(r1, v) if r1 == r => {
+                if let Some(v) = byte(&v.to_le_bytes(), offset) {
+                    return Some(v.wrapping_add(added));
+                }
+            },
+            // Reads the current cell, and adds to the one at the target
+            AddPtr8Mul(r1, target, _) if r1 == r => {
+                if offset == 0u64.wrapping_add_signed(target) {
+                    return None;
+                }
+            },
+            MovImm(r1, _) | MovImmVar(r1, _) | Mov(r1, _) if r1 != r => {},
+            IsZero(_) | IsZeroPtr8(_) | CheckPointer(_, _) => {},
+            // Writing output keeps the tape and the pointer, which the call preserves
+            NamedBlackBox(ref name, _, _) if name == "write" && r.preserved_by_calls() => {},
+            _ => return None,
+        }
     }
+    // The tape is zeroed on startup
+    Some(added)
+}
 
-    pub fn add_pass(&mut self, pass: Pass) -> PassId {
-        if self.passes.iter().any(|p| p.name == pass.name) {
-            panic!("Pass named {} already exists", pass.name);
+/// Body of a loop starting with `Label(start)` at `ops[index]` and ending with `JumpNonZero(start)`,
+/// if the loop only adds constants to cells, keeps the pointer `r` in place and decrements the
+/// current cell exactly once per iteration. Returns the body without flag tests, and the index of
+/// the closing jump.
+fn counted_loop_body(ops: &[Instruction], index: usize) -> Option<(Register64, Vec<Instruction>, usize)> {
+    use Instruction::*;
+    let start = if let Label(start) = &ops[index] {
+        start
+    } else {
+        return None;
+    };
+    let mut pointer: Option<Register64> = None;
+    let mut offset: u64 = 0;
+    let mut counter_delta: u8 = 0;
+    let mut flags_from_counter = false;
+    let mut body = Vec::new();
+    for (i, op) in ops.iter().enumerate().skip(index + 1) {
+        let r = match *op {
+            AddImm(r, n) => {
+                offset = offset.wrapping_add(n);
+                flags_from_counter = false;
+                r
+            },
+            SubImm(r, n) => {
+                offset = offset.wrapping_sub(n);
+                flags_from_counter = false;
+                r
+            },
+            AddPtr8Imm(r, v) => {
+                if offset == 0 {
+                    counter_delta = counter_delta.wrapping_add(v);
+                }
+                flags_from_counter = offset == 0;
+                r
+            },
+            MovPtr8Imm(r, _) if offset != 0 => r,
+            CheckPointer(r, _) => {
+                flags_from_counter = false;
+                r
+            },
+            IsZeroPtr8(r) => {
+                flags_from_counter = offset == 0;
+                r
+            },
+            JumpNonZero(ref target) if target == start => {
+                let r = pointer?;
+                if offset == 0 && counter_delta == 255 && flags_from_counter {
+                    return Some((r, body, i));
+                }
+                return None;
+            },
+            _ => return None,
+        };
+        if *pointer.get_or_insert(r) != r {
+            return None;
+        }
+        if let IsZeroPtr8(_) = op {
+        } else {
+            body.push(op.clone());
         }
-        self.passes.push(pass);
-        PassId(self.passes.len() - 1)
     }
+    None
+}
 
-    pub fn get_id(&self, name: &str) -> PassId {
-        self.passes
-            .iter()
-            .enumerate()
-            .find_map(|(i, p)| if p.name == name { Some(PassId(i)) } else { None })
-            .unwrap_or_else(|| panic!("Pass {} not defined yet", name))
-    }
+/// Unrolls loops that have a small, statically known trip count
+pub fn optimize_unroll(ops: Vec<Instruction>, options: &CompileOptions) -> Vec<Instruction> {
+    use Instruction::*;
+    let references = jump_counts(&ops);
+    let mut result: Vec<Instruction> = Vec::new();
+    let mut index: usize = 0;
+    while index < ops.len() {
+        if let Label(start) = &ops[index] {
+            if let (Some(1), Some((r, body, end))) = (references.get(start), counted_loop_body(&ops, index)) {
+                // Skip-if-zero check guarding the loop entry, jumping to the label after the loop
+                let mut guard: usize = 0;
+                if let (Some(JumpZero(target)), Some(Label(label))) = (result.last(), ops.get(end + 1)) {
+                    if target == label {
+                        guard = 1;
+                        if result.len() > 1 && result[result.len() - 2] == IsZeroPtr8(r) {
+                            guard = 2;
+                        }
+                    }
+                }
+                let value = known_cell_value(&result, result.len() - guard, r);
+                let count = match value {
+                    // Loop is skipped by the guard
+                    Some(0) if guard > 0 => Some(0),
+                    // Without a guard, the body would be executed before the first check
+                    Some(0) | None => None,
+                    Some(v) => Some(usize::from(v)),
+                };
+                if let Some(count) = count {
+                    if count * body.len() <= options.max_unroll {
+                        if count > 0 {
+                            result.truncate(result.len() - guard);
+                        }
+                        for _ in 0..count {
+                            result.extend(body.iter().cloned());
+                        }
+                        // Falling out of the loop leaves zero flag set by the counter
+                        if count > 0 && ops.get(end + 1).map_or(false, Instruction::reads_zf) {
+                            result.push(IsZeroPtr8(r));
+                        }
+                        index = end + 1;
+                        continue;
+                    }
+                }
+            }
+        }
 
-    pub fn get(&self, id: PassId) -> Pass {
-        self.passes[id.0].clone()
+        result.push(ops[index].clone());
+        index += 1;
     }
+    result
 }
 
-/// Removes redundant movs
-pub fn optimize(mut ops: Vec<Instruction>) -> Vec<Instruction> {
-    let mut optimizer = Optimizer::new();
-
-    macro_rules! pass {
-        ($optimizer:ident; $name:ident; $($cleanup:ident),*) => {
-            $optimizer.add_pass(Pass {
-                name: stringify!($name).to_owned(),
-                function: $name,
-                cleanup: vec![$(optimizer.get_id(stringify!($cleanup)),)*],
-            })
-        };
-        ($optimizer:ident; $name:ident) => {pass!($optimizer; $name;)};
-    };
-
-    pass!(optimizer; optimize_remove_unused_labels);
-    pass!(optimizer; optimize_start_cells; optimize_remove_unused_labels);
-    pass!(optimizer; optimize_zero_loop);
-    pass!(optimizer; optimize_zero_flags; optimize_remove_unused_labels);
-    pass!(optimizer; optimize_remove_nops; optimize_remove_unused_labels);
-    pass!(optimizer; optimize_adjancent_mem_movs; optimize_remove_nops, optimize_zero_loop);
-    pass!(optimizer; optimize_adjacent);
-    pass!(optimizer; optimize_constant_output);
-    pass!(optimizer; optimize_dead_jumps; optimize_remove_unused_labels, optimize_remove_nops);
-    pass!(optimizer; optimize_jump_skip_recheck; optimize_remove_unused_labels, optimize_dead_jumps);
-    pass!(optimizer; optimize_remove_dead_code; optimize_remove_unused_labels, optimize_remove_nops);
-    pass!(optimizer; optimize_exit; optimize_remove_unused_labels, optimize_dead_jumps, optimize_zero_flags, optimize_remove_nops);
-
-    let mut queue: Vec<_> = optimizer.passes.iter().cloned().rev().collect();
-    while let Some(pass) = queue.pop() {
-        log::trace!("Optimization: {}", pass.name);
-        ops = (pass.function)(ops);
-        ops = move_data_to_end(ops);
-        for pass_id in pass.cleanup {
-            let p = optimizer.get(pass_id);
-            if queue.last() != Some(&p) {
-                queue.push(p);
+/// Multiply loop, e.g. `[->+>+<<]` duplicating the counter cell into the next two:
+/// a counted loop only adding constants is replaced with adding the counter times
+/// each constant to its cell, and storing zero to the counter.
+/// Cells left of the counter are checked against the tape start once.
+/// The counter always ends at zero, so `[>+<-]` adds it destructively to the next cell,
+/// and keeping it takes a second loop moving a copy back, which is replaced on its own.
+/// Loops changing the counter by other than `-1` per iteration are kept, e.g. `[-->++<]`
+/// which only exits when the counter starts even.
+pub fn optimize_multiply_loop(ops: Vec<Instruction>) -> Vec<Instruction> {
+    use Instruction::*;
+    let references = jump_counts(&ops);
+    let mut result: Vec<Instruction> = Vec::new();
+    let mut index: usize = 0;
+    while index < ops.len() {
+        if let Label(start) = &ops[index] {
+            if let (Some(1), Some((r, body, end))) = (references.get(start), counted_loop_body(&ops, index)) {
+                let mut factors: BTreeMap<i64, u8> = BTreeMap::new();
+                // Lowest checked offset, and the tape start it is checked against
+                let mut lowest: i64 = 0;
+                let mut base: Option<Register64> = None;
+                let mut offset: i64 = 0;
+                let mut stores = false;
+                for op in &body {
+                    match *op {
+                        AddImm(_, n) => offset = offset.wrapping_add_unsigned(n),
+                        SubImm(_, n) => offset = offset.wrapping_sub_unsigned(n),
+                        AddPtr8Imm(_, v) if offset != 0 => {
+                            let factor = factors.entry(offset).or_insert(0);
+                            *factor = factor.wrapping_add(v);
+                        },
+                        CheckPointer(_, b) if offset < lowest => {
+                            lowest = offset;
+                            base = Some(b);
+                        },
+                        MovPtr8Imm(_, _) => stores = true,
+                        _ => {},
+                    }
+                }
+                if !stores {
+                    if let Some(base) = base {
+                        result.push(SubImm(r, lowest.unsigned_abs()));
+                        result.push(CheckPointer(r, base));
+                        result.push(AddImm(r, lowest.unsigned_abs()));
+                    }
+                    for (offset, factor) in factors {
+                        if factor != 0 {
+                            result.push(AddPtr8Mul(r, offset, factor));
+                        }
+                    }
+                    result.push(MovPtr8Imm(r, 0));
+                    // Falling out of the loop leaves zero flag set by the counter
+                    if let Some(next) = ops.get(end + 1) {
+                        if next.reads_zf() {
+                            result.push(IsZeroPtr8(r));
+                        }
+                    }
+                    index = end + 1;
+                    continue;
+                }
             }
         }
+
+        result.push(ops[index].clone());
+        index += 1;
     }
-    ops
+    result
 }
 
-// TODO: Future optimizations:
-// `[<]` and `[>]` to scan loops
-// `,[>,]` to read until EOF
-// `[.>]` to print null-terminated string, i.e. scan and print
-
-// dec rbx
-// inc byte [rbx]
-// inc rbx
+/// Stores of a constant the loop starting at `index` makes on every iteration, that can be made
+/// once before it instead, as `(offset, value, index)`, along with the pointer register.
+/// The loop must be straight-line code only moving the pointer, adding to cells and storing to them,
+/// returning the pointer to the counter.
+/// A store is invariant when nothing earlier in the body accesses its cell,
+/// so no iteration sees the value it had before, and nothing later writes to it.
+fn invariant_stores(ops: &[Instruction], index: usize) -> Option<(Register64, Vec<(i64, u8, usize)>)> {
+    use Instruction::*;
+    let start = if let Label(start) = &ops[index] {
+        start
+    } else {
+        return None;
+    };
+    let mut pointer: Option<Register64> = None;
+    let mut offset: i64 = 0;
+    // First access to each cell, which is `Some` when it is a store
+    let mut first: BTreeMap<i64, Option<(u8, usize)>> = BTreeMap::new();
+    let mut written_again: BTreeSet<i64> = BTreeSet::new();
+    for (i, op) in ops.iter().enumerate().skip(index + 1) {
+        let r = match *op {
+            AddImm(r, n) => {
+                offset = offset.wrapping_add_unsigned(n);
+                r
+            },
+            SubImm(r, n) => {
+                offset = offset.wrapping_sub_unsigned(n);
+                r
+            },
+            AddPtr8Imm(r, _) => {
+                first.entry(offset).or_insert(None);
+                written_again.insert(offset);
+                r
+            },
+            AddPtr8Mul(r, target, _) => {
+                first.entry(offset).or_insert(None);
+                first.entry(offset.wrapping_add(target)).or_insert(None);
+                written_again.insert(offset.wrapping_add(target));
+                r
+            },
+            MovPtr8Imm(r, v) => {
+                if first.contains_key(&offset) {
+                    written_again.insert(offset);
+                } else {
+                    first.insert(offset, Some((v, i)));
+                }
+                r
+            },
+            IsZeroPtr8(r) => {
+                first.entry(offset).or_insert(None);
+                r
+            },
+            CheckPointer(r, _) => r,
+            JumpNonZero(ref target) if target == start => {
+                let r = pointer?;
+                if offset != 0 {
+                    return None;
+                }
+                let stores: Vec<_> = first
+                    .into_iter()
+                    .filter(|(offset, _)| *offset > 0 && !written_again.contains(offset))
+                    .filter_map(|(offset, store)| store.map(|(v, i)| (offset, v, i)))
+                    .collect();
+                return if stores.is_empty() { None } else { Some((r, stores)) };
+            },
+            _ => return None,
+        };
+        if *pointer.get_or_insert(r) != r {
+            return None;
+        }
+    }
+    None
+}
+
+/// Hoists stores of a constant that a loop repeats on every iteration, e.g. `[-]+` in `[>[-]+<-]`,
+/// to just before the loop, where only entering it runs them.
+/// Stores left of the counter are kept, as the body checks the pointer against the tape start before them,
+/// and so are loops whose start is jumped to from elsewhere.
+pub fn optimize_hoist_stores(ops: Vec<Instruction>) -> Vec<Instruction> {
+    use Instruction::*;
+    let references = jump_counts(&ops);
+    let mut result: Vec<Instruction> = Vec::new();
+    let mut index: usize = 0;
+    while index < ops.len() {
+        if let Label(start) = &ops[index] {
+            if let (Some(1), Some((r, stores))) = (references.get(start), invariant_stores(&ops, index)) {
+                for &(offset, v, _) in &stores {
+                    result.push(AddImm(r, offset.unsigned_abs()));
+                    result.push(MovPtr8Imm(r, v));
+                    result.push(SubImm(r, offset.unsigned_abs()));
+                }
+                result.push(ops[index].clone());
+                index += 1;
+                while !matches!(&ops[index], JumpNonZero(target) if target == start) {
+                    if stores.iter().all(|&(_, _, i)| i != index) {
+                        result.push(ops[index].clone());
+                    }
+                    index += 1;
+                }
+                continue;
+            }
+        }
+
+        result.push(ops[index].clone());
+        index += 1;
+    }
+    result
+}
+
+/// Register holding the current cell while it is cached, which nothing else uses.
+/// Calls may overwrite it, but none happen while the cell is in it.
+const CELL_REGISTER: Register64 = Register64::r11;
+
+/// Whether the instruction neither moves the pointer `r`, uses the cell register
+/// nor reaches memory or the flow of control, so the cell may stay in the register across it
+fn keeps_cell_cached(op: &Instruction, r: Register64) -> bool {
+    use Instruction::*;
+    match *op {
+        // Zeroing with `xor` sets the flags, unlike its effects say
+        MovImm(_, 0) => false,
+        MovImm(r1, _) | MovImmVar(r1, _) | AddImm(r1, _) | SubImm(r1, _) => r1 != r && r1 != CELL_REGISTER,
+        Mov(r1, r2) => r1 != r && r1 != CELL_REGISTER && r2 != CELL_REGISTER,
+        _ => false,
+    }
+}
+
+/// Keeps the cell under pointer `r` in the cell register over `ops`, which only add to it
+/// and test it, or keep it cached. Loads it before the first instruction and stores it back
+/// after the last one if it was changed. Neither sets the flags, so the ones of the last add or test stay.
+fn cache_cell(ops: &[Instruction], r: Register64) -> Vec<Instruction> {
+    use Instruction::*;
+    let mut result = vec![MovRegPtr8(CELL_REGISTER, r)];
+    let mut changed = false;
+    for op in ops {
+        result.push(match *op {
+            AddPtr8Imm(_, v) => {
+                changed = true;
+                // Only the lowest byte changes, so the register still holds the zero-extended cell
+                AddReg8Imm(CELL_REGISTER, v)
+            },
+            IsZeroPtr8(_) => IsZero(CELL_REGISTER),
+            _ => op.clone(),
+        });
+    }
+    if changed {
+        result.push(MovPtr8Reg(r, CELL_REGISTER));
+    }
+    result
+}
+
+/// Within each straight-line block, keeps the cell in a register over instructions that add to it
+/// several times without moving the pointer, turning each read-modify-write of memory into an add
+/// to the register. The cell is stored back before anything could see it in memory:
+/// a pointer move, IO, a call or a jump. Kept only where it lowers the estimated cost.
+pub fn optimize_cache_cell(ops: Vec<Instruction>) -> Vec<Instruction> {
+    use Instruction::*;
+    let mut cfg = Cfg::new(ops);
+    for block in &mut cfg.blocks {
+        let ops = &block.ops;
+        let mut result = Vec::new();
+        let mut index: usize = 0;
+        while index < ops.len() {
+            let r = match ops[index] {
+                AddPtr8Imm(r, _) | IsZeroPtr8(r) if r != CELL_REGISTER => r,
+                _ => {
+                    result.push(ops[index].clone());
+                    index += 1;
+                    continue;
+                },
+            };
+            let accesses_cell =
+                |op: &Instruction| matches!(*op, AddPtr8Imm(r1, _) | IsZeroPtr8(r1) if r1 == r);
+            // The cell is stored right after the last access, before the instructions kept after it
+            let window = ops[index..]
+                .iter()
+                .take_while(|op| accesses_cell(op) || keeps_cell_cached(op, r))
+                .count();
+            let end = index + ops[index..index + window].iter().rposition(accesses_cell).unwrap() + 1;
+            let cached = cache_cell(&ops[index..end], r);
+            let cost = |ops: &[Instruction]| ops.iter().map(Instruction::cost).sum::<u32>();
+            if cost(&cached) < cost(&ops[index..end]) {
+                result.extend(cached);
+            } else {
+                result.extend(ops[index..end].iter().cloned());
+            }
+            index = end;
+        }
+        block.ops = result;
+    }
+    cfg.into_instructions()
+}
+
+/// Renames the local labels, the ones starting with `.`, to `.@0`, `.@1`, ... in the order they are
+/// defined, along with the jumps and movs referring to them, so that the names don't depend on
+/// which passes created and removed labels.
+/// Labels named in the source of other instructions, e.g. the underflow handler of pointer checks,
+/// keep their names, as do the global ones, e.g. data and entry points.
+pub fn renumber_labels(ops: Vec<Instruction>) -> Vec<Instruction> {
+    use Instruction::*;
+    let referenced = source_names(
+        ops.iter()
+            .filter(|op| matches!(op, BlackBox(_, _) | NamedBlackBox(_, _, _) | CheckPointer(_, _))),
+    );
+    let mut names: BTreeMap<LabelId, LabelId> = BTreeMap::new();
+    for op in &ops {
+        if let Label(label) = op {
+            if label.is_local() && !referenced.contains(&label.name()) {
+                let name = LabelId::from(format!("{}{}", COMPILER_LABEL_PREFIX, names.len()));
+                names.insert(*label, name);
+            }
+        }
+    }
+    let rename = |label: LabelId| names.get(&label).copied().unwrap_or(label);
+    ops.into_iter()
+        .map(|op| match op {
+            Label(label) => Label(rename(label)),
+            Jump(label) => Jump(rename(label)),
+            JumpZero(label) => JumpZero(rename(label)),
+            JumpNonZero(label) => JumpNonZero(rename(label)),
+            MovImmVar(r, label) => MovImmVar(r, rename(label.as_str().into()).name()),
+            op => op,
+        })
+        .collect()
+}
+
+/// Separates instructions and data
+pub fn separate_data(mut ops: Vec<Instruction>) -> (Vec<Instruction>, Vec<Instruction>) {
+    use Instruction::*;
+    let mut data: Vec<Instruction> = Vec::new();
+    let mut index: usize = 0;
+    while index < ops.len() {
+        if let Data(_, _) = ops[index] {
+            data.push(ops.remove(index));
+            continue;
+        }
+        index += 1;
+    }
+    data.sort();
+    (ops, data)
+}
+
+/// Moves data instructions to the end of data buffer
+pub fn move_data_to_end(ops: Vec<Instruction>) -> Vec<Instruction> {
+    let (mut ops, data) = separate_data(ops);
+    ops.extend(data.into_iter());
+    ops
+}
+
+/// Passes are told apart by their names, as comparing the functions compares their addresses
+#[derive(Debug, Clone, Copy)]
+pub struct Pass {
+    /// Name of the pass
+    name: &'static str,
+    /// What the pass does, shown by `--explain`
+    description: &'static str,
+    /// Actual function
+    function: fn(Vec<Instruction>, &CompileOptions) -> Vec<Instruction>,
+    /// Passes to be executed immediately after this, defined earlier in the pipeline
+    cleanup: &'static [&'static str],
+    /// Makes the code larger for speed, so it is skipped when optimizing for size
+    grows_code: bool,
+    /// Logged with the source position of each loop the pass removes
+    loop_message: Option<&'static str>,
+    /// Warning about each loop the pass removes, for passes removing only loops that are likely mistakes
+    loop_warning: Option<fn(Span) -> Warning>,
+}
+
+/// All passes of the optimizer, in the order they are run
+const PIPELINE: &[Pass] = &[
+    Pass {
+        name: "optimize_remove_unused_labels",
+        description: "Removes labels that no jump refers to",
+        function: |ops, _| optimize_remove_unused_labels(ops),
+        cleanup: &[],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_start_cells",
+        description: "Stores the values added to cells at the start of the program, as the tape is zeroed",
+        function: |ops, _| optimize_start_cells(ops),
+        cleanup: &["optimize_remove_unused_labels"],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_zero_loop",
+        description:
+            "Replaces `[-]` and `[+]` loops, and the check skipping them, with storing zero to the cell",
+        function: |ops, _| optimize_zero_loop(ops),
+        cleanup: &["optimize_remove_unused_labels"],
+        grows_code: false,
+        loop_message: Some("Replaced zeroing loop with a store"),
+        loop_warning: None,
+    },
+    // Before zero flags, as combining a store with an add drops the flags set by the add
+    Pass {
+        name: "optimize_adjacent",
+        description:
+            "Combines adjacent instructions, e.g. two adds to the same cell or a store followed by an add",
+        function: |ops, _| optimize_adjacent(ops),
+        cleanup: &[],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_zero_flags",
+        description:
+            "Removes comparisons of a cell with zero when an add to the same cell already set the flags",
+        function: |ops, _| optimize_zero_flags(ops),
+        cleanup: &["optimize_remove_unused_labels"],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_remove_nops",
+        description:
+            "Removes instructions without effects, and flag changes overwritten before they are read",
+        function: |ops, _| optimize_remove_nops(ops),
+        cleanup: &["optimize_remove_unused_labels"],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_adjancent_mem_movs",
+        description: "Combines stores to consecutive cells into one wider store",
+        function: optimize_adjancent_mem_movs,
+        cleanup: &["optimize_remove_nops", "optimize_adjacent"],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_unroll",
+        description:
+            "Unrolls loops with a small trip count known at compile time, up to `max-unroll` instructions",
+        function: optimize_unroll,
+        cleanup: &["optimize_remove_unused_labels", "optimize_adjacent"],
+        grows_code: true,
+        loop_message: Some("Unrolled loop"),
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_multiply_loop",
+        description: "Replaces loops adding the counter cell to others, e.g. `[->+>+<<]`, with multiply-adds",
+        function: |ops, _| optimize_multiply_loop(ops),
+        cleanup: &["optimize_remove_unused_labels", "optimize_adjacent"],
+        grows_code: false,
+        loop_message: Some("Replaced multiply loop with multiply-adds"),
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_constant_output",
+        description: "Writes consecutive outputs of known values with a single write of a constant string",
+        function: optimize_constant_output,
+        cleanup: &[],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_redundant_movs",
+        description:
+            "Removes movs of a value the register already holds, e.g. the same argument for adjacent calls",
+        function: |ops, _| optimize_redundant_movs(ops),
+        cleanup: &[],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_dead_jumps",
+        description: "Removes conditional jumps that repeat an earlier jump on the same flags",
+        function: |ops, _| optimize_dead_jumps(ops),
+        cleanup: &["optimize_remove_unused_labels", "optimize_remove_nops"],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_jump_skip_recheck",
+        description: "Jumps past a check whose result is already known from the check before the jump",
+        function: |ops, _| optimize_jump_skip_recheck(ops),
+        cleanup: &["optimize_remove_unused_labels", "optimize_dead_jumps"],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_loop_after_loop",
+        description:
+            "Jumps past a loop starting where another loop on the same cell ends, `][`, or after `[-]`",
+        function: |ops, _| optimize_loop_after_loop(ops),
+        cleanup: &["optimize_remove_unused_labels"],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    // After loop after loop, as a scan loop no longer starts with a check that could be skipped
+    Pass {
+        name: "optimize_scan_loop",
+        description:
+            "Replaces the check skipping a loop that only moves the pointer, e.g. `[>>>]`, with moving back by the stride",
+        function: |ops, _| optimize_scan_loop(ops),
+        cleanup: &["optimize_remove_unused_labels"],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_hoist_stores",
+        description: "Stores constants a loop stores on every iteration, e.g. `[>[-]+<-]`, once before it",
+        function: |ops, _| optimize_hoist_stores(ops),
+        cleanup: &["optimize_adjacent", "optimize_multiply_loop", "optimize_remove_unused_labels"],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    // After adjacent, as adds combined into one need no register
+    Pass {
+        name: "optimize_cache_cell",
+        description:
+            "Keeps the cell in a register over several adds without a pointer move, IO or jump between them",
+        function: |ops, _| optimize_cache_cell(ops),
+        cleanup: &["optimize_zero_flags", "optimize_remove_nops"],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+    Pass {
+        name: "optimize_remove_dead_code",
+        description: "Removes code after an unconditional jump that is never reached",
+        function: |ops, _| optimize_remove_dead_code(ops),
+        cleanup: &["optimize_remove_unused_labels", "optimize_remove_nops"],
+        grows_code: false,
+        loop_message: Some("Removed unreachable loop"),
+        loop_warning: Some(Warning::UnreachableLoop),
+    },
+    Pass {
+        name: "optimize_remove_unreachable",
+        description: "Removes code that no path from the start reaches, e.g. after a loop that never exits",
+        function: |ops, _| optimize_remove_unreachable(ops),
+        cleanup: &["optimize_remove_unused_labels", "optimize_remove_nops"],
+        grows_code: false,
+        loop_message: Some("Removed unreachable loop"),
+        loop_warning: Some(Warning::UnreachableLoop),
+    },
+    Pass {
+        name: "optimize_exit",
+        description: "Removes instructions before exit whose effects are never seen",
+        function: |ops, _| optimize_exit(ops),
+        cleanup: &[
+            "optimize_remove_unused_labels",
+            "optimize_dead_jumps",
+            "optimize_zero_flags",
+            "optimize_remove_nops",
+        ],
+        grows_code: false,
+        loop_message: None,
+        loop_warning: None,
+    },
+];
+
+/// Pairs of passes where the first must run before the second, as the comments of the pipeline say
+const ORDER_CONSTRAINTS: &[(&str, &str)] = &[
+    ("optimize_adjacent", "optimize_zero_flags"),
+    ("optimize_loop_after_loop", "optimize_scan_loop"),
+    ("optimize_adjacent", "optimize_cache_cell"),
+];
+
+/// What a pass did in one run of the optimizer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PassStat {
+    /// Number of times the pass ran, not counting the ones skipped as its input was unchanged
+    pub runs: usize,
+    /// Number of runs that changed the program
+    pub changes: usize,
+    /// Instructions removed by the runs that shortened the program, including labels
+    pub removed: usize,
+    /// Instructions added by the runs that lengthened the program, including labels
+    pub added: usize,
+}
+
+/// What each pass that ran did, by the name of the pass
+pub type PassStats = BTreeMap<&'static str, PassStat>;
+
+/// Source position of the loop starting at each label
+pub type LoopSources = BTreeMap<LabelId, Span>;
+
+/// What the optimizer did to loops, at their source position
+pub type LoopNotes = Vec<(Span, String)>;
+
+/// Source positions of the loops with a jump back in `before` but not in `after`, in source order
+fn removed_loops(before: &[Instruction], after: &[Instruction], sources: &LoopSources) -> Vec<Span> {
+    let jumps = |ops: &[Instruction]| -> BTreeSet<LabelId> {
+        ops.iter()
+            .filter_map(|op| match op {
+                Instruction::JumpNonZero(label) if sources.contains_key(label) => Some(*label),
+                _ => None,
+            })
+            .collect()
+    };
+    let after = jumps(after);
+    let mut spans: Vec<Span> = jumps(before)
+        .difference(&after)
+        .map(|label| sources[label])
+        .collect();
+    spans.sort_by_key(|span| span.offset);
+    spans
+}
+
+/// Pass of the pipeline with the given name
+fn find_pass(name: &str) -> Option<Pass> {
+    PIPELINE.iter().copied().find(|pass| pass.name == name)
+}
+
+/// Index of the first conditional jump that may be reached without the zero flag testing a value.
+/// Only a compare, or arithmetic on a cell or on the cached cell, sets it, and a call, another
+/// flag-setting instruction or a store makes it stale, on some way into the block or within it.
+/// A counter decremented just before the jump, e.g. of the bytes left to write, tests itself.
+fn unset_flags_jump(ops: &[Instruction]) -> Option<usize> {
+    use Instruction::*;
+    // Whether the flag tests a value after `op`, given whether it did before it
+    let after = |op: &Instruction, set: bool| match op {
+        IsZero(_) | IsZeroPtr8(_) | IsZeroPtr16(_) | IsZeroPtr32(_) => true,
+        AddReg8Imm(_, _) | AddPtr8Imm(_, _) | AddPtr16Imm(_, _) | AddPtr32Imm(_, _) => true,
+        AddPtr64Imm(_, _) | AddPtr8Mul(_, _, _) => true,
+        // The compares of the target ABI, e.g. of the value returned by a call
+        BlackBox(source, _) => source.lines().last().map_or(false, |line| {
+            line.starts_with("cmp ") || line.starts_with("test ")
+        }),
+        Label(_) | Jump(_) | JumpZero(_) | JumpNonZero(_) => set,
+        // `xor` clears the register
+        MovImm(_, 0) => false,
+        MovPtr8Imm(_, _) | MovPtr16Imm(_, _) | MovPtr32Imm(_, _) | MovPtr64Imm(_, _) => false,
+        // Unlike storing the cached cell, which keeps the value its test tells about
+        MovPtr128(_, _) | MovPtr256(_, _) => false,
+        _ => set && !op.affects_zero_flag(),
+    };
+    let cfg = Cfg::new(ops.to_vec());
+    let entries = cfg.forward(
+        &false,
+        |ops, set| ops.iter().fold(*set, |set, op| after(op, set)),
+        |a, b| *a && *b,
+    );
+    let mut index = 0;
+    for (block, entry) in cfg.blocks.iter().zip(entries) {
+        index += block.ops.len();
+        // Blocks no way reaches are fine, e.g. a loop whose body was removed
+        let entry = match entry {
+            Some(entry) => entry,
+            None => continue,
+        };
+        if let Some((JumpZero(_) | JumpNonZero(_), before)) = block.ops.split_last() {
+            let counter = matches!(before.last(), Some(AddImm(_, _) | SubImm(_, _)));
+            if !counter && !before.iter().fold(entry, |set, op| after(op, set)) {
+                return Some(index - 1);
+            }
+        }
+    }
+    None
+}
+
+/// Unified diff of the source lines of two instruction listings
+pub fn listing_diff(before: &[Instruction], after: &[Instruction]) -> String {
+    let listing = |ops: &[Instruction]| -> Vec<String> {
+        ops.iter()
+            .flat_map(|op| op.to_source().lines().map(str::to_owned).collect::<Vec<_>>())
+            .collect()
+    };
+    format!(
+        "--- before\n+++ after\n{}",
+        diff::unified_diff(&listing(before), &listing(after), 3).trim_end()
+    )
+}
+
+/// FNV-1a, as the hasher of the standard library needs `std`
+pub(crate) struct Fnv(u64);
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+/// Hash of a whole program, used to detect passes that changed nothing
+fn hash_of(ops: &[Instruction]) -> u64 {
+    let mut hasher = Fnv::default();
+    ops.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Logs the changes made by a pass
+fn trace_pass(name: &str, before: &[Instruction], after: &[Instruction]) {
+    if before == after {
+        return;
+    }
+    log::info!(
+        "Pass {} changed the program:\n{}",
+        name,
+        listing_diff(before, after)
+    );
+}
+
+/// Cleanup passes of the pass with the given options
+fn cleanup_passes(pass: &Pass, options: &CompileOptions) -> Vec<Pass> {
+    match options.pass_cleanup.get(pass.name) {
+        Some(names) => names.iter().filter_map(|name| find_pass(name)).collect(),
+        None => pass.cleanup.iter().map(|name| find_pass(name).unwrap()).collect(),
+    }
+}
+
+/// Fails with a cycle of passes that are cleanup passes of each other with the given cleanup,
+/// from a pass back to itself, as the optimizer could then run them until it is stopped
+pub fn check_pass_cleanup(cleanup: &BTreeMap<String, Vec<String>>) -> Result<(), Vec<String>> {
+    let next_passes = |name: &str| -> Vec<String> {
+        match cleanup.get(name) {
+            Some(names) => names.clone(),
+            None => find_pass(name)
+                .map(|pass| pass.cleanup.iter().map(|name| (*name).to_owned()).collect())
+                .unwrap_or_default(),
+        }
+    };
+    // Depth-first search, keeping the passes on the path with their cleanup passes left to visit
+    let mut visited: BTreeSet<String> = BTreeSet::new();
+    for pass in PIPELINE {
+        let mut path = vec![(pass.name.to_owned(), next_passes(pass.name))];
+        while let Some((_, next)) = path.last_mut() {
+            match next.pop() {
+                Some(name) => {
+                    if let Some(start) = path.iter().position(|(on_path, _)| *on_path == name) {
+                        let mut cycle: Vec<String> = path[start..].iter().map(|(n, _)| n.clone()).collect();
+                        cycle.push(name);
+                        return Err(cycle);
+                    }
+                    if !visited.contains(&name) {
+                        let next = next_passes(&name);
+                        path.push((name, next));
+                    }
+                },
+                None => {
+                    let (name, _) = path.pop().unwrap();
+                    visited.insert(name);
+                },
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name, description and cleanup passes of each optimizer pass, in the order they are run
+pub fn describe_passes() -> Vec<(&'static str, &'static str, Vec<&'static str>)> {
+    PIPELINE
+        .iter()
+        .map(|pass| (pass.name, pass.description, pass.cleanup.to_vec()))
+        .collect()
+}
+
+/// Passes in the order they are run with the given options,
+/// i.e. the ones named in `pass_order` followed by the rest in the order of the pipeline
+fn ordered_passes(options: &CompileOptions) -> Vec<Pass> {
+    let named = options.pass_order.iter().filter_map(|name| find_pass(name));
+    let mut passes: Vec<Pass> = Vec::new();
+    for pass in named.chain(PIPELINE.iter().copied()) {
+        if passes.iter().all(|p| p.name != pass.name) {
+            passes.push(pass);
+        }
+    }
+    passes
+}
+
+/// Fails with the first pair of passes that the order runs the wrong way around
+pub fn check_pass_order(order: &[String]) -> Result<(), (&'static str, &'static str)> {
+    let position = |name: &str| order.iter().position(|n| n == name);
+    for &(first, second) in ORDER_CONSTRAINTS {
+        if let (Some(a), Some(b)) = (position(first), position(second)) {
+            if a > b {
+                return Err((first, second));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Orders of all passes that differ from the given one by moving a single pass,
+/// keeping the passes that must run before another one before it
+pub fn neighbour_pass_orders(order: &[String]) -> Vec<Vec<String>> {
+    let mut orders = Vec::new();
+    for from in 0..order.len() {
+        for to in 0..order.len() {
+            // Moving a pass one step forwards is the same as moving the next one back
+            if from == to || to + 1 == from {
+                continue;
+            }
+            let mut moved = order.to_vec();
+            let pass = moved.remove(from);
+            moved.insert(to, pass);
+            if check_pass_order(&moved).is_ok() {
+                orders.push(moved);
+            }
+        }
+    }
+    orders
+}
+
+/// Names of all passes in the order they are run with the given options
+pub fn pass_order(options: &CompileOptions) -> Vec<String> {
+    ordered_passes(options)
+        .iter()
+        .map(|pass| pass.name.to_owned())
+        .collect()
+}
+
+/// Runs only the named pass, without its cleanup passes. `None` if there is no such pass.
+pub fn run_pass(name: &str, ops: Vec<Instruction>, options: &CompileOptions) -> Option<Vec<Instruction>> {
+    let pass = find_pass(name)?;
+    Some(move_data_to_end((pass.function)(ops, options)))
+}
+
+/// Runs the passes of the pipeline, except the ones disabled in the options
+pub fn optimize(ops: Vec<Instruction>, options: &CompileOptions) -> Vec<Instruction> {
+    optimize_with_sources(ops, options, &LoopSources::new(), &mut Warnings::new()).0
+}
+
+/// Like `optimize`, but also returns the loops each pass removes at their source position,
+/// which are logged, and pushes the warnings about them
+pub fn optimize_with_sources(
+    ops: Vec<Instruction>, options: &CompileOptions, sources: &LoopSources, warnings: &mut Warnings,
+) -> (Vec<Instruction>, LoopNotes) {
+    optimize_with_stats(ops, options, sources, warnings, &mut PassStats::new())
+}
+
+/// Like `optimize_with_sources`, but also adds what each pass did to `stats`
+pub fn optimize_with_stats(
+    mut ops: Vec<Instruction>, options: &CompileOptions, sources: &LoopSources, warnings: &mut Warnings,
+    stats: &mut PassStats,
+) -> (Vec<Instruction>, LoopNotes) {
+    let track_loops = !sources.is_empty();
+    let mut notes = LoopNotes::new();
+    let enabled = |pass: &Pass| {
+        let skipped = pass.grows_code && options.opt_for == OptGoal::Size;
+        !skipped && !options.disabled_passes.iter().any(|d| d == pass.name)
+    };
+    let mut queue: Vec<Pass> = ordered_passes(options).into_iter().filter(enabled).rev().collect();
+    // Passes are pure, so a pass that left its input unchanged will do so again.
+    // The inputs are found by their hash, and compared in full as different ones may share it.
+    let mut no_ops: BTreeMap<(&'static str, u64), Vec<Vec<Instruction>>> = BTreeMap::new();
+    while let Some(pass) = queue.pop() {
+        let input_hash = hash_of(&ops);
+        if no_ops
+            .get(&(pass.name, input_hash))
+            .map_or(false, |inputs| inputs.contains(&ops))
+        {
+            log::trace!("Optimization: {} (skipped, unchanged input)", pass.name);
+            continue;
+        }
+        log::trace!("Optimization: {}", pass.name);
+        let before = ops.clone();
+        ops = (pass.function)(ops, options);
+        ops = move_data_to_end(ops);
+        let stat = stats.entry(pass.name).or_default();
+        stat.runs += 1;
+        if ops.len() < before.len() {
+            stat.removed += before.len() - ops.len();
+        } else {
+            stat.added += ops.len() - before.len();
+        }
+        if options.trace_passes {
+            trace_pass(pass.name, &before, &ops);
+        }
+        if track_loops {
+            for span in removed_loops(&before, &ops, sources) {
+                let note = match pass.loop_message {
+                    Some(message) => message.to_owned(),
+                    None => format!("Removed loop in {}", pass.name),
+                };
+                log::debug!("{} at line {}", note, span);
+                notes.push((span, note));
+                if let Some(warning) = pass.loop_warning {
+                    warnings.push(warning(span));
+                }
+            }
+        }
+        if ops == before {
+            no_ops.entry((pass.name, input_hash)).or_default().push(before);
+        } else {
+            stat.changes += 1;
+        }
+        debug_assert_eq!(
+            unset_flags_jump(&ops),
+            None,
+            "Pass {} left a conditional jump without flags set in its block",
+            pass.name
+        );
+        for p in cleanup_passes(&pass, options).into_iter().filter(enabled) {
+            if queue.last().map(|last| last.name) != Some(p.name) {
+                queue.push(p);
+            }
+        }
+    }
+    // Enabled and disabled along with the pass, which stops at labels
+    if find_pass("optimize_zero_flags").map_or(false, |pass| enabled(&pass)) {
+        let name = "optimize_zero_flags_across_labels";
+        let before = ops.clone();
+        ops = optimize_zero_flags_across_labels(ops);
+        if options.trace_passes {
+            trace_pass(name, &before, &ops);
+        }
+        let stat = stats.entry(name).or_default();
+        stat.runs += 1;
+        if ops != before {
+            stat.changes += 1;
+            stat.removed += before.len() - ops.len();
+        }
+        debug_assert_eq!(
+            unset_flags_jump(&ops),
+            None,
+            "Following the zero flag across labels left a conditional jump without flags set"
+        );
+    }
+    (ops, notes)
+}
+
+// TODO: Future optimizations:
+// `,[>,]` to read until EOF
+// `[.>]` to print null-terminated string, i.e. scan and print
+
+// dec rbx
+// inc byte [rbx]
+// inc rbx
 // dec byte [rbx]
 // TO
 // inc byte [rbx - 1]
 // inc byte [rbx]
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_pass_cleanup, check_pass_order, cleanup_passes, move_data_to_end, neighbour_pass_orders,
+        optimize, optimize_adjacent, optimize_adjancent_mem_movs, optimize_cache_cell,
+        optimize_constant_output, optimize_hoist_stores, optimize_multiply_loop, optimize_redundant_movs,
+        optimize_remove_nops, optimize_remove_unreachable, optimize_scan_loop, optimize_start_cells,
+        optimize_unroll, optimize_with_stats, optimize_zero_flags, optimize_zero_flags_across_labels,
+        optimize_zero_loop, ordered_passes, pass_order, removed_loops, renumber_labels, unset_flags_jump,
+        BTreeMap, LoopSources, Pass, PassStats, ORDER_CONSTRAINTS, PIPELINE,
+    };
+    use crate::error::Warnings;
+    use crate::instruction::Instruction::{self, *};
+    use crate::instruction::Register64::{r11, r12, rbx, rcx, rdi, rdx, rsi};
+    use crate::instruction::{Effects, Register64};
+    use crate::label::LabelId;
+    use crate::options::{CompileOptions, CpuFeature, OptGoal};
+    use crate::parser::Span;
+
+    /// `[<++>-]` with the counter set to `count`
+    fn counted_loop(count: u8, decrement: u8) -> Vec<Instruction> {
+        vec![
+            MovPtr8Imm(rbx, count),
+            IsZeroPtr8(rbx),
+            JumpZero(".end".into()),
+            Label(".start".into()),
+            SubImm(rbx, 1),
+            AddPtr8Imm(rbx, 2),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, decrement),
+            JumpNonZero(".start".into()),
+            Label(".end".into()),
+        ]
+    }
+
+    #[test]
+    fn test_unroll() {
+        let body = vec![
+            SubImm(rbx, 1),
+            AddPtr8Imm(rbx, 2),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 255),
+        ];
+        let mut expected = vec![MovPtr8Imm(rbx, 3)];
+        for _ in 0..3 {
+            expected.extend(body.iter().cloned());
+        }
+        expected.push(Label(".end".into()));
+        assert_eq!(
+            optimize_unroll(counted_loop(3, 255), &CompileOptions::default()),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_unroll_counter_set_earlier() {
+        // `+++>++.<`, then `[<++>-]` on the first cell
+        let mut ops = vec![
+            MovPtr16Imm(rbx, 0x0203),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            MovImm(rdi, 1),
+            Mov(rsi, rbx),
+            MovImm(rdx, 1),
+            NamedBlackBox("write".to_owned(), "call write".to_owned(), Effects::VOLATILE),
+            SubImm(rbx, 1),
+        ];
+        let prefix = ops.clone();
+        ops.extend(counted_loop(3, 255).split_off(1));
+        let unrolled = optimize_unroll(ops.clone(), &CompileOptions::default());
+        assert_eq!(unrolled[..prefix.len()], prefix[..]);
+        assert_eq!(unrolled.len(), prefix.len() + 3 * 4 + 1);
+
+        // Reading input may change the counter
+        let index = ops.iter().position(|op| matches!(op, NamedBlackBox(..))).unwrap();
+        ops[index] = NamedBlackBox("read".to_owned(), "call read".to_owned(), Effects::VOLATILE);
+        assert_eq!(optimize_unroll(ops.clone(), &CompileOptions::default()), ops);
+
+        // So may a multiply-add from another cell
+        let mut ops = vec![MovPtr8Imm(rbx, 3), SubImm(rbx, 1), AddPtr8Mul(rbx, 1, 2), AddImm(rbx, 1)];
+        ops.extend(counted_loop(3, 255).split_off(1));
+        assert_eq!(optimize_unroll(ops.clone(), &CompileOptions::default()), ops);
+    }
+
+    #[test]
+    fn test_unroll_zero_trips() {
+        assert_eq!(
+            optimize_unroll(counted_loop(0, 255), &CompileOptions::default()),
+            vec![
+                MovPtr8Imm(rbx, 0),
+                IsZeroPtr8(rbx),
+                JumpZero(".end".into()),
+                Label(".end".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unroll_other_guard() {
+        // A jump past another loop doesn't skip this one when the counter is zero
+        let mut ops = counted_loop(0, 255);
+        ops[2] = JumpZero(".other".into());
+        ops.push(Label(".other".into()));
+        assert_eq!(optimize_unroll(ops.clone(), &CompileOptions::default()), ops);
+    }
+
+    #[test]
+    fn test_unroll_over_budget() {
+        let options = CompileOptions {
+            max_unroll: 11,
+            ..CompileOptions::default()
+        };
+        assert_eq!(
+            optimize_unroll(counted_loop(3, 255), &options),
+            counted_loop(3, 255)
+        );
+    }
+
+    #[test]
+    fn test_unroll_unpredictable_counter() {
+        let options = CompileOptions::default();
+        assert_eq!(
+            optimize_unroll(counted_loop(4, 254), &options),
+            counted_loop(4, 254)
+        );
+    }
+
+    #[test]
+    fn test_zero_flags() {
+        // `[-]` and `[+]`
+        for &delta in &[255, 1] {
+            let ops = vec![
+                IsZeroPtr8(rbx),
+                JumpZero(".label1".into()),
+                Label(".label0".into()),
+                AddPtr8Imm(rbx, delta),
+                IsZeroPtr8(rbx),
+                JumpNonZero(".label0".into()),
+                Label(".label1".into()),
+            ];
+            let mut expected = ops.clone();
+            expected.remove(4);
+            assert_eq!(optimize_zero_flags(ops), expected);
+        }
+
+        // `[>+<-]`, with flag-neutral instructions in between
+        let ops = vec![
+            Label(".label0".into()),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+            CheckPointer(rbx, r12),
+            AddPtr8Imm(rbx, 255),
+            Mov(rsi, rbx),
+            IsZeroPtr8(rbx),
+            JumpNonZero(".label0".into()),
+        ];
+        let mut expected = ops.clone();
+        expected.remove(7);
+        assert_eq!(optimize_zero_flags(ops), expected);
+
+        // `[->+<]` ends with a pointer move, so the flags are not from the counter
+        let ops = vec![
+            Label(".label0".into()),
+            AddPtr8Imm(rbx, 255),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+            CheckPointer(rbx, r12),
+            IsZeroPtr8(rbx),
+            JumpNonZero(".label0".into()),
+        ];
+        assert_eq!(optimize_zero_flags(ops.clone()), ops);
+
+        // The cell or the pointer changes after the add
+        for op in &[MovPtr8Imm(rbx, 0), Mov(rbx, rsi)] {
+            let ops = vec![
+                AddPtr8Imm(rbx, 1),
+                op.clone(),
+                IsZeroPtr8(rbx),
+                JumpNonZero(".label0".into()),
+            ];
+            assert_eq!(optimize_zero_flags(ops.clone()), ops);
+        }
+
+        // `[-][`, the flag tested at the end of the loop is known after the label it jumps past
+        let ops = vec![
+            IsZeroPtr8(rbx),
+            JumpZero(".label1".into()),
+            Label(".label0".into()),
+            AddPtr8Imm(rbx, 255),
+            JumpNonZero(".label0".into()),
+            Label(".label1".into()),
+            IsZeroPtr8(rbx),
+            JumpZero(".label2".into()),
+        ];
+        let mut expected = ops.clone();
+        expected.remove(6);
+        assert_eq!(optimize_zero_flags(ops.clone()), ops);
+        assert_eq!(optimize_zero_flags_across_labels(ops), expected);
+
+        // A jump with flags of a moved pointer, or a label reached from outside, keeps the test
+        let ops = vec![
+            AddPtr8Imm(rbx, 1),
+            JumpZero(".label0".into()),
+            AddImm(rbx, 1),
+            Label(".label0".into()),
+            IsZeroPtr8(rbx),
+        ];
+        assert_eq!(optimize_zero_flags_across_labels(ops.clone()), ops);
+        let ops = vec![AddPtr8Imm(rbx, 1), Label("global".into()), IsZeroPtr8(rbx)];
+        assert_eq!(optimize_zero_flags_across_labels(ops.clone()), ops);
+    }
+
+    #[test]
+    fn test_start_cells() {
+        let ops = vec![
+            AddPtr8Imm(rbx, 2),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 0),
+            IsZeroPtr8(rbx),
+            JumpZero(".label1".into()),
+        ];
+        let expected = vec![
+            MovPtr8Imm(rbx, 2),
+            AddImm(rbx, 1),
+            IsZeroPtr8(rbx),
+            JumpZero(".label1".into()),
+        ];
+        assert_eq!(optimize_start_cells(ops), expected);
+
+        // 256 `+` with the check removed, so the jump reads the flags of adding zero
+        let ops = vec![AddPtr8Imm(rbx, 0), JumpZero(".label1".into())];
+        assert_eq!(optimize_start_cells(ops.clone()), ops);
+
+        // A zero-add second, between two adds to the same cell
+        let ops = vec![
+            AddPtr8Imm(rbx, 5),
+            AddPtr8Imm(rbx, 0),
+            AddPtr8Imm(rbx, 3),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 7),
+        ];
+        let expected = vec![
+            MovPtr8Imm(rbx, 5),
+            AddPtr8Imm(rbx, 3),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 7),
+        ];
+        assert_eq!(optimize_start_cells(ops), expected);
+
+        // Zero-adds first and second, the whole program being adds
+        let ops = vec![
+            AddPtr8Imm(rbx, 0),
+            AddPtr8Imm(rbx, 0),
+            AddImm(rbx, 2),
+            AddPtr8Imm(rbx, 9),
+        ];
+        assert_eq!(optimize_start_cells(ops), vec![
+            AddImm(rbx, 2),
+            MovPtr8Imm(rbx, 9)
+        ]);
+
+        // Moving left may reach a cell that was already stored
+        let ops = vec![AddPtr8Imm(rbx, 1), SubImm(rbx, 1), AddPtr8Imm(rbx, 1)];
+        assert_eq!(optimize_start_cells(ops), vec![
+            MovPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+        ]);
+    }
+
+    #[test]
+    fn test_unset_flags_jump() {
+        let ops = vec![
+            IsZeroPtr8(rbx),
+            JumpZero(".label1".into()),
+            Label(".label0".into()),
+            AddPtr8Imm(rbx, 255),
+            MovImm(rsi, 1),
+            JumpNonZero(".label0".into()),
+            JumpZero(".label1".into()),
+            Label(".label1".into()),
+        ];
+        assert_eq!(unset_flags_jump(&ops), None);
+        assert_eq!(unset_flags_jump(&ops[1..]), Some(0));
+
+        // A call, `xor` zeroing a register, or a store in between leaves the flags stale
+        for op in &[
+            NamedBlackBox("putchar".to_owned(), "call putchar".to_owned(), Effects::VOLATILE),
+            MovImm(rsi, 0),
+            MovPtr8Imm(rbx, 1),
+        ] {
+            let mut ops = ops.clone();
+            ops[4] = op.clone();
+            assert_eq!(unset_flags_jump(&ops), Some(5));
+        }
+
+        // The flags of the check before the loop reach the jump every way around the loop,
+        // and the loop is fine once no way reaches it
+        let mut ops = ops;
+        ops.remove(3);
+        assert_eq!(unset_flags_jump(&ops), None);
+        ops.insert(2, Jump(".label1".into()));
+        assert_eq!(unset_flags_jump(&ops), None);
+
+        // The compares of the target ABI, and a counter decremented just before the jump
+        let ops = vec![
+            BlackBox("cmp rax, -1".to_owned(), Effects::VOLATILE),
+            JumpZero(".label0".into()),
+            Label(".label0".into()),
+            SubImm(rcx, 1),
+            JumpNonZero(".label0".into()),
+        ];
+        assert_eq!(unset_flags_jump(&ops), None);
+        assert_eq!(unset_flags_jump(&ops[2..]), None);
+        let mut ops = ops;
+        ops[0] = BlackBox("mov rax, -1".to_owned(), Effects::VOLATILE);
+        assert_eq!(unset_flags_jump(&ops), Some(1));
+
+        // A label is reached from the start without the flags set, and from a check with them
+        let ops = vec![
+            Jump(".label1".into()),
+            Label(".label0".into()),
+            IsZeroPtr8(rbx),
+            Label(".label1".into()),
+            JumpZero(".label0".into()),
+        ];
+        assert_eq!(unset_flags_jump(&ops), Some(4));
+        assert_eq!(unset_flags_jump(&ops[1..]), None);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "left a conditional jump without flags set")]
+    fn test_unset_flags_assertion() {
+        // The call clobbers the flags set by the compare
+        let ops = vec![
+            IsZeroPtr8(rbx),
+            NamedBlackBox("putchar".to_owned(), "call putchar".to_owned(), Effects::VOLATILE),
+            JumpZero(".label0".into()),
+            AddPtr8Imm(rbx, 1),
+            Label(".label0".into()),
+        ];
+        optimize(ops, &CompileOptions::default());
+    }
+
+    #[test]
+    fn test_zero_loop() {
+        // `[-]-`
+        let ops = vec![
+            IsZeroPtr8(rbx),
+            JumpZero(".label1".into()),
+            Label(".label0".into()),
+            AddPtr8Imm(rbx, 255),
+            IsZeroPtr8(rbx),
+            JumpNonZero(".label0".into()),
+            Label(".label1".into()),
+            AddPtr8Imm(rbx, 255),
+        ];
+        assert_eq!(optimize_zero_loop(ops.clone()), vec![
+            MovPtr8Imm(rbx, 0),
+            Label(".label1".into()),
+            AddPtr8Imm(rbx, 255),
+        ]);
+        assert_eq!(optimize(ops, &CompileOptions::default()), vec![MovPtr8Imm(
+            rbx, 255
+        )]);
+
+        // `[-][-]` stores to the same cell twice
+        let ops = vec![
+            Label(".label0".into()),
+            AddPtr8Imm(rbx, 255),
+            JumpNonZero(".label0".into()),
+            Label(".label1".into()),
+            AddPtr8Imm(rbx, 1),
+            JumpNonZero(".label1".into()),
+        ];
+        assert_eq!(optimize(ops, &CompileOptions::default()), vec![MovPtr8Imm(
+            rbx, 0
+        )]);
+    }
+
+    /// Writes the cell under `r0` to stdout
+    fn write(r0: Register64) -> Vec<Instruction> {
+        vec![
+            MovImm(rdi, 1),
+            Mov(rsi, r0),
+            MovImm(rdx, 1),
+            NamedBlackBox("write".to_owned(), "call write".to_owned(), Effects::VOLATILE),
+        ]
+    }
+
+    #[test]
+    fn test_cache_cell() {
+        let label = || LabelId::from(".label0");
+        let ops = vec![
+            Label(label()),
+            AddPtr8Imm(rbx, 2),
+            Mov(rsi, rbx),
+            AddPtr8Imm(rbx, 3),
+            AddImm(rdx, 1),
+            AddPtr8Imm(rbx, 4),
+            IsZeroPtr8(rbx),
+            JumpNonZero(label()),
+            // A single add is cheaper in memory, and the pointer move ends the block
+            AddPtr8Imm(rbx, 1),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            MovImm(rdi, 0),
+            AddPtr8Imm(rbx, 1),
+        ];
+        let cached = vec![
+            Label(label()),
+            MovRegPtr8(r11, rbx),
+            AddReg8Imm(r11, 2),
+            Mov(rsi, rbx),
+            AddReg8Imm(r11, 3),
+            AddImm(rdx, 1),
+            AddReg8Imm(r11, 4),
+            IsZero(r11),
+            // Stored before the jump, which reads the flags set by the test
+            MovPtr8Reg(rbx, r11),
+            JumpNonZero(label()),
+        ];
+        let mut expected = cached.clone();
+        expected.extend(ops[8..].iter().cloned());
+        assert_eq!(optimize_cache_cell(ops), expected);
+
+        // The last add already set the flags for the cached cell
+        let mut expected = cached.clone();
+        expected.remove(7);
+        assert_eq!(optimize_zero_flags(cached), expected);
+        assert_eq!(unset_flags_jump(&expected), None);
+    }
+
+    #[test]
+    fn test_redundant_movs() {
+        // `.+.`, the arguments must be set again as the call overwrites them
+        let mut ops = write(rbx);
+        ops.push(AddPtr8Imm(rbx, 1));
+        ops.extend(write(rbx));
+        assert_eq!(optimize_redundant_movs(ops.clone()), ops);
+
+        // Values of registers preserved by calls are still known after them
+        let mut ops = vec![MovImm(r12, 5)];
+        ops.extend(write(rbx));
+        ops.push(MovImm(r12, 5));
+        ops.extend(write(r12));
+        let mut expected = ops.clone();
+        expected.remove(5);
+        assert_eq!(optimize_redundant_movs(ops), expected);
+
+        // Loading a cell overwrites the register
+        let ops = vec![MovImm(r12, 5), MovRegPtr8(r12, rbx), MovImm(r12, 5)];
+        assert_eq!(optimize_redundant_movs(ops.clone()), ops);
+
+        // `..`, the pointer is copied to `rsi` again for the second call though it didn't move
+        let mut ops = write(rbx);
+        ops.extend(write(rbx));
+        assert_eq!(optimize_redundant_movs(ops.clone()), ops);
+
+        // A copy of the pointer in a register preserved by calls is kept until either register changes
+        let mut ops = vec![Mov(r12, rbx)];
+        ops.extend(write(rbx));
+        ops.push(Mov(r12, rbx));
+        let mut expected = ops.clone();
+        expected.pop();
+        assert_eq!(optimize_redundant_movs(ops), expected);
+        let ops = vec![Mov(r12, rbx), AddImm(rbx, 1), Mov(r12, rbx)];
+        assert_eq!(optimize_redundant_movs(ops.clone()), ops);
+
+        // Values set before both the jump to a label and the fall through to it are known after it
+        let ops = vec![
+            MovImm(r12, 5),
+            JumpZero(".label0".into()),
+            AddImm(rbx, 1),
+            Label(".label0".into()),
+            MovImm(r12, 5),
+        ];
+        let mut expected = ops.clone();
+        expected.pop();
+        assert_eq!(optimize_redundant_movs(ops), expected);
+
+        // but not if a jump back to the label changes them
+        let ops = vec![
+            MovImm(r12, 5),
+            Label(".label0".into()),
+            MovImm(r12, 5),
+            AddImm(r12, 1),
+            JumpNonZero(".label0".into()),
+        ];
+        assert_eq!(optimize_redundant_movs(ops.clone()), ops);
+    }
+
+    #[test]
+    fn test_constant_output_at_end() {
+        // Writes ending the instructions are kept, e.g. at the end of a streamed window
+        let mut ops = vec![MovPtr8Imm(rbx, 3)];
+        ops.extend(write(rbx));
+        let mut expected = vec![MovPtr8Imm(rbx, 3), MovImm(rdi, 1)];
+        expected.push(MovImmVar(rsi, "constant_output0".to_owned()));
+        expected.extend(write(rbx)[2..].iter().cloned());
+        expected.push(Data("constant_output0".to_owned(), vec![3]));
+        assert_eq!(
+            optimize_constant_output(ops.clone(), &CompileOptions::default()),
+            expected
+        );
+
+        // The length is referred to by the symbol defined after the data
+        let options = CompileOptions {
+            data_length_symbols: true,
+            ..CompileOptions::default()
+        };
+        expected[3] = MovImmVar(rdx, "constant_output0_len".to_owned());
+        assert_eq!(optimize_constant_output(ops, &options), expected);
+    }
+
+    #[test]
+    fn test_constant_output() {
+        let store_and_write = |v: u8| {
+            let mut ops = vec![MovPtr8Imm(rbx, v)];
+            ops.extend(write(rbx));
+            ops
+        };
+        let constant_write = |name: &str, len: u64| {
+            vec![
+                MovImm(rdi, 1),
+                MovImmVar(rsi, name.to_owned()),
+                MovImm(rdx, len),
+                NamedBlackBox("write".to_owned(), "call write".to_owned(), Effects::VOLATILE),
+            ]
+        };
+
+        // `Hi!` written a byte at a time, then an add interrupting the run before `ab`
+        let mut ops: Vec<_> = b"Hi!".iter().flat_map(|&v| store_and_write(v)).collect();
+        ops.push(AddPtr8Imm(rbx, 1));
+        ops.extend(b"ab".iter().flat_map(|&v| store_and_write(v)));
+
+        // The bytes so far are flushed before the add, which sees the last stored value
+        let mut expected = vec![MovPtr8Imm(rbx, b'!')];
+        expected.extend(constant_write("constant_output0", 3));
+        expected.push(AddPtr8Imm(rbx, 1));
+        expected.push(MovPtr8Imm(rbx, b'b'));
+        expected.extend(constant_write("constant_output1", 2));
+        expected.push(Data("constant_output0".to_owned(), b"Hi!".to_vec()));
+        expected.push(Data("constant_output1".to_owned(), b"ab".to_vec()));
+        assert_eq!(optimize_constant_output(ops, &CompileOptions::default()), expected);
+
+        // Each form of write gets its own run
+        let write_underscore = NamedBlackBox("write".to_owned(), "call _write".to_owned(), Effects::VOLATILE);
+        let mut ops = store_and_write(b'a');
+        ops.extend(store_and_write(b'b'));
+        ops.extend(store_and_write(b'c'));
+        ops[14] = write_underscore.clone();
+        let mut expected = vec![MovPtr8Imm(rbx, b'b')];
+        expected.extend(constant_write("constant_output0", 2));
+        expected.push(MovPtr8Imm(rbx, b'c'));
+        expected.extend(constant_write("constant_output1", 1));
+        expected[9] = write_underscore;
+        expected.push(Data("constant_output0".to_owned(), b"ab".to_vec()));
+        expected.push(Data("constant_output1".to_owned(), b"c".to_vec()));
+        assert_eq!(optimize_constant_output(ops, &CompileOptions::default()), expected);
+    }
+
+    #[test]
+    fn test_vector_mem_movs() {
+        let stores = |count: u8| -> Vec<Instruction> {
+            (0..count)
+                .flat_map(|imm| vec![MovPtr8Imm(rbx, imm + 1), AddImm(rbx, 1)])
+                .collect()
+        };
+        let bytes: Vec<u8> = (1..=16).collect();
+        let label = "vector_0102030405060708090a0b0c0d0e0f10".to_owned();
+
+        // Without a target CPU at most four cells are combined
+        assert_eq!(
+            optimize_adjancent_mem_movs(stores(16), &CompileOptions::default())[..2],
+            [MovPtr32Imm(rbx, 0x0403_0201), AddImm(rbx, 4)]
+        );
+
+        let options = CompileOptions {
+            target_cpu: Some(CpuFeature::Sse2),
+            ..CompileOptions::default()
+        };
+        assert_eq!(optimize_adjancent_mem_movs(stores(19), &options), vec![
+            MovPtr128(rbx, label.clone()),
+            AddImm(rbx, 16),
+            MovPtr16Imm(rbx, 0x1211),
+            AddImm(rbx, 2),
+            MovPtr8Imm(rbx, 19),
+            AddImm(rbx, 1),
+            Data(label.clone(), bytes.clone()),
+        ]);
+
+        // Runs shorter than a 32-byte vector still use 16-byte ones
+        let options = CompileOptions {
+            target_cpu: Some(CpuFeature::Avx2),
+            ..CompileOptions::default()
+        };
+        let result = optimize_adjancent_mem_movs(stores(20), &options);
+        assert_eq!(result[0], MovPtr128(rbx, label.clone()));
+        assert_eq!(result.last(), Some(&Data(label, bytes)));
+        let result = optimize_adjancent_mem_movs(stores(32), &options);
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[1], AddImm(rbx, 32));
+    }
+
+    /// Loop as lowered from `[` body `]`
+    fn bf_loop(body: Vec<Instruction>) -> Vec<Instruction> {
+        let mut ops = vec![
+            IsZeroPtr8(rbx),
+            JumpZero(".end".into()),
+            Label(".start".into()),
+        ];
+        ops.extend(body);
+        ops.extend(vec![
+            IsZeroPtr8(rbx),
+            JumpNonZero(".start".into()),
+            Label(".end".into()),
+        ]);
+        ops
+    }
+
+    /// Multiply-adds between the check skipping the loop and its end label
+    fn multiply_adds(adds: Vec<Instruction>) -> Vec<Instruction> {
+        let mut ops = vec![IsZeroPtr8(rbx), JumpZero(".end".into())];
+        ops.extend(adds);
+        ops.push(MovPtr8Imm(rbx, 0));
+        ops.push(Label(".end".into()));
+        ops
+    }
+
+    #[test]
+    fn test_multiply_loop() {
+        // `[->+>+<<]`
+        let duplicate = bf_loop(vec![
+            AddPtr8Imm(rbx, 255),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            SubImm(rbx, 2),
+            CheckPointer(rbx, r12),
+        ]);
+        assert_eq!(
+            optimize_multiply_loop(duplicate),
+            multiply_adds(vec![AddPtr8Mul(rbx, 1, 1), AddPtr8Mul(rbx, 2, 1)])
+        );
+
+        // `[>+>+>+<<<-]`, with the counter decremented last
+        let triplicate = bf_loop(vec![
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            SubImm(rbx, 3),
+            CheckPointer(rbx, r12),
+            AddPtr8Imm(rbx, 255),
+        ]);
+        assert_eq!(
+            optimize_multiply_loop(triplicate),
+            multiply_adds(vec![
+                AddPtr8Mul(rbx, 1, 1),
+                AddPtr8Mul(rbx, 2, 1),
+                AddPtr8Mul(rbx, 3, 1),
+            ])
+        );
+
+        // `[->+++>>--<<<<+>]`: each offset gets its own factor, the left one is checked first
+        let factors = bf_loop(vec![
+            AddPtr8Imm(rbx, 255),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 3),
+            AddImm(rbx, 2),
+            AddPtr8Imm(rbx, 254),
+            SubImm(rbx, 4),
+            CheckPointer(rbx, r12),
+            AddPtr8Imm(rbx, 1),
+            AddImm(rbx, 1),
+        ]);
+        assert_eq!(
+            optimize_multiply_loop(factors),
+            multiply_adds(vec![
+                SubImm(rbx, 1),
+                CheckPointer(rbx, r12),
+                AddImm(rbx, 1),
+                AddPtr8Mul(rbx, -1, 1),
+                AddPtr8Mul(rbx, 1, 3),
+                AddPtr8Mul(rbx, 3, 254),
+            ])
+        );
+
+        // `[>+<-]`, a destructive add of the counter to the next cell
+        let add = bf_loop(vec![
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+            CheckPointer(rbx, r12),
+            AddPtr8Imm(rbx, 255),
+        ]);
+        assert_eq!(
+            optimize_multiply_loop(add),
+            multiply_adds(vec![AddPtr8Mul(rbx, 1, 1)])
+        );
+
+        // `[->+>+<<]>>[-<<+>>]`, a copy keeping the counter, is two destructive adds
+        let mut copy = bf_loop(vec![
+            AddPtr8Imm(rbx, 255),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            SubImm(rbx, 2),
+            CheckPointer(rbx, r12),
+        ]);
+        copy.push(AddImm(rbx, 2));
+        let move_back = vec![
+            IsZeroPtr8(rbx),
+            JumpZero(".end2".into()),
+            Label(".start2".into()),
+            AddPtr8Imm(rbx, 255),
+            SubImm(rbx, 2),
+            CheckPointer(rbx, r12),
+            AddPtr8Imm(rbx, 1),
+            AddImm(rbx, 2),
+            IsZeroPtr8(rbx),
+            JumpNonZero(".start2".into()),
+            Label(".end2".into()),
+        ];
+        copy.extend(move_back);
+        let mut expected = multiply_adds(vec![AddPtr8Mul(rbx, 1, 1), AddPtr8Mul(rbx, 2, 1)]);
+        expected.extend(vec![
+            AddImm(rbx, 2),
+            IsZeroPtr8(rbx),
+            JumpZero(".end2".into()),
+            SubImm(rbx, 2),
+            CheckPointer(rbx, r12),
+            AddImm(rbx, 2),
+            AddPtr8Mul(rbx, -2, 1),
+            MovPtr8Imm(rbx, 0),
+            Label(".end2".into()),
+        ]);
+        assert_eq!(optimize_multiply_loop(copy), expected);
+
+        // The counter is not decremented once per iteration
+        let ops = bf_loop(vec![
+            AddPtr8Imm(rbx, 254),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+        ]);
+        assert_eq!(optimize_multiply_loop(ops.clone()), ops);
+
+        // `[-->++<]` only exits for even counters
+        let ops = bf_loop(vec![
+            AddPtr8Imm(rbx, 254),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 2),
+            SubImm(rbx, 1),
+            CheckPointer(rbx, r12),
+        ]);
+        assert_eq!(optimize_multiply_loop(ops.clone()), ops);
+
+        // `[+>+<]` counts up, so its trip count isn't the counter
+        let ops = bf_loop(vec![
+            AddPtr8Imm(rbx, 1),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+            CheckPointer(rbx, r12),
+        ]);
+        assert_eq!(optimize_multiply_loop(ops.clone()), ops);
+    }
+
+    #[test]
+    fn test_hoist_stores() {
+        // `[>[-]+<-]`
+        let ops = bf_loop(vec![
+            AddImm(rbx, 1),
+            MovPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+            CheckPointer(rbx, r12),
+            AddPtr8Imm(rbx, 255),
+        ]);
+        let mut expected = vec![
+            IsZeroPtr8(rbx),
+            JumpZero(".end".into()),
+            AddImm(rbx, 1),
+            MovPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+        ];
+        let body = vec![AddImm(rbx, 1), SubImm(rbx, 1), CheckPointer(rbx, r12), AddPtr8Imm(rbx, 255)];
+        expected.extend(bf_loop(body).split_off(2));
+        assert_eq!(optimize_hoist_stores(ops), expected);
+
+        // `[>[-]>+<<-]`, where the add to the next cell doesn't alias the store
+        let ops = bf_loop(vec![
+            AddImm(rbx, 1),
+            MovPtr8Imm(rbx, 0),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            SubImm(rbx, 2),
+            CheckPointer(rbx, r12),
+            AddPtr8Imm(rbx, 255),
+        ]);
+        assert_eq!(optimize_hoist_stores(ops)[2..5], [AddImm(rbx, 1), MovPtr8Imm(rbx, 0), SubImm(rbx, 1)]);
+
+        // `[>+[-]+<-]` reads the cell before the store
+        let ops = bf_loop(vec![
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            MovPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+            CheckPointer(rbx, r12),
+            AddPtr8Imm(rbx, 255),
+        ]);
+        assert_eq!(optimize_hoist_stores(ops.clone()), ops);
+
+        // `[>[-]+<->+<]` adds to the cell after the store
+        let ops = bf_loop(vec![
+            AddImm(rbx, 1),
+            MovPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+            CheckPointer(rbx, r12),
+            AddPtr8Imm(rbx, 255),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 1),
+            SubImm(rbx, 1),
+        ]);
+        assert_eq!(optimize_hoist_stores(ops.clone()), ops);
+
+        // A multiply-add to the cell after the store
+        let ops = bf_loop(vec![
+            AddImm(rbx, 1),
+            MovPtr8Imm(rbx, 0),
+            SubImm(rbx, 1),
+            AddPtr8Mul(rbx, 1, 2),
+            AddPtr8Imm(rbx, 255),
+        ]);
+        assert_eq!(optimize_hoist_stores(ops.clone()), ops);
+
+        // `[>[-]+]` doesn't return to the counter, so each iteration stores to a different cell
+        let ops = bf_loop(vec![AddImm(rbx, 1), MovPtr8Imm(rbx, 1)]);
+        assert_eq!(optimize_hoist_stores(ops.clone()), ops);
+
+        // `[<[-]+>-]` stores left of the counter, before which the pointer is checked
+        let ops = bf_loop(vec![
+            SubImm(rbx, 1),
+            CheckPointer(rbx, r12),
+            MovPtr8Imm(rbx, 1),
+            AddImm(rbx, 1),
+            AddPtr8Imm(rbx, 255),
+        ]);
+        assert_eq!(optimize_hoist_stores(ops.clone()), ops);
+
+        // `[>[-]+.<-]` has output between the store and the end of the body
+        let ops = bf_loop(vec![
+            AddImm(rbx, 1),
+            MovPtr8Imm(rbx, 1),
+            NamedBlackBox("write".to_owned(), "call write".to_owned(), Effects::VOLATILE),
+            SubImm(rbx, 1),
+            CheckPointer(rbx, r12),
+            AddPtr8Imm(rbx, 255),
+        ]);
+        assert_eq!(optimize_hoist_stores(ops.clone()), ops);
+    }
+
+    #[test]
+    fn test_renumber_labels() {
+        let ops = vec![
+            Label(".label3".into()),
+            CheckPointer(rbx, r12),
+            JumpZero(".jump_skip_recheck0".into()),
+            Label(".interface_linux1".into()),
+            JumpNonZero(".label3".into()),
+            Label(".jump_skip_recheck0".into()),
+            MovImmVar(rsi, "constant_output0".to_owned()),
+            Jump(".interface_linux1".into()),
+            Label("pointer_underflow".into()),
+        ];
+        let expected = vec![
+            Label(".@0".into()),
+            CheckPointer(rbx, r12),
+            JumpZero(".@2".into()),
+            Label(".@1".into()),
+            JumpNonZero(".@0".into()),
+            Label(".@2".into()),
+            MovImmVar(rsi, "constant_output0".to_owned()),
+            Jump(".@1".into()),
+            Label("pointer_underflow".into()),
+        ];
+        assert_eq!(renumber_labels(ops), expected);
+
+        // A label named in the source of another instruction keeps its name
+        let ops = vec![
+            Label(".interface_bare_metal0".into()),
+            BlackBox("jmp .interface_bare_metal0".to_owned(), Effects::VOLATILE),
+        ];
+        assert_eq!(renumber_labels(ops.clone()), ops);
+    }
+
+    #[test]
+    fn test_removed_loops() {
+        let span = |offset| Span {
+            offset,
+            line: 1,
+            column: offset + 1,
+        };
+        let sources: LoopSources = vec![(".start".into(), span(3)), (".other".into(), span(0))]
+            .into_iter()
+            .collect();
+        let ops = bf_loop(vec![AddPtr8Imm(rbx, 255)]);
+        assert_eq!(
+            removed_loops(&ops, &optimize_zero_loop(ops.clone()), &sources),
+            vec![span(3)]
+        );
+        assert_eq!(removed_loops(&ops, &ops, &sources), vec![]);
+    }
+
+    #[test]
+    fn test_scan_loop() {
+        let scan = |step: Instruction, check: bool| {
+            let mut ops = vec![
+                IsZeroPtr8(rbx),
+                JumpZero(".end".into()),
+                Label(".start".into()),
+                step,
+            ];
+            if check {
+                ops.push(CheckPointer(rbx, r12));
+            }
+            ops.extend(vec![
+                IsZeroPtr8(rbx),
+                JumpNonZero(".start".into()),
+                Label(".end".into()),
+            ]);
+            ops
+        };
+        // `[>]`, `[>>]` and `[>>>]`, then `[<]`, `[<<]` and `[<<<]` with and without the pointer check
+        let cases = [1, 2, 3]
+            .iter()
+            .map(|&n| (scan(AddImm(rbx, n), false), SubImm(rbx, n)))
+            .chain([1, 2, 3].iter().flat_map(|&n| {
+                vec![
+                    (scan(SubImm(rbx, n), true), AddImm(rbx, n)),
+                    (scan(SubImm(rbx, n), false), AddImm(rbx, n)),
+                ]
+            }));
+        for (ops, back) in cases {
+            let mut expected = vec![back.clone()];
+            expected.extend(ops[2..].iter().cloned());
+            assert_eq!(optimize_scan_loop(ops.clone()), expected);
+            // The loop still sets the flags of the jumps, and exits with the zero flag of the check.
+            // The cells are zero at the start of the program, so the scan follows a read.
+            let read = BlackBox("call read".to_owned(), Effects::VOLATILE);
+            let optimized = optimize([vec![read], ops].concat(), &CompileOptions::default());
+            assert_eq!(unset_flags_jump(&optimized), None);
+            assert_eq!(optimized[1], back);
+            assert_eq!(optimized[optimized.len() - 2..], [
+                IsZeroPtr8(rbx),
+                JumpNonZero(".start".into())
+            ]);
+        }
+
+        // The body changes a cell
+        let mut ops = scan(AddImm(rbx, 1), false);
+        ops.insert(4, AddPtr8Imm(rbx, 1));
+        assert_eq!(optimize_scan_loop(ops.clone()), ops);
+    }
+
+    #[test]
+    fn test_remove_unreachable() {
+        // `+[]` never exits, so nothing after it runs
+        let ops = vec![
+            AddPtr8Imm(rbx, 1),
+            Label(".start".into()),
+            Jump(".start".into()),
+            AddImm(rbx, 1),
+            Label(".other".into()),
+            MovPtr8Imm(rbx, 0),
+            Data("constant_output0".to_owned(), vec![1]),
+        ];
+        assert_eq!(
+            optimize_remove_unreachable(ops.clone()),
+            [ops[..3].to_vec(), ops[6..].to_vec()].concat()
+        );
+
+        // Both ways of a conditional jump are reached, and code jumped to is kept
+        let ops = vec![
+            IsZeroPtr8(rbx),
+            JumpZero(".end".into()),
+            Jump(".body".into()),
+            AddImm(rbx, 1),
+            Label(".body".into()),
+            AddPtr8Imm(rbx, 1),
+            Label(".end".into()),
+            CheckPointer(rbx, r12),
+        ];
+        let mut expected = ops.clone();
+        expected.remove(3);
+        assert_eq!(optimize_remove_unreachable(ops), expected);
+    }
+
+    #[test]
+    fn test_adjacent_net_zero() {
+        // The jump reads the zero flag of the sub, so the add left of the pair is no nop
+        let ops = vec![
+            Label(".label0".into()),
+            AddImm(rbx, 2),
+            SubImm(rbx, 2),
+            JumpNonZero(".label0".into()),
+        ];
+        let combined = optimize_adjacent(ops);
+        assert_eq!(combined[1..], [AddImm(rbx, 0), JumpNonZero(".label0".into())]);
+        assert_eq!(optimize_remove_nops(combined.clone()), combined);
+
+        // Flags set again before the jump are dead, so the add is removed
+        let ops = vec![AddImm(rbx, 2), SubImm(rbx, 2), IsZeroPtr8(rbx), JumpZero(".end".into())];
+        assert_eq!(optimize_remove_nops(optimize_adjacent(ops)), [
+            IsZeroPtr8(rbx),
+            JumpZero(".end".into())
+        ]);
+    }
+
+    #[test]
+    fn test_pipeline() {
+        // Cleanup passes are defined earlier, so the order of the pipeline is clear
+        for (index, pass) in PIPELINE.iter().enumerate() {
+            for cleanup in pass.cleanup {
+                assert!(
+                    PIPELINE[..index].iter().any(|p| p.name == *cleanup),
+                    "{} is not defined before {}",
+                    cleanup,
+                    pass.name
+                );
+            }
+            assert!(PIPELINE[..index].iter().all(|p| p.name != pass.name));
+        }
+
+        // `[-]` is kept as a loop
+        let ops = vec![
+            Label(".label0".into()),
+            AddPtr8Imm(rbx, 255),
+            JumpNonZero(".label0".into()),
+        ];
+        let options = CompileOptions {
+            disabled_passes: vec![
+                "optimize_zero_loop".to_owned(),
+                "optimize_multiply_loop".to_owned(),
+            ],
+            ..CompileOptions::default()
+        };
+        assert_eq!(optimize(ops.clone(), &options), ops);
+
+        // Unrolling is skipped when optimizing for size
+        let options = CompileOptions {
+            opt_for: OptGoal::Size,
+            disabled_passes: vec!["optimize_multiply_loop".to_owned()],
+            ..CompileOptions::default()
+        };
+        let optimized = optimize(counted_loop(3, 255), &options);
+        assert!(optimized.contains(&JumpNonZero(".start".into())));
+        let optimized = optimize(counted_loop(3, 255), &CompileOptions::default());
+        assert!(!optimized.contains(&JumpNonZero(".start".into())));
+    }
+
+    #[test]
+    fn test_pass_order() {
+        let default = pass_order(&CompileOptions::default());
+        assert_eq!(default.len(), PIPELINE.len());
+        assert_eq!(check_pass_order(&default), Ok(()));
+
+        // Named passes run first, the rest keep their order
+        let options = CompileOptions {
+            pass_order: vec!["optimize_exit".to_owned(), "optimize_zero_loop".to_owned()],
+            ..CompileOptions::default()
+        };
+        let order = pass_order(&options);
+        assert_eq!(order[..2], options.pass_order[..]);
+        assert_eq!(order.len(), PIPELINE.len());
+        let rest: Vec<_> = default.iter().filter(|name| !options.pass_order.contains(name)).collect();
+        assert!(order[2..].iter().eq(rest));
+
+        let (first, second) = ORDER_CONSTRAINTS[0];
+        let reversed = vec![second.to_owned(), first.to_owned()];
+        assert_eq!(check_pass_order(&reversed), Err((first, second)));
+
+        let neighbours = neighbour_pass_orders(&default);
+        assert!(!neighbours.is_empty());
+        for order in &neighbours {
+            assert_ne!(*order, default);
+            assert_eq!(check_pass_order(order), Ok(()));
+            let mut sorted = order.clone();
+            sorted.sort();
+            let mut expected = default.clone();
+            expected.sort();
+            assert_eq!(sorted, expected);
+        }
+        let mut unique = neighbours.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), neighbours.len());
+    }
+
+    #[test]
+    fn test_pass_cleanup() {
+        assert_eq!(check_pass_cleanup(&BTreeMap::new()), Ok(()));
+
+        // optimize_zero_loop is cleaned up by optimize_remove_unused_labels
+        let mut cleanup = BTreeMap::new();
+        cleanup.insert(
+            "optimize_remove_unused_labels".to_owned(),
+            vec!["optimize_zero_loop".to_owned()],
+        );
+        assert!(check_pass_cleanup(&cleanup).is_err());
+        cleanup.insert("optimize_zero_loop".to_owned(), Vec::new());
+        assert_eq!(check_pass_cleanup(&cleanup), Ok(()));
+        cleanup.insert("optimize_exit".to_owned(), vec!["optimize_exit".to_owned()]);
+        assert_eq!(
+            check_pass_cleanup(&cleanup),
+            Err(vec!["optimize_exit".to_owned(), "optimize_exit".to_owned()])
+        );
+
+        // Without cleanup passes, each pass runs once
+        let options = CompileOptions {
+            pass_cleanup: PIPELINE.iter().map(|pass| (pass.name.to_owned(), Vec::new())).collect(),
+            ..CompileOptions::default()
+        };
+        let mut stats = PassStats::new();
+        optimize_with_stats(
+            counted_loop(3, 255),
+            &options,
+            &LoopSources::new(),
+            &mut Warnings::new(),
+            &mut stats,
+        );
+        assert!(stats.values().all(|stat| stat.runs == 1));
+    }
+
+    #[test]
+    fn test_no_op_passes_skipped() {
+        // Running every pass of the queue gives the same as skipping the ones that changed nothing
+        let options = CompileOptions::default();
+        for ops in vec![counted_loop(3, 255), loop_nests(2)] {
+            let mut queue: Vec<Pass> = ordered_passes(&options).into_iter().rev().collect();
+            let mut expected = ops.clone();
+            let mut runs = 0;
+            while let Some(pass) = queue.pop() {
+                expected = move_data_to_end((pass.function)(expected, &options));
+                runs += 1;
+                for p in cleanup_passes(&pass, &options) {
+                    if queue.last().map(|last| last.name) != Some(p.name) {
+                        queue.push(p);
+                    }
+                }
+            }
+            let mut stats = PassStats::new();
+            let (optimized, _) =
+                optimize_with_stats(ops, &options, &LoopSources::new(), &mut Warnings::new(), &mut stats);
+            assert_eq!(optimized, optimize_zero_flags_across_labels(expected));
+            // Less the run following the zero flag across labels, which isn't in the queue
+            let skipped_runs = stats.values().map(|stat| stat.runs).sum::<usize>() - 1;
+            assert!(skipped_runs < runs, "{} runs of {} skipped none", skipped_runs, runs);
+        }
+    }
+
+    /// `,[>,[>...-]<-]<` nested eight deep, `count` times one after another
+    fn loop_nests(count: usize) -> Vec<Instruction> {
+        let read = || NamedBlackBox("read".to_owned(), "call read".to_owned(), Effects::VOLATILE);
+        let labels = |nest, depth| -> (LabelId, LabelId) {
+            (
+                format!(".start{}_{}", nest, depth).into(),
+                format!(".end{}_{}", nest, depth).into(),
+            )
+        };
+        let mut ops = Vec::new();
+        for nest in 0..count {
+            for depth in 0..8 {
+                let (start, end) = labels(nest, depth);
+                ops.extend(vec![
+                    read(),
+                    IsZeroPtr8(rbx),
+                    JumpZero(end),
+                    Label(start),
+                    AddImm(rbx, 1),
+                ]);
+            }
+            for depth in (0..8).rev() {
+                let (start, end) = labels(nest, depth);
+                ops.extend(vec![
+                    AddPtr8Imm(rbx, 255),
+                    JumpNonZero(start),
+                    Label(end),
+                    SubImm(rbx, 1),
+                ]);
+            }
+        }
+        ops
+    }
+
+    #[test]
+    fn test_pass_time() {
+        extern crate std;
+        use std::time::{Duration, Instant};
+
+        // Shortest of a few runs, as a single one may be slowed down by the other tests
+        let options = CompileOptions::default();
+        let time = |pass: &Pass, ops: &[Instruction]| {
+            (0..3)
+                .map(|_| {
+                    let ops = ops.to_vec();
+                    let start = Instant::now();
+                    (pass.function)(ops, &options);
+                    start.elapsed()
+                })
+                .min()
+                .unwrap()
+        };
+        // Four times the loops take about four times as long, a pass scanning the program
+        // for each label would take sixteen times as long
+        let (short, long) = (loop_nests(250), loop_nests(1000));
+        for pass in PIPELINE {
+            let (short, long) = (time(pass, &short), time(pass, &long));
+            assert!(
+                long < short * 8 + Duration::from_millis(1),
+                "{} took {:?} for 2000 loops and {:?} for 8000 loops",
+                pass.name,
+                short,
+                long
+            );
+        }
+    }
+}
@@ -0,0 +1,434 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use strum_macros::{EnumString, EnumVariantNames};
+
+use crate::error::{CompileError, CompileResult};
+use crate::parser::Span;
+
+/// Most cells the tape can have, as the stack is grown for it by a 32-bit immediate
+pub const MAX_TAPE_SIZE: usize = i32::MAX as usize;
+
+/// What `,` stores to the current cell when input has ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(EnumString, EnumVariantNames))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[cfg_attr(feature = "std", strum(serialize_all = "lowercase"))]
+pub enum EofPolicy {
+    /// Set the cell to zero
+    Zero,
+    /// Set the cell to 255, i.e. `-1`
+    Max,
+    /// Leave the cell unchanged
+    Unchanged,
+}
+impl EofPolicy {
+    /// Value stored on end of input, or `None` if the cell is not modified
+    pub fn value(self) -> Option<u8> {
+        match self {
+            Self::Zero => Some(0),
+            Self::Max => Some(255),
+            Self::Unchanged => None,
+        }
+    }
+}
+
+/// Where the pointer starts on the tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(EnumString, EnumVariantNames))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[cfg_attr(feature = "std", strum(serialize_all = "lowercase"))]
+pub enum TapeMode {
+    /// Start at the first cell, moving left of it is an error
+    Standard,
+    /// Start at the middle of the tape, allowing moves to the left
+    Bidirectional,
+}
+
+/// What the optimizations favor when they trade one for the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(EnumString, EnumVariantNames))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[cfg_attr(feature = "std", strum(serialize_all = "lowercase"))]
+pub enum OptGoal {
+    /// Faster code, e.g. by unrolling loops and copying the bodies of hot loops
+    Speed,
+    /// Unrolls loops up to the unroll budget, but copies the bodies of hot loops only once
+    Balanced,
+    /// Smaller code, unrolling only loops that shrink to about the size of the loop,
+    /// and clearing the tape with `rep stosb` instead of vector stores
+    Size,
+}
+
+/// Unroll budget when optimizing for size, about the size of a short loop
+const SIZE_UNROLL_BUDGET: usize = 8;
+
+/// Vector instructions the compiled program may use for bulk stores to the tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(EnumString, EnumVariantNames))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[cfg_attr(feature = "std", strum(serialize_all = "lowercase"))]
+pub enum CpuFeature {
+    /// 16-byte stores, available on every x86-64 CPU
+    Sse2,
+    /// 32-byte stores
+    Avx2,
+}
+impl CpuFeature {
+    /// Bytes written by the widest store
+    pub fn vector_bytes(self) -> usize {
+        match self {
+            Self::Sse2 => 16,
+            Self::Avx2 => 32,
+        }
+    }
+}
+
+/// Bytes of a cell wider than a byte that `.` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(EnumString, EnumVariantNames))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[cfg_attr(feature = "std", strum(serialize_all = "lowercase"))]
+pub enum WideOutput {
+    /// The lowest byte, as most dialects with wider cells do
+    Low,
+    /// All bytes of the cell, lowest first
+    Full,
+}
+
+/// Command linking the object file into an executable, see `CompileOptions::linker_driver`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "std", derive(EnumString, EnumVariantNames))]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+#[cfg_attr(feature = "std", strum(serialize_all = "lowercase"))]
+pub enum LinkerDriver {
+    Clang,
+    Gcc,
+    /// The linker itself, given the C runtime and the dynamic linker explicitly
+    Ld,
+}
+impl LinkerDriver {
+    pub fn command(self) -> &'static str {
+        match self {
+            Self::Clang => "clang",
+            Self::Gcc => "gcc",
+            Self::Ld => "ld",
+        }
+    }
+}
+
+/// Addresses used by the bare metal target, which has no operating system to allocate
+/// the tape or to do IO
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(default, rename_all = "kebab-case")
+)]
+pub struct BareMetal {
+    /// Address of the first cell of the tape, which is cleared at startup
+    pub tape_address: u64,
+    /// Memory-mapped register written by `.`, e.g. the transmit register of a UART
+    pub output_address: u64,
+    /// Memory-mapped register read by `,`, `None` reads end of input
+    pub input_address: Option<u64>,
+    /// Function called with the exit code in `rdi` to stop the program, `None` halts the CPU
+    pub exit_symbol: Option<String>,
+    /// IO port of the serial port used by the freestanding target, e.g. `0x3f8` for COM1
+    pub serial_port: u16,
+}
+impl Default for BareMetal {
+    fn default() -> Self {
+        Self {
+            tape_address: 0x20_0000,
+            output_address: 0x1000_0000,
+            input_address: None,
+            exit_symbol: None,
+            serial_port: 0x3f8,
+        }
+    }
+}
+
+/// Range of cells wider than a byte, declared by the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WideCells {
+    /// First cell of the range
+    pub first: usize,
+    /// Last cell of the range, inclusive
+    pub last: usize,
+    /// Bytes in each cell, 2 or 4
+    pub bytes: u8,
+}
+
+/// Execution counts of a loop, gathered by a profiling run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoopCounts {
+    /// Number of times the loop was reached
+    pub entries: u64,
+    /// Total number of iterations
+    pub iterations: u64,
+}
+
+/// Options passed to the compiler
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(default, rename_all = "kebab-case")
+)]
+pub struct CompileOptions {
+    /// Optimization level:
+    /// `0` disables all optimizations,
+    /// `1` skips compile-time evaluation of the program,
+    /// `2` enables everything
+    pub opt_level: u8,
+    /// Number of cells on the tape
+    pub tape_size: usize,
+    /// Where the pointer starts on the tape
+    pub tape: TapeMode,
+    /// Behavior of `,` on end of input
+    pub eof: EofPolicy,
+    /// Whether optimizations favor code size or speed
+    pub opt_for: OptGoal,
+    /// Maximum number of instructions a loop can be unrolled into, `0` disables unrolling
+    pub max_unroll: usize,
+    /// Replace loops that run at most once with a check skipping the body, removing the jump back
+    pub conditional_loops: bool,
+    /// Maximum number of steps executed when evaluating the program at compile time
+    pub eval_budget: usize,
+    /// Loops nested deeper than this are warned about, as they make the optimizer slow.
+    /// With `deny_warnings` compiling stops before the optimizer runs.
+    pub max_loop_depth: usize,
+    /// Evaluate `,` at compile time as end of input, for programs that are run without input.
+    /// Otherwise the evaluation stops at the first `,`, as the input is unknown.
+    pub assume_no_input: bool,
+    /// Vector instructions used to zero the tape and to store runs of cells,
+    /// `None` uses general-purpose registers only
+    pub target_cpu: Option<CpuFeature>,
+    /// Define a `<name>_len` symbol after each data blob, and write constant strings using it
+    pub data_length_symbols: bool,
+    /// Log a diff of the instructions changed by each optimizer pass
+    pub trace_passes: bool,
+    /// Fail with `CompileError::DeniedWarnings` if compiling gives any warnings.
+    /// Warnings about loops are only given with `loop_spans`.
+    pub deny_warnings: bool,
+    /// Names of optimizer passes to skip, also when scheduled as cleanup of another pass
+    pub disabled_passes: Vec<String>,
+    /// Number of programs `compile_programs` lowers and optimizes at the same time, each on a thread
+    pub threads: usize,
+    /// Names of optimizer passes in the order they are run, the passes not named run after them
+    /// in their default order. Empty runs all passes in the default order.
+    pub pass_order: Vec<String>,
+    /// Cleanup passes of the named passes, run after each run of one that changed the program.
+    /// These replace the default cleanup passes of the pass, the passes not named keep theirs.
+    pub pass_cleanup: BTreeMap<String, Vec<String>>,
+    /// Loop counts from a profiling run, in the source order of `[`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub profile: Vec<Option<LoopCounts>>,
+    /// Source positions of the loops, in the source order of `[`, to log what the optimizer does to them
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub loop_spans: Vec<Span>,
+    /// Source positions of the `]` closing each loop, in the source order of `[`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub loop_end_spans: Vec<Span>,
+    /// Source file named by NASM `%line` directives before the start and the end of each loop,
+    /// so that the assembler reports errors at the source line. `None` writes no directives.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub line_directives: Option<String>,
+    /// Write a comment with the source commands before the instructions of each of them,
+    /// runs like `+++++` combined into one instruction together. The optimizer passes are skipped,
+    /// and the opt level must be below 2, as the optimizations don't keep the source of the instructions.
+    /// Only the targets lowered to x86-64 instructions have the comments.
+    pub source_comments: bool,
+    /// Map the tape between two inaccessible pages instead of allocating it on the stack,
+    /// so that moving right of the last cell faults right away instead of overwriting the stack.
+    /// Only the x86-64 targets linked with a C library can map memory, the other ones lowered
+    /// to x86-64 instructions reject it.
+    pub guard_pages: bool,
+    /// Checksum of the source, from `source_checksum`, embedded as the data `source_checksum`
+    /// in little-endian order. Only the targets lowered to x86-64 instructions embed it.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub source_checksum: Option<u64>,
+    /// Assembly file named by a comment at the start of the assembly with the commands that build it,
+    /// `None` writes no comment
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub build_comment: Option<String>,
+    /// Average number of iterations per entry above which a profiled loop is unrolled
+    pub hot_loop_iterations: u64,
+    /// Input stored in the binary and read by `,` instead of stdin
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub embedded_input: Option<Vec<u8>>,
+    /// Addresses used when compiling for the bare metal target
+    pub bare_metal: BareMetal,
+    /// Cells with a wider type, from the pragmas of the source. Empty keeps all cells bytes.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub wide_cells: Vec<WideCells>,
+    /// Bytes of a wide cell written by `.`
+    pub wide_output: WideOutput,
+    /// Links with this command and the arguments it needs for the target,
+    /// instead of the default linker of the target
+    pub linker_driver: Option<LinkerDriver>,
+}
+impl CompileOptions {
+    /// Maximum number of instructions a loop is unrolled into, lowered when optimizing for size
+    pub fn unroll_budget(&self) -> usize {
+        match self.opt_for {
+            OptGoal::Speed | OptGoal::Balanced => self.max_unroll,
+            OptGoal::Size => self.max_unroll.min(SIZE_UNROLL_BUDGET),
+        }
+    }
+
+    /// Maximum number of copies of a hot loop body between the exit checks, 1 keeps the loop as is
+    pub fn hot_loop_copies(&self) -> usize {
+        match self.opt_for {
+            OptGoal::Speed => 4,
+            OptGoal::Balanced => 2,
+            OptGoal::Size => 1,
+        }
+    }
+
+    /// Fails if the options can't be used for compiling
+    pub fn validate(&self) -> CompileResult<()> {
+        if self.opt_level > 2 {
+            return Err(CompileError::InvalidOption(
+                "opt_level",
+                "the levels are 0, 1 and 2",
+            ));
+        }
+        if self.threads == 0 {
+            return Err(CompileError::InvalidOption(
+                "threads",
+                "compiling needs at least one thread",
+            ));
+        }
+        if self.tape_size == 0 {
+            return Err(CompileError::InvalidOption(
+                "tape_size",
+                "the tape needs at least one cell",
+            ));
+        }
+        if self.tape_size > MAX_TAPE_SIZE {
+            return Err(CompileError::TapeTooLarge(self.tape_size));
+        }
+        if self.source_comments && self.opt_level >= 2 {
+            return Err(CompileError::InvalidOption(
+                "source_comments",
+                "compile-time evaluation doesn't keep the source of the steps",
+            ));
+        }
+        self.validate_wide_cells()
+    }
+
+    /// Fails if the wide cells are not on the tape or overlap
+    fn validate_wide_cells(&self) -> CompileResult<()> {
+        let invalid = |reason| Err(CompileError::InvalidOption("wide_cells", reason));
+        if self.wide_cells.is_empty() {
+            return Ok(());
+        }
+        if self.tape != TapeMode::Standard {
+            return invalid("requires the standard tape");
+        }
+        for (i, wide) in self.wide_cells.iter().enumerate() {
+            if wide.bytes != 2 && wide.bytes != 4 {
+                return invalid("cells are 2 or 4 bytes wide");
+            }
+            if wide.first > wide.last || wide.last >= self.tape_size {
+                return invalid("the range must be on the tape");
+            }
+            let overlaps = |other: &WideCells| other.first <= wide.last && wide.first <= other.last;
+            if self.wide_cells[..i].iter().any(overlaps) {
+                return invalid("the ranges overlap");
+            }
+        }
+        if self.tape_bytes() > MAX_TAPE_SIZE {
+            return Err(CompileError::TapeTooLarge(self.tape_bytes()));
+        }
+        Ok(())
+    }
+
+    /// Bytes in the given cell
+    pub fn cell_bytes(&self, cell: usize) -> u8 {
+        self.wide_cells
+            .iter()
+            .find(|wide| wide.first <= cell && cell <= wide.last)
+            .map_or(1, |wide| wide.bytes)
+    }
+
+    /// Offset of the given cell from the start of the tape in bytes
+    pub fn cell_offset(&self, cell: usize) -> usize {
+        let extra: usize = self
+            .wide_cells
+            .iter()
+            .map(|wide| (cell.min(wide.last + 1).saturating_sub(wide.first)) * usize::from(wide.bytes - 1))
+            .sum();
+        cell + extra
+    }
+
+    /// Size of the tape in bytes
+    pub fn tape_bytes(&self) -> usize {
+        self.cell_offset(self.tape_size)
+    }
+}
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            opt_level: 2,
+            tape_size: 30000,
+            tape: TapeMode::Standard,
+            eof: EofPolicy::Zero,
+            opt_for: OptGoal::Balanced,
+            max_unroll: 64,
+            max_loop_depth: 1000,
+            conditional_loops: true,
+            eval_budget: 10_000_000,
+            assume_no_input: false,
+            target_cpu: None,
+            data_length_symbols: false,
+            trace_passes: false,
+            deny_warnings: false,
+            disabled_passes: Vec::new(),
+            threads: 1,
+            pass_order: Vec::new(),
+            pass_cleanup: BTreeMap::new(),
+            profile: Vec::new(),
+            loop_spans: Vec::new(),
+            loop_end_spans: Vec::new(),
+            line_directives: None,
+            source_comments: false,
+            guard_pages: false,
+            source_checksum: None,
+            build_comment: None,
+            hot_loop_iterations: 1000,
+            embedded_input: None,
+            bare_metal: BareMetal::default(),
+            wide_cells: Vec::new(),
+            wide_output: WideOutput::Low,
+            linker_driver: None,
+        }
+    }
+}
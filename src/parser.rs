@@ -1,4 +1,11 @@
-use std::fmt;
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::error::{CompileError, CompileResult};
+use crate::options::WideCells;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Token {
@@ -10,6 +17,8 @@ pub enum Token {
     Input,
     JumpForwards,
     JumpBackwards,
+    /// Assembly between `{!` and `!}`, only parsed by `try_parse_inline_asm`
+    InlineAsm(&'static str),
 }
 impl Token {
     pub fn parse(c: char) -> Option<Self> {
@@ -28,6 +37,9 @@ impl Token {
 }
 impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Self::InlineAsm(asm) = self {
+            return write!(f, "{}{}{}", ASM_START, asm, ASM_END);
+        }
         write!(f, "{}", match self {
             Self::Next => '>',
             Self::Prev => '<',
@@ -37,40 +49,253 @@ impl fmt::Display for Token {
             Self::Input => ',',
             Self::JumpForwards => '[',
             Self::JumpBackwards => ']',
+            Self::InlineAsm(_) => unreachable!(),
         })
     }
 }
 
+/// Position of a token in the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    /// Byte offset
+    pub offset: usize,
+    /// Line number, starting from 1
+    pub line: usize,
+    /// Column number in characters, starting from 1
+    pub column: usize,
+}
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// Tokens of the source, panicking on unbalanced brackets unlike `try_parse`
 pub fn parse(s: &str) -> Vec<Token> {
+    parse_spanned(s).into_iter().map(|(token, _)| token).collect()
+}
+
+/// Like `parse`, but keeps the source position of each token
+pub fn parse_spanned(s: &str) -> Vec<(Token, Span)> {
+    try_parse_spanned(s).unwrap_or_else(|error| panic!("{}", error))
+}
+
+/// Like `parse`, but fails on unbalanced brackets instead of panicking
+pub fn try_parse(s: &str) -> CompileResult<Vec<Token>> {
+    Ok(try_parse_spanned(s)?
+        .into_iter()
+        .map(|(token, _)| token)
+        .collect())
+}
+
+/// Like `parse_spanned`, but fails on unbalanced brackets instead of panicking
+pub fn try_parse_spanned(s: &str) -> CompileResult<Vec<(Token, Span)>> {
+    try_parse_with(s, &ParseOptions::default())
+}
+
+/// Like `try_parse_spanned`, but a region between `{!` and `!}` is an `InlineAsm` token.
+/// The assembly is leaked to keep tokens `Copy`, as a source is parsed only once.
+pub fn try_parse_inline_asm(s: &str) -> CompileResult<Vec<(Token, Span)>> {
+    try_parse_with(s, &ParseOptions {
+        inline_asm: true,
+        ..ParseOptions::default()
+    })
+}
+
+/// What the parser accepts besides the commands, by default any other character is a comment
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Parse regions between `{!` and `!}` as inline assembly, like `try_parse_inline_asm`
+    pub inline_asm: bool,
+    /// Characters that are an error instead of a comment, e.g. commands of other variants
+    /// of Brainfuck, or typos of commands
+    pub reserved: BTreeSet<char>,
+}
+
+/// Like `try_parse_spanned`, but with the given options
+pub fn try_parse_with(s: &str, options: &ParseOptions) -> CompileResult<Vec<(Token, Span)>> {
+    tokenize(s, options)
+}
+
+/// Length of the `#!` line starting an executable script, which is no part of the program
+/// even if the path of the interpreter has commands in it. Zero without one.
+fn shebang_length(s: &str) -> usize {
+    if s.starts_with("#!") {
+        s.find('\n').unwrap_or_else(|| s.len())
+    } else {
+        0
+    }
+}
+
+/// Fails like `try_parse` on unbalanced brackets, but without collecting the tokens,
+/// e.g. to check a large source on every edit
+pub fn check_balance(s: &str) -> CompileResult<()> {
+    let shebang = shebang_length(s);
+    let mut depth: usize = 0;
+    let mut line: usize = 1;
+    let mut column: usize = 1;
+    for (offset, c) in s.char_indices().skip_while(|&(offset, _)| offset < shebang) {
+        match c {
+            '[' => depth += 1,
+            ']' if depth == 0 => {
+                return Err(CompileError::UnbalancedBracket {
+                    position: Span { offset, line, column },
+                    kind: Token::JumpBackwards,
+                });
+            },
+            ']' => depth -= 1,
+            _ => {},
+        }
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    if depth == 0 {
+        return Ok(());
+    }
+
+    // The innermost unclosed bracket is the last one not closed after it
+    let mut closing: usize = 0;
+    let offset = s
+        .char_indices()
+        .rev()
+        .take_while(|&(offset, _)| offset >= shebang)
+        .find_map(|(offset, c)| match c {
+            ']' => {
+                closing += 1;
+                None
+            },
+            '[' if closing == 0 => Some(offset),
+            '[' => {
+                closing -= 1;
+                None
+            },
+            _ => None,
+        })
+        .unwrap();
+    let line_start = s[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+    Err(CompileError::UnbalancedBracket {
+        position: Span {
+            offset,
+            line: s[..offset].matches('\n').count() + 1,
+            column: s[line_start..offset].chars().count() + 1,
+        },
+        kind: Token::JumpForwards,
+    })
+}
+
+/// Starts a comment line giving cells a wider type, e.g. `@cells 10 19 u16`
+const WIDE_CELLS_PRAGMA: &str = "@cells";
+
+/// Cells declared wider than a byte by `@cells FIRST LAST u16` or `u32` lines,
+/// with both cell numbers included in the range
+pub fn parse_wide_cells(s: &str) -> CompileResult<Vec<WideCells>> {
     let mut result = Vec::new();
-    for c in s.chars() {
-        if let Some(token) = Token::parse(c) {
-            result.push(token);
+    let mut offset = 0;
+    for (index, line) in s.split('\n').enumerate() {
+        let trimmed = line.trim_start();
+        let column = line.len() - trimmed.len();
+        let span = Span {
+            offset: offset + column,
+            line: index + 1,
+            column: line[..column].chars().count() + 1,
+        };
+        offset += line.len() + 1;
+        let mut words = trimmed.split_whitespace();
+        if words.next() != Some(WIDE_CELLS_PRAGMA) {
+            continue;
         }
+        let words: Vec<&str> = words.collect();
+        let wide = match words[..] {
+            [first, last, kind] => {
+                let bytes = match kind {
+                    "u16" => 2,
+                    "u32" => 4,
+                    _ => return Err(CompileError::InvalidPragma(span)),
+                };
+                match (first.parse(), last.parse()) {
+                    (Ok(first), Ok(last)) if first <= last => WideCells { first, last, bytes },
+                    _ => return Err(CompileError::InvalidPragma(span)),
+                }
+            },
+            _ => return Err(CompileError::InvalidPragma(span)),
+        };
+        result.push(wide);
     }
+    Ok(result)
+}
 
-    // check bracket balance
-    let mut level: usize = 0;
-    for r in result.iter().copied() {
-        if r == Token::JumpForwards {
-            level += 1;
-        } else if r == Token::JumpBackwards {
-            if level == 0 {
-                panic!("Unbalanced ']'");
+const ASM_START: &str = "{!";
+const ASM_END: &str = "!}";
+
+/// Start of the local labels named by the compiler, which inline assembly can't contain
+/// so that its labels don't collide with them
+pub const COMPILER_LABEL_PREFIX: &str = ".@";
+
+fn tokenize(s: &str, options: &ParseOptions) -> CompileResult<Vec<(Token, Span)>> {
+    let mut result = Vec::new();
+    let mut line: usize = 1;
+    let mut column: usize = 1;
+    // Offset after the shebang line or the inline assembly being skipped
+    let mut asm_end = shebang_length(s);
+    for (offset, c) in s.char_indices() {
+        if offset >= asm_end {
+            let span = Span { offset, line, column };
+            if options.inline_asm && s[offset..].starts_with(ASM_START) {
+                let start = offset + ASM_START.len();
+                let length = s[start..]
+                    .find(ASM_END)
+                    .ok_or(CompileError::UnterminatedInlineAsm(span))?;
+                let asm = s[start..start + length].trim().to_owned();
+                if asm.contains(COMPILER_LABEL_PREFIX) {
+                    return Err(CompileError::CompilerLabel(span));
+                }
+                result.push((Token::InlineAsm(Box::leak(asm.into_boxed_str())), span));
+                asm_end = start + length + ASM_END.len();
+            } else if let Some(token) = Token::parse(c) {
+                result.push((token, span));
+            } else if options.reserved.contains(&c) {
+                return Err(CompileError::ReservedCharacter(c, span));
             }
-            level -= 1;
+        }
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
         }
     }
-    if level != 0 {
-        panic!("Unbalanced '['");
+
+    // check bracket balance
+    let mut open: Vec<Span> = Vec::new();
+    for &(kind, position) in &result {
+        if kind == Token::JumpForwards {
+            open.push(position);
+        } else if kind == Token::JumpBackwards && open.pop().is_none() {
+            return Err(CompileError::UnbalancedBracket { position, kind });
+        }
+    }
+    if let Some(&position) = open.last() {
+        return Err(CompileError::UnbalancedBracket {
+            position,
+            kind: Token::JumpForwards,
+        });
     }
 
-    result
+    Ok(result)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{parse, Token};
+    use super::{
+        check_balance, parse, parse_spanned, parse_wide_cells, try_parse, try_parse_inline_asm,
+        try_parse_with, ParseOptions, Span, Token,
+    };
+    use crate::error::CompileError;
+    use crate::options::WideCells;
 
     #[test]
     fn test_parse() {
@@ -83,4 +308,167 @@ mod tests {
             Token::JumpBackwards,
         ]);
     }
+
+    #[test]
+    fn test_shebang() {
+        // The path of the interpreter is no part of the program
+        let source = "#!/opt/bf-[1.0]/bin/brain_opt --interpret\n+.";
+        assert_eq!(parse_spanned(source), vec![
+            (Token::Increment, Span {
+                offset: 42,
+                line: 2,
+                column: 1,
+            }),
+            (Token::Output, Span {
+                offset: 43,
+                line: 2,
+                column: 2,
+            }),
+        ]);
+        assert!(check_balance(source).is_ok());
+        assert_eq!(parse("#!/usr/bin/env brain-opt"), vec![]);
+        // Only on the first line
+        assert_eq!(parse(" #!+"), vec![Token::Increment]);
+    }
+
+    #[test]
+    fn test_parse_spanned() {
+        let tokens = parse_spanned("+ x\n [-]");
+        assert_eq!(
+            tokens[0],
+            (Token::Increment, Span {
+                offset: 0,
+                line: 1,
+                column: 1,
+            })
+        );
+        assert_eq!(
+            tokens[1],
+            (Token::JumpForwards, Span {
+                offset: 5,
+                line: 2,
+                column: 2,
+            })
+        );
+        assert_eq!(format!("{}", tokens[3].1), "2:4");
+    }
+
+    #[test]
+    fn test_unbalanced() {
+        let position = |result| match result {
+            Err(CompileError::UnbalancedBracket { position, kind }) => (kind, format!("{}", position)),
+            result => panic!("Unexpected {:?}", result),
+        };
+        assert_eq!(
+            position(try_parse("+[]]")),
+            (Token::JumpBackwards, "1:4".to_owned())
+        );
+        assert_eq!(
+            position(try_parse("[\n [[]")),
+            (Token::JumpForwards, "2:2".to_owned())
+        );
+        assert_eq!(try_parse("[->+<]").unwrap(), parse("[->+<]"));
+    }
+
+    #[test]
+    fn test_reserved() {
+        let options = ParseOptions {
+            reserved: "#!".chars().collect(),
+            ..ParseOptions::default()
+        };
+        assert!(matches!(
+            try_parse_with("+\n.#", &options),
+            Err(CompileError::ReservedCharacter('#', Span { line: 2, column: 2, .. }))
+        ));
+        // Other comments and the commands are still parsed, as is all of the source by default
+        assert_eq!(
+            try_parse_with("+x.", &options).unwrap(),
+            try_parse_with("+x.", &ParseOptions::default()).unwrap()
+        );
+        assert!(try_parse_with("+\n.#", &ParseOptions::default()).is_ok());
+
+        // Reserved characters in inline assembly are part of it
+        let options = ParseOptions {
+            inline_asm: true,
+            ..options
+        };
+        assert_eq!(
+            try_parse_with("{! nop # x !}", &options).unwrap(),
+            try_parse_inline_asm("{! nop # x !}").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_check_balance() {
+        assert!(check_balance("+[->[-]<]x\n[]").is_ok());
+        assert!(check_balance("").is_ok());
+        for source in &["+[]]", "[\n [[]", "ä[\n [[]]\n]ö]", "][", "x[[]\n[]", "#!/opt/[bf/bin\n[[]"] {
+            let expected = try_parse(source).unwrap_err();
+            match (check_balance(source), expected) {
+                (
+                    Err(CompileError::UnbalancedBracket { position, kind }),
+                    CompileError::UnbalancedBracket {
+                        position: expected_position,
+                        kind: expected_kind,
+                    },
+                ) => assert_eq!((position, kind), (expected_position, expected_kind), "{}", source),
+                result => panic!("Unexpected {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn test_inline_asm() {
+        let tokens = try_parse_inline_asm("+{! mov rax, 1\nnop !}\n[-]{!!}").unwrap();
+        assert_eq!(
+            tokens[1],
+            (Token::InlineAsm("mov rax, 1\nnop"), Span {
+                offset: 1,
+                line: 1,
+                column: 2,
+            })
+        );
+        assert_eq!(tokens[2].1.line, 3);
+        assert_eq!(tokens[5].0, Token::InlineAsm(""));
+        assert_eq!(format!("{}", tokens[1].0), "{!mov rax, 1\nnop!}");
+
+        // Without the extension the assembly is a comment, except for its commands
+        assert_eq!(parse("{! mov rax, 1 !}"), vec![Token::Input]);
+        match try_parse_inline_asm("+\n {! nop") {
+            Err(CompileError::UnterminatedInlineAsm(position)) => assert_eq!(format!("{}", position), "2:2"),
+            result => panic!("Unexpected {:?}", result),
+        }
+        assert!(matches!(
+            try_parse_inline_asm("+{! jmp .@0 !}"),
+            Err(CompileError::CompilerLabel(Span { column: 2, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_parse_wide_cells() {
+        let source = "@cells 2 5 u16\n+>+\n  @cells 10 10 u32 counter\n";
+        assert_eq!(parse_wide_cells(&source.replace(" counter", "")).unwrap(), vec![
+            WideCells {
+                first: 2,
+                last: 5,
+                bytes: 2,
+            },
+            WideCells {
+                first: 10,
+                last: 10,
+                bytes: 4,
+            },
+        ]);
+        match parse_wide_cells(source) {
+            Err(CompileError::InvalidPragma(position)) => assert_eq!(position, Span {
+                offset: 21,
+                line: 3,
+                column: 3,
+            }),
+            result => panic!("Unexpected {:?}", result),
+        }
+        assert!(parse_wide_cells("@cells 5 2 u16").is_err());
+        assert!(parse_wide_cells("@cells 0 1 u8").is_err());
+        assert!(parse_wide_cells("cells 0 1 u8").unwrap().is_empty());
+    }
 }
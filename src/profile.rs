@@ -0,0 +1,109 @@
+//! Loop counts recorded from an interpreted run, used to guide optimization
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::interpreter::{FastInterpreter, PointerUnderflow, IO};
+use crate::options::LoopCounts;
+use crate::parser::{Span, Token};
+
+/// Loop counts of a profiling run, keyed by the source offset of each `[`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile(pub HashMap<usize, LoopCounts>);
+impl Profile {
+    /// Runs the program and records how often each loop runs.
+    /// On pointer underflow the counts up to that point are returned with the error.
+    pub fn record(tokens: &[(Token, Span)], io: &mut dyn IO) -> (Self, Result<(), PointerUnderflow>) {
+        let plain: Vec<Token> = tokens.iter().map(|(t, _)| *t).collect();
+        let mut counts = Vec::new();
+        let result = FastInterpreter::new().run_profiled(&plain, io, &mut counts);
+        let profile = Self(loop_offsets(tokens).zip(counts).collect());
+        (profile, result)
+    }
+
+    /// Parses the format written by `Display`, the error is the first invalid line number
+    pub fn parse(text: &str) -> Result<Self, usize> {
+        let mut result = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<u64> = line
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()
+                .map_err(|_| index + 1)?;
+            if let [offset, entries, iterations] = fields[..] {
+                result.insert(offset as usize, LoopCounts { entries, iterations });
+            } else {
+                return Err(index + 1);
+            }
+        }
+        Ok(Self(result))
+    }
+
+    /// Counts of each loop in the source order of `[`, as used by `CompileOptions::profile`
+    pub fn loop_counts(&self, tokens: &[(Token, Span)]) -> Vec<Option<LoopCounts>> {
+        loop_offsets(tokens)
+            .map(|offset| self.0.get(&offset).copied())
+            .collect()
+    }
+}
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# offset entries iterations")?;
+        let mut offsets: Vec<_> = self.0.keys().copied().collect();
+        offsets.sort_unstable();
+        for offset in offsets {
+            let counts = self.0[&offset];
+            writeln!(f, "{} {} {}", offset, counts.entries, counts.iterations)?;
+        }
+        Ok(())
+    }
+}
+
+/// Source offsets of each `[`
+fn loop_offsets(tokens: &[(Token, Span)]) -> impl Iterator<Item = usize> + '_ {
+    tokens
+        .iter()
+        .filter(|(t, _)| *t == Token::JumpForwards)
+        .map(|(_, span)| span.offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profile;
+    use crate::interpreter::BufferIO;
+    use crate::options::LoopCounts;
+    use crate::parser::parse_spanned;
+
+    #[test]
+    fn test_profile() {
+        let tokens = parse_spanned("++[>+++[-]<-] ,[.,]");
+        let mut io = BufferIO::new(b"ab");
+        let (profile, result) = Profile::record(&tokens, &mut io);
+        assert_eq!(result, Ok(()));
+        assert_eq!(io.output, b"ab");
+        assert_eq!(profile.loop_counts(&tokens), vec![
+            Some(LoopCounts {
+                entries: 1,
+                iterations: 2
+            }),
+            Some(LoopCounts {
+                entries: 2,
+                iterations: 6
+            }),
+            Some(LoopCounts {
+                entries: 1,
+                iterations: 2
+            }),
+        ]);
+
+        let text = profile.to_string();
+        assert_eq!(text, "# offset entries iterations\n2 1 2\n7 2 6\n15 1 2\n");
+        assert_eq!(Profile::parse(&text), Ok(profile));
+        assert_eq!(Profile::parse("1 2 3\n\n1 x 3"), Err(3));
+        assert_eq!(Profile::parse("1 2"), Err(1));
+    }
+}
@@ -0,0 +1,138 @@
+//! Structured report of a compilation, for build tools and editors
+
+use strum_macros::{EnumString, EnumVariantNames};
+
+use crate::error::Warnings;
+use crate::instruction::Instruction;
+use crate::optimizer::PassStats;
+use crate::options::CompileOptions;
+use crate::stats::Stats;
+use crate::target_abi::{Assembler, LinkerInfo, ABI};
+
+/// Format of the report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames)]
+#[strum(serialize_all = "lowercase")]
+pub enum ReportFormat {
+    Json,
+}
+
+/// What one optimizer pass did
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "report", derive(serde::Serialize))]
+pub struct PassReport {
+    pub name: String,
+    /// Number of times the pass ran
+    pub runs: usize,
+    /// Number of runs that changed the program
+    pub changes: usize,
+    /// Instructions removed by the pass, including labels
+    pub removed: usize,
+    /// Instructions added by the pass, including labels
+    pub added: usize,
+}
+
+/// Size of one data blob
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "report", derive(serde::Serialize))]
+pub struct DataReport {
+    pub label: String,
+    pub bytes: usize,
+}
+
+/// Time each phase of a compilation took, in microseconds
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "report", derive(serde::Serialize))]
+pub struct Timings {
+    pub parse_us: u64,
+    /// Lowering, optimizing and writing the assembly
+    pub optimize_us: u64,
+    /// Zero if nothing was assembled
+    pub assemble_us: u64,
+    /// Zero if nothing was linked
+    pub link_us: u64,
+}
+
+/// Summary of a compilation
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "report", derive(serde::Serialize))]
+pub struct Report {
+    /// Path of the source file, if compiled from one
+    pub source: Option<String>,
+    /// Target triple, e.g. `x86_64-unknown-linux-gnu`
+    pub target: String,
+    /// Assembler command, e.g. `nasm`
+    pub assembler: String,
+    /// Object file format e.g. `elf64`
+    pub object_format: String,
+    /// Linker command, e.g. `gcc`
+    pub linker: String,
+    pub linker_args: Vec<String>,
+    pub libraries: Vec<String>,
+    pub entrypoint: String,
+    /// What the optimizations favored, e.g. `balanced`
+    pub opt_for: String,
+    /// Instructions before the optimizer
+    pub before: Stats,
+    /// Instructions of the optimized program
+    pub after: Stats,
+    /// Passes that ran, by name
+    pub passes: Vec<PassReport>,
+    pub warnings: Vec<String>,
+    pub data: Vec<DataReport>,
+    /// Path of the output file, if it is kept
+    pub output: Option<String>,
+    pub timings: Timings,
+}
+impl Report {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        abi: ABI, options: &CompileOptions, link: &LinkerInfo, before: Stats, after: Stats,
+        passes: &PassStats, warnings: &Warnings, data: &[Instruction],
+    ) -> Self {
+        Self {
+            source: None,
+            target: abi.triple().to_owned(),
+            assembler: match &link.assembler {
+                Assembler::Nasm => "nasm".to_owned(),
+                Assembler::Gnu(command) => command.clone(),
+            },
+            object_format: link.object_format.clone(),
+            linker: link.linker_cmd.clone(),
+            linker_args: link.linker_args.clone(),
+            libraries: link.libraries.clone(),
+            entrypoint: link.entrypoint.clone(),
+            opt_for: format!("{:?}", options.opt_for).to_lowercase(),
+            before,
+            after,
+            passes: passes
+                .iter()
+                .map(|(name, stat)| PassReport {
+                    name: (*name).to_owned(),
+                    runs: stat.runs,
+                    changes: stat.changes,
+                    removed: stat.removed,
+                    added: stat.added,
+                })
+                .collect(),
+            warnings: warnings.as_slice().iter().map(ToString::to_string).collect(),
+            data: data
+                .iter()
+                .filter_map(|op| match op {
+                    Instruction::Data(label, bytes) => Some(DataReport {
+                        label: label.clone(),
+                        bytes: bytes.len(),
+                    }),
+                    _ => None,
+                })
+                .collect(),
+            output: None,
+            timings: Timings::default(),
+        }
+    }
+
+    /// The report as pretty-printed JSON
+    #[cfg(feature = "report")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("The report is always serializable")
+    }
+}
@@ -0,0 +1,214 @@
+//! RISC-V backend for rv64 Linux, lowering the steps directly since the x86-64 instructions
+//! can't be encoded for it. The lowering is unoptimized: every step is a few loads, stores and branches.
+
+use crate::compiler::{Label, Step};
+use crate::error::{CompileError, CompileResult};
+use crate::options::{CompileOptions, TapeMode};
+use crate::stats::{StepStats, Stats};
+use crate::target_abi::{Assembler, LinkerInfo};
+
+/// Human-readable description of the target and its toolchain
+pub const DESCRIPTION: &str = "Linux rv64: ELF64 object assembled with GNU as, linked with ld without libc, \
+                               tape pointer in s1 and tape start in s2";
+
+/// Canonical target triple
+pub const TRIPLE: &str = "riscv64-unknown-linux-gnu";
+
+/// Exits with status 1 when the pointer moves left of the tape start
+const UNDERFLOW_LABEL: &str = ".Lunderflow";
+
+/// Linux system call numbers, shared by all of the newer architectures
+const SYS_READ: u8 = 63;
+const SYS_WRITE: u8 = 64;
+const SYS_EXIT: u8 = 93;
+
+/// Largest immediate of `addi`
+const MAX_IMMEDIATE: u64 = 2047;
+
+pub fn linker_info() -> LinkerInfo {
+    // The host toolchain has no prefix
+    let (assembler, linker) = if cfg!(target_arch = "riscv64") {
+        ("as", "ld")
+    } else {
+        ("riscv64-linux-gnu-as", "riscv64-linux-gnu-ld")
+    };
+    LinkerInfo {
+        entrypoint: "_start".to_owned(),
+        libraries: Vec::new(),
+        externs: Vec::new(),
+        object_format: "elf64".to_owned(),
+        assembler: Assembler::Gnu(assembler.to_owned()),
+        linker_cmd: linker.to_owned(),
+        linker_args: Vec::new(),
+    }
+}
+
+/// Assembly of the program body
+struct Function {
+    asm: String,
+    instructions: usize,
+    next_label: usize,
+}
+impl Function {
+    fn line(&mut self, line: &str) {
+        self.asm.push_str("    ");
+        self.asm.push_str(line);
+        self.asm.push('\n');
+        self.instructions += 1;
+    }
+
+    fn label(&mut self, name: &str) {
+        self.asm.push_str(name);
+        self.asm.push_str(":\n");
+    }
+
+    fn next_label(&mut self) -> String {
+        let result = format!(".Lskip{}", self.next_label);
+        self.next_label += 1;
+        result
+    }
+
+    /// Moves the pointer right by `count` cells with `add`, or left with `sub`
+    fn move_pointer(&mut self, count: u64, operation: &str) {
+        if count <= MAX_IMMEDIATE {
+            let sign = if operation == "sub" { "-" } else { "" };
+            self.line(&format!("addi s1, s1, {}{}", sign, count));
+        } else {
+            self.line(&format!("li t0, {}", count));
+            self.line(&format!("{} s1, s1, t0", operation));
+        }
+    }
+
+    fn syscall(&mut self, number: u8) {
+        self.line(&format!("li a7, {}", number));
+        self.line("ecall");
+    }
+
+    fn exit(&mut self, code: u8) {
+        self.line(&format!("li a0, {}", code));
+        self.syscall(SYS_EXIT);
+    }
+
+    /// Reads or writes the current cell with a system call, moving a single byte
+    fn transfer(&mut self, fd: u8, number: u8) {
+        self.line(&format!("li a0, {}", fd));
+        self.line("mv a1, s1");
+        self.line("li a2, 1");
+        self.syscall(number);
+    }
+
+    fn step(&mut self, step: Step, options: &CompileOptions) -> CompileResult<()> {
+        match step {
+            Step::Next(n) => self.move_pointer(n, "add"),
+            Step::Prev(n) => {
+                self.move_pointer(n, "sub");
+                self.line(&format!("bltu s1, s2, {}", UNDERFLOW_LABEL));
+            },
+            // The immediate fits any byte, and the store drops the carry
+            Step::Add(n) => {
+                self.line("lbu t0, 0(s1)");
+                self.line(&format!("addi t0, t0, {}", n));
+                self.line("sb t0, 0(s1)");
+            },
+            Step::Output => self.transfer(1, SYS_WRITE),
+            Step::Input => {
+                self.transfer(0, SYS_READ);
+                // Nothing is stored when no byte was read, at end of input or on an error
+                if let Some(value) = options.eof.value() {
+                    let skip = self.next_label();
+                    self.line(&format!("bgtz a0, {}", skip));
+                    self.line(&format!("li t0, {}", value));
+                    self.line("sb t0, 0(s1)");
+                    self.label(&skip);
+                }
+            },
+            // Branches only reach 4 KiB, so the label is jumped to with `j` and the branch skips it
+            Step::JumpToIf(condition, label) => {
+                let skip = self.next_label();
+                self.line("lbu t0, 0(s1)");
+                let branch = if condition { "beqz" } else { "bnez" };
+                self.line(&format!("{} t0, {}", branch, skip));
+                self.line(&format!("j {}", label_name(label)));
+                self.label(&skip);
+            },
+            Step::JumpTo(label) => self.line(&format!("j {}", label_name(label))),
+            Step::Label(label) => self.label(&label_name(label)),
+            Step::InlineAsm(_) => {
+                return Err(CompileError::InvalidOption(
+                    "inline_asm",
+                    "the assembly is x86-64, so it can't be used on riscv64",
+                ));
+            },
+        }
+        Ok(())
+    }
+}
+
+fn label_name(label: Label) -> String {
+    format!(".Lloop{}", label.0)
+}
+
+/// GNU assembly of the steps, with `_start` running the program using Linux system calls,
+/// and statistics counting each instruction with a cost of one
+pub(crate) fn program(steps: &[Step], options: &CompileOptions) -> CompileResult<(String, Stats)> {
+    if !options.wide_cells.is_empty() {
+        return Err(CompileError::InvalidOption(
+            "wide_cells",
+            "only the x86-64 targets support them",
+        ));
+    }
+    if options.embedded_input.is_some() {
+        return Err(CompileError::InvalidOption(
+            "embedded_input",
+            "only the x86-64 targets support it",
+        ));
+    }
+    if options.linker_driver.is_some() {
+        return Err(CompileError::InvalidOption(
+            "linker_driver",
+            "riscv64 is linked with the GNU cross linker",
+        ));
+    }
+    let start = match options.tape {
+        TapeMode::Standard => 0,
+        TapeMode::Bidirectional => options.tape_size / 2,
+    };
+    let mut function = Function {
+        asm: String::new(),
+        instructions: 0,
+        next_label: 0,
+    };
+    function.line("la s2, tape");
+    function.line("mv s1, s2");
+    if start > 0 {
+        function.move_pointer(start as u64, "add");
+    }
+    for step in steps {
+        function.step(*step, options)?;
+    }
+    function.exit(0);
+    function.label(UNDERFLOW_LABEL);
+    function.exit(1);
+
+    let linker_info = linker_info();
+    let mut asm = String::new();
+    asm.push_str("# Generated by brain_opt\n");
+    if let Some(source) = &options.build_comment {
+        asm.push_str(&linker_info.build_comment(source));
+    }
+    // Without a C runtime gp is never set up, so the linker must not relax addresses relative to it
+    asm.push_str("    .option norelax\n");
+    asm.push_str(&linker_info.to_assembly());
+    asm.push_str(&format!("    .text\n{}:\n", linker_info.entrypoint));
+    asm.push_str(&function.asm);
+    asm.push_str(&format!(
+        "\n    .bss\n    .balign 16\ntape:\n    .zero {}\n",
+        options.tape_size
+    ));
+    let stats = Stats {
+        instructions: function.instructions,
+        cost: function.instructions as u64,
+        steps: StepStats::default(),
+    };
+    Ok((asm, stats))
+}
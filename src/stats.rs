@@ -0,0 +1,49 @@
+use std::fmt;
+
+use crate::instruction::Instruction;
+
+/// How much the optimizations of the steps shortened the program, before it is lowered to instructions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "report", derive(serde::Serialize))]
+pub struct StepStats {
+    /// Number of tokens of the source
+    pub tokens: usize,
+    /// Number of steps after combining repeated commands, including loop labels
+    pub combined: usize,
+    /// Number of steps after the start of the program was evaluated at compile time
+    pub after_startup: usize,
+}
+
+/// Statistics of the optimized program
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "report", derive(serde::Serialize))]
+pub struct Stats {
+    /// Number of executable instructions, excluding labels
+    pub instructions: usize,
+    /// Sum of the estimated costs of all instructions, lower is better
+    pub cost: u64,
+    /// Counts of the steps the instructions were lowered from, all zero from `Stats::from_instructions`
+    pub steps: StepStats,
+}
+impl Stats {
+    /// Statistics of the instructions, without the steps they were lowered from
+    pub fn from_instructions(ops: &[Instruction]) -> Self {
+        Self {
+            instructions: ops
+                .iter()
+                .filter(|op| op.effects().is_some() && !matches!(op, Instruction::Label(_)))
+                .count(),
+            cost: ops.iter().map(|op| u64::from(op.cost())).sum(),
+            steps: StepStats::default(),
+        }
+    }
+}
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Tokens: {}", self.steps.tokens)?;
+        writeln!(f, "Steps after combining: {}", self.steps.combined)?;
+        writeln!(f, "Steps after startup evaluation: {}", self.steps.after_startup)?;
+        writeln!(f, "Instructions: {}", self.instructions)?;
+        write!(f, "Estimated cost: {}", self.cost)
+    }
+}
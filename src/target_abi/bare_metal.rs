@@ -0,0 +1,119 @@
+use crate::instruction::{Effects, Instruction, Register64};
+use crate::label::LabelId;
+use crate::options::{BareMetal, EofPolicy};
+
+use super::{Assembler, LinkerInfo, Operations};
+
+/// Memory-mapped IO
+const IO: Effects = Effects {
+    flags: true,
+    registers: true,
+    control_flow: false,
+    stack: false,
+    io: true,
+};
+
+pub struct Interface {
+    next_label: usize,
+    config: BareMetal,
+}
+impl Interface {
+    pub fn new(config: BareMetal) -> Self {
+        Self {
+            next_label: 0,
+            config,
+        }
+    }
+
+    fn get_label(&mut self) -> LabelId {
+        let result = LabelId::from(format!(".interface_bare_metal{}", self.next_label));
+        self.next_label += 1;
+        result
+    }
+}
+impl Operations for Interface {
+    fn description(&self) -> &'static str {
+        "Freestanding x86-64: ELF64 object assembled with nasm for linking into a kernel, \
+         tape at a fixed address, memory-mapped IO, tape pointer in rbx"
+    }
+
+    fn triple(&self) -> &'static str {
+        "x86_64-unknown-none"
+    }
+
+    fn linker_info(&self) -> LinkerInfo {
+        LinkerInfo {
+            entrypoint: "_start".to_owned(),
+            libraries: Vec::new(),
+            externs: self.config.exit_symbol.iter().cloned().collect(),
+            object_format: "elf64".to_owned(),
+            assembler: Assembler::Nasm,
+            linker_cmd: "ld".to_owned(),
+            linker_args: Vec::new(),
+        }
+    }
+
+    fn stack_note(&self) -> Option<&'static str> {
+        Some("section .note.GNU-stack noalloc noexec nowrite progbits")
+    }
+
+    fn tape_address(&self) -> Option<u64> {
+        Some(self.config.tape_address)
+    }
+
+    /// Calls the exit function if one is given, otherwise halts with interrupts disabled
+    fn exit(&mut self, code: u8) -> Vec<Instruction> {
+        use Instruction::*;
+        match &self.config.exit_symbol {
+            Some(symbol) => vec![
+                MovImm(Register64::rdi, u64::from(code)),
+                NamedBlackBox(symbol.clone(), format!("call {}", symbol), Effects::VOLATILE),
+            ],
+            None => {
+                let label = self.get_label();
+                vec![
+                    Label(label),
+                    BlackBox("cli\nhlt".to_owned(), Effects::VOLATILE),
+                    Jump(label),
+                ]
+            },
+        }
+    }
+
+    /// Reads the input register, or stores the end of input value if there is none
+    fn read_byte(&mut self, pointer: Register64, eof: EofPolicy) -> Vec<Instruction> {
+        match self.config.input_address {
+            Some(address) => vec![Instruction::BlackBox(
+                format!("mov rdx, {:#x}\nmov al, [rdx]\nmov [{}], al", address, pointer),
+                IO,
+            )],
+            None => eof
+                .value()
+                .map(|value| Instruction::MovPtr8Imm(pointer, value))
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Writes the bytes to the output register one at a time
+    fn write_bytes(&mut self, pointer: Register64, count: u64) -> Vec<Instruction> {
+        use Instruction::*;
+        let address = self.config.output_address;
+        if count == 1 {
+            return vec![BlackBox(
+                format!("mov al, [{}]\nmov rdx, {:#x}\nmov [rdx], al", pointer, address),
+                IO,
+            )];
+        }
+        let label = self.get_label();
+        vec![
+            MovImm(Register64::rdx, address),
+            Mov(Register64::rsi, pointer),
+            MovImm(Register64::rcx, count),
+            Label(label),
+            BlackBox("mov al, [rsi]\nmov [rdx], al\ninc rsi".to_owned(), IO),
+            SubImm(Register64::rcx, 1),
+            JumpNonZero(label),
+        ]
+    }
+}
@@ -0,0 +1,135 @@
+use crate::instruction::{Effects, Instruction, Register64};
+use crate::label::LabelId;
+use crate::options::{BareMetal, EofPolicy};
+
+use super::{Assembler, LinkerInfo, Operations};
+
+/// Port IO
+const IO: Effects = Effects {
+    flags: true,
+    registers: true,
+    control_flow: false,
+    stack: false,
+    io: true,
+};
+
+/// Line status register bit set when a received byte is ready
+const DATA_READY: u8 = 0x01;
+
+/// Line status register bit set when the transmitter can take another byte
+const TRANSMIT_EMPTY: u8 = 0x20;
+
+pub struct Interface {
+    next_label: usize,
+    config: BareMetal,
+}
+impl Interface {
+    pub fn new(config: BareMetal) -> Self {
+        Self {
+            next_label: 0,
+            config,
+        }
+    }
+
+    fn get_label(&mut self) -> LabelId {
+        let result = LabelId::from(format!(".interface_freestanding{}", self.next_label));
+        self.next_label += 1;
+        result
+    }
+
+    /// Waits until the line status register of the serial port has the bit set
+    fn wait_for(&mut self, bit: u8) -> Vec<Instruction> {
+        use Instruction::*;
+        let label = self.get_label();
+        vec![
+            Label(label),
+            BlackBox(
+                format!(
+                    "mov dx, {:#x}\nin al, dx\ntest al, {:#x}",
+                    u32::from(self.config.serial_port) + 5,
+                    bit
+                ),
+                IO,
+            ),
+            JumpZero(label),
+        ]
+    }
+}
+impl Operations for Interface {
+    fn description(&self) -> &'static str {
+        "Freestanding x86-64: ELF64 object assembled with nasm for linking into a flat binary, \
+         tape at a fixed address, serial port IO, tape pointer in rbx"
+    }
+
+    fn triple(&self) -> &'static str {
+        "x86_64-unknown-none"
+    }
+
+    fn linker_info(&self) -> LinkerInfo {
+        LinkerInfo {
+            entrypoint: "_start".to_owned(),
+            libraries: Vec::new(),
+            externs: Vec::new(),
+            object_format: "elf64".to_owned(),
+            assembler: Assembler::Nasm,
+            linker_cmd: "ld".to_owned(),
+            linker_args: vec!["--oformat".to_owned(), "binary".to_owned()],
+        }
+    }
+
+    fn stack_note(&self) -> Option<&'static str> {
+        Some("section .note.GNU-stack noalloc noexec nowrite progbits")
+    }
+
+    fn tape_address(&self) -> Option<u64> {
+        Some(self.config.tape_address)
+    }
+
+    /// Halts with interrupts disabled, there is nothing to return to
+    fn exit(&mut self, _code: u8) -> Vec<Instruction> {
+        use Instruction::*;
+        let label = self.get_label();
+        vec![
+            Label(label),
+            BlackBox("cli\nhlt".to_owned(), Effects::VOLATILE),
+            Jump(label),
+        ]
+    }
+
+    /// Waits for a byte from the serial port, so there is no end of input
+    fn read_byte(&mut self, pointer: Register64, _eof: EofPolicy) -> Vec<Instruction> {
+        let mut result = self.wait_for(DATA_READY);
+        result.push(Instruction::BlackBox(
+            format!(
+                "mov dx, {:#x}\nin al, dx\nmov [{}], al",
+                self.config.serial_port, pointer
+            ),
+            IO,
+        ));
+        result
+    }
+
+    /// Writes the bytes to the serial port one at a time, each once the transmitter is ready
+    fn write_bytes(&mut self, pointer: Register64, count: u64) -> Vec<Instruction> {
+        use Instruction::*;
+        let label = self.get_label();
+        let mut result = vec![
+            Mov(Register64::rsi, pointer),
+            MovImm(Register64::rcx, count),
+            Label(label),
+        ];
+        result.extend(self.wait_for(TRANSMIT_EMPTY));
+        result.extend(vec![
+            BlackBox(
+                format!(
+                    "mov dx, {:#x}\nmov al, [rsi]\nout dx, al\ninc rsi",
+                    self.config.serial_port
+                ),
+                IO,
+            ),
+            SubImm(Register64::rcx, 1),
+            JumpNonZero(label),
+        ]);
+        result
+    }
+}
@@ -0,0 +1,147 @@
+use crate::instruction::{Effects, Instruction, Register64};
+use crate::options::EofPolicy;
+
+use super::{LinkerInfo, Operations};
+
+/// The program as a function of the System V AMD64 calling convention, `int name(void)`,
+/// returning the exit code instead of exiting. Everything else is done like on the wrapped target.
+pub struct Interface {
+    target: Box<dyn Operations>,
+    symbol: String,
+    /// Position-independent, to be linked into a shared library
+    shared: bool,
+}
+impl Interface {
+    pub fn new(target: Box<dyn Operations>, name: &str) -> Self {
+        let symbol = format!("{}{}", target.symbol_prefix(), name);
+        Self {
+            target,
+            symbol,
+            shared: false,
+        }
+    }
+
+    /// Like `new`, but for a shared library: data is addressed relative to `rip`,
+    /// the C library is called through the procedure linkage table of ELF objects,
+    /// and the object is linked into a library instead of an executable
+    pub fn shared(target: Box<dyn Operations>, name: &str) -> Self {
+        Self {
+            shared: true,
+            ..Self::new(target, name)
+        }
+    }
+
+    /// Calls of the wrapped target, through the procedure linkage table in a shared ELF object.
+    /// Mach-O linkers create the stubs for calls to other images themselves.
+    fn calls(&self, ops: Vec<Instruction>) -> Vec<Instruction> {
+        if !self.shared || self.target.linker_info().object_format != "elf64" {
+            return ops;
+        }
+        ops.into_iter()
+            .map(|op| match op {
+                Instruction::NamedBlackBox(name, source, effects) => {
+                    Instruction::NamedBlackBox(name, format!("{} wrt ..plt", source), effects)
+                },
+                op => op,
+            })
+            .collect()
+    }
+
+    /// Saves the registers the program uses that the caller expects to be preserved,
+    /// leaving the stack aligned like at the entry point of an executable
+    pub fn prologue() -> Instruction {
+        Instruction::BlackBox(
+            "push rbp\nmov rbp, rsp\npush rbx\npush r12\nsub rsp, 8".to_owned(),
+            Effects::VOLATILE,
+        )
+    }
+}
+impl Operations for Interface {
+    fn description(&self) -> &'static str {
+        self.target.description()
+    }
+
+    fn triple(&self) -> &'static str {
+        self.target.triple()
+    }
+
+    /// The function is the only global symbol, so objects of several programs can be linked together
+    fn linker_info(&self) -> LinkerInfo {
+        let link = self.target.linker_info();
+        let mut linker_args = link.linker_args;
+        if self.shared {
+            linker_args.retain(|arg| arg != "-no-pie");
+            let shared = if link.object_format == "macho64" { "-dylib" } else { "-shared" };
+            linker_args.insert(0, shared.to_owned());
+        }
+        LinkerInfo {
+            entrypoint: self.symbol.clone(),
+            linker_args,
+            ..link
+        }
+    }
+
+    fn text_section(&self) -> &'static str {
+        self.target.text_section()
+    }
+
+    fn data_section(&self) -> &'static str {
+        self.target.data_section()
+    }
+
+    fn rodata_section(&self) -> &'static str {
+        self.target.rodata_section()
+    }
+
+    fn stack_note(&self) -> Option<&'static str> {
+        self.target.stack_note()
+    }
+
+    fn rip_relative_data(&self) -> bool {
+        self.shared || self.target.rip_relative_data()
+    }
+
+    fn symbol_prefix(&self) -> &'static str {
+        self.target.symbol_prefix()
+    }
+
+    fn tape_address(&self) -> Option<u64> {
+        self.target.tape_address()
+    }
+
+    fn startup(&mut self) -> Vec<Instruction> {
+        self.target.startup()
+    }
+
+    /// Frees the tape and restores the registers saved by the prologue, returning the code
+    fn exit(&mut self, code: u8) -> Vec<Instruction> {
+        vec![Instruction::BlackBox(
+            format!(
+                "lea rsp, [rbp - 16]\npop r12\npop rbx\npop rbp\nmov eax, {}\nret",
+                code
+            ),
+            Effects::VOLATILE,
+        )]
+    }
+
+    fn map_guarded(
+        &mut self, pointer: Register64, pages: u64, exit: Vec<Instruction>,
+    ) -> Option<Vec<Instruction>> {
+        let ops = self.target.map_guarded(pointer, pages, exit)?;
+        Some(self.calls(ops))
+    }
+
+    fn read_byte(&mut self, pointer: Register64, eof: EofPolicy) -> Vec<Instruction> {
+        let ops = self.target.read_byte(pointer, eof);
+        self.calls(ops)
+    }
+
+    fn write_bytes(&mut self, pointer: Register64, count: u64) -> Vec<Instruction> {
+        let ops = self.target.write_bytes(pointer, count);
+        self.calls(ops)
+    }
+
+    fn read_embedded_byte(&mut self, pointer: Register64, eof: EofPolicy, length: usize) -> Vec<Instruction> {
+        self.target.read_embedded_byte(pointer, eof, length)
+    }
+}
@@ -1,78 +1,211 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
 use crate::instruction::{Effects, Instruction, Register64};
+use crate::label::LabelId;
+use crate::options::{EofPolicy, LinkerDriver};
+
+use super::{libc_map_guarded, Assembler, LinkerInfo, Operations};
+
+/// Directory of the C runtime objects linked in when `ld` is used directly,
+/// if `cc` can't find them, as on Debian and Ubuntu
+const CRT_DIR: &str = "/usr/lib/x86_64-linux-gnu";
+
+/// Dynamic linker of the x86-64 glibc, loading libc for programs linked with `ld` directly.
+/// The path is given by the x86-64 ABI, but some distributions keep it elsewhere.
+const DYNAMIC_LINKER: &str = "/lib64/ld-linux-x86-64.so.2";
+
+/// `errno` of a system call interrupted by a signal before it read anything
+const EINTR: u8 = 4;
 
-use super::{LinkerInfo, Operations};
+/// `MAP_ANONYMOUS` of `mmap`, mapping memory without a file
+const MAP_ANONYMOUS: u64 = 0x20;
+
+/// Path of a file the C compiler links with, `None` if there is no `cc` or it doesn't find the file
+fn cc_file(name: &str) -> Option<PathBuf> {
+    let output = Command::new("cc")
+        .arg(format!("-print-file-name={}", name))
+        .output()
+        .ok()?;
+    // Files it doesn't find are printed back as given
+    let path = PathBuf::from(String::from_utf8(output.stdout).ok()?.trim());
+    if output.status.success() && path.is_absolute() {
+        Some(path)
+    } else {
+        None
+    }
+}
 
 pub struct Interface {
     next_label: usize,
+    /// Whether the tape is allocated on the stack, and freed before exiting
+    stack_tape: bool,
 }
 impl Interface {
-    pub fn new() -> Self {
-        Self { next_label: 0 }
+    pub fn new(stack_tape: bool) -> Self {
+        Self {
+            next_label: 0,
+            stack_tape,
+        }
     }
 
-    fn get_label(&mut self) -> String {
-        let result = format!(".interface_linux{}", self.next_label);
+    fn get_label(&mut self) -> LabelId {
+        let result = LabelId::from(format!(".interface_linux{}", self.next_label));
         self.next_label += 1;
         result
     }
 }
 impl Operations for Interface {
+    fn description(&self) -> &'static str {
+        "Linux x86-64: ELF64 object assembled with nasm, linked with clang against libc, tape pointer in rbx"
+    }
+
+    fn triple(&self) -> &'static str {
+        "x86_64-unknown-linux-gnu"
+    }
+
     fn linker_info(&self) -> LinkerInfo {
         LinkerInfo {
             entrypoint: "main".to_owned(),
-            libraries: vec!["libc".to_owned()],
-            externs: vec!["read".to_owned(), "write".to_owned(), "exit".to_owned()],
+            libraries: Vec::new(),
+            externs: vec![
+                "read".to_owned(),
+                "write".to_owned(),
+                "exit".to_owned(),
+                "__errno_location".to_owned(),
+                "mmap".to_owned(),
+                "mprotect".to_owned(),
+            ],
             object_format: "elf64".to_owned(),
+            assembler: Assembler::Nasm,
             linker_cmd: "clang".to_owned(),
             linker_args: vec!["-no-pie".to_owned()],
         }
     }
 
-    fn exit(&mut self) -> Vec<Instruction> {
+    /// Both compiler drivers build position-independent executables by default,
+    /// which the absolute addresses of the tape and the data can't be in.
+    /// `ld` is given the C runtime starting `main`, the last object of which comes after libc.
+    fn driver_linker_info(&self, driver: LinkerDriver) -> Option<LinkerInfo> {
+        let mut link = self.linker_info();
+        link.linker_cmd = driver.command().to_owned();
+        if driver == LinkerDriver::Ld {
+            let crt_dir = cc_file("crt1.o")
+                .and_then(|path| Some(path.parent()?.to_path_buf()))
+                .unwrap_or_else(|| PathBuf::from(CRT_DIR));
+            let dynamic_linker = if Path::new(DYNAMIC_LINKER).exists() {
+                PathBuf::from(DYNAMIC_LINKER)
+            } else {
+                cc_file("ld-linux-x86-64.so.2").unwrap_or_else(|| PathBuf::from(DYNAMIC_LINKER))
+            };
+            link.linker_args = vec![
+                "-dynamic-linker".to_owned(),
+                dynamic_linker.display().to_string(),
+                format!("-L{}", crt_dir.display()),
+                crt_dir.join("crt1.o").display().to_string(),
+                crt_dir.join("crti.o").display().to_string(),
+            ];
+            link.libraries = vec!["c".to_owned(), ":crtn.o".to_owned()];
+        }
+        Some(link)
+    }
+
+    fn text_section(&self) -> &'static str {
+        "section .text progbits alloc exec nowrite align=16"
+    }
+
+    fn data_section(&self) -> &'static str {
+        "section .data progbits alloc noexec write align=4"
+    }
+
+    fn rodata_section(&self) -> &'static str {
+        "section .rodata progbits alloc noexec nowrite align=4"
+    }
+
+    fn stack_note(&self) -> Option<&'static str> {
+        Some("section .note.GNU-stack noalloc noexec nowrite progbits")
+    }
+
+    fn exit(&mut self, code: u8) -> Vec<Instruction> {
         use Instruction::*;
-        vec![
-            BlackBox("add rsp, 30000".to_owned(), Effects {
+        let mut ops = Vec::new();
+        if self.stack_tape {
+            ops.push(BlackBox("add rsp, $arraylen".to_owned(), Effects {
                 flags: true,
                 registers: true,
                 control_flow: true,
                 stack: true,
                 io: true,
-            }),
-            MovImm(Register64::rdi, 0),
-            NamedBlackBox("exit".to_owned(), "call exit".to_owned(), Effects {
+            }));
+        }
+        ops.push(MovImm(Register64::rdi, u64::from(code)));
+        ops.push(NamedBlackBox(
+            "exit".to_owned(),
+            "call exit".to_owned(),
+            Effects {
                 flags: true,
                 registers: true,
                 control_flow: true,
                 stack: true,
                 io: true,
-            }),
-        ]
+            },
+        ));
+        ops
+    }
+
+    fn map_guarded(
+        &mut self, pointer: Register64, pages: u64, exit: Vec<Instruction>,
+    ) -> Option<Vec<Instruction>> {
+        let labels = (self.get_label(), self.get_label());
+        Some(libc_map_guarded(pointer, pages, "", MAP_ANONYMOUS, labels, exit))
     }
 
     /// https://linux.die.net/man/2/read
-    fn read_byte(&mut self, pointer: Register64) -> Vec<Instruction> {
+    /// The read is retried if a signal interrupted it, and any other error is handled like end of file.
+    fn read_byte(&mut self, pointer: Register64, eof: EofPolicy) -> Vec<Instruction> {
         use Instruction::*;
-        let label_end = self.get_label();
-        vec![
+        let label_retry = self.get_label();
+        let label_read = self.get_label();
+        let mut result = vec![
+            Label(label_retry),
             MovImm(Register64::rdi, 0),
             Instruction::Mov(Register64::rsi, pointer),
             MovImm(Register64::rdx, 1),
-            BlackBox("call read".to_owned(), Effects {
+            NamedBlackBox("read".to_owned(), "call read".to_owned(), Effects {
                 flags: true,
                 registers: true,
                 control_flow: false,
                 stack: false,
                 io: true,
             }),
-            IsZero(Register64::rax),
-            JumpNonZero(label_end.clone()),
-            // End of file
-            MovPtr8Imm(Register64::rsi, 0),
-            Label(label_end),
-        ]
+            // Only an error returns a negative count
+            BlackBox("cmp rax, -1".to_owned(), Effects::VOLATILE),
+            JumpNonZero(label_read),
+            NamedBlackBox(
+                "__errno_location".to_owned(),
+                "call __errno_location".to_owned(),
+                Effects::VOLATILE,
+            ),
+            BlackBox(format!("cmp dword [rax], {}", EINTR), Effects::VOLATILE),
+            JumpZero(label_retry),
+            MovImm(Register64::rax, 0),
+            Label(label_read),
+        ];
+        if let Some(value) = eof.value() {
+            let label_end = self.get_label();
+            result.extend(vec![
+                IsZero(Register64::rax),
+                JumpNonZero(label_end),
+                // End of file, the call may have overwritten rsi
+                MovPtr8Imm(pointer, value),
+                Label(label_end),
+            ]);
+        }
+        result
     }
 
     /// https://linux.die.net/man/2/write
+    /// The wrapper of the system call, which unlike `putchar` of stdio buffers nothing
     fn write_bytes(&mut self, pointer: Register64, count: u64) -> Vec<Instruction> {
         use Instruction::*;
         vec![
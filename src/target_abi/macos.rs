@@ -1,6 +1,11 @@
 use crate::instruction::{Effects, Instruction, Register64};
+use crate::label::LabelId;
+use crate::options::{EofPolicy, LinkerDriver};
 
-use super::{LinkerInfo, Operations};
+use super::{libc_map_guarded, Assembler, LinkerInfo, Operations};
+
+/// `MAP_ANON` of `mmap`, mapping memory without a file
+const MAP_ANONYMOUS: u64 = 0x1000;
 
 pub struct Interface {
     next_label: usize,
@@ -10,19 +15,34 @@ impl Interface {
         Self { next_label: 0 }
     }
 
-    fn get_label(&mut self) -> String {
-        let result = format!(".interface_macos{}", self.next_label);
+    fn get_label(&mut self) -> LabelId {
+        let result = LabelId::from(format!(".interface_macos{}", self.next_label));
         self.next_label += 1;
         result
     }
 }
 impl Operations for Interface {
+    fn description(&self) -> &'static str {
+        "macOS x86-64: Mach-O object assembled with nasm, linked with ld against libSystem, tape pointer in rbx"
+    }
+
+    fn triple(&self) -> &'static str {
+        "x86_64-apple-darwin"
+    }
+
     fn linker_info(&self) -> LinkerInfo {
         LinkerInfo {
             entrypoint: "_main".to_owned(),
-            libraries: vec!["libc".to_owned()],
-            externs: vec!["_read".to_owned(), "_write".to_owned(), "_exit".to_owned()],
+            libraries: Vec::new(),
+            externs: vec![
+                "_read".to_owned(),
+                "_write".to_owned(),
+                "_exit".to_owned(),
+                "_mmap".to_owned(),
+                "_mprotect".to_owned(),
+            ],
             object_format: "macho64".to_owned(),
+            assembler: Assembler::Nasm,
             linker_cmd: "ld".to_owned(),
             linker_args: vec![
                 "-lSystem".to_owned(),
@@ -32,10 +52,29 @@ impl Operations for Interface {
         }
     }
 
-    fn exit(&mut self) -> Vec<Instruction> {
+    /// The compiler drivers link libSystem themselves, and take the minimum version as a flag
+    fn driver_linker_info(&self, driver: LinkerDriver) -> Option<LinkerInfo> {
+        let mut link = self.linker_info();
+        if driver != LinkerDriver::Ld {
+            link.linker_cmd = driver.command().to_owned();
+            link.linker_args = vec!["-mmacosx-version-min=10.10.0".to_owned()];
+        }
+        Some(link)
+    }
+
+    fn symbol_prefix(&self) -> &'static str {
+        "_"
+    }
+
+    /// The linker expects position-independent code, so data can't be addressed by an absolute `mov`
+    fn rip_relative_data(&self) -> bool {
+        true
+    }
+
+    fn exit(&mut self, code: u8) -> Vec<Instruction> {
         use Instruction::*;
         vec![
-            MovImm(Register64::rdi, 0),
+            MovImm(Register64::rdi, u64::from(code)),
             NamedBlackBox("exit".to_owned(), "call _exit".to_owned(), Effects {
                 flags: true,
                 registers: true,
@@ -46,10 +85,16 @@ impl Operations for Interface {
         ]
     }
 
-    fn read_byte(&mut self, pointer: Register64) -> Vec<Instruction> {
+    fn map_guarded(
+        &mut self, pointer: Register64, pages: u64, exit: Vec<Instruction>,
+    ) -> Option<Vec<Instruction>> {
+        let labels = (self.get_label(), self.get_label());
+        Some(libc_map_guarded(pointer, pages, "_", MAP_ANONYMOUS, labels, exit))
+    }
+
+    fn read_byte(&mut self, pointer: Register64, eof: EofPolicy) -> Vec<Instruction> {
         use Instruction::*;
-        let label_end = self.get_label();
-        vec![
+        let mut result = vec![
             MovImm(Register64::rdi, 0),
             Instruction::Mov(Register64::rsi, pointer),
             MovImm(Register64::rdx, 1),
@@ -60,12 +105,18 @@ impl Operations for Interface {
                 stack: false,
                 io: true,
             }),
-            IsZero(Register64::rax),
-            JumpNonZero(label_end.clone()),
-            // End of file
-            MovPtr8Imm(Register64::rsi, 0),
-            Label(label_end),
-        ]
+        ];
+        if let Some(value) = eof.value() {
+            let label_end = self.get_label();
+            result.extend(vec![
+                IsZero(Register64::rax),
+                JumpNonZero(label_end),
+                // End of file, the call may have overwritten rsi
+                MovPtr8Imm(pointer, value),
+                Label(label_end),
+            ]);
+        }
+        result
     }
 
     fn write_bytes(&mut self, pointer: Register64, count: u64) -> Vec<Instruction> {
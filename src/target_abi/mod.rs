@@ -1,21 +1,51 @@
+mod bare_metal;
+mod freestanding;
+pub(crate) mod function;
 mod linux;
 mod macos;
 
+use std::path::Path;
+
 use strum_macros::{EnumString, EnumVariantNames};
 
-use crate::instruction::{Instruction, Register64};
+use crate::error::{CompileError, CompileResult};
+use crate::instruction::{Effects, Instruction, Register64};
+use crate::label::LabelId;
+use crate::options::{CompileOptions, EofPolicy, LinkerDriver};
+use crate::riscv;
+
+/// Data label of the input embedded in the binary, followed by one byte read on end of input
+pub const EMBEDDED_INPUT_LABEL: &str = "embedded_input";
+
+/// Data label of the 64-bit read position in the embedded input
+pub const EMBEDDED_POSITION_LABEL: &str = "embedded_input_position";
+
+/// Size of the pages mapped by `Operations::map_guarded`
+pub const PAGE_SIZE: u64 = 4096;
+
+/// Assembler of the generated source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Assembler {
+    /// `nasm`, given the object format with `-f`
+    Nasm,
+    /// GNU `as` with the given command, e.g. `riscv64-linux-gnu-as`
+    Gnu(String),
+}
 
 /// Instructions for linking
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LinkerInfo {
     /// Entry point symbol name, e.g. `main`
     pub entrypoint: String,
-    /// Libraries to link against, e.g. `libc`
+    /// Additional libraries to link against, passed as `-l<name>` after the object file,
+    /// e.g. `:libc.so.6`
     pub libraries: Vec<String>,
     /// External symbols, e.g. `write`
     pub externs: Vec<String>,
     /// Object file format e.g. `elf64`
     pub object_format: String,
+    /// Assembler of the generated source
+    pub assembler: Assembler,
     /// Linker command, e.g. `gcc`
     pub linker_cmd: String,
     /// Linker extra arguments, e.g. `vec!["-no-pie"]`
@@ -24,38 +54,214 @@ pub struct LinkerInfo {
 impl LinkerInfo {
     /// Creates required assembly header
     pub fn to_assembly(&self) -> String {
-        let mut r: String = self.externs.iter().map(|e| format!("extern {}\n", e)).collect();
-        r.push_str(&format!("global {}\n", self.entrypoint));
+        let (extern_directive, global_directive) = match self.assembler {
+            Assembler::Nasm => ("extern", "global"),
+            Assembler::Gnu(_) => ("    .extern", "    .globl"),
+        };
+        let mut r: String = self
+            .externs
+            .iter()
+            .map(|e| format!("{} {}\n", extern_directive, e))
+            .collect();
+        r.push_str(&format!("{} {}\n", global_directive, self.entrypoint));
         r
     }
+
+    /// Comment with the commands that assemble `source` and link it into an executable
+    /// named like it without the extension, as the command line tool runs them
+    pub fn build_comment(&self, source: &str) -> String {
+        let output = Path::new(source).with_extension("");
+        let output = output.to_string_lossy();
+        let object = format!("{}.o", output);
+        let (comment, assemble) = match &self.assembler {
+            Assembler::Nasm => (
+                ";",
+                format!("nasm -f {} -o {} {}", self.object_format, object, source),
+            ),
+            Assembler::Gnu(command) => ("#", format!("{} -o {} {}", command, object, source)),
+        };
+        let mut link = vec![self.linker_cmd.clone()];
+        link.extend(self.linker_args.iter().cloned());
+        link.extend(vec!["-o".to_owned(), output.into_owned(), object]);
+        link.extend(self.libraries.iter().map(|library| format!("-l{}", library)));
+        format!(
+            "{c} Build with:\n{c}   {}\n{c}   {}\n",
+            assemble,
+            link.join(" "),
+            c = comment
+        )
+    }
 }
 
 pub trait Operations {
+    /// Human-readable description of the target and its toolchain
+    fn description(&self) -> &'static str;
+
+    /// Canonical target triple, e.g. `x86_64-unknown-linux-gnu`
+    fn triple(&self) -> &'static str;
+
     /// Linker info
     fn linker_info(&self) -> LinkerInfo;
 
+    /// Linker info for linking with the driver instead, `None` if the target isn't linked with it
+    fn driver_linker_info(&self, driver: LinkerDriver) -> Option<LinkerInfo> {
+        Some(self.linker_info()).filter(|link| link.linker_cmd == driver.command())
+    }
+
+    /// Section directive for the code, which is never written to
+    fn text_section(&self) -> &'static str {
+        "section .text"
+    }
+
+    /// Section directive for data written by the program
+    fn data_section(&self) -> &'static str {
+        "section .data"
+    }
+
+    /// Section directive for constant data
+    fn rodata_section(&self) -> &'static str {
+        "section .rodata"
+    }
+
+    /// Section written at the end of the program marking the stack non-executable, if needed
+    fn stack_note(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Whether addresses of data are loaded relative to `rip`, as position-independent code requires
+    fn rip_relative_data(&self) -> bool {
+        false
+    }
+
+    /// Prefix of the symbols of C functions, e.g. `_` on macOS
+    fn symbol_prefix(&self) -> &'static str {
+        ""
+    }
+
+    /// Fixed address of the tape, `None` allocates it on the stack
+    fn tape_address(&self) -> Option<u64> {
+        None
+    }
+
     /// Program startup code
     fn startup(&mut self) -> Vec<Instruction> {
         Vec::new()
     }
 
-    /// Stop program execution with successful exit code
-    fn exit(&mut self) -> Vec<Instruction>;
+    /// Maps `pages` pages of zeroed memory, the first and the last one inaccessible,
+    /// and stores the address of the second one in `pointer`, running `exit` if mapping fails.
+    /// `None` if the target can't map memory.
+    fn map_guarded(
+        &mut self, _pointer: Register64, _pages: u64, _exit: Vec<Instruction>,
+    ) -> Option<Vec<Instruction>> {
+        None
+    }
+
+    /// Stop program execution with the given exit code
+    fn exit(&mut self, code: u8) -> Vec<Instruction>;
 
-    /// Reads a single byte from stdin
-    fn read_byte(&mut self, pointer: Register64) -> Vec<Instruction>;
+    /// Reads a single byte from stdin, handling end of input according to `eof`
+    fn read_byte(&mut self, pointer: Register64, eof: EofPolicy) -> Vec<Instruction>;
 
-    /// Writes `count` bytes to stdout
+    /// Writes `count` bytes to stdout before the next instruction, without buffering,
+    /// so that a prompt is visible before a following read blocks.
+    /// `pointer` holds the address of the first byte, which needn't be on the tape:
+    /// the dispatcher of `compile_programs` writes its usage text from the data this way.
     fn write_bytes(&mut self, pointer: Register64, count: u64) -> Vec<Instruction>;
+
+    /// Reads a single byte from the input embedded in the binary instead of stdin.
+    /// The position stops at `length`, where the byte stored on end of input is read.
+    fn read_embedded_byte(&mut self, pointer: Register64, eof: EofPolicy, length: usize) -> Vec<Instruction> {
+        let mut src = vec![
+            format!("mov rax, [rel {}]", EMBEDDED_POSITION_LABEL),
+            format!("lea rcx, [rel {}]", EMBEDDED_INPUT_LABEL),
+            "movzx edx, byte [rcx + rax]".to_owned(),
+        ];
+        if eof.value().is_none() {
+            src.push(format!("movzx esi, byte [{}]", pointer));
+        }
+        src.push(format!("cmp rax, {}", length));
+        if eof.value().is_none() {
+            src.push("cmovae edx, esi".to_owned());
+        }
+        // Carry is set only while input remains
+        src.push("adc rax, 0".to_owned());
+        src.push(format!("mov [rel {}], rax", EMBEDDED_POSITION_LABEL));
+        src.push(format!("mov [{}], dl", pointer));
+        vec![Instruction::BlackBox(src.join("\n"), Effects {
+            flags: true,
+            registers: true,
+            control_flow: false,
+            stack: false,
+            io: true,
+        })]
+    }
+}
+
+/// `Operations::map_guarded` with the `mmap` and `mprotect` of the C library, called with `prefix`.
+/// `anonymous` is the `MAP_ANONYMOUS` flag of the target, and `exit` ends the program after `fail`.
+fn libc_map_guarded(
+    pointer: Register64, pages: u64, prefix: &str, anonymous: u64, (fail, done): (LabelId, LabelId),
+    exit: Vec<Instruction>,
+) -> Vec<Instruction> {
+    use Instruction::*;
+    const PROT_NONE: u64 = 0;
+    const PROT_READ_WRITE: u64 = 3;
+    const MAP_PRIVATE: u64 = 2;
+    let call = |name: &str| {
+        NamedBlackBox(name.to_owned(), format!("call {}{}", prefix, name), Effects::VOLATILE)
+    };
+    let mut result = vec![
+        MovImm(Register64::rdi, 0),
+        MovImm(Register64::rsi, pages * PAGE_SIZE),
+        MovImm(Register64::rdx, PROT_NONE),
+        MovImm(Register64::rcx, MAP_PRIVATE | anonymous),
+        // No file descriptor
+        BlackBox("mov r8, -1".to_owned(), Effects::VOLATILE),
+        MovImm(Register64::r9, 0),
+        call("mmap"),
+        // `MAP_FAILED`
+        BlackBox("cmp rax, -1".to_owned(), Effects::VOLATILE),
+        JumpZero(fail),
+        Mov(pointer, Register64::rax),
+        AddImm(pointer, PAGE_SIZE),
+        Mov(Register64::rdi, pointer),
+        MovImm(Register64::rsi, (pages - 2) * PAGE_SIZE),
+        MovImm(Register64::rdx, PROT_READ_WRITE),
+        call("mprotect"),
+        IsZero(Register64::rax),
+        JumpZero(done),
+        Label(fail),
+    ];
+    result.extend(exit);
+    result.push(Label(done));
+    result
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, EnumVariantNames)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
 #[strum(serialize_all = "lowercase")]
 pub enum ABI {
     Linux,
     MacOS,
+    /// Freestanding code for a kernel or firmware, configured with `CompileOptions::bare_metal`
+    BareMetal,
+    /// Flat binary for a loader that has entered long mode, with IO through the serial port,
+    /// configured with `CompileOptions::bare_metal`
+    Freestanding,
+    /// Linux on rv64, lowering the steps to RISC-V assembly instead of the x86-64 instructions
+    RiscV64,
 }
 impl ABI {
+    /// Every target, in the order of the variants
+    pub fn all() -> &'static [Self] {
+        &[Self::Linux, Self::MacOS, Self::BareMetal, Self::Freestanding, Self::RiscV64]
+    }
+
     pub fn pick_default() -> Option<Self> {
         if cfg!(target_os = "linux") {
             Some(Self::Linux)
@@ -67,10 +273,72 @@ impl ABI {
         }
     }
 
-    pub fn operations(self) -> Box<dyn Operations> {
+    /// Whether this is the ABI of the current platform, so that the host toolchain can link for it
+    pub fn is_host(self) -> bool {
         match self {
-            Self::Linux => Box::new(linux::Interface::new()),
-            Self::MacOS => Box::new(macos::Interface::new()),
+            Self::Linux => cfg!(target_os = "linux"),
+            Self::MacOS => cfg!(target_os = "macos"),
+            Self::BareMetal | Self::Freestanding => false,
+            Self::RiscV64 => cfg!(all(target_os = "linux", target_arch = "riscv64")),
+        }
+    }
+
+    /// Human-readable description of the target and its toolchain
+    pub fn description(self) -> &'static str {
+        match self.interface(&CompileOptions::default()) {
+            Some(operations) => operations.description(),
+            None => riscv::DESCRIPTION,
+        }
+    }
+
+    /// Canonical target triple
+    pub fn triple(self) -> &'static str {
+        match self.interface(&CompileOptions::default()) {
+            Some(operations) => operations.triple(),
+            None => riscv::TRIPLE,
+        }
+    }
+
+    /// Linker info, for the linker driver of the options if one is given
+    pub fn linker_info(self, options: &CompileOptions) -> LinkerInfo {
+        match self.interface(options) {
+            Some(operations) => options
+                .linker_driver
+                .and_then(|driver| operations.driver_linker_info(driver))
+                .unwrap_or_else(|| operations.linker_info()),
+            None => riscv::linker_info(),
         }
     }
+
+    /// Lowering of the x86-64 instructions for the target,
+    /// or an error for targets that lower the steps to their own assembly
+    /// and for a linker driver the target isn't linked with
+    pub fn operations(self, options: &CompileOptions) -> CompileResult<Box<dyn Operations>> {
+        let operations = self.interface(options).ok_or(CompileError::InvalidOption(
+            "target",
+            "riscv64 only supports compiling a single program to assembly",
+        ))?;
+        if let Some(driver) = options.linker_driver {
+            if operations.driver_linker_info(driver).is_none() {
+                return Err(CompileError::InvalidOption(
+                    "linker_driver",
+                    "the target isn't linked with the driver",
+                ));
+            }
+        }
+        Ok(operations)
+    }
+
+    /// Lowering of the x86-64 instructions for the target, `None` for riscv64.
+    /// Unlike `operations` this only depends on the target, so the other properties of
+    /// the target are looked up with it.
+    fn interface(self, options: &CompileOptions) -> Option<Box<dyn Operations>> {
+        Some(match self {
+            Self::Linux => Box::new(linux::Interface::new(!options.guard_pages)),
+            Self::MacOS => Box::new(macos::Interface::new()),
+            Self::BareMetal => Box::new(bare_metal::Interface::new(options.bare_metal.clone())),
+            Self::Freestanding => Box::new(freestanding::Interface::new(options.bare_metal.clone())),
+            Self::RiscV64 => return None,
+        })
+    }
 }
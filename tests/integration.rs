@@ -1,12 +1,35 @@
 use std::fs;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 use tempfile::tempdir;
 
 use assert_cmd::prelude::*;
 
+use brain_opt::interpreter::Interpreter;
+use brain_opt::parse;
+
+/// Whether the commands are installed, checked by running them with `-v`
+fn installed(commands: &[&str]) -> bool {
+    commands
+        .iter()
+        .all(|command| Command::new(command).arg("-v").output().is_ok())
+}
+
+/// Whether NASM and the linker of the host target are installed, as most tests assemble and link
+/// the programs. Without them, those tests return early instead of failing on the missing commands.
+fn toolchain_installed() -> bool {
+    let found = installed(&["nasm", "clang"]);
+    if !found {
+        eprintln!("Skipped, as NASM or clang isn't installed");
+    }
+    found
+}
+
 fn assert_output<P: AsRef<Path>>(path: P, input: &'static [u8], output: &'static [u8]) {
     let td = tempdir().unwrap();
     let execpath = td.path().join("executable");
@@ -41,6 +64,27 @@ fn assert_output<P: AsRef<Path>>(path: P, input: &'static [u8], output: &'static
     assert_eq!(res.stdout, output);
 }
 
+/// Compiles the sources into one binary, and runs the program selected by `name`
+fn assert_programs_output(paths: &[&str], name: &str, output: &'static [u8], status: i32) {
+    let td = tempdir().unwrap();
+    let execpath = td.path().join("executable");
+    let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+    let compiler = cmd
+        .args(paths)
+        .arg("--output")
+        .arg(execpath.as_os_str())
+        .output()
+        .unwrap();
+    println!("<compiler stderr>");
+    println!("{}", String::from_utf8_lossy(&compiler.stderr));
+    println!("</compiler stderr>");
+    assert!(compiler.status.success());
+
+    let res = Command::new(execpath).arg(name).output().unwrap();
+    assert_eq!(res.status.code(), Some(status));
+    assert_eq!(res.stdout, output);
+}
+
 fn get_assembly<P: AsRef<Path>>(path: P) -> String {
     let td = tempdir().unwrap();
     let asmpath = td.path().join("out.asm");
@@ -61,13 +105,34 @@ fn get_assembly<P: AsRef<Path>>(path: P) -> String {
     String::from_utf8(fs::read(asmpath).unwrap()).unwrap()
 }
 
+fn get_precomputed_output<P: AsRef<Path>>(path: P) -> Vec<u8> {
+    let td = tempdir().unwrap();
+    let outpath = td.path().join("output");
+    let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+    let compiler = cmd
+        .arg(path.as_ref().as_os_str())
+        .arg("--precompute-output")
+        .arg("--output")
+        .arg(outpath.clone())
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+    fs::read(outpath).unwrap()
+}
+
 #[test]
 fn test_helloworld() {
+    if !toolchain_installed() {
+        return;
+    }
     assert_output("examples/helloworld.bf", b"", b"Hello World!\n");
 }
 
 #[test]
 fn test_cat() {
+    if !toolchain_installed() {
+        return;
+    }
     assert_output("examples/cat.bf", b"", b"");
     assert_output("examples/cat.bf", b"copypaste", b"copypaste");
     assert_output("examples/cat.bf", b"a\nb", b"a\nb");
@@ -75,6 +140,9 @@ fn test_cat() {
 
 #[test]
 fn test_bubblesort() {
+    if !toolchain_installed() {
+        return;
+    }
     assert_output("examples/bubblesort_bytes.bf", b"", b"");
     assert_output("examples/bubblesort_bytes.bf", b"213", b"123");
     assert_output("examples/bubblesort_bytes.bf", b"123", b"123");
@@ -84,6 +152,9 @@ fn test_bubblesort() {
 
 #[test]
 fn test_quicksort() {
+    if !toolchain_installed() {
+        return;
+    }
     assert_output("examples/quicksort_bytes.bf", b"", b"");
     assert_output("examples/quicksort_bytes.bf", b"213", b"123");
     assert_output("examples/quicksort_bytes.bf", b"123", b"123");
@@ -93,6 +164,9 @@ fn test_quicksort() {
 
 #[test]
 fn test_rot13() {
+    if !toolchain_installed() {
+        return;
+    }
     assert_output("examples/rot13.bf", b"", b"");
     assert_output("examples/rot13.bf", b"a", b"n");
     assert_output("examples/rot13.bf", b"Hello World!", b"Uryyb Jbeyq!");
@@ -100,6 +174,49 @@ fn test_rot13() {
     assert_output("examples/rot13.bf", b"a=1", b"n=1");
 }
 
+/// The examples run by dbfi, the interpreter written in Brainfuck, given the program and its input after a `!`
+#[test]
+fn test_dbfi() {
+    if !toolchain_installed() {
+        return;
+    }
+    let interpreter = "examples/dbfi.bf";
+    let hello = concat!(include_str!("../examples/helloworld.bf"), "!");
+    assert_output(interpreter, hello.as_bytes(), b"Hello World!\n");
+    let rot13 = concat!(include_str!("../examples/rot13.bf"), "!Hello World!");
+    assert_output(interpreter, rot13.as_bytes(), b"Uryyb Jbeyq!");
+    let quicksort = concat!(include_str!("../examples/quicksort_bytes.bf"), "!987654321");
+    assert_output(interpreter, quicksort.as_bytes(), b"123456789");
+}
+
+/// dbfi running a copy of itself, which runs the hello world
+#[test]
+#[ignore]
+fn test_dbfi_self() {
+    if !toolchain_installed() {
+        return;
+    }
+    let interpreter = "examples/dbfi.bf";
+    let hello = concat!(
+        include_str!("../examples/dbfi.bf"),
+        "!",
+        include_str!("../examples/helloworld.bf"),
+        "!"
+    );
+    assert_output(interpreter, hello.as_bytes(), b"Hello World!\n");
+}
+
+#[test]
+fn test_multiple_programs() {
+    if !toolchain_installed() {
+        return;
+    }
+    let paths = ["examples/helloworld.bf", "examples/print_ab.bf"];
+    assert_programs_output(&paths, "helloworld", b"Hello World!\n", 0);
+    assert_programs_output(&paths, "print_ab", b"ab", 0);
+    assert_programs_output(&paths, "cat", b"helloworld print_ab\n", 2);
+}
+
 #[test]
 #[should_panic]
 fn fail_helloworld() {
@@ -130,8 +247,874 @@ fn fail_rot13() {
     assert_output("examples/rot13.bf", b"abc", b"abc");
 }
 
+#[test]
+fn test_precompute_output_helloworld() {
+    assert_eq!(
+        get_precomputed_output("examples/helloworld.bf"),
+        b"Hello World!\n"
+    );
+}
+
 #[test]
 fn test_assembly_helloworld() {
+    if !toolchain_installed() {
+        return;
+    }
     let asm = get_assembly("examples/helloworld.bf");
     assert!(asm.contains("\"Hello World!\""));
 }
+
+#[test]
+fn test_deterministic_assembly() {
+    if !toolchain_installed() {
+        return;
+    }
+    // Separate runs of the compiler, so that hash maps are seeded differently
+    for entry in fs::read_dir("examples").unwrap() {
+        let path = entry.unwrap().path();
+        // Other files, such as the linker script, are not programs
+        if path.extension() != Some("bf".as_ref()) {
+            continue;
+        }
+        assert_eq!(get_assembly(&path), get_assembly(&path), "{:?}", path);
+    }
+}
+
+#[test]
+fn test_build_script_compile_file() {
+    if !installed(&["nasm", "ar"]) {
+        return;
+    }
+    let td = tempdir().unwrap();
+    let out_path = td.path().join("hello.o");
+    let options = brain_opt::CompileOptions::default();
+    let path = brain_opt::build_script::compile_file(
+        "examples/helloworld.bf",
+        &out_path,
+        brain_opt::ABI::Linux,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(path, out_path);
+    assert!(fs::read(out_path).unwrap().starts_with(b"\x7fELF"));
+    // Linked by cargo as the library `hello`
+    assert!(fs::read(td.path().join("libhello.a")).unwrap().starts_with(b"!<arch>\n"));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_non_executable_stack() {
+    // Checked only where binutils are installed
+    if !toolchain_installed() || !installed(&["readelf"]) {
+        return;
+    }
+    let td = tempdir().unwrap();
+    let execpath = td.path().join("executable");
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/helloworld.bf")
+        .arg("--output")
+        .arg(execpath.as_os_str())
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+
+    let res = Command::new("readelf")
+        .arg("--program-headers")
+        .arg("--wide")
+        .arg(execpath.as_os_str())
+        .output()
+        .unwrap();
+    let headers = String::from_utf8_lossy(&res.stdout);
+    let stack = headers
+        .lines()
+        .find(|line| line.trim_start().starts_with("GNU_STACK"))
+        .expect("No GNU_STACK program header");
+    // Flags are the second to last column, e.g. `RW` or `RWE`
+    let flags = stack.split_whitespace().rev().nth(1).unwrap();
+    assert!(!flags.contains('E'), "{}", stack);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_guard_pages() {
+    use std::os::unix::process::ExitStatusExt;
+
+    if !toolchain_installed() {
+        return;
+    }
+    // Stores input to the cells from the first one on, until it reads a zero
+    let td = tempdir().unwrap();
+    let source = td.path().join("fill.bf");
+    fs::write(&source, ",[>,]").unwrap();
+    let execpath = td.path().join("executable");
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg(source.as_os_str())
+        .args(&["--guard-pages", "--tape-size", "16"])
+        .arg("--output")
+        .arg(execpath.as_os_str())
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+
+    let run = |input: &[u8]| {
+        let mut p = Command::new(&execpath).stdin(Stdio::piped()).spawn().unwrap();
+        p.stdin.as_mut().unwrap().write_all(input).unwrap();
+        p.wait().unwrap()
+    };
+    assert_eq!(run(&[1; 15]).code(), Some(0));
+    // The seventeenth byte is stored right of the last cell, in the guard page
+    assert_eq!(run(&[1; 17]).signal(), Some(11));
+}
+
+#[test]
+fn test_target_cpu() {
+    if !toolchain_installed() {
+        return;
+    }
+    // Forty cells after a zero one are stored with vectors before the input, and printed after it
+    let td = tempdir().unwrap();
+    let source = td.path().join("cells.bf");
+    let mut program = String::from(">");
+    for value in 0..40 {
+        program.push_str(&"+".repeat(65 + value));
+        program.push('>');
+    }
+    program.push_str(",[<]>[.>]");
+    fs::write(&source, program).unwrap();
+
+    let mut cpus = vec!["sse2"];
+    if is_x86_feature_detected!("avx2") {
+        cpus.push("avx2");
+    }
+    for cpu in cpus {
+        let execpath = td.path().join(cpu);
+        let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg(source.as_os_str())
+            .arg("--target-cpu")
+            .arg(cpu)
+            .arg("--output")
+            .arg(execpath.as_os_str())
+            .output()
+            .unwrap();
+        assert!(compiler.status.success());
+
+        let mut p = Command::new(execpath)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        p.stdin.as_mut().unwrap().write_all(b"!").unwrap();
+        let res = p.wait_with_output().unwrap();
+        assert!(res.status.success());
+        let expected: Vec<u8> = (b'A'..b'A' + 40).chain(b"!".iter().copied()).collect();
+        assert_eq!(res.stdout, expected);
+    }
+}
+
+#[test]
+fn test_inline_asm() {
+    if !toolchain_installed() {
+        return;
+    }
+    let td = tempdir().unwrap();
+    let source = td.path().join("asm.bf");
+    fs::write(&source, "++++++++[>++++++++<-]>{! inc byte [$pointer] !}.").unwrap();
+    let execpath = td.path().join("executable");
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg(source.as_os_str())
+        .arg("--allow-inline-asm")
+        .arg("--output")
+        .arg(execpath.as_os_str())
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+
+    let res = Command::new(execpath).output().unwrap();
+    assert!(res.status.success());
+    assert_eq!(res.stdout, b"A");
+}
+
+#[test]
+fn test_deny_warnings() {
+    let td = tempdir().unwrap();
+    let source = td.path().join("infinite.bf");
+    fs::write(&source, ",[].").unwrap();
+    let compile = |deny: bool| {
+        let mut command = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
+        command.arg(source.as_os_str()).arg("--skip-compilation");
+        if deny {
+            command.arg("--deny-warnings");
+        }
+        command.output().unwrap()
+    };
+    let res = compile(false);
+    assert!(res.status.success());
+    assert!(String::from_utf8_lossy(&res.stderr).contains("never exits"));
+    assert!(!compile(true).status.success());
+
+    // Examples compile cleanly
+    let res = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/helloworld.bf")
+        .arg("--skip-compilation")
+        .arg("--deny-warnings")
+        .output()
+        .unwrap();
+    assert!(res.status.success());
+}
+
+#[test]
+fn test_json() {
+    let td = tempdir().unwrap();
+    let source = td.path().join("infinite.bf");
+    fs::write(&source, ",[].").unwrap();
+    let res = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg(source.as_os_str())
+        .arg("--skip-compilation")
+        .arg("--json")
+        .output()
+        .unwrap();
+    assert!(res.status.success());
+    // The warning is only in the report
+    assert!(res.stderr.is_empty());
+    let report = String::from_utf8_lossy(&res.stdout);
+    assert!(report.contains(&format!("\"source\": \"{}\"", source.display())));
+    assert!(report.contains("\"tokens\": 4"));
+    assert!(report.contains("never exits"));
+    assert!(report.contains("\"assemble_us\": 0"));
+}
+
+#[test]
+fn test_pass_order() {
+    if !toolchain_installed() {
+        return;
+    }
+    let compile = |order: &str| {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg("examples/helloworld.bf")
+            .arg("--skip-compilation")
+            .arg("--pass-order")
+            .arg(order)
+            .output()
+            .unwrap()
+    };
+    assert!(compile("optimize_exit,optimize_zero_loop").status.success());
+    assert!(!compile("optimize_scan_loop,optimize_loop_after_loop").status.success());
+    assert!(!compile("optimize_nothing").status.success());
+
+    // The order found is written as a config line, and gives the same output
+    let td = tempdir().unwrap();
+    let config = td.path().join("tuned.toml");
+    let res = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/helloworld.bf")
+        .arg("--tune-passes")
+        .arg("-o")
+        .arg(config.as_os_str())
+        .output()
+        .unwrap();
+    assert!(res.status.success());
+    assert!(fs::read_to_string(&config).unwrap().starts_with("pass-order = ["));
+    let executable = td.path().join("helloworld");
+    let res = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/helloworld.bf")
+        .arg("--config")
+        .arg(config.as_os_str())
+        .arg("-o")
+        .arg(executable.as_os_str())
+        .output()
+        .unwrap();
+    assert!(res.status.success());
+    let res = Command::new(&executable).output().unwrap();
+    assert_eq!(res.stdout, b"Hello World!\n");
+}
+
+#[test]
+fn test_config() {
+    let td = tempdir().unwrap();
+    let source = td.path().join("wide.bf");
+    fs::write(&source, "@cells 0 0 u16\n++++++++[>++++++++<-]>.").unwrap();
+    let config = td.path().join("config.toml");
+    let compile = |contents: &str| {
+        fs::write(&config, contents).unwrap();
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg(source.as_os_str())
+            .arg("--config")
+            .arg(config.as_os_str())
+            .arg("--skip-compilation")
+            .arg("--assembly")
+            .arg("-")
+            .output()
+            .unwrap()
+    };
+
+    // The cell widths of the source are used as with --wide-cells
+    let res = compile("opt-level = 1\nwide-cells = true\ndisabled-passes = [\"optimize_unroll\"]\n");
+    assert!(res.status.success());
+    assert!(String::from_utf8_lossy(&res.stdout).contains("word [rbx]"));
+    let res = compile("opt-level = 1\n");
+    assert!(res.status.success());
+    assert!(!String::from_utf8_lossy(&res.stdout).contains("word [rbx]"));
+
+    // Disabled passes are checked like the ones given with --disable-pass
+    let res = compile("disabled-passes = [\"optimize_nothing\"]\n");
+    assert!(!res.status.success());
+}
+
+#[test]
+fn test_archive() {
+    if !toolchain_installed() || !installed(&["cc"]) {
+        return;
+    }
+    // Functions of the archive called from C, the second one returning 1 as its pointer underflows
+    let td = tempdir().unwrap();
+    fs::write(
+        td.path().join("hello-world.bf"),
+        include_str!("../examples/helloworld.bf"),
+    )
+    .unwrap();
+    fs::write(td.path().join("underflow.bf"), ",.[<]").unwrap();
+    let archive = td.path().join("programs.a");
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg(td.path().join("hello-world.bf"))
+        .arg(td.path().join("underflow.bf"))
+        .arg("--archive")
+        .arg("--output")
+        .arg(archive.as_os_str())
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+
+    let host = td.path().join("host.c");
+    fs::write(
+        &host,
+        "int hello_world(void);\nint underflow(void);\n\
+         int main(void) { return hello_world() * 10 + underflow() + hello_world() * 100; }\n",
+    )
+    .unwrap();
+    let execpath = td.path().join("executable");
+    let linker = Command::new("cc")
+        .arg("-no-pie")
+        .arg(host.as_os_str())
+        .arg(archive.as_os_str())
+        .arg("-o")
+        .arg(execpath.as_os_str())
+        .status()
+        .unwrap();
+    assert!(linker.success());
+
+    let mut p = Command::new(&execpath)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    p.stdin.as_mut().unwrap().write_all(b"A").unwrap();
+    let res = p.wait_with_output().unwrap();
+    assert_eq!(res.status.code(), Some(1));
+    assert_eq!(res.stdout, b"Hello World!\nAHello World!\n");
+}
+
+#[test]
+fn test_shared() {
+    if !toolchain_installed() || !installed(&["cc"]) {
+        return;
+    }
+    // Functions of the shared library called from a position-independent C program
+    let td = tempdir().unwrap();
+    fs::write(
+        td.path().join("hello-world.bf"),
+        include_str!("../examples/helloworld.bf"),
+    )
+    .unwrap();
+    fs::write(td.path().join("underflow.bf"), ",.[<]").unwrap();
+    let library = td.path().join("libprograms.so");
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg(td.path().join("hello-world.bf"))
+        .arg(td.path().join("underflow.bf"))
+        .arg("--shared")
+        .arg("--output")
+        .arg(library.as_os_str())
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+
+    let host = td.path().join("host.c");
+    fs::write(
+        &host,
+        "int hello_world(void);\nint underflow(void);\n\
+         int main(void) { return hello_world() * 10 + underflow() + hello_world() * 100; }\n",
+    )
+    .unwrap();
+    let execpath = td.path().join("executable");
+    let linker = Command::new("cc")
+        .arg("-pie")
+        .arg(host.as_os_str())
+        .arg("-L")
+        .arg(td.path())
+        .arg("-lprograms")
+        .arg("-o")
+        .arg(execpath.as_os_str())
+        .status()
+        .unwrap();
+    assert!(linker.success());
+
+    let mut p = Command::new(&execpath)
+        .env("LD_LIBRARY_PATH", td.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    p.stdin.as_mut().unwrap().write_all(b"A").unwrap();
+    let res = p.wait_with_output().unwrap();
+    assert_eq!(res.status.code(), Some(1));
+    assert_eq!(res.stdout, b"Hello World!\nAHello World!\n");
+}
+
+#[test]
+fn test_bare_metal() {
+    if !installed(&["nasm"]) {
+        return;
+    }
+    // The object file is written without linking, as it runs without an operating system
+    let td = tempdir().unwrap();
+    let objpath = td.path().join("hello.o");
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/helloworld.bf")
+        .arg("--target")
+        .arg("baremetal")
+        .arg("--output-address")
+        .arg("0x10000000")
+        .arg("--exit-symbol")
+        .arg("kernel_exit")
+        .arg("--output")
+        .arg(objpath.as_os_str())
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+    assert!(fs::read(objpath).unwrap().starts_with(b"\x7fELF"));
+}
+
+#[test]
+fn test_wrapping_add() {
+    if !toolchain_installed() {
+        return;
+    }
+    // 256 `+` leave the cell unchanged, but the add still sets the zero flag read by `[`
+    let wrap = "+".repeat(256);
+    let cases: [(String, &[u8], &[u8]); 3] = [
+        (format!(",{}[.[-]]", wrap), b"A", b"A"),
+        (format!(",{}[.[-]]", wrap), b"\0", b""),
+        (format!("{}[.]{}.", wrap, "+".repeat(65)), b"", b"A"),
+    ];
+    let td = tempdir().unwrap();
+    for (program, input, output) in &cases {
+        let source = td.path().join("wrap.bf");
+        fs::write(&source, program).unwrap();
+        let execpath = td.path().join("executable");
+        // Without compile-time evaluation, so the adds reach the optimizer passes
+        let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg(source.as_os_str())
+            .args(["-O1", "--eval-budget", "0", "--output"])
+            .arg(execpath.as_os_str())
+            .output()
+            .unwrap();
+        assert!(compiler.status.success());
+
+        let mut p = Command::new(&execpath)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        p.stdin.as_mut().unwrap().write_all(input).unwrap();
+        let res = p.wait_with_output().unwrap();
+        assert!(res.status.success());
+        assert_eq!(&res.stdout, output);
+    }
+}
+
+#[test]
+fn test_loop_after_loop() {
+    if !toolchain_installed() {
+        return;
+    }
+    // The second loop of `][`, or one after `[-]`, is jumped past, and its end reads the zero flag
+    let cases: [(&str, &[&str], &[u8], &[u8]); 3] = [
+        (
+            "<<[+-][[---[-]----[[++----+++++-----]]]-----]",
+            &["-O2", "--tape", "bidirectional"],
+            b"",
+            b"",
+        ),
+        (
+            ".[<>>[--++++>>]++++,---][-.>>>]>,--+>>><<--.",
+            &["-O1"],
+            b"Uw\x0b\xaa",
+            b"\x00\xfe",
+        ),
+        (">>>[--,>>>-][+++++++++[-[-][[+]>>>>]]<-]", &["-O1"], b"", b""),
+    ];
+    let td = tempdir().unwrap();
+    for (program, args, input, output) in &cases {
+        let source = td.path().join("loops.bf");
+        fs::write(&source, program).unwrap();
+        let execpath = td.path().join("executable");
+        let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg(source.as_os_str())
+            .args(*args)
+            .arg("--output")
+            .arg(execpath.as_os_str())
+            .output()
+            .unwrap();
+        assert!(compiler.status.success());
+
+        let mut p = Command::new(&execpath)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        p.stdin.take().unwrap().write_all(input).unwrap();
+        // A stale zero flag may keep the program looping forever
+        let mut waited = Duration::from_secs(0);
+        while p.try_wait().unwrap().is_none() {
+            if waited >= Duration::from_secs(10) {
+                p.kill().unwrap();
+                panic!("{:?} didn't exit", program);
+            }
+            thread::sleep(Duration::from_millis(10));
+            waited += Duration::from_millis(10);
+        }
+        let res = p.wait_with_output().unwrap();
+        assert!(res.status.success());
+        assert_eq!(&res.stdout, output, "{:?} with input {:?}", program, input);
+    }
+}
+
+#[test]
+fn test_interpreter_semantics() {
+    if !toolchain_installed() {
+        return;
+    }
+    // Wrapping cells, end of input, and moving left of the first cell after writing output
+    let cases: [(&str, &[u8]); 6] = [
+        ("-.+.", b""),
+        (",.,.", b""),
+        (",.,.", b"a"),
+        ("+.,[<<]", b"x"),
+        ("+.>,[<<].", b""),
+        (",[<,]", b"ab"),
+    ];
+    let td = tempdir().unwrap();
+    for (program, input) in &cases {
+        let expected = Interpreter::new().run_status(&parse(program), input);
+        let source = td.path().join("boundary.bf");
+        fs::write(&source, program).unwrap();
+        let execpath = td.path().join("executable");
+        // Both with the program evaluated at compile time and without it
+        for budget in &["0", "10000"] {
+            let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+                .unwrap()
+                .arg(source.as_os_str())
+                .args(["--eval-budget", *budget, "--output"])
+                .arg(execpath.as_os_str())
+                .output()
+                .unwrap();
+            assert!(compiler.status.success());
+
+            let mut p = Command::new(&execpath)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            p.stdin.as_mut().unwrap().write_all(input).unwrap();
+            let res = p.wait_with_output().unwrap();
+            assert_eq!(
+                (res.stdout, res.status.code().unwrap()),
+                expected,
+                "{:?} with input {:?} differs from the interpreter",
+                program,
+                input
+            );
+        }
+    }
+}
+
+#[test]
+fn test_disasm() {
+    if !toolchain_installed() || !installed(&["objdump"]) {
+        return;
+    }
+    let td = tempdir().unwrap();
+    let res = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/helloworld.bf")
+        .arg("--disasm")
+        .arg("--output")
+        .arg(td.path().join("hello").as_os_str())
+        .output()
+        .unwrap();
+    assert!(res.status.success());
+    let disassembly = String::from_utf8(res.stdout).unwrap();
+    assert!(disassembly.contains("Disassembly of section .text"));
+    assert!(td.path().join("hello").exists());
+}
+
+#[test]
+fn test_interpret() {
+    // Runs without a target, exiting with the status of a compiled binary
+    let td = tempdir().unwrap();
+    let source = td.path().join("underflow.bf");
+    fs::write(&source, ",.[<]").unwrap();
+    let mut p = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg(source.as_os_str())
+        .arg("--interpret")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    p.stdin.as_mut().unwrap().write_all(b"A").unwrap();
+    let res = p.wait_with_output().unwrap();
+    assert_eq!(res.status.code(), Some(1));
+    assert_eq!(res.stdout, b"A");
+
+    let res = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/helloworld.bf")
+        .arg("--interpret")
+        .output()
+        .unwrap();
+    assert!(res.status.success());
+    assert_eq!(res.stdout, b"Hello World!\n");
+
+    // The interpreter has no tape to the left of the first cell, nor one of a fixed size
+    for (flag, tape) in &[
+        ("--interpret", ["--tape", "bidirectional"]),
+        ("--dump-tape", ["--tape-size", "100"]),
+        ("--profile=/dev/null", ["--tape", "bidirectional"]),
+    ] {
+        let res = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg("examples/helloworld.bf")
+            .arg(flag)
+            .args(tape)
+            .output()
+            .unwrap();
+        assert!(!res.status.success());
+        assert!(res.stdout.is_empty());
+    }
+}
+
+#[test]
+fn test_interpret_prompt_before_input() {
+    // `A` written before stdin is read, then the input echoed
+    let td = tempdir().unwrap();
+    let source = td.path().join("prompt.bf");
+    fs::write(&source, "++++++++[>++++++++<-]>+.,.").unwrap();
+    let mut p = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg(source.as_os_str())
+        .arg("--interpret")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = p.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut prompt = [0];
+        stdout.read_exact(&mut prompt).unwrap();
+        sender.send((prompt, stdout)).unwrap();
+    });
+    let (prompt, mut stdout) = match receiver.recv_timeout(Duration::from_secs(10)) {
+        Ok(received) => received,
+        Err(_) => {
+            p.kill().unwrap();
+            panic!("No prompt before reading the input");
+        },
+    };
+    assert_eq!(&prompt, b"A");
+
+    p.stdin.take().unwrap().write_all(b"x").unwrap();
+    let mut rest = Vec::new();
+    stdout.read_to_end(&mut rest).unwrap();
+    assert!(p.wait().unwrap().success());
+    assert_eq!(rest, b"x");
+}
+
+#[test]
+fn test_scan_loops() {
+    if !toolchain_installed() {
+        return;
+    }
+    // Strides of 1, 2 and 3 in both directions, starting right of zeroed cells so that
+    // the scans left stop on the tape, and the last scan starting on a zero cell
+    let program = format!(
+        "{}{}{}[>]<.[<<]>.>>>>[>>>]<.[<]>.<<[<<<]>>.>[>>]<<.[>]>>[>]<.[>][<<<].",
+        ">".repeat(9),
+        ",>".repeat(12),
+        "<".repeat(12)
+    );
+    let td = tempdir().unwrap();
+    let source = td.path().join("scan.bf");
+    fs::write(&source, program).unwrap();
+    let execpath = td.path().join("executable");
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg(source.as_os_str())
+        .arg("--output")
+        .arg(execpath.as_os_str())
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+
+    let mut p = Command::new(&execpath)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    p.stdin.as_mut().unwrap().write_all(b"ab\0cdef\0g\0hi\0").unwrap();
+    let res = p.wait_with_output().unwrap();
+    assert!(res.status.success());
+    assert_eq!(res.stdout, b"bafcaeg\0");
+}
+
+#[test]
+fn test_wide_cells() {
+    if !toolchain_installed() {
+        return;
+    }
+    // Cell 1 counts 300 iterations, which only fits when it is wider than a byte
+    let td = tempdir().unwrap();
+    let source = td.path().join("wide.bf");
+    let program = "@cells 1 2 u32\n++++++++[>>>++++++++<<<-]>>>+<<<\n++++++++++++++++++++++++++++++[>++++++++++<-]>[>>.<<-]";
+    fs::write(&source, program).unwrap();
+    let execpath = td.path().join("executable");
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg(source.as_os_str())
+        .arg("--wide-cells")
+        .arg("--output")
+        .arg(execpath.as_os_str())
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+
+    let res = Command::new(execpath).output().unwrap();
+    assert!(res.status.success());
+    assert_eq!(res.stdout, vec![b'A'; 300]);
+}
+
+#[test]
+fn test_prompt_before_input() {
+    if !toolchain_installed() {
+        return;
+    }
+    // `?` written before the `,`, both computed at compile time and written by the loop
+    let td = tempdir().unwrap();
+    let source = td.path().join("prompt.bf");
+    fs::write(&source, "+++++++[>+++++++++<-]>.,.").unwrap();
+    for opt_level in &["-O0", "-O2"] {
+        let execpath = td.path().join("executable");
+        let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg(source.as_os_str())
+            .arg(opt_level)
+            .arg("--output")
+            .arg(execpath.as_os_str())
+            .output()
+            .unwrap();
+        assert!(compiler.status.success());
+
+        let mut p = Command::new(&execpath)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // Output held in a buffer until exit would never arrive, as the program waits for the input
+        let mut stdout = p.stdout.take().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut prompt = [0];
+            stdout.read_exact(&mut prompt).unwrap();
+            sender.send((prompt, stdout)).unwrap();
+        });
+        let (prompt, mut stdout) = match receiver.recv_timeout(Duration::from_secs(10)) {
+            Ok(received) => received,
+            Err(_) => {
+                p.kill().unwrap();
+                panic!("No prompt before reading the input with {}", opt_level);
+            },
+        };
+        assert_eq!(&prompt, b"?");
+
+        // Closing stdin after the answer
+        p.stdin.take().unwrap().write_all(b"y").unwrap();
+        let mut rest = Vec::new();
+        stdout.read_to_end(&mut rest).unwrap();
+        assert!(p.wait().unwrap().success());
+        assert_eq!(rest, b"y");
+    }
+}
+
+#[test]
+fn test_target_all() {
+    // Every target compiles, and the host one prints the same as the compile-time evaluation
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/helloworld.bf")
+        .arg("--target")
+        .arg("all")
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/helloworld.bf")
+        .arg("examples/cat.bf")
+        .arg("--target")
+        .arg("all")
+        .output()
+        .unwrap();
+    assert!(!compiler.status.success());
+}
+
+#[test]
+fn test_emit_dot() {
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/cat.bf")
+        .arg("--emit-dot")
+        .arg("-")
+        .arg("--skip-compilation")
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+    let dot = String::from_utf8(compiler.stdout).unwrap();
+    assert!(dot.starts_with("digraph cfg {\n"));
+    assert!(dot.contains("[label=\"zero\"]"));
+
+    // Printing the graph replaces the executable, like `--emit-llvm`
+    let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("examples/cat.bf")
+        .arg("--emit-dot")
+        .arg("-")
+        .output()
+        .unwrap();
+    assert!(compiler.status.success());
+    assert_eq!(String::from_utf8(compiler.stdout).unwrap(), dot);
+}
@@ -0,0 +1,138 @@
+//! Compiles randomly generated programs with full optimization,
+//! and compares the output of the binaries with the reference interpreter.
+//! `RANDOM_PROGRAMS` and `RANDOM_SEED` select how many programs are tested and which ones.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use tempfile::tempdir;
+
+use assert_cmd::prelude::*;
+
+use brain_opt::interpreter::Interpreter;
+use brain_opt::parse;
+
+/// Number of cells the generated programs use
+const CELLS: usize = 8;
+/// Maximum nesting depth of loops
+const MAX_DEPTH: usize = 2;
+/// Programs writing more are skipped, as evaluating them at compile time takes long
+const MAX_OUTPUT: usize = 1000;
+
+/// Xorshift generator, so that a failing seed reproduces the same programs
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform value in `0..n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Appends `count` random commands to `source`, starting and ending at cell `pointer`.
+/// Cells that count the iterations of the enclosing loops are not modified,
+/// and each loop decrements or increments its own counter once per iteration,
+/// so every program terminates.
+fn generate(rng: &mut Rng, source: &mut String, pointer: &mut usize, counters: &[usize], count: usize) {
+    for _ in 0..count {
+        let writable = !counters.contains(pointer);
+        match rng.below(12) {
+            0 | 1 if writable => source.push('+'),
+            2 | 3 if writable => source.push('-'),
+            4 | 5 if *pointer + 1 < CELLS => {
+                source.push('>');
+                *pointer += 1;
+            },
+            6 | 7 if *pointer > 0 => {
+                source.push('<');
+                *pointer -= 1;
+            },
+            8 => source.push('.'),
+            9 if writable => source.push(','),
+            10 | 11 if writable && counters.len() < MAX_DEPTH => {
+                let start = *pointer;
+                let mut inner = counters.to_vec();
+                inner.push(start);
+                source.push('[');
+                let length = rng.below(8);
+                generate(rng, source, pointer, &inner, length);
+                while *pointer < start {
+                    source.push('>');
+                    *pointer += 1;
+                }
+                while *pointer > start {
+                    source.push('<');
+                    *pointer -= 1;
+                }
+                source.push(if rng.below(4) == 0 { '+' } else { '-' });
+                source.push(']');
+            },
+            _ => {},
+        }
+    }
+}
+
+#[test]
+fn test_random_programs() {
+    // Only where NASM and the linker are installed, as the programs are assembled and linked
+    if ["nasm", "clang"]
+        .iter()
+        .any(|command| Command::new(command).arg("-v").output().is_err())
+    {
+        return;
+    }
+    let count: usize = env::var("RANDOM_PROGRAMS").map_or(25, |v| v.parse().unwrap());
+    let seed: u64 = env::var("RANDOM_SEED").map_or(1, |v| v.parse().unwrap());
+    let mut rng = Rng(seed.max(1));
+    let td = tempdir().unwrap();
+
+    for index in 0..count {
+        let mut source = String::new();
+        let length = 1 + rng.below(40);
+        generate(&mut rng, &mut source, &mut 0, &[], length);
+        let input: Vec<u8> = (0..rng.below(6)).map(|_| rng.next() as u8).collect();
+        let expected = Interpreter::new().run_collect(&parse(&source), &input).unwrap();
+        if expected.len() > MAX_OUTPUT {
+            continue;
+        }
+
+        let srcpath = td.path().join(format!("program{}.bf", index));
+        let execpath = td.path().join(format!("program{}", index));
+        fs::write(&srcpath, &source).unwrap();
+        let compiler = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg(srcpath.as_os_str())
+            .arg("--output")
+            .arg(execpath.as_os_str())
+            .output()
+            .unwrap();
+        assert!(
+            compiler.status.success(),
+            "Compiling {:?} failed:\n{}",
+            source,
+            String::from_utf8_lossy(&compiler.stderr)
+        );
+
+        let mut p = Command::new(execpath)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        p.stdin.as_mut().unwrap().write_all(&input).unwrap();
+        let res = p.wait_with_output().unwrap();
+        assert!(res.status.success(), "{:?} exited with {}", source, res.status);
+        assert_eq!(
+            res.stdout, expected,
+            "{:?} with input {:?} differs from the interpreter",
+            source, input
+        );
+    }
+}
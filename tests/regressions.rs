@@ -0,0 +1,77 @@
+//! Replays the programs in `tests/regressions` through the parser, the compiler and the interpreter.
+//! Each `.bf` file is an input that once crashed or hung, with an optional `.txt` note of the same name.
+//! Errors are fine, panics and hangs are not, so adding a regression is just adding a file.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use brain_opt::interpreter::{BufferIO, Executor, Interpreter};
+use brain_opt::{compile_tokens, try_parse, CompileOptions, ABI};
+
+/// Steps evaluated at compile time, small so that programs running forever stop quickly
+const EVAL_BUDGET: usize = 10_000;
+/// Tokens executed by the interpreter before giving up
+const MAX_STEPS: usize = 100_000;
+/// Time a single program may take, the budgets above bound all loops of the program itself
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+fn corpus() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("regressions");
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "bf"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn replay(source: &str) {
+    let tokens = match try_parse(source) {
+        Ok(tokens) => tokens,
+        Err(_) => return,
+    };
+
+    let options = CompileOptions {
+        eval_budget: EVAL_BUDGET,
+        ..CompileOptions::default()
+    };
+    let _ = compile_tokens(tokens.clone(), ABI::Linux, &options);
+
+    let mut executor = Executor::new(Interpreter::new(), &tokens);
+    let mut io = BufferIO::new(b"");
+    for _ in 0..MAX_STEPS {
+        if executor.is_done() || executor.step(&mut io).is_err() {
+            break;
+        }
+    }
+}
+
+#[test]
+fn test_regressions() {
+    let paths = corpus();
+    assert!(!paths.is_empty());
+    for path in paths {
+        println!("{}", path.display());
+        let source = String::from_utf8_lossy(&fs::read(&path).unwrap()).into_owned();
+        let (sender, receiver) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            replay(&source);
+            sender.send(()).unwrap();
+        });
+        match receiver.recv_timeout(TIMEOUT) {
+            Ok(()) => worker.join().unwrap(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                panic!("{} panicked", path.display());
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                panic!("{} didn't finish in {:?}", path.display(), TIMEOUT)
+            },
+        }
+    }
+}
@@ -0,0 +1,2 @@
+{!mov rax, 1
++++
\ No newline at end of file